@@ -1,30 +1,49 @@
 use easy_fs::{
     BlockDevice,
     EasyFileSystem,
+    IoError,
+    set_time_source,
+    FEATURE_EXTENTS,
+    FEATURE_SNAPSHOTS,
+    FEATURE_COMPRESSION,
 };
+#[cfg(test)]
+use easy_fs::{get_block_cache, block_cache_sync, block_cache_reload, crc32c, OpenError};
+#[cfg(test)]
+use fat32_fs::FatFileSystem;
 use std::fs::{File, OpenOptions, read_dir};
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::sync::Mutex;
 use std::sync::Arc;
-use clap::{Arg, App};
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{Arg, App, ArgMatches, SubCommand};
 
 const BLOCK_SZ: usize = 512;
 
+fn unix_time(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+}
+
+fn host_time() -> u32 {
+    unix_time(SystemTime::now())
+}
+
 struct BlockFile(Mutex<File>);
 
 impl BlockDevice for BlockFile {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    /// Reading past the end of the image is an error, not a short block.
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), IoError> {
         let mut file = self.0.lock().unwrap();
         file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|_| IoError::Device(block_id))
     }
 
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), IoError> {
         let mut file = self.0.lock().unwrap();
         file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+            .and_then(|_| file.write_all(buf))
+            .map_err(|_| IoError::Device(block_id))
     }
 }
 
@@ -46,25 +65,93 @@ fn easy_fs_pack() -> std::io::Result<()> {
             .takes_value(true)
             .help("Executable target dir(with backslash)")    
         )
+        .arg(Arg::with_name("extents")
+            .short("e")
+            .long("extents")
+            .help("Map blocks of files by extents")
+        )
+        .arg(Arg::with_name("block-size")
+            .short("b")
+            .long("block-size")
+            .takes_value(true)
+            .help("Bytes in a block, 512 by default, or 1024, 2048, 4096")
+        )
+        .arg(Arg::with_name("label")
+            .short("l")
+            .long("label")
+            .takes_value(true)
+            .help("Volume label, at most 16 bytes")
+        )
+        .arg(Arg::with_name("no-compress")
+            .long("no-compress")
+            .help("Store apps as they are instead of compressed")
+        )
+        .arg(Arg::with_name("snapshots")
+            .short("S")
+            .long("snapshots")
+            .help("Allow taking snapshots of the image")
+        )
+        .subcommand(SubCommand::with_name("snapshot")
+            .about("Take a snapshot of the image in the target dir")
+        )
+        .subcommand(SubCommand::with_name("list-snapshots")
+            .about("List snapshots of the image in the target dir")
+        )
+        .subcommand(SubCommand::with_name("rollback")
+            .about("Bring the image in the target dir back to a snapshot")
+            .arg(Arg::with_name("ID").required(true))
+        )
+        .subcommand(SubCommand::with_name("delete-snapshot")
+            .about("Delete a snapshot of the image in the target dir")
+            .arg(Arg::with_name("ID").required(true))
+        )
+        .subcommand(SubCommand::with_name("defrag")
+            .about("Move every file of the image in the target dir into one run of blocks")
+        )
+        .subcommand(SubCommand::with_name("shrink")
+            .about("Move blocks of the image in the target dir down and cut it after them")
+        )
         .get_matches();
-    let src_path = matches.value_of("source").unwrap();
     let target_path = matches.value_of("target").unwrap();
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        return easy_fs_command(target_path, name, sub_matches);
+    }
+    let src_path = matches.value_of("source").unwrap();
     println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+    let block_size: usize = matches.value_of("block-size")
+        .map_or(BLOCK_SZ, |size| size.parse().expect("Invalid block size!"));
+    assert!(
+        block_size.is_power_of_two() && (BLOCK_SZ..=4096).contains(&block_size),
+        "Invalid block size!",
+    );
+    set_time_source(host_time);
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(8192 * 512).unwrap();
+        f.set_len(8192 * block_size as u64).unwrap();
         f
     })));
-    // 4MiB, at most 4095 files
-    let efs = EasyFileSystem::create(
+    let mut features = if matches.is_present("extents") { FEATURE_EXTENTS } else { 0 };
+    if matches.is_present("snapshots") {
+        features |= FEATURE_SNAPSHOTS;
+    }
+    if !matches.is_present("no-compress") {
+        features |= FEATURE_COMPRESSION;
+    }
+    // 8192 blocks, 4MiB with at most 4095 files by default, the inode bitmap
+    // and so the inode area grow with the block size
+    let efs = EasyFileSystem::create_with_block_size(
         block_file.clone(),
+        block_size,
         8192,
         1,
-    );
+        features,
+    ).expect("Error when formatting easy-fs!");
+    efs.lock().set_label(matches.value_of("label").unwrap_or("")).unwrap();
+    efs.lock().set_uuid(rand::random()).unwrap();
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
     let apps: Vec<_> = read_dir(src_path)
         .unwrap()
@@ -81,14 +168,62 @@ fn easy_fs_pack() -> std::io::Result<()> {
         let mut all_data: Vec<u8> = Vec::new();
         host_file.read_to_end(&mut all_data).unwrap();
         // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
+        let inode = root_inode.create(app.as_str()).unwrap().unwrap();
         // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+        inode.write_at(0, all_data.as_slice()).unwrap();
+        // keep the mtime of the host file
+        let mtime = unix_time(host_file.metadata().unwrap().modified().unwrap());
+        inode.set_times(mtime, mtime).unwrap();
+        // apps belong to root and everyone may run them
+        inode.set_mode(0o755).unwrap();
+        // mostly zero padding, and the kernel only reads them
+        inode.compress().unwrap();
     }
     // list apps
-    for app in root_inode.ls() {
+    for app in root_inode.ls().unwrap() {
         println!("{}", app);
     }
+    efs.lock().unmount().expect("Error when writing back easy-fs!");
+    Ok(())
+}
+
+/// Run a command on the image packed before.
+fn easy_fs_command(target_path: &str, command: &str, matches: &ArgMatches) -> std::io::Result<()> {
+    set_time_source(host_time);
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("{}{}", target_path, "fs.img"))?
+    )));
+    let efs = EasyFileSystem::open(block_file.clone()).expect("Error when opening easy-fs!");
+    let id = || -> usize {
+        matches.value_of("ID").unwrap().parse().expect("Invalid snapshot id!")
+    };
+    let mut efs = efs.lock();
+    match command {
+        "snapshot" => match efs.snapshot().unwrap() {
+            Some(id) => println!("snapshot {}", id),
+            None => println!("snapshots are not enabled or the table is full"),
+        },
+        "list-snapshots" => for (id, time) in efs.snapshots().unwrap() {
+            println!("{}\t{}", id, time);
+        },
+        "rollback" => assert!(efs.rollback(id()).unwrap(), "No such snapshot!"),
+        "delete-snapshot" => assert!(efs.delete_snapshot(id()).unwrap(), "No such snapshot!"),
+        "defrag" => println!("{} files moved", efs.defrag().unwrap()),
+        "shrink" => {
+            let total_blocks = efs.shrink().unwrap().expect("Delete snapshots before shrinking!");
+            efs.unmount().expect("Error when writing back easy-fs!");
+            // blocks cut off are written back with the rest, so only now
+            let len = total_blocks as u64 * efs.block_device.block_size() as u64;
+            block_file.0.lock().unwrap().set_len(len)?;
+            println!("shrunk to {} blocks", total_blocks);
+            return Ok(());
+        }
+        _ => unreachable!(),
+    }
+    efs.unmount().expect("Error when writing back easy-fs!");
     Ok(())
 }
 
@@ -107,29 +242,29 @@ fn efs_test() -> std::io::Result<()> {
         block_file.clone(),
         4096,
         1,
-    );
-    let efs = EasyFileSystem::open(block_file.clone());
+    ).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
     let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
+    root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
+    for name in root_inode.ls().unwrap() {
         println!("{}", name);
     }
-    let filea = root_inode.find("filea").unwrap();
+    let filea = root_inode.find("filea").unwrap().unwrap();
     let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
+    filea.write_at(0, greet_str.as_bytes()).unwrap();
     //let mut buffer = [0u8; 512];
     let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
+    let len = filea.read_at(0, &mut buffer).unwrap();
     assert_eq!(
         greet_str,
         core::str::from_utf8(&buffer[..len]).unwrap(),
     );
 
     let mut random_str_test = |len: usize| {
-        filea.clear();
+        filea.clear().unwrap();
         assert_eq!(
-            filea.read_at(0, &mut buffer),
+            filea.read_at(0, &mut buffer).unwrap(),
             0,
         );
         let mut str = String::new();
@@ -138,12 +273,12 @@ fn efs_test() -> std::io::Result<()> {
         for _ in 0..len {
             str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
         }
-        filea.write_at(0, str.as_bytes());
+        filea.write_at(0, str.as_bytes()).unwrap();
         let mut read_buffer = [0u8; 127];
         let mut offset = 0usize;
         let mut read_str = String::new();
         loop {
-            let len = filea.read_at(offset, &mut read_buffer);
+            let len = filea.read_at(offset, &mut read_buffer).unwrap();
             if len == 0 {
                 break;
             }
@@ -165,4 +300,1040 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(2000 * BLOCK_SZ);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn efs_times_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_times.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    set_time_source(host_time);
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = host_time();
+    let filea = root_inode.create("filea").unwrap().unwrap();
+    let (atime, mtime, ctime) = filea.times().unwrap();
+    assert!(atime >= before && mtime >= before && ctime >= before);
+    assert!(root_inode.times().unwrap().1 >= before);
+    // an old file being read
+    filea.set_times(1000, 1000).unwrap();
+    assert_eq!(filea.times().unwrap().1, 1000);
+    filea.read_at(0, &mut [0u8; 16]).unwrap();
+    assert!(filea.times().unwrap().0 >= before);
+    // a recent atime is not updated again
+    let recent = host_time() + 100;
+    filea.set_times(recent, 1000).unwrap();
+    filea.read_at(0, &mut [0u8; 16]).unwrap();
+    assert_eq!(filea.times().unwrap().0, recent);
+    filea.write_at(0, b"hello").unwrap();
+    assert!(filea.times().unwrap().1 >= before);
+    Ok(())
+}
+
+#[test]
+fn efs_unlink_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_unlink.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.attr().unwrap(), (0o1777, 0, 0));
+    let filea = root_inode.create("filea").unwrap().unwrap();
+    root_inode.create("fileb").unwrap().unwrap();
+    assert_eq!(filea.attr().unwrap(), (0o644, 0, 0));
+    filea.set_owner(1000, 100).unwrap();
+    filea.set_mode(0o600).unwrap();
+    assert_eq!(filea.attr().unwrap(), (0o600, 1000, 100));
+    filea.write_at(0, &[1u8; 100 * BLOCK_SZ]).unwrap();
+    assert!(root_inode.unlink("filea").unwrap());
+    assert!(!root_inode.unlink("filea").unwrap());
+    assert!(root_inode.find("filea").unwrap().is_none());
+    assert_eq!(root_inode.ls().unwrap(), ["fileb"]);
+    // the entry is reused, the inode and blocks of filea are kept for its
+    // handle
+    let filec = root_inode.create("filec").unwrap().unwrap();
+    assert_eq!(root_inode.ls().unwrap(), ["filec", "fileb"]);
+    assert_eq!(filec.attr().unwrap(), (0o644, 0, 0));
+    assert_eq!(filec.read_at(0, &mut [0u8; 16]).unwrap(), 0);
+    filec.write_at(0, &[2u8; 100 * BLOCK_SZ]).unwrap();
+    let mut buffer = vec![0u8; 100 * BLOCK_SZ];
+    assert_eq!(filea.read_at(0, &mut buffer).unwrap(), buffer.len());
+    assert!(buffer.iter().all(|b| *b == 1));
+    // and freed with it, filea is inode 1
+    let inode_allocated = || {
+        let efs = efs.lock();
+        efs.inode_bitmap.is_allocated(&efs.block_device, 1).unwrap()
+    };
+    assert!(inode_allocated());
+    drop(filea);
+    assert!(!inode_allocated());
+    Ok(())
+}
+
+#[test]
+fn efs_extents_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_extents.img")?;
+        f.set_len(8192 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create_with_features(block_file.clone(), 8192, 1, FEATURE_EXTENTS).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap().unwrap();
+    let fileb = root_inode.create("fileb").unwrap().unwrap();
+    // growing both files block by block scatters them into many extents
+    let block_data = |file: u8, block: usize| [file ^ block as u8; BLOCK_SZ];
+    for block in 0..300 {
+        filea.write_at(block * BLOCK_SZ, &block_data(0xa, block)).unwrap();
+        fileb.write_at(block * BLOCK_SZ, &block_data(0xb, block)).unwrap();
+    }
+    let mut buffer = [0u8; BLOCK_SZ];
+    for block in 0..300 {
+        filea.read_at(block * BLOCK_SZ, &mut buffer).unwrap();
+        assert_eq!(buffer, block_data(0xa, block));
+        fileb.read_at(block * BLOCK_SZ, &mut buffer).unwrap();
+        assert_eq!(buffer, block_data(0xb, block));
+    }
+    // reading on after extents in front were merged through another handle
+    let filea2 = root_inode.find("filea").unwrap().unwrap();
+    filea.read_at(200 * BLOCK_SZ, &mut buffer).unwrap();
+    filea2.punch_hole(10 * BLOCK_SZ, 3 * BLOCK_SZ).unwrap();
+    for block in 200..300 {
+        filea.read_at(block * BLOCK_SZ, &mut buffer).unwrap();
+        assert_eq!(buffer, block_data(0xa, block));
+    }
+    filea.read_at(10 * BLOCK_SZ, &mut buffer).unwrap();
+    assert_eq!(buffer, [0u8; BLOCK_SZ]);
+    drop(filea2);
+    // all blocks are back once the files are removed, so a large file
+    // fits in again and takes a few extents
+    filea.clear().unwrap();
+    assert!(root_inode.unlink("fileb").unwrap());
+    let data: Vec<u8> = (0..3000 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    assert_eq!(filea.write_at(0, &data).unwrap(), data.len());
+    let mut read_data = vec![0u8; data.len()];
+    assert_eq!(filea.read_at(0, &mut read_data).unwrap(), data.len());
+    assert_eq!(data, read_data);
+    Ok(())
+}
+
+#[test]
+fn efs_extent_limit_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_extent_limit.img")?;
+        f.set_len(20480 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create_with_features(block_file.clone(), 20480, 1, FEATURE_EXTENTS).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // files grown block by block in turn leave single free blocks between
+    // each other once every other one is removed, with no free space
+    // after them once the image is cut
+    let files: Vec<_> = (0..256)
+        .map(|i| root_inode.create(&format!("file{}", i)).unwrap().unwrap())
+        .collect();
+    for block in 0..64 {
+        for file in files.iter() {
+            assert_eq!(file.write_at(block * BLOCK_SZ, &[0xa5u8; BLOCK_SZ]).unwrap(), BLOCK_SZ);
+        }
+    }
+    assert!(efs.lock().shrink().unwrap().is_some());
+    for i in (0..256).step_by(2) {
+        assert!(root_inode.unlink(&format!("file{}", i)).unwrap());
+    }
+    drop(files);
+    // every free block, the data and extent blocks of the removed files, is
+    // taken for the many extents needed and given back once they do not
+    // fit in the inode
+    let filea = root_inode.create("filea").unwrap().unwrap();
+    for _ in 0..2 {
+        assert_eq!(filea.fallocate(0, 128 * 65 * BLOCK_SZ, false), Err(IoError::TooLarge));
+        let mut buffer = [0u8; BLOCK_SZ];
+        assert_eq!(filea.read_at(0, &mut buffer).unwrap(), 0);
+    }
+    filea.fallocate(0, 4000 * BLOCK_SZ, false).unwrap();
+    let mut buffer = [0xffu8; BLOCK_SZ];
+    assert_eq!(filea.read_at(3999 * BLOCK_SZ, &mut buffer).unwrap(), BLOCK_SZ);
+    assert!(buffer.iter().all(|b| *b == 0));
+    Ok(())
+}
+
+#[test]
+fn efs_large_file_test() -> std::io::Result<()> {
+    // 10MiB, enough for a file using the triple indirect block
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_large.img")?;
+        f.set_len(20480 * 512).unwrap();
+        f
+    })));
+    EasyFileSystem::create(block_file.clone(), 20480, 1).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap().unwrap();
+    let data: Vec<u8> = (0..17000 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    assert_eq!(filea.write_at(0, &data).unwrap(), data.len());
+    let mut read_data = vec![0u8; data.len()];
+    assert_eq!(filea.read_at(0, &mut read_data).unwrap(), data.len());
+    assert_eq!(data, read_data);
+    // everything is freed, so the file can be written again
+    filea.clear().unwrap();
+    assert_eq!(filea.write_at(0, &data).unwrap(), data.len());
+    assert_eq!(filea.read_at(16999 * BLOCK_SZ, &mut read_data[..BLOCK_SZ]).unwrap(), BLOCK_SZ);
+    assert_eq!(read_data[..BLOCK_SZ], data[16999 * BLOCK_SZ..]);
+    Ok(())
+}
+
+#[test]
+fn efs_sparse_test() -> std::io::Result<()> {
+    for &features in [0, FEATURE_EXTENTS].iter() {
+        // 2MiB with about 3000 data blocks
+        let block_file = Arc::new(BlockFile(Mutex::new({
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("target/fs_sparse.img")?;
+            f.set_len(4096 * 512).unwrap();
+            f
+        })));
+        EasyFileSystem::create_with_features(block_file.clone(), 4096, 1, features).unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        // far beyond the free space if the gap were allocated
+        let filea = root_inode.create("filea").unwrap().unwrap();
+        assert_eq!(filea.write_at(4 << 20, b"x").unwrap(), 1);
+        let mut buffer = [0xffu8; BLOCK_SZ];
+        assert_eq!(filea.read_at(1 << 20, &mut buffer).unwrap(), BLOCK_SZ);
+        assert!(buffer.iter().all(|b| *b == 0));
+        assert_eq!(filea.read_at(4 << 20, &mut buffer).unwrap(), 1);
+        assert_eq!(buffer[0], b'x');
+        // fill a hole in the middle
+        assert_eq!(filea.write_at(2 << 20, b"y").unwrap(), 1);
+        assert_eq!(filea.read_at(2 << 20, &mut buffer[..2]).unwrap(), 2);
+        assert_eq!(buffer[..2], [b'y', 0]);
+        // nothing to write at or past the end
+        assert_eq!(filea.write_at((4 << 20) + 1, b""), Ok(0));
+        assert_eq!(filea.write_at(5 << 20, b""), Ok(0));
+        assert_eq!(filea.read_at(4 << 20, &mut buffer).unwrap(), 1);
+        // punching keeps the size, partial blocks at both ends are zeroed
+        let fileb = root_inode.create("fileb").unwrap().unwrap();
+        let data: Vec<u8> = (0..2000 * BLOCK_SZ).map(|i| (i % 251) as u8 | 1).collect();
+        assert_eq!(fileb.write_at(0, &data).unwrap(), data.len());
+        fileb.punch_hole(5 * BLOCK_SZ + 100, 10 * BLOCK_SZ).unwrap();
+        let mut read_data = vec![0u8; data.len()];
+        assert_eq!(fileb.read_at(0, &mut read_data).unwrap(), data.len());
+        for (i, (read, written)) in read_data.iter().zip(data.iter()).enumerate() {
+            let punched = (5 * BLOCK_SZ + 100..15 * BLOCK_SZ + 100).contains(&i);
+            assert_eq!(*read, if punched { 0 } else { *written });
+        }
+        // the punched blocks are free again, there is no room otherwise
+        fileb.punch_hole(0, data.len()).unwrap();
+        let filec = root_inode.create("filec").unwrap().unwrap();
+        assert_eq!(filec.write_at(0, &data).unwrap(), data.len());
+        assert_eq!(fileb.read_at(0, &mut read_data[..BLOCK_SZ]).unwrap(), BLOCK_SZ);
+        assert!(read_data[..BLOCK_SZ].iter().all(|b| *b == 0));
+        // allocating without growing stops at the end of the file
+        let filed = root_inode.create("filed").unwrap().unwrap();
+        filed.fallocate(0, BLOCK_SZ, true).unwrap();
+        assert_eq!(filed.read_at(0, &mut buffer).unwrap(), 0);
+        filed.fallocate(0, 3 * BLOCK_SZ, false).unwrap();
+        assert_eq!(filed.read_at(2 * BLOCK_SZ, &mut buffer).unwrap(), BLOCK_SZ);
+        assert!(buffer.iter().all(|b| *b == 0));
+        // the size is 32 bits, and just over 1000MiB is reached through
+        // indirect3
+        let (largest, too_large) = if features == 0 {
+            (1000 << 20, 1100 << 20)
+        } else {
+            (u32::MAX as usize - 1, u32::MAX as usize)
+        };
+        let filee = root_inode.create("filee").unwrap().unwrap();
+        assert_eq!(filee.write_at(largest, b"x").unwrap(), 1);
+        assert_eq!(filee.write_at(too_large, b"x"), Err(IoError::TooLarge));
+        assert_eq!(filee.fallocate(largest, too_large - largest + 1, false), Err(IoError::TooLarge));
+        assert_eq!(filee.read_at(largest, &mut buffer).unwrap(), 1);
+        assert_eq!(buffer[0], b'x');
+        assert!(root_inode.unlink("filee").unwrap());
+        assert!(root_inode.unlink("filea").unwrap());
+        assert!(root_inode.unlink("filec").unwrap());
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_block_size_test() -> std::io::Result<()> {
+    for &block_size in [1024usize, 4096].iter() {
+        // 16MiB
+        let total_blocks = (16 << 20) / block_size;
+        let block_file = Arc::new(BlockFile(Mutex::new({
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("target/fs_block_size.img")?;
+            f.set_len(16 << 20).unwrap();
+            f
+        })));
+        EasyFileSystem::create_with_block_size(
+            block_file.clone(),
+            block_size,
+            total_blocks as u32,
+            1,
+            0,
+        ).unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        // enough files for the root directory to use several blocks
+        for i in 0..100 {
+            root_inode.create(format!("file{}", i).as_str()).unwrap().unwrap();
+        }
+        // large enough for indirect2 with 1KiB blocks
+        let data: Vec<u8> = (0..(3 << 20)).map(|i| (i % 247) as u8).collect();
+        let filea = root_inode.find("file42").unwrap().unwrap();
+        assert_eq!(filea.write_at(100, &data).unwrap(), data.len());
+        drop(root_inode);
+        drop(filea);
+        // the block size is read back from the image
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        assert_eq!(root_inode.ls().unwrap().len(), 100);
+        let filea = root_inode.find("file42").unwrap().unwrap();
+        let mut read_data = vec![0u8; data.len() + 100];
+        assert_eq!(filea.read_at(0, &mut read_data).unwrap(), data.len() + 100);
+        assert!(read_data[..100].iter().all(|b| *b == 0));
+        assert_eq!(read_data[100..], data[..]);
+        filea.clear().unwrap();
+        assert!(root_inode.unlink("file42").unwrap());
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_format_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_format.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    efs.lock().set_label("a very long volume label").unwrap();
+    efs.lock().set_uuid([7; 16]).unwrap();
+    efs.lock().unmount().unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    assert!(efs.lock().was_clean());
+    assert_eq!(efs.lock().label().unwrap(), "a very long volu");
+    assert_eq!(efs.lock().uuid().unwrap(), [7; 16]);
+    // it is still marked mounted without `unmount`
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    assert!(!efs.lock().was_clean());
+    efs.lock().unmount().unwrap();
+    // change the SuperBlock on the image behind the back of the cache,
+    // its checksum at 84 is kept valid
+    let patch = |offset: usize, value: u32| {
+        let mut sector = [0u8; BLOCK_SZ];
+        block_file.read_block(0, &mut sector).unwrap();
+        sector[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+        let checksum = crc32c(&sector[..84]);
+        sector[84..88].copy_from_slice(&checksum.to_le_bytes());
+        block_file.write_block(0, &sector).unwrap();
+    };
+    let mut sector = [0u8; BLOCK_SZ];
+    block_file.read_block(0, &mut sector).unwrap();
+    sector[40] ^= 1;
+    block_file.write_block(0, &sector).unwrap();
+    assert_eq!(EasyFileSystem::open(block_file.clone()).err(), Some(OpenError::BadChecksum));
+    // incompatible features at 24, version at 28, compatible features at 36
+    patch(40, 0);
+    patch(36, 1 << 31);
+    assert!(EasyFileSystem::open(block_file.clone()).is_ok());
+    let device: Arc<dyn BlockDevice> = block_file.clone();
+    block_cache_sync(&device).unwrap();
+    patch(24, 1 << 31);
+    assert_eq!(
+        EasyFileSystem::open(block_file.clone()).err(),
+        Some(OpenError::UnsupportedFeatures(1 << 31)),
+    );
+    patch(24, 0);
+    patch(28, 100);
+    assert_eq!(
+        EasyFileSystem::open(block_file.clone()).err(),
+        Some(OpenError::UnsupportedVersion(100)),
+    );
+    // the magics of the layouts before timestamps and before modes
+    for &magic in [0x3b800001, 0x3b800002].iter() {
+        patch(0, magic);
+        assert_eq!(
+            EasyFileSystem::open(block_file.clone()).err(),
+            Some(OpenError::UnsupportedVersion(0)),
+        );
+    }
+    patch(0, 0);
+    assert_eq!(EasyFileSystem::open(block_file.clone()).err(), Some(OpenError::NotEasyFs));
+    Ok(())
+}
+
+#[test]
+fn efs_checksum_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_checksum.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // 15 entries fit in a block of the directory
+    for i in 0..20 {
+        root_inode.create(format!("file{}", i).as_str()).unwrap().unwrap();
+    }
+    let file0 = root_inode.find("file0").unwrap().unwrap();
+    file0.write_at(0, &[1u8; 30 * BLOCK_SZ]).unwrap();
+    // looking the changed block of an inode up without checksum keeps its
+    // checksum
+    file0.set_mode(0o600).unwrap();
+    get_block_cache(2, block_file.clone()).unwrap();
+    assert!(efs.lock().verify().is_empty());
+    efs.lock().unmount().unwrap();
+    // inodes are 128 bytes from block 2, root is inode 0 and file{i} is
+    // inode i + 1; direct pointers are at 4 and indirect1 at 92
+    let word = |block: &[u8], offset: usize| {
+        u32::from_ne_bytes([block[offset], block[offset + 1], block[offset + 2], block[offset + 3]])
+    };
+    let mut block = [0u8; BLOCK_SZ];
+    block_file.read_block(2, &mut block).unwrap();
+    let dir_block = word(&block, 8) as usize;
+    let indirect_block = word(&block, 128 + 92) as usize;
+    let corrupt = |block_id: usize, offset: usize| {
+        let mut block = [0u8; BLOCK_SZ];
+        block_file.read_block(block_id, &mut block).unwrap();
+        block[offset] ^= 0xff;
+        block_file.write_block(block_id, &block).unwrap();
+    };
+    // the second block of the directory, the inode of file5 and the
+    // indirect block of file0
+    corrupt(dir_block, 0);
+    corrupt(3, 256 + 8);
+    corrupt(indirect_block, 0);
+    // a new device so that nothing comes from the cache
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open("target/fs_checksum.img")?
+    )));
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.find("file19").err(), Some(IoError::Corrupted(dir_block)));
+    let file5 = root_inode.find("file5").unwrap().unwrap();
+    assert_eq!(file5.attr().err(), Some(IoError::Corrupted(3)));
+    let file0 = root_inode.find("file0").unwrap().unwrap();
+    let mut buffer = [0u8; BLOCK_SZ];
+    // direct blocks can still be read
+    assert_eq!(file0.read_at(0, &mut buffer), Ok(BLOCK_SZ));
+    assert_eq!(
+        file0.read_at(25 * BLOCK_SZ, &mut buffer).err(),
+        Some(IoError::Corrupted(indirect_block)),
+    );
+    let errors = efs.lock().verify();
+    assert_eq!(errors.len(), 3);
+    for &block_id in [3, dir_block, indirect_block].iter() {
+        assert!(errors.contains(&IoError::Corrupted(block_id)));
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_device_error_test() -> std::io::Result<()> {
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_device_error.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap().unwrap();
+    filea.write_at(0, &[1u8; 20 * BLOCK_SZ]).unwrap();
+    efs.lock().unmount().unwrap();
+    // cut the image in front of the 11th block of filea, whose direct
+    // pointers are at 4 of inode 1
+    let mut block = [0u8; BLOCK_SZ];
+    block_file.read_block(2, &mut block).unwrap();
+    let offset = 128 + 4 + 10 * 4;
+    let lost_block = u32::from_ne_bytes([
+        block[offset], block[offset + 1], block[offset + 2], block[offset + 3],
+    ]) as usize;
+    block_file.0.lock().unwrap().set_len((lost_block * BLOCK_SZ) as u64)?;
+    // a read-only device so that writing back fails as well
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).open("target/fs_device_error.img")?
+    )));
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.find("filea").unwrap().unwrap();
+    let mut buffer = [0u8; BLOCK_SZ];
+    assert_eq!(filea.read_at(0, &mut buffer), Ok(BLOCK_SZ));
+    assert_eq!(
+        filea.read_at(10 * BLOCK_SZ, &mut buffer).err(),
+        Some(IoError::Device(lost_block)),
+    );
+    assert_eq!(efs.lock().unmount().err(), Some(IoError::Device(0)));
+    Ok(())
+}
+
+#[test]
+fn efs_no_space_test() -> std::io::Result<()> {
+    for &features in [0, FEATURE_EXTENTS].iter() {
+        // 2MiB with about 3000 data blocks
+        let block_file = Arc::new(BlockFile(Mutex::new({
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("target/fs_no_space.img")?;
+            f.set_len(4096 * 512).unwrap();
+            f
+        })));
+        EasyFileSystem::create_with_features(block_file.clone(), 4096, 1, features).unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let filea = root_inode.create("filea").unwrap().unwrap();
+        let fileb = root_inode.create("fileb").unwrap().unwrap();
+        // grow both files block by block until the disk is full, the last
+        // blocks also need indirect blocks or blocks of extents
+        let fill = || {
+            let mut written = 0usize;
+            for block in 0.. {
+                for file in [&filea, &fileb].iter() {
+                    match file.write_at(block * BLOCK_SZ, &[2u8; BLOCK_SZ]) {
+                        Ok(_) => written += 1,
+                        Err(err) => {
+                            assert_eq!(err, IoError::NoSpace);
+                            return written;
+                        }
+                    }
+                }
+            }
+            unreachable!();
+        };
+        let written = fill();
+        assert!(written > 2500);
+        filea.clear().unwrap();
+        fileb.clear().unwrap();
+        // a write larger than the free space leaves the size alone, only
+        // indirect blocks are kept
+        assert_eq!(filea.write_at(0, &[1u8; 4096 * BLOCK_SZ]), Err(IoError::NoSpace));
+        assert_eq!(filea.read_at(0, &mut [0u8; 1]), Ok(0));
+        filea.clear().unwrap();
+        // every block taken by the failed writes is given back
+        assert_eq!(fill(), written);
+        assert!(efs.lock().verify().is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_raw_write_test() -> std::io::Result<()> {
+    // 1KiB blocks, so that the cache is on a SectorGroup over the device
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs_raw_write.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    let device: Arc<dyn BlockDevice> = block_file.clone();
+    EasyFileSystem::create_with_block_size(device.clone(), 1024, 2048, 1, 0).unwrap();
+    let efs = EasyFileSystem::open(device.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap().unwrap();
+    filea.write_at(0, &[0x5au8; 1024]).unwrap();
+    block_cache_sync(&device).unwrap();
+    let mut sector = [0u8; BLOCK_SZ];
+    let data_sector = (0..4096)
+        .step_by(2)
+        .find(|id| {
+            block_file.read_block(*id, &mut sector).unwrap();
+            sector.iter().all(|b| *b == 0x5a)
+        })
+        .unwrap();
+    // the first half of the still cached data block, written behind the cache
+    block_file.write_block(data_sector, &[0xa5u8; BLOCK_SZ]).unwrap();
+    block_cache_reload(&device, data_sector..data_sector + 1).unwrap();
+    let mut buffer = [0u8; 1024];
+    assert_eq!(filea.read_at(0, &mut buffer), Ok(1024));
+    assert!(buffer[..BLOCK_SZ].iter().all(|b| *b == 0xa5));
+    assert!(buffer[BLOCK_SZ..].iter().all(|b| *b == 0x5a));
+    // writing the other half back keeps it
+    filea.write_at(BLOCK_SZ, &[0x33u8; BLOCK_SZ]).unwrap();
+    block_cache_sync(&device).unwrap();
+    block_file.read_block(data_sector, &mut sector).unwrap();
+    assert!(sector.iter().all(|b| *b == 0xa5));
+    Ok(())
+}
+
+#[test]
+fn efs_snapshot_test() -> std::io::Result<()> {
+    for &features in [FEATURE_SNAPSHOTS, FEATURE_SNAPSHOTS | FEATURE_EXTENTS].iter() {
+        let block_file = Arc::new(BlockFile(Mutex::new({
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("target/fs_snapshot.img")?;
+            f.set_len(8192 * 512).unwrap();
+            f
+        })));
+        EasyFileSystem::create_with_features(block_file.clone(), 4096, 1, features).unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        // big enough for the indirect blocks
+        let big: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+        let filea = root_inode.create("filea").unwrap().unwrap();
+        filea.write_at(0, &big).unwrap();
+        root_inode.create("fileb").unwrap().unwrap().write_at(0, b"fileb").unwrap();
+        // the data bitmap follows the inode bitmap and 1024 blocks of
+        // inodes, then come the reference counts, all 0 without snapshots
+        let used_blocks = || {
+            let device: Arc<dyn BlockDevice> = block_file.clone();
+            block_cache_sync(&device).unwrap();
+            let mut block = [0u8; BLOCK_SZ];
+            (1026..1030).map(|block_id| {
+                block_file.read_block(block_id, &mut block).unwrap();
+                block.iter().map(|byte| byte.count_ones()).sum::<u32>()
+            }).sum::<u32>()
+        };
+        let used = used_blocks();
+        assert_eq!(efs.lock().snapshot().unwrap(), Some(0));
+        assert_eq!(efs.lock().snapshots().unwrap().len(), 1);
+        let check = || {
+            let filea = root_inode.find("filea").unwrap().unwrap();
+            let mut data = vec![0u8; big.len() + 1];
+            assert_eq!(filea.read_at(0, &mut data).unwrap(), big.len());
+            assert_eq!(&data[..big.len()], big.as_slice());
+            let fileb = root_inode.find("fileb").unwrap().unwrap();
+            assert_eq!(fileb.read_at(0, &mut data).unwrap(), 5);
+            assert_eq!(&data[..5], b"fileb");
+            assert!(root_inode.find("filec").unwrap().is_none());
+            assert!(efs.lock().verify().is_empty());
+        };
+        for _ in 0..2 {
+            // a destructive run after the snapshot
+            let filea = root_inode.find("filea").unwrap().unwrap();
+            filea.write_at(100 * BLOCK_SZ + 7, &[0xff; 3 * BLOCK_SZ]).unwrap();
+            filea.write_at(big.len(), &[1; BLOCK_SZ]).unwrap();
+            // still open across the rollback which brings it back
+            let fileb = root_inode.find("fileb").unwrap().unwrap();
+            assert!(root_inode.unlink("fileb").unwrap());
+            root_inode.create("filec").unwrap().unwrap().write_at(0, &big).unwrap();
+            assert!(efs.lock().verify().is_empty());
+            assert!(used_blocks() > used);
+            assert!(efs.lock().rollback(0).unwrap());
+            drop(fileb);
+            check();
+        }
+        assert!(!efs.lock().rollback(1).unwrap());
+        assert!(efs.lock().delete_snapshot(0).unwrap());
+        assert!(efs.lock().snapshots().unwrap().is_empty());
+        assert_eq!(used_blocks(), used);
+        check();
+        // without snapshots the files are written in place again
+        root_inode.find("filea").unwrap().unwrap().write_at(0, &[0; BLOCK_SZ]).unwrap();
+        assert_eq!(used_blocks(), used);
+        efs.lock().unmount().unwrap();
+    }
+    // snapshots are only taken with the feature
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open("target/fs_snapshot.img")?
+    )));
+    let efs = EasyFileSystem::create(block_file, 4096, 1).unwrap();
+    assert_eq!(efs.lock().snapshot().unwrap(), None);
+    Ok(())
+}
+
+#[test]
+fn efs_defrag_test() -> std::io::Result<()> {
+    for &features in [0, FEATURE_EXTENTS].iter() {
+        let block_file = Arc::new(BlockFile(Mutex::new({
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("target/fs_defrag.img")?;
+            f.set_len(8192 * 512).unwrap();
+            f
+        })));
+        EasyFileSystem::create_with_features(block_file.clone(), 8192, 1, features).unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        // written interleaved, the files take turns in the data area
+        let block_data = |file: usize, block: usize| [(file * 7 + block) as u8; BLOCK_SZ];
+        let files: Vec<_> = (0..3)
+            .map(|i| root_inode.create(format!("file{}", i).as_str()).unwrap().unwrap())
+            .collect();
+        for block in 0..100 {
+            for (i, file) in files.iter().enumerate() {
+                file.write_at(block * BLOCK_SZ, &block_data(i, block)).unwrap();
+            }
+        }
+        let check = |files: &[usize]| {
+            let mut buffer = [0u8; BLOCK_SZ];
+            for &i in files {
+                let file = root_inode.find(format!("file{}", i).as_str()).unwrap().unwrap();
+                for block in 0..100 {
+                    file.read_at(block * BLOCK_SZ, &mut buffer).unwrap();
+                    assert_eq!(buffer, block_data(i, block));
+                }
+            }
+            assert!(efs.lock().verify().is_empty());
+        };
+        assert_eq!(efs.lock().defrag().unwrap(), 3);
+        assert_eq!(efs.lock().defrag().unwrap(), 0);
+        check(&[0, 1, 2]);
+        // the runs left by file1 are moved down when shrinking
+        assert!(root_inode.unlink("file1").unwrap());
+        let total_blocks = efs.lock().shrink().unwrap().unwrap();
+        assert!(total_blocks < 8192);
+        check(&[0, 2]);
+        efs.lock().unmount().unwrap();
+        block_file.0.lock().unwrap().set_len(total_blocks as u64 * 512)?;
+        // a new device so that nothing comes from the cache
+        let block_file = Arc::new(BlockFile(Mutex::new(
+            OpenOptions::new().read(true).write(true).open("target/fs_defrag.img")?
+        )));
+        let efs = EasyFileSystem::open(block_file).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        check(&[0, 2]);
+        // blocks are only taken from what is left of the image
+        assert!(root_inode.unlink("file0").unwrap());
+        let file3 = root_inode.create("file3").unwrap().unwrap();
+        file3.write_at(0, &[3; 50 * BLOCK_SZ]).unwrap();
+        check(&[2]);
+        efs.lock().unmount().unwrap();
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_compression_test() -> std::io::Result<()> {
+    for &features in [FEATURE_COMPRESSION, FEATURE_COMPRESSION | FEATURE_EXTENTS].iter() {
+        let block_file = Arc::new(BlockFile(Mutex::new({
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("target/fs_compression.img")?;
+            f.set_len(8192 * 512).unwrap();
+            f
+        })));
+        EasyFileSystem::create_with_features(block_file.clone(), 4096, 1, features).unwrap();
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        // like an ELF: repetitive code, random data, zero padding and a
+        // hole, ending in the middle of a block
+        let mut data: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i % 13 * 7) as u8).collect();
+        data.extend((0..9 * BLOCK_SZ).map(|_| rand::random::<u8>()));
+        data.extend(vec![0u8; 30 * BLOCK_SZ + 100]);
+        let file = root_inode.create("elf").unwrap().unwrap();
+        file.write_at(0, &data[..60 * BLOCK_SZ]).unwrap();
+        file.write_at(70 * BLOCK_SZ, &data[70 * BLOCK_SZ..]).unwrap();
+        // the data bitmap follows the inode bitmap and 1024 blocks of inodes
+        let used_blocks = || {
+            let device: Arc<dyn BlockDevice> = block_file.clone();
+            block_cache_sync(&device).unwrap();
+            let mut block = [0u8; BLOCK_SZ];
+            block_file.read_block(1026, &mut block).unwrap();
+            block.iter().map(|byte| byte.count_ones()).sum::<u32>()
+        };
+        let used = used_blocks();
+        assert!(file.compress().unwrap());
+        assert!(used_blocks() < used - 40);
+        let check = |data: &[u8]| {
+            let file = root_inode.find("elf").unwrap().unwrap();
+            let mut read_data = vec![0u8; data.len() + 10];
+            assert_eq!(file.read_at(0, &mut read_data).unwrap(), data.len());
+            assert_eq!(&read_data[..data.len()], data);
+            // across clusters and from the middle of one
+            for &(offset, len) in [(100, 3000), (4 * BLOCK_SZ - 1, 2), (45 * BLOCK_SZ + 7, 9000)].iter() {
+                assert_eq!(file.read_at(offset, &mut read_data[..len]).unwrap(), len);
+                assert_eq!(&read_data[..len], &data[offset..offset + len]);
+            }
+            // a block at a time like the kernel does
+            for offset in (0..data.len()).step_by(BLOCK_SZ) {
+                let len = BLOCK_SZ.min(data.len() - offset);
+                assert_eq!(file.read_at(offset, &mut read_data[..BLOCK_SZ]).unwrap(), len);
+                assert_eq!(&read_data[..len], &data[offset..offset + len]);
+            }
+            assert!(efs.lock().verify().is_empty());
+        };
+        check(&data);
+        // a new device so that nothing comes from the cache
+        efs.lock().unmount().unwrap();
+        let block_file = Arc::new(BlockFile(Mutex::new(
+            OpenOptions::new().read(true).write(true).open("target/fs_compression.img")?
+        )));
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let file = root_inode.find("elf").unwrap().unwrap();
+        let mut read_data = vec![0u8; data.len()];
+        assert_eq!(file.read_at(0, &mut read_data).unwrap(), data.len());
+        assert_eq!(read_data, data);
+        assert_eq!(file.read_at(0, &mut read_data[..BLOCK_SZ]).unwrap(), BLOCK_SZ);
+        // changing it stores it as it is again
+        file.write_at(5, b"changed").unwrap();
+        assert_eq!(file.read_at(0, &mut read_data[..BLOCK_SZ]).unwrap(), BLOCK_SZ);
+        assert_eq!(&read_data[5..12], b"changed");
+        data[5..12].copy_from_slice(b"changed");
+        assert_eq!(file.read_at(0, &mut read_data).unwrap(), data.len());
+        assert_eq!(read_data, data);
+        assert!(efs.lock().verify().is_empty());
+        // and it can be compressed again
+        assert!(file.compress().unwrap());
+        assert_eq!(file.read_at(0, &mut read_data).unwrap(), data.len());
+        assert_eq!(read_data, data);
+        efs.lock().unmount().unwrap();
+    }
+    // files are only compressed with the feature
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open("target/fs_compression.img")?
+    )));
+    let efs = EasyFileSystem::create(block_file, 4096, 1).unwrap();
+    let file = EasyFileSystem::root_inode(&efs).create("elf").unwrap().unwrap();
+    file.write_at(0, &[0; BLOCK_SZ]).unwrap();
+    assert!(!file.compress().unwrap());
+    Ok(())
+}
+
+#[test]
+fn fat32_test() -> std::io::Result<()> {
+    // 64MiB, FAT32 needs at least 65525 clusters
+    let total_sectors = 131072;
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fat32.img")?;
+        f.set_len(total_sectors as u64 * 512).unwrap();
+        f
+    })));
+    FatFileSystem::create(
+        block_file.clone(),
+        total_sectors,
+        1,
+    ).unwrap();
+    let fs = FatFileSystem::open(block_file.clone()).unwrap().unwrap();
+    let root_inode = FatFileSystem::root_inode(&fs);
+    root_inode.create("FILEA.TXT").unwrap().unwrap();
+    root_inode.create("a file with a long name.txt").unwrap().unwrap();
+    root_inode.create("a file with a long name 2.txt").unwrap().unwrap();
+    assert!(root_inode.create("filea.txt").unwrap().is_none());
+    // an open file is kept
+    let removed = root_inode.create("to be removed").unwrap().unwrap();
+    assert!(!root_inode.unlink("TO BE REMOVED").unwrap());
+    drop(removed);
+    assert!(root_inode.unlink("TO BE REMOVED").unwrap());
+    assert!(root_inode.find("to be removed").unwrap().is_none());
+    let names = root_inode.ls().unwrap();
+    for name in names.iter() {
+        println!("{}", name);
+    }
+    assert_eq!(names, [
+        "FILEA.TXT",
+        "a file with a long name.txt",
+        "a file with a long name 2.txt",
+    ]);
+    let filea = root_inode.find("a file with a long name 2.txt").unwrap().unwrap();
+    let greet_str = "Hello, world!";
+    filea.write_at(0, greet_str.as_bytes()).unwrap();
+    let mut buffer = [0u8; 233];
+    let len = filea.read_at(0, &mut buffer).unwrap();
+    assert_eq!(
+        greet_str,
+        core::str::from_utf8(&buffer[..len]).unwrap(),
+    );
+
+    let mut random_str_test = |len: usize| {
+        filea.clear().unwrap();
+        assert_eq!(
+            filea.read_at(0, &mut buffer).unwrap(),
+            0,
+        );
+        let mut str = String::new();
+        // random digit
+        for _ in 0..len {
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
+        }
+        filea.write_at(0, str.as_bytes()).unwrap();
+        let mut read_buffer = [0u8; 127];
+        let mut offset = 0usize;
+        let mut read_str = String::new();
+        loop {
+            let len = filea.read_at(offset, &mut read_buffer).unwrap();
+            if len == 0 {
+                break;
+            }
+            offset += len;
+            read_str.push_str(
+                core::str::from_utf8(&read_buffer[..len]).unwrap()
+            );
+        }
+        assert_eq!(str, read_str);
+    };
+
+    random_str_test(4 * BLOCK_SZ);
+    random_str_test(8 * BLOCK_SZ + BLOCK_SZ / 2);
+    random_str_test(100 * BLOCK_SZ);
+    random_str_test(2000 * BLOCK_SZ);
+
+    // everything can be read back after remounting
+    let fs = FatFileSystem::open(block_file.clone()).unwrap().unwrap();
+    let root_inode = FatFileSystem::root_inode(&fs);
+    assert_eq!(root_inode.ls().unwrap().len(), 3);
+    let filea = root_inode.find("A FILE WITH A LONG NAME 2.TXT").unwrap().unwrap();
+    assert!(filea.read_at(2000 * BLOCK_SZ - 1, &mut buffer).unwrap() == 1);
+    // a directory is not written as a file
+    assert_eq!(root_inode.write_at(0, b"x"), Err(IoError::IsDir));
+    assert_eq!(root_inode.clear(), Err(IoError::IsDir));
+    assert_eq!(root_inode.ls().unwrap().len(), 3);
+
+    // a damaged FAT is reported, the root directory has its entry in the
+    // first FAT sector after 32 reserved ones
+    let root_cluster = fs.lock().root_cluster();
+    fs.lock().set_fat(root_cluster, root_cluster).unwrap();
+    assert_eq!(root_inode.ls().err(), Some(IoError::Corrupted(32)));
+    fs.lock().set_fat(root_cluster, 0x0fff_0000).unwrap();
+    assert_eq!(root_inode.find("FILEA.TXT").err(), Some(IoError::Corrupted(32)));
+    fs.lock().set_fat(root_cluster, 0x0fff_ffff).unwrap();
+    assert_eq!(root_inode.ls().unwrap().len(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn fat32_partition_test() -> std::io::Result<()> {
+    let total_sectors = 131072;
+    let start_sector = 2048u32;
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fat32_volume.img")?;
+        f.set_len(total_sectors as u64 * 512).unwrap();
+        f
+    })));
+    let fs = FatFileSystem::create(block_file.clone(), total_sectors, 1).unwrap();
+    let root_inode = FatFileSystem::root_inode(&fs);
+    let filea = root_inode.create("FILEA.TXT").unwrap().unwrap();
+    filea.write_at(0, b"Hello, partition!").unwrap();
+    drop(filea);
+    block_cache_sync(&block_file).unwrap();
+    // put the volume into the second partition of a disk, after an empty one
+    let mut disk = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open("target/fat32_disk.img")?;
+    let mut mbr = [0u8; BLOCK_SZ];
+    let entry = 446 + 16;
+    mbr[entry + 4] = 0x0c;
+    mbr[entry + 8..entry + 12].copy_from_slice(&start_sector.to_le_bytes());
+    mbr[entry + 12..entry + 16].copy_from_slice(&total_sectors.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    disk.write_all(&mbr)?;
+    disk.seek(SeekFrom::Start(start_sector as u64 * 512))?;
+    std::io::copy(&mut File::open("target/fat32_volume.img")?, &mut disk)?;
+    let disk: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(disk)));
+
+    let fs = FatFileSystem::open(disk.clone()).unwrap().unwrap();
+    let root_inode = FatFileSystem::root_inode(&fs);
+    let filea = root_inode.find("filea.txt").unwrap().unwrap();
+    let mut buffer = [0u8; 64];
+    let len = filea.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"Hello, partition!");
+    let fileb = root_inode.create("FILEB.TXT").unwrap().unwrap();
+    fileb.write_at(0, &[1u8; 4 * BLOCK_SZ]).unwrap();
+    drop(fileb);
+    block_cache_sync(&disk).unwrap();
+    // nothing is written in front of the partition
+    let mut sector = [0u8; BLOCK_SZ];
+    disk.read_block(0, &mut sector).unwrap();
+    assert_eq!(sector, mbr);
+    for sector_id in 1..start_sector as usize {
+        disk.read_block(sector_id, &mut sector).unwrap();
+        assert!(sector.iter().all(|b| *b == 0));
+    }
+    // the volume is still readable without the MBR
+    let volume = Arc::new(BlockFile(Mutex::new({
+        let mut f = File::open("target/fat32_disk.img")?;
+        let mut volume = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fat32_volume.img")?;
+        f.seek(SeekFrom::Start(start_sector as u64 * 512))?;
+        std::io::copy(&mut f, &mut volume)?;
+        volume
+    })));
+    let fs = FatFileSystem::open(volume).unwrap().unwrap();
+    let root_inode = FatFileSystem::root_inode(&fs);
+    assert_eq!(root_inode.ls().unwrap(), ["FILEA.TXT", "FILEB.TXT"]);
+    let fileb = root_inode.find("FILEB.TXT").unwrap().unwrap();
+    assert_eq!(fileb.read_at(0, &mut [0u8; 8 * BLOCK_SZ]), Ok(4 * BLOCK_SZ));
+    Ok(())
+}
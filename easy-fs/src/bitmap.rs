@@ -1,40 +1,40 @@
 use alloc::sync::Arc;
 use super::{
     BlockDevice,
-    BLOCK_SZ,
+    IoError,
     get_block_cache,
 };
 
-type BitmapBlock = [u64; 64];
-
-const BLOCK_BITS: usize = BLOCK_SZ * 8;
-
 pub struct Bitmap {
     start_block_id: usize,
     blocks: usize,
-}
-
-/// Return (block_pos, bits64_pos, inner_pos)
-fn decomposition(mut bit: usize) -> (usize, usize, usize) {
-    let block_pos = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
-    (block_pos, bit / 64, bit % 64)
+    /// Bits in a block of the file system.
+    block_bits: usize,
 }
 
 impl Bitmap {
-    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+    pub fn new(start_block_id: usize, blocks: usize, block_size: usize) -> Self {
         Self {
             start_block_id,
             blocks,
+            block_bits: block_size * 8,
         }
     }
 
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+    /// Return (block_pos, bits64_pos, inner_pos)
+    fn decomposition(&self, mut bit: usize) -> (usize, usize, usize) {
+        let block_pos = bit / self.block_bits;
+        bit %= self.block_bits;
+        (block_pos, bit / 64, bit % 64)
+    }
+
+    /// Return `None` if every bit is allocated.
+    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Option<usize>, IoError> {
         for block_id in 0..self.blocks {
             let pos = get_block_cache(
                 block_id + self.start_block_id as usize,
                 Arc::clone(block_device),
-            ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            )?.lock().modify_slice(|bitmap_block: &mut [u64]| {
                 if let Some((bits64_pos, inner_pos)) = bitmap_block
                     .iter()
                     .enumerate()
@@ -44,30 +44,118 @@ impl Bitmap {
                     }) {
                     // modify cache
                     bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
+                    Some(block_id * self.block_bits + bits64_pos * 64 + inner_pos as usize)
                 } else {
                     None
                 }
             });
             if pos.is_some() {
-                return pos;
+                return Ok(pos);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Allocate up to `max` consecutive bits below `limit` and return
+    /// (first bit, count).
+    ///
+    /// The run starts from `goal` if it is free, so that a file keeps
+    /// growing contiguously. Otherwise it starts from the first run of free
+    /// bits long enough, or the first free bit if there is no such run.
+    pub fn alloc_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: usize,
+        max: usize,
+        limit: usize,
+    ) -> Result<Option<(usize, usize)>, IoError> {
+        let limit = limit.min(self.maximum());
+        let start = if goal < limit && !self.is_allocated(block_device, goal)? {
+            goal
+        } else {
+            match self.find_run(block_device, max, limit)? {
+                Some(start) => start,
+                None => return Ok(None),
+            }
+        };
+        let mut len = 0usize;
+        while len < max && start + len < limit && !self.is_allocated(block_device, start + len)? {
+            let (block_pos, bits64_pos, inner_pos) = self.decomposition(start + len);
+            get_block_cache(
+                block_pos + self.start_block_id,
+                Arc::clone(block_device)
+            )?.lock().modify_slice(|bitmap_block: &mut [u64]| {
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            });
+            len += 1;
+        }
+        Ok(Some((start, len)))
+    }
+
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<bool, IoError> {
+        let (block_pos, bits64_pos, inner_pos) = self.decomposition(bit);
+        Ok(get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        )?.lock().read_slice(|bitmap_block: &[u64]| {
+            bitmap_block[bits64_pos] & (1u64 << inner_pos) != 0
+        }))
+    }
+
+    /// Return the start of the first run of `len` free bits below `limit`,
+    /// or the first free bit if there is no such run.
+    fn find_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        len: usize,
+        limit: usize,
+    ) -> Result<Option<usize>, IoError> {
+        let mut first_free = None;
+        let mut run_start = 0usize;
+        let mut run_len = 0usize;
+        for block_pos in 0..self.blocks {
+            let bitmap_block = get_block_cache(
+                block_pos + self.start_block_id,
+                Arc::clone(block_device),
+            )?.lock().read_slice(|bitmap_block: &[u64]| bitmap_block.to_vec());
+            for (bits64_pos, bits64) in bitmap_block.iter().enumerate() {
+                let base = block_pos * self.block_bits + bits64_pos * 64;
+                if base >= limit {
+                    return Ok(first_free);
+                }
+                for inner_pos in 0..64 {
+                    let bit = base + inner_pos;
+                    if bit >= limit || bits64 & (1u64 << inner_pos) != 0 {
+                        run_len = 0;
+                        continue;
+                    }
+                    if run_len == 0 {
+                        run_start = bit;
+                        first_free.get_or_insert(bit);
+                    }
+                    run_len += 1;
+                    if run_len >= len {
+                        return Ok(Some(run_start));
+                    }
+                }
             }
         }
-        None
+        Ok(first_free)
     }
 
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
-        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<(), IoError> {
+        let (block_pos, bits64_pos, inner_pos) = self.decomposition(bit);
         get_block_cache(
             block_pos + self.start_block_id,
             Arc::clone(block_device)
-        ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+        )?.lock().modify_slice(|bitmap_block: &mut [u64]| {
             assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
             bitmap_block[bits64_pos] -= 1u64 << inner_pos;
         });
+        Ok(())
     }
 
     pub fn maximum(&self) -> usize {
-        self.blocks * BLOCK_BITS
+        self.blocks * self.block_bits
     }
 }
\ No newline at end of file
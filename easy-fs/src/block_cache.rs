@@ -1,54 +1,132 @@
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    IoError,
+    crc32c,
 };
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
 use alloc::sync::Arc;
 use lazy_static::*;
 use spin::Mutex;
 
 pub struct BlockCache {
-    cache: [u8; BLOCK_SZ],
+    /// `block_size()` bytes of the device, kept in u64s so that any
+    /// structure read from it is aligned.
+    cache: Vec<u64>,
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
     modified: bool,
+    /// How the block is checksummed if it is metadata.
+    checksum: Option<Checksum>,
+    /// Changes whenever the content may change and is never reused, so
+    /// that what is worked out from the content can be checked against it.
+    version: usize,
+}
+
+/// The last `BlockCache::version` given out.
+static LAST_VERSION: AtomicUsize = AtomicUsize::new(0);
+
+fn next_version() -> usize {
+    LAST_VERSION.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Where the checksums of a metadata block are.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// The last 4 bytes are the CRC32C of the rest of the block.
+    Tail,
+    /// The block is an array of records of this many bytes, each ending with
+    /// the CRC32C of the rest of it.
+    Records(usize),
+}
+
+impl Checksum {
+    fn record_size(self, block_size: usize) -> usize {
+        match self {
+            Self::Tail => block_size,
+            Self::Records(size) => size,
+        }
+    }
+
+    /// Records of zeros are valid, they have never been written since the
+    /// block was zeroed.
+    pub fn verify(self, block: &[u8]) -> bool {
+        block.chunks(self.record_size(block.len())).all(|record| {
+            let (data, sum) = record.split_at(record.len() - 4);
+            let sum = u32::from_le_bytes([sum[0], sum[1], sum[2], sum[3]]);
+            sum == crc32c(data) || record.iter().all(|b| *b == 0)
+        })
+    }
+
+    fn update(self, block: &mut [u8]) {
+        let record_size = self.record_size(block.len());
+        for record in block.chunks_mut(record_size) {
+            let (data, sum) = record.split_at_mut(record.len() - 4);
+            sum.copy_from_slice(&crc32c(data).to_le_bytes());
+        }
+    }
 }
 
 impl BlockCache {
-    /// Load a new BlockCache from disk.
+    /// Load a new BlockCache from disk, checking its checksum if any.
     pub fn new(
         block_id: usize, 
-        block_device: Arc<dyn BlockDevice>
-    ) -> Self {
-        let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
-        Self {
+        block_device: Arc<dyn BlockDevice>,
+        checksum: Option<Checksum>,
+    ) -> Result<Self, IoError> {
+        let mut cache = vec![0u64; block_device.block_size() / 8];
+        block_device.read_block(block_id, Self::as_bytes_mut(&mut cache))?;
+        if let Some(checksum) = checksum {
+            if !checksum.verify(Self::as_bytes_mut(&mut cache)) {
+                return Err(IoError::Corrupted(block_id));
+            }
+        }
+        Ok(Self {
             cache,
             block_id,
             block_device,
             modified: false,
-        }
+            checksum,
+            version: next_version(),
+        })
+    }
+
+    fn as_bytes_mut(cache: &mut [u64]) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(cache.as_mut_ptr() as *mut u8, cache.len() * 8) }
+    }
+
+    fn len(&self) -> usize {
+        self.cache.len() * 8
     }
 
     fn addr_of_offset(&self, offset: usize) -> usize {
-        &self.cache[offset] as *const _ as usize
+        self.cache.as_ptr() as usize + offset
     }
 
     pub fn get_ref<T>(&self, offset: usize) -> &T where T: Sized {
         let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
+        assert!(offset + type_size <= self.len());
         let addr = self.addr_of_offset(offset);
         unsafe { &*(addr as *const T) } 
     }
 
     pub fn get_mut<T>(&mut self, offset: usize) -> &mut T where T: Sized {
         let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
+        assert!(offset + type_size <= self.len());
         self.modified = true;
+        self.version = next_version();
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
 
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
     pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
         f(self.get_ref(offset))
     }
@@ -57,24 +135,86 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
-    pub fn sync(&mut self) {
+    /// View the whole block as a slice of `T`, for structures whose length
+    /// depends on the block size.
+    pub fn read_slice<T, V>(&self, f: impl FnOnce(&[T]) -> V) -> V {
+        let len = self.len() / core::mem::size_of::<T>();
+        let addr = self.addr_of_offset(0);
+        f(unsafe { core::slice::from_raw_parts(addr as *const T, len) })
+    }
+
+    pub fn modify_slice<T, V>(&mut self, f: impl FnOnce(&mut [T]) -> V) -> V {
+        let len = self.len() / core::mem::size_of::<T>();
+        self.modified = true;
+        self.version = next_version();
+        let addr = self.addr_of_offset(0);
+        f(unsafe { core::slice::from_raw_parts_mut(addr as *mut T, len) })
+    }
+
+    /// The block stays modified if it can not be written, so that it is
+    /// tried again by the next sync.
+    pub fn sync(&mut self) -> Result<(), IoError> {
         if self.modified {
+            let block_id = self.block_id;
+            let block = Self::as_bytes_mut(&mut self.cache);
+            if let Some(checksum) = self.checksum {
+                checksum.update(block);
+            }
+            self.block_device.write_block(block_id, block)?;
             self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
         }
+        Ok(())
+    }
+
+    /// Read the block again, as the device was written to behind the cache.
+    /// Changes not written back are lost.
+    fn reload(&mut self) -> Result<(), IoError> {
+        self.modified = false;
+        self.version = next_version();
+        let block = Self::as_bytes_mut(&mut self.cache);
+        self.block_device.read_block(self.block_id, block)?;
+        if let Some(checksum) = self.checksum {
+            if !checksum.verify(block) {
+                return Err(IoError::Corrupted(self.block_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the block is on `block_device`, directly or through a
+    /// `SectorGroup` on it.
+    fn on_device(&self, block_device: &Arc<dyn BlockDevice>) -> bool {
+        let target = device_id(block_device);
+        device_id(&self.block_device) == target
+            || matches!(self.block_device.inner_device(), Some(inner) if device_id(inner) == target)
+    }
+
+    fn covers(&self, block_device: &Arc<dyn BlockDevice>, sectors: &Range<usize>) -> bool {
+        let per_block = self.block_device.block_size() / BLOCK_SZ;
+        self.on_device(block_device)
+            && self.block_id * per_block < sectors.end
+            && (self.block_id + 1) * per_block > sectors.start
     }
 }
 
 impl Drop for BlockCache {
+    /// Blocks are synced when they are evicted, where errors can still be
+    /// reported, so this is a last attempt.
     fn drop(&mut self) {
-        self.sync()
+        let _ = self.sync();
     }
 }
 
 const BLOCK_CACHE_SIZE: usize = 16;
 
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    queue: VecDeque<(usize, usize, Arc<Mutex<BlockCache>>)>,
+}
+
+/// Caches are keyed by device as well, so several file systems can be
+/// mounted at the same time.
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
 }
 
 impl BlockCacheManager {
@@ -82,34 +222,51 @@ impl BlockCacheManager {
         Self { queue: VecDeque::new() }
     }
 
+    /// A cached block is trusted and keeps the checksum it was loaded with,
+    /// `block_cache_discard` drops blocks whose use ends so that their next
+    /// use loads them with its own.
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
-    ) -> Arc<Mutex<BlockCache>> {
+        checksum: Option<Checksum>,
+    ) -> Result<Arc<Mutex<BlockCache>>, IoError> {
+        let device_id = device_id(&block_device);
         if let Some(pair) = self.queue
             .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
+            .find(|pair| pair.0 == block_id && pair.1 == device_id) {
+                Ok(Arc::clone(&pair.2))
         } else {
             // substitute
             if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self.queue
+                // from front to tail, a block that can not be written back
+                // stays so that it is not lost
+                let mut error = None;
+                let victim = self.queue
                     .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
+                    .position(|pair| {
+                        Arc::strong_count(&pair.2) == 1 && match pair.2.lock().sync() {
+                            Ok(()) => true,
+                            Err(err) => {
+                                error.get_or_insert(err);
+                                false
+                            }
+                        }
+                    });
+                match (victim, error) {
+                    (Some(idx), _) => {
+                        self.queue.drain(idx..=idx);
+                    }
+                    (None, Some(err)) => return Err(err),
+                    (None, None) => panic!("Run out of BlockCache!"),
                 }
             }
             // load block into mem and push back
             let block_cache = Arc::new(Mutex::new(
-                BlockCache::new(block_id, Arc::clone(&block_device))
+                BlockCache::new(block_id, Arc::clone(&block_device), checksum)?
             ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+            self.queue.push_back((block_id, device_id, Arc::clone(&block_cache)));
+            Ok(block_cache)
         }
     }
 }
@@ -120,9 +277,92 @@ lazy_static! {
     );
 }
 
+/// Load a block without checksums. Fails if the device fails to read it,
+/// or to write back the block evicted for it.
 pub fn get_block_cache(
     block_id: usize,
     block_device: Arc<dyn BlockDevice>
-) -> Arc<Mutex<BlockCache>> {
-    BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device)
-}
\ No newline at end of file
+) -> Result<Arc<Mutex<BlockCache>>, IoError> {
+    BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device, None)
+}
+
+/// Load a metadata block, checking its checksum if it is not cached. The
+/// checksum is updated when the block is written back.
+pub fn get_checked_block_cache(
+    block_id: usize,
+    block_device: Arc<dyn BlockDevice>,
+    checksum: Checksum,
+) -> Result<Arc<Mutex<BlockCache>>, IoError> {
+    BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device, Some(checksum))
+}
+
+/// Write every modified block in the cache back to the device. All of them
+/// are tried, the first error is returned.
+pub fn block_cache_sync_all() -> Result<(), IoError> {
+    let manager = BLOCK_CACHE_MANAGER.lock();
+    let mut result = Ok(());
+    for (_, _, cache) in manager.queue.iter() {
+        let synced = cache.lock().sync();
+        result = result.and(synced);
+    }
+    result
+}
+
+/// Write back the blocks of one device, and of `SectorGroup`s on it, so
+/// that errors of other devices do not show up here.
+pub fn block_cache_sync(block_device: &Arc<dyn BlockDevice>) -> Result<(), IoError> {
+    let manager = BLOCK_CACHE_MANAGER.lock();
+    let mut result = Ok(());
+    for (_, _, cache) in manager.queue.iter() {
+        let mut block = cache.lock();
+        if block.on_device(block_device) {
+            let synced = block.sync();
+            result = result.and(synced);
+        }
+    }
+    result
+}
+
+/// Zero a block on the device and drop its cached copy, for a block freed
+/// or formatted, so that whatever it is used for next loads it with the
+/// right checksum. Its changes not written back are lost.
+pub fn block_cache_discard(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> Result<(), IoError> {
+    let device_id = device_id(block_device);
+    let discarded = {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        manager.queue
+            .iter()
+            .position(|pair| pair.0 == block_id && pair.1 == device_id)
+            .and_then(|idx| manager.queue.remove(idx))
+    };
+    // not locked under the manager, whoever holds the block may be waiting
+    // for the manager
+    if let Some((_, _, cache)) = discarded {
+        cache.lock().modified = false;
+    }
+    block_device.write_block(block_id, &vec![0u8; block_device.block_size()])
+}
+
+/// Forget the cached blocks over `sectors` of `block_device` after they were
+/// written to without going through the cache, so that they are not served
+/// stale nor written back over the new data. Blocks in use are read again.
+/// The caller syncs them first if their changes are to be kept.
+pub fn block_cache_reload(block_device: &Arc<dyn BlockDevice>, sectors: Range<usize>) -> Result<(), IoError> {
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    let mut result = Ok(());
+    manager.queue.retain(|(_, _, cache)| {
+        let mut block = cache.lock();
+        if !block.covers(block_device, &sectors) {
+            return true;
+        }
+        if Arc::strong_count(cache) == 1 {
+            // loaded and checked again when next used
+            block.modified = false;
+            return false;
+        }
+        let reloaded = block.reload();
+        result = core::mem::replace(&mut result, Ok(())).and(reloaded);
+        true
+    });
+    result
+}
@@ -1,6 +1,80 @@
 use core::any::Any;
+use alloc::sync::Arc;
+use super::BLOCK_SZ;
 
+/// Why a block could not be read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoError {
+    /// The checksum of the block with this id does not match its content.
+    Corrupted(usize),
+    /// The device failed to transfer the block with this id.
+    Device(usize),
+    /// The file would need more blocks or extents than its inode can map.
+    TooLarge,
+    /// No free block or inode is left.
+    NoSpace,
+    /// The inode is a directory, whose content is not written as a file's.
+    IsDir,
+}
+
+/// Devices are addressed in sectors of `BLOCK_SZ` bytes.
 pub trait BlockDevice : Send + Sync + Any {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
-    fn write_block(&self, block_id: usize, buf: &[u8]);
+    /// Fail with `IoError::Device` if the device reports an error.
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), IoError>;
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), IoError>;
+    /// Bytes in a unit addressed by `read_block` and `write_block`, only
+    /// devices made by `SectorGroup` differ from `BLOCK_SZ`.
+    fn block_size(&self) -> usize {
+        BLOCK_SZ
+    }
+    /// The device a `SectorGroup` addresses in blocks, `None` for others.
+    fn inner_device(&self) -> Option<&Arc<dyn BlockDevice>> {
+        None
+    }
+}
+
+/// Address a device in blocks of several sectors, for file systems whose
+/// blocks are larger than `BLOCK_SZ`.
+pub struct SectorGroup {
+    device: Arc<dyn BlockDevice>,
+    sectors: usize,
+}
+
+impl SectorGroup {
+    /// Return `device` itself if a block is a single sector.
+    pub fn wrap(device: Arc<dyn BlockDevice>, block_size: usize) -> Arc<dyn BlockDevice> {
+        assert!(block_size % BLOCK_SZ == 0);
+        if block_size == BLOCK_SZ {
+            device
+        } else {
+            Arc::new(Self {
+                device,
+                sectors: block_size / BLOCK_SZ,
+            })
+        }
+    }
+}
+
+impl BlockDevice for SectorGroup {
+    /// Errors are reported for the whole block rather than the sector.
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), IoError> {
+        for (i, sector) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.device.read_block(block_id * self.sectors + i, sector)
+                .map_err(|_| IoError::Device(block_id))?;
+        }
+        Ok(())
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), IoError> {
+        for (i, sector) in buf.chunks(BLOCK_SZ).enumerate() {
+            self.device.write_block(block_id * self.sectors + i, sector)
+                .map_err(|_| IoError::Device(block_id))?;
+        }
+        Ok(())
+    }
+    fn block_size(&self) -> usize {
+        self.sectors * BLOCK_SZ
+    }
+    fn inner_device(&self) -> Option<&Arc<dyn BlockDevice>> {
+        Some(&self.device)
+    }
 }
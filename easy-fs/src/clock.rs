@@ -0,0 +1,17 @@
+use spin::Mutex;
+
+/// Where inode timestamps come from, in seconds since the Unix epoch.
+///
+/// easy-fs has no idea about time itself, so the kernel or the host tool
+/// should set it up before the file system is modified; until then all
+/// timestamps are 0.
+static TIME_SOURCE: Mutex<fn() -> u32> = Mutex::new(|| 0);
+
+pub fn set_time_source(time_source: fn() -> u32) {
+    *TIME_SOURCE.lock() = time_source;
+}
+
+pub fn current_time() -> u32 {
+    let time_source = *TIME_SOURCE.lock();
+    time_source()
+}
@@ -7,115 +7,344 @@ use super::{
     DiskInode,
     DiskInodeType,
     Inode,
+    IoError,
+    SectorGroup,
+    BlockCache,
+    Checksum,
+    INODE_CHECKSUM,
     get_block_cache,
+    get_checked_block_cache,
+    block_cache_sync_all,
+    block_cache_sync,
+    block_cache_discard,
+    current_time,
+    FEATURE_EXTENTS,
+    FEATURE_SNAPSHOTS,
+    FEATURE_COMPRESSION,
+    OpenError,
 };
 use crate::BLOCK_SZ;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 
 pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
     pub inode_bitmap: Bitmap,
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
+    refcount_start_block: u32,
+    /// 0 without `FEATURE_SNAPSHOTS`, so that no block is shared.
+    refcount_blocks: u32,
     data_area_start_block: u32,
+    data_area_blocks: u32,
+    snapshot_table: u32,
+    incompat_features: u32,
+    /// Whether the image had been unmounted cleanly before this mount.
+    was_clean: bool,
+    /// Inodes with `Inode` handles, see `open_inode`.
+    open_inodes: BTreeMap<u32, OpenInode>,
 }
 
-type DataBlock = [u8; BLOCK_SZ];
+/// Handles on an inode, and whether it was unlinked meanwhile, so that it
+/// is freed with the last of them rather than under their feet.
+#[derive(Default)]
+struct OpenInode {
+    handles: usize,
+    unlinked: bool,
+}
 
 impl EasyFileSystem {
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
+    ) -> Result<Arc<Mutex<Self>>, IoError> {
+        Self::create_with_features(block_device, total_blocks, inode_bitmap_blocks, 0)
+    }
+
+    /// Create a file system with incompatible `FEATURE_*` flags enabled.
+    pub fn create_with_features(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        features: u32,
+    ) -> Result<Arc<Mutex<Self>>, IoError> {
+        Self::create_with_block_size(
+            block_device,
+            BLOCK_SZ,
+            total_blocks,
+            inode_bitmap_blocks,
+            features,
+        )
+    }
+
+    /// Create a file system whose blocks are `block_size` bytes, a multiple
+    /// of `BLOCK_SZ`. `total_blocks` and `inode_bitmap_blocks` count such
+    /// blocks rather than sectors.
+    pub fn create_with_block_size(
+        block_device: Arc<dyn BlockDevice>,
+        block_size: usize,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        features: u32,
+    ) -> Result<Arc<Mutex<Self>>, IoError> {
+        let block_device = SectorGroup::wrap(block_device, block_size);
         // calculate block size of areas & create bitmaps
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
+        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize, block_size);
         let inode_num = inode_bitmap.maximum();
         let inode_area_blocks =
-            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+            ((inode_num * core::mem::size_of::<DiskInode>() + block_size - 1) / block_size) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
+        let free_blocks = total_blocks - 1 - inode_total_blocks;
+        // a byte of reference count for every data block, sized as if the
+        // refcount area counted itself as well
+        let refcount_blocks = if features & FEATURE_SNAPSHOTS != 0 {
+            (free_blocks + block_size as u32) / (block_size as u32 + 1)
+        } else {
+            0
+        };
+        let data_total_blocks = free_blocks - refcount_blocks;
+        // a bitmap block covers itself and the data blocks of its bits
+        let block_bits = block_size as u32 * 8;
+        let data_bitmap_blocks = (data_total_blocks + block_bits) / (block_bits + 1);
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
         let data_bitmap = Bitmap::new(
             (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
             data_bitmap_blocks as usize,
+            block_size,
         );
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            refcount_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            refcount_blocks,
+            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks + refcount_blocks,
+            data_area_blocks,
+            snapshot_table: 0,
+            incompat_features: features,
+            was_clean: true,
+            open_inodes: BTreeMap::new(),
         };
         // clear all blocks
         for i in 0..total_blocks {
-            get_block_cache(
-                i as usize, 
-                Arc::clone(&block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                for byte in data_block.iter_mut() { *byte = 0; }
-            });
+            block_cache_discard(i as usize, &block_device)?;
         }
         // initialize SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
-        .lock()
-        .modify(0, |super_block: &mut SuperBlock| {
+        efs.modify_super_block(|super_block| {
             super_block.initialize(
                 total_blocks,
                 inode_bitmap_blocks,
                 inode_area_blocks,
                 data_bitmap_blocks,
+                refcount_blocks,
                 data_area_blocks,
+                features,
+                block_size,
             );
-        });
+            // mounted from now on
+            super_block.set_clean(false);
+        })?;
         // write back immediately
         // create a inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
+        assert_eq!(efs.alloc_inode()?, 0);
         let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
-        get_block_cache(
+        get_checked_block_cache(
             root_inode_block_id as usize,
-            Arc::clone(&block_device)
-        )
+            Arc::clone(&block_device),
+            INODE_CHECKSUM,
+        )?
         .lock()
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-            disk_inode.initialize(DiskInodeType::Directory);
+            disk_inode.initialize(DiskInodeType::Directory, current_time(), efs.uses_extents());
         });
-        Arc::new(Mutex::new(efs))
+        if features & FEATURE_SNAPSHOTS != 0 {
+            let snapshot_table = efs.alloc_data()?;
+            efs.snapshot_table = snapshot_table;
+            efs.modify_super_block(|super_block| super_block.snapshot_table = snapshot_table)?;
+        }
+        Ok(Arc::new(Mutex::new(efs)))
     }
 
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
-        // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+    /// Mount an image, which stays marked dirty until `unmount`.
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>, OpenError> {
+        // The block size is only known from the SuperBlock, so its sector is
+        // read without the cache. Blocks cached by an earlier mount of the
+        // device have to reach it first; a failure here may come from any
+        // device, so it is left to reading the SuperBlock below.
+        let _ = block_cache_sync_all();
+        let mut sector = [0u32; BLOCK_SZ / 4];
+        block_device.read_block(0, unsafe {
+            core::slice::from_raw_parts_mut(sector.as_mut_ptr() as *mut u8, BLOCK_SZ)
+        }).map_err(OpenError::Io)?;
+        let super_block = unsafe { &*(sector.as_ptr() as *const SuperBlock) };
+        super_block.check()?;
+        let block_size = super_block.block_size();
+        let inode_total_blocks =
+            super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
+        let refcount_start_block = 1 + inode_total_blocks + super_block.data_bitmap_blocks;
+        let efs = Self {
+            block_device: SectorGroup::wrap(block_device, block_size),
+            inode_bitmap: Bitmap::new(
+                1,
+                super_block.inode_bitmap_blocks as usize,
+                block_size,
+            ),
+            data_bitmap: Bitmap::new(
+                (1 + inode_total_blocks) as usize,
+                super_block.data_bitmap_blocks as usize,
+                block_size,
+            ),
+            inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
+            refcount_start_block,
+            refcount_blocks: super_block.refcount_blocks,
+            data_area_start_block: refcount_start_block + super_block.refcount_blocks,
+            data_area_blocks: super_block.data_area_blocks,
+            snapshot_table: super_block.snapshot_table,
+            incompat_features: super_block.incompat_features,
+            was_clean: super_block.is_clean(),
+            open_inodes: BTreeMap::new(),
+        };
+        efs.modify_super_block(|super_block| super_block.set_clean(false))
+            .map_err(OpenError::Io)?;
+        Ok(Arc::new(Mutex::new(efs)))
+    }
+
+    /// Mark the image clean and write everything back, nothing should be
+    /// changed afterwards.
+    pub fn unmount(&mut self) -> Result<(), IoError> {
+        self.modify_super_block(|super_block| super_block.set_clean(true))?;
+        block_cache_sync(&self.block_device)
+    }
+
+    /// Whether the image had been unmounted cleanly before it was mounted
+    /// this time. If not, it may have been left inconsistent.
+    pub fn was_clean(&self) -> bool {
+        self.was_clean
+    }
+
+    fn read_super_block<V>(&self, f: impl FnOnce(&SuperBlock) -> V) -> Result<V, IoError> {
+        Ok(get_block_cache(0, Arc::clone(&self.block_device))?
             .lock()
-            .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
-                let inode_total_blocks =
-                    super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
-                    block_device,
-                    inode_bitmap: Bitmap::new(
-                        1,
-                        super_block.inode_bitmap_blocks as usize
-                    ),
-                    data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
-                        super_block.data_bitmap_blocks as usize,
-                    ),
-                    inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
-            })        
+            .read(0, f))
+    }
+
+    fn modify_super_block<V>(&self, f: impl FnOnce(&mut SuperBlock) -> V) -> Result<V, IoError> {
+        Ok(get_block_cache(0, Arc::clone(&self.block_device))?
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                let ret = f(super_block);
+                super_block.update_checksum();
+                ret
+            }))
+    }
+
+    /// Check the checksums of the SuperBlock, of every allocated inode and of
+    /// the blocks with checksums it uses, and return the corrupted blocks.
+    ///
+    /// Blocks are read from the device after writing the cache back, so
+    /// that damage done to the image underneath the cache is found too.
+    ///
+    /// Blocks the device fails to read are reported as well, as is the
+    /// bitmap of inodes if it can not be read, which stops the pass.
+    pub fn verify(&self) -> Vec<IoError> {
+        let mut errors: Vec<IoError> = Vec::new();
+        let mut report = |error: IoError| if !errors.contains(&error) {
+            errors.push(error);
+        };
+        if let Err(error) = block_cache_sync(&self.block_device) {
+            report(error);
+        }
+        // kept in u64s like `BlockCache` so that a DiskInode can be read in it
+        let mut buffer = vec![0u64; self.block_device.block_size() / 8];
+        let block_device = &self.block_device;
+        let mut read_raw = |block_id: u32| -> Result<&[u8], IoError> {
+            let block = unsafe {
+                core::slice::from_raw_parts_mut(
+                    buffer.as_mut_ptr() as *mut u8,
+                    block_device.block_size(),
+                )
+            };
+            block_device.read_block(block_id as usize, block)?;
+            Ok(block)
+        };
+        match read_raw(0) {
+            Ok(block) => {
+                let super_block = unsafe { &*(block.as_ptr() as *const SuperBlock) };
+                if !super_block.checksum_ok() {
+                    report(IoError::Corrupted(0));
+                }
+            }
+            Err(error) => report(error),
+        }
+        let inode_size = core::mem::size_of::<DiskInode>();
+        for inode_id in 0..self.inode_bitmap.maximum() {
+            match self.inode_bitmap.is_allocated(block_device, inode_id) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(error) => {
+                    report(error);
+                    break;
+                }
+            }
+            let (block_id, offset) = self.get_disk_inode_pos(inode_id as u32);
+            let record = match read_raw(block_id) {
+                Ok(block) => &block[offset..offset + inode_size],
+                Err(error) => {
+                    report(error);
+                    continue;
+                }
+            };
+            if !INODE_CHECKSUM.verify(record) {
+                report(IoError::Corrupted(block_id as usize));
+                continue;
+            }
+            let disk_inode = unsafe { &*(record.as_ptr() as *const DiskInode) };
+            let mut blocks: Vec<u32> = Vec::new();
+            if let Err(error) = disk_inode.checked_blocks(block_device, &mut blocks) {
+                report(error);
+            }
+            for block_id in blocks {
+                match read_raw(block_id) {
+                    Ok(block) if !Checksum::Tail.verify(block) => {
+                        report(IoError::Corrupted(block_id as usize));
+                    }
+                    Ok(_) => {}
+                    Err(error) => report(error),
+                }
+            }
+        }
+        errors
+    }
+
+    pub fn label(&self) -> Result<String, IoError> {
+        self.read_super_block(|super_block| String::from(super_block.label()))
+    }
+
+    /// Labels longer than 16 bytes are cut.
+    pub fn set_label(&self, label: &str) -> Result<(), IoError> {
+        self.modify_super_block(|super_block| super_block.set_label(label))
+    }
+
+    pub fn uuid(&self) -> Result<[u8; 16], IoError> {
+        self.read_super_block(|super_block| super_block.uuid)
+    }
+
+    pub fn set_uuid(&self, uuid: [u8; 16]) -> Result<(), IoError> {
+        self.modify_super_block(|super_block| super_block.uuid = uuid)
     }
 
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
         let block_device = Arc::clone(&efs.lock().block_device);
         // acquire efs lock temporarily
         let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
+        efs.lock().open_inode(0);
         // release efs lock
         Inode::new(
+            0,
             block_id,
             block_offset,
             Arc::clone(efs),
@@ -125,7 +354,7 @@ impl EasyFileSystem {
 
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
         let inode_size = core::mem::size_of::<DiskInode>();
-        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        let inodes_per_block = (self.block_device.block_size() / inode_size) as u32;
         let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
         (block_id, (inode_id % inodes_per_block) as usize * inode_size)
     }
@@ -134,28 +363,531 @@ impl EasyFileSystem {
         self.data_area_start_block + data_block_id
     }
 
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    pub fn alloc_inode(&mut self) -> Result<u32, IoError> {
+        Ok(self.inode_bitmap.alloc(&self.block_device)?.ok_or(IoError::NoSpace)? as u32)
     }
 
-    /// Return a block ID not ID in the data area.
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    pub fn dealloc_inode(&mut self, inode_id: u32) -> Result<(), IoError> {
+        self.inode_bitmap.dealloc(&self.block_device, inode_id as usize)
     }
 
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(
+    /// Count an `Inode` handle made for `inode_id`.
+    pub fn open_inode(&mut self, inode_id: u32) {
+        self.open_inodes.entry(inode_id).or_default().handles += 1;
+    }
+
+    /// Drop an `Inode` handle, and free the inode if it was the last one
+    /// of an unlinked inode.
+    pub fn close_inode(&mut self, inode_id: u32) -> Result<(), IoError> {
+        let open = self.open_inodes.get_mut(&inode_id).unwrap();
+        open.handles -= 1;
+        if open.handles > 0 {
+            return Ok(());
+        }
+        let unlinked = open.unlinked;
+        self.open_inodes.remove(&inode_id);
+        if unlinked {
+            self.free_inode(inode_id)?;
+        }
+        Ok(())
+    }
+
+    /// Free an inode removed from its directory, or leave it to
+    /// `close_inode` while there are handles on it.
+    pub fn unlink_inode(&mut self, inode_id: u32) -> Result<(), IoError> {
+        match self.open_inodes.get_mut(&inode_id) {
+            Some(open) => {
+                open.unlinked = true;
+                Ok(())
+            }
+            None => self.free_inode(inode_id),
+        }
+    }
+
+    fn free_inode(&mut self, inode_id: u32) -> Result<(), IoError> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_checked_block_cache(
             block_id as usize,
-            Arc::clone(&self.block_device)
-        )
-        .lock()
-        .modify(0, |data_block: &mut DataBlock| {
-            data_block.iter_mut().for_each(|p| { *p = 0; })
-        });
+            Arc::clone(&self.block_device),
+            INODE_CHECKSUM,
+        )?.lock().modify(block_offset, |disk_inode: &mut DiskInode| {
+            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device)?;
+            for data_block in data_blocks_dealloc.into_iter() {
+                self.dealloc_data(data_block)?;
+            }
+            Ok(())
+        })?;
+        self.dealloc_inode(inode_id)
+    }
+
+    /// Return a block ID not ID in the data area.
+    ///
+    /// The data bitmap may have more bits than the data area has blocks,
+    /// especially after `shrink`, so the area is the limit.
+    pub fn alloc_data(&mut self) -> Result<u32, IoError> {
+        Ok(self.alloc_data_run(None, 1)?.0)
+    }
+
+    /// Allocate up to `max` consecutive data blocks, starting from block
+    /// `goal` if possible, and return (first block ID, count).
+    pub fn alloc_data_run(&mut self, goal: Option<u32>, max: usize) -> Result<(u32, usize), IoError> {
+        let goal = goal
+            .and_then(|goal| goal.checked_sub(self.data_area_start_block))
+            .map_or(usize::MAX, |goal| goal as usize);
+        let (start, len) = self.data_bitmap
+            .alloc_run(&self.block_device, goal, max, self.data_area_blocks as usize)?
+            .ok_or(IoError::NoSpace)?;
+        Ok((start as u32 + self.data_area_start_block, len))
+    }
+
+    /// Whether new inodes should map their blocks by extents.
+    pub fn uses_extents(&self) -> bool {
+        self.incompat_features & FEATURE_EXTENTS != 0
+    }
+
+    /// Whether files may be compressed.
+    pub fn allows_compression(&self) -> bool {
+        self.incompat_features & FEATURE_COMPRESSION != 0
+    }
+
+    /// A block still used by another owner only loses this one.
+    pub fn dealloc_data(&mut self, block_id: u32) -> Result<(), IoError> {
+        if self.is_shared(block_id)? {
+            return self.modify_refcount(block_id, |count| *count -= 1);
+        }
+        block_cache_discard(block_id as usize, &self.block_device)?;
         self.data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize
         )
     }
 
+    /// Return (block id, offset) of the reference count of a data block.
+    fn refcount_pos(&self, block_id: u32) -> (usize, usize) {
+        let index = (block_id - self.data_area_start_block) as usize;
+        let block_size = self.block_device.block_size();
+        (self.refcount_start_block as usize + index / block_size, index % block_size)
+    }
+
+    /// The reference count of a data block is the number of its owners
+    /// besides the first one, the live file system and every snapshot
+    /// being an owner each.
+    fn modify_refcount(&self, block_id: u32, f: impl FnOnce(&mut u8)) -> Result<(), IoError> {
+        let (refcount_block, offset) = self.refcount_pos(block_id);
+        get_block_cache(refcount_block, Arc::clone(&self.block_device))?
+            .lock()
+            .modify(offset, f);
+        Ok(())
+    }
+
+    /// Whether a data block is also used by a snapshot, or the other way
+    /// round, so that it has to be copied before it is changed.
+    pub fn is_shared(&self, block_id: u32) -> Result<bool, IoError> {
+        if self.refcount_blocks == 0 {
+            return Ok(false);
+        }
+        let (refcount_block, offset) = self.refcount_pos(block_id);
+        Ok(get_block_cache(refcount_block, Arc::clone(&self.block_device))?
+            .lock()
+            .read(offset, |count: &u8| *count > 0))
+    }
+
+    fn block_cache(
+        &self,
+        block_id: u32,
+        checksum: Option<Checksum>,
+    ) -> Result<Arc<Mutex<BlockCache>>, IoError> {
+        match checksum {
+            Some(checksum) => get_checked_block_cache(
+                block_id as usize,
+                Arc::clone(&self.block_device),
+                checksum,
+            ),
+            None => get_block_cache(block_id as usize, Arc::clone(&self.block_device)),
+        }
+    }
+
+    /// Return a block the caller may change in place of `block_id`: the
+    /// block itself if it is not shared, otherwise a copy of it, which the
+    /// caller has to point to instead. `checksum` is the one of the block.
+    pub fn cow_block(&mut self, block_id: u32, checksum: Option<Checksum>) -> Result<u32, IoError> {
+        if !self.is_shared(block_id)? {
+            return Ok(block_id);
+        }
+        let new_block_id = self.alloc_data()?;
+        self.copy_block(block_id, new_block_id, checksum)?;
+        self.modify_refcount(block_id, |count| *count -= 1)?;
+        Ok(new_block_id)
+    }
+
+    /// Copy block `from` to block `to` through the cache, `checksum` is the
+    /// one of the block.
+    fn copy_block(&self, from: u32, to: u32, checksum: Option<Checksum>) -> Result<(), IoError> {
+        let content = self.block_cache(from, checksum)?
+            .lock()
+            .read_slice(|block: &[u8]| block.to_vec());
+        self.block_cache(to, checksum)?
+            .lock()
+            .modify_slice(|block: &mut [u8]| block.copy_from_slice(&content));
+        Ok(())
+    }
+
+    /// Blocks of the inode bitmap and the inode area, which follow the
+    /// SuperBlock.
+    fn inode_table_blocks(&self) -> usize {
+        let block_size = self.block_device.block_size();
+        let inode_area_bytes = self.inode_bitmap.maximum() * core::mem::size_of::<DiskInode>();
+        (self.inode_area_start_block - 1) as usize + (inode_area_bytes + block_size - 1) / block_size
+    }
+
+    /// Return the blocks of every allocated inode, marking the inodes
+    /// shared if `share`.
+    fn live_blocks(&self, share: bool) -> Result<Vec<u32>, IoError> {
+        let mut v: Vec<u32> = Vec::new();
+        for inode_id in 0..self.inode_bitmap.maximum() {
+            if !self.inode_bitmap.is_allocated(&self.block_device, inode_id)? {
+                continue;
+            }
+            let (block_id, offset) = self.get_disk_inode_pos(inode_id as u32);
+            let blocks = get_checked_block_cache(
+                block_id as usize,
+                Arc::clone(&self.block_device),
+                INODE_CHECKSUM,
+            )?.lock().modify(offset, |disk_inode: &mut DiskInode| {
+                if share {
+                    disk_inode.set_shared(true);
+                }
+                disk_inode.blocks(&self.block_device)
+            })?;
+            v.extend(blocks);
+        }
+        Ok(v)
+    }
+
+    fn snapshot_table(&self) -> Result<Arc<Mutex<BlockCache>>, IoError> {
+        get_checked_block_cache(
+            self.snapshot_table as usize,
+            Arc::clone(&self.block_device),
+            INODE_CHECKSUM,
+        )
+    }
+
+    /// Return (id, creation time) of every snapshot.
+    ///
+    /// A snapshot is a `DiskInode` in the snapshot table whose content is
+    /// a copy of the inode bitmap and the inode area, with holes for blocks
+    /// of free inodes. Slots of empty inodes are free.
+    pub fn snapshots(&self) -> Result<Vec<(usize, u32)>, IoError> {
+        if self.snapshot_table == 0 {
+            return Ok(Vec::new());
+        }
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let table = self.snapshot_table()?;
+        let table = table.lock();
+        Ok((0..self.block_device.block_size() / inode_size)
+            .map(|id| (id, table.read(id * inode_size, |snapshot: &DiskInode| {
+                (snapshot.size, snapshot.ctime)
+            })))
+            .filter(|(_, (size, _))| *size != 0)
+            .map(|(id, (_, time))| (id, time))
+            .collect())
+    }
+
+    /// Freeze the inode table and return the id of the new snapshot, or
+    /// `None` without `FEATURE_SNAPSHOTS` or if the snapshot table is full.
+    ///
+    /// Every block of the live file system gets the snapshot as one more
+    /// owner, so it is copied before it is written.
+    pub fn snapshot(&mut self) -> Result<Option<usize>, IoError> {
+        if self.snapshot_table == 0 {
+            return Ok(None);
+        }
+        let taken = self.snapshots()?;
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let block_size = self.block_device.block_size();
+        let id = match (0..block_size / inode_size).find(|id| taken.iter().all(|(i, _)| i != id)) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        for block_id in self.live_blocks(true)? {
+            self.modify_refcount(block_id, |count| *count += 1)?;
+        }
+        // blocks of the inode area without inodes in use are left as holes
+        let bitmap_blocks = (self.inode_area_start_block - 1) as usize;
+        let inodes_per_block = block_size / inode_size;
+        let mut copied: Vec<usize> = (0..bitmap_blocks).collect();
+        for j in bitmap_blocks..self.inode_table_blocks() {
+            let first = (j - bitmap_blocks) * inodes_per_block;
+            for inode_id in first..first + inodes_per_block {
+                if self.inode_bitmap.is_allocated(&self.block_device, inode_id)? {
+                    copied.push(j);
+                    break;
+                }
+            }
+        }
+        let table_size = (self.inode_table_blocks() * block_size) as u32;
+        let block_device = Arc::clone(&self.block_device);
+        let time = current_time();
+        self.snapshot_table()?.lock().modify(id * inode_size, |snapshot: &mut DiskInode| {
+            snapshot.initialize(DiskInodeType::File, time, false);
+            snapshot.size = table_size;
+            for j in copied {
+                let checksum = if j < bitmap_blocks { None } else { Some(INODE_CHECKSUM) };
+                let content = self.block_cache(1 + j as u32, checksum)?
+                    .lock()
+                    .read_slice(|block: &[u8]| block.to_vec());
+                // a single block, nothing is left to free if it fails
+                snapshot.alloc_blocks(j as u32, j as u32 + 1, &block_device, || self.alloc_data(), &mut Vec::new())?;
+                snapshot.write_at(j * block_size, &content, &block_device)?;
+            }
+            Ok(())
+        })?;
+        Ok(Some(id))
+    }
+
+    /// Bring the inode table back to snapshot `id`, which is kept so that
+    /// it can be rolled back to again. Return false if there is no such
+    /// snapshot. Inodes opened before refer to the restored ones afterwards.
+    pub fn rollback(&mut self, id: usize) -> Result<bool, IoError> {
+        if self.snapshots()?.iter().all(|(i, _)| *i != id) {
+            return Ok(false);
+        }
+        // the live file system stops owning its blocks ...
+        for block_id in self.live_blocks(false)? {
+            self.dealloc_data(block_id)?;
+        }
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let block_size = self.block_device.block_size();
+        let bitmap_blocks = (self.inode_area_start_block - 1) as usize;
+        self.snapshot_table()?.lock().read(id * inode_size, |snapshot: &DiskInode| {
+            let mut content = vec![0u8; block_size];
+            for j in 0..self.inode_table_blocks() {
+                // holes read as zeros
+                snapshot.read_at(j * block_size, &mut content, &self.block_device)?;
+                let checksum = if j < bitmap_blocks { None } else { Some(INODE_CHECKSUM) };
+                self.block_cache(1 + j as u32, checksum)?
+                    .lock()
+                    .modify_slice(|block: &mut [u8]| block.copy_from_slice(&content));
+            }
+            Ok(())
+        })?;
+        // ... and owns those of the snapshot as well
+        for block_id in self.live_blocks(true)? {
+            self.modify_refcount(block_id, |count| *count += 1)?;
+        }
+        // inodes unlinked since are back in their directories or free
+        // already, the last handle must not free them
+        for open in self.open_inodes.values_mut() {
+            open.unlinked = false;
+        }
+        Ok(true)
+    }
+
+    /// Remove snapshot `id` and free the blocks nothing else uses, return
+    /// false if there is no such snapshot.
+    pub fn delete_snapshot(&mut self, id: usize) -> Result<bool, IoError> {
+        if self.snapshots()?.iter().all(|(i, _)| *i != id) {
+            return Ok(false);
+        }
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let bitmap_bytes = (self.inode_area_start_block - 1) as usize * self.block_device.block_size();
+        let blocks = self.snapshot_table()?.lock().modify(id * inode_size, |snapshot: &mut DiskInode| {
+            let mut bitmap = vec![0u64; bitmap_bytes / 8];
+            snapshot.read_at(0, as_bytes_mut(&mut bitmap), &self.block_device)?;
+            let mut v: Vec<u32> = Vec::new();
+            // kept in u64s like `BlockCache` so that a DiskInode can be read in it
+            let mut record = vec![0u64; inode_size / 8];
+            for inode_id in 0..self.inode_bitmap.maximum() {
+                if bitmap[inode_id / 64] & (1u64 << (inode_id % 64)) == 0 {
+                    continue;
+                }
+                snapshot.read_at(
+                    bitmap_bytes + inode_id * inode_size,
+                    as_bytes_mut(&mut record),
+                    &self.block_device,
+                )?;
+                let disk_inode = unsafe { &*(record.as_ptr() as *const DiskInode) };
+                v.extend(disk_inode.blocks(&self.block_device)?);
+            }
+            // the copy of the inode table belongs to the snapshot alone
+            v.extend(snapshot.clear_size(&self.block_device)?);
+            Ok(v)
+        })?;
+        for block_id in blocks {
+            self.dealloc_data(block_id)?;
+        }
+        Ok(true)
+    }
+
+    /// Move the data of every file spread over more than one run of blocks
+    /// into a single run, if there is a free one long enough, and return
+    /// the number of files moved. It is done in place while the file system
+    /// is in use. Blocks shared with a snapshot are left where they are.
+    pub fn defrag(&mut self) -> Result<usize, IoError> {
+        let mut files = 0usize;
+        let block_device = Arc::clone(&self.block_device);
+        for inode_id in 0..self.inode_bitmap.maximum() {
+            if !self.inode_bitmap.is_allocated(&block_device, inode_id)? {
+                continue;
+            }
+            let (block_id, offset) = self.get_disk_inode_pos(inode_id as u32);
+            let freed = get_checked_block_cache(
+                block_id as usize,
+                Arc::clone(&block_device),
+                INODE_CHECKSUM,
+            )?.lock().modify(offset, |disk_inode: &mut DiskInode| {
+                let data: Vec<u32> = disk_inode.block_map(&block_device)?
+                    .into_iter()
+                    .filter(|block_id| *block_id != 0)
+                    .collect();
+                if data.windows(2).all(|pair| pair[1] == pair[0] + 1) {
+                    return Ok(None);
+                }
+                for &block_id in data.iter() {
+                    if self.is_shared(block_id)? {
+                        return Ok(None);
+                    }
+                }
+                let start = match self.data_bitmap.alloc_run(
+                    &block_device,
+                    usize::MAX,
+                    data.len(),
+                    self.data_area_blocks as usize,
+                )? {
+                    Some((start, len)) if len == data.len() => {
+                        start as u32 + self.data_area_start_block
+                    }
+                    Some((start, len)) => {
+                        for bit in start..start + len {
+                            self.data_bitmap.dealloc(&block_device, bit)?;
+                        }
+                        return Ok(None);
+                    }
+                    None => return Ok(None),
+                };
+                if disk_inode.is_shared() {
+                    disk_inode.unshare(&block_device, |block_id| {
+                        self.cow_block(block_id, Some(Checksum::Tail))
+                    })?;
+                }
+                let checksum = if disk_inode.is_dir() { Some(Checksum::Tail) } else { None };
+                let mut moved: BTreeMap<u32, u32> = BTreeMap::new();
+                for (i, &block_id) in data.iter().enumerate() {
+                    self.copy_block(block_id, start + i as u32, checksum)?;
+                    moved.insert(block_id, start + i as u32);
+                }
+                let mut freed = disk_inode.move_data(&moved, &block_device, || self.alloc_data())?;
+                freed.extend(data);
+                Ok(Some(freed))
+            })?;
+            if let Some(freed) = freed {
+                for block_id in freed {
+                    self.dealloc_data(block_id)?;
+                }
+                files += 1;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Move used blocks down to the start of the data area and cut the
+    /// area right after the last of them. Return the new number of blocks
+    /// of the file system, after which the device may be trimmed once it
+    /// is unmounted, or `None` if there are snapshots.
+    pub fn shrink(&mut self) -> Result<Option<u32>, IoError> {
+        if !self.snapshots()?.is_empty() {
+            return Ok(None);
+        }
+        let block_device = Arc::clone(&self.block_device);
+        let mut used = 0usize;
+        for bit in 0..self.data_area_blocks as usize {
+            if self.data_bitmap.is_allocated(&block_device, bit)? {
+                used += 1;
+            }
+        }
+        // every block at or above `limit` moves below it, unless blocks of
+        // extents added on the way take up the room
+        let limit = self.data_area_start_block + used as u32;
+        if self.snapshot_table != 0 {
+            let snapshot_table = self.move_block(self.snapshot_table, Some(INODE_CHECKSUM), limit)?;
+            self.snapshot_table = snapshot_table;
+            self.modify_super_block(|super_block| super_block.snapshot_table = snapshot_table)?;
+        }
+        for inode_id in 0..self.inode_bitmap.maximum() {
+            if !self.inode_bitmap.is_allocated(&block_device, inode_id)? {
+                continue;
+            }
+            let (block_id, offset) = self.get_disk_inode_pos(inode_id as u32);
+            let freed = get_checked_block_cache(
+                block_id as usize,
+                Arc::clone(&block_device),
+                INODE_CHECKSUM,
+            )?.lock().modify(offset, |disk_inode: &mut DiskInode| {
+                disk_inode.move_indirect(&block_device, |block_id| {
+                    self.move_block(block_id, Some(Checksum::Tail), limit)
+                })?;
+                let checksum = if disk_inode.is_dir() { Some(Checksum::Tail) } else { None };
+                let mut moved: BTreeMap<u32, u32> = BTreeMap::new();
+                for block_id in disk_inode.block_map(&block_device)? {
+                    if block_id >= limit {
+                        moved.insert(block_id, self.move_block(block_id, checksum, limit)?);
+                    }
+                }
+                disk_inode.move_data(&moved, &block_device, || self.alloc_data_below(limit))
+            })?;
+            for block_id in freed {
+                self.dealloc_data(block_id)?;
+            }
+        }
+        let mut data_area_blocks = 0u32;
+        for bit in (0..self.data_area_blocks as usize).rev() {
+            if self.data_bitmap.is_allocated(&block_device, bit)? {
+                data_area_blocks = bit as u32 + 1;
+                break;
+            }
+        }
+        self.data_area_blocks = data_area_blocks;
+        let total_blocks = self.data_area_start_block + data_area_blocks;
+        self.modify_super_block(|super_block| {
+            super_block.data_area_blocks = data_area_blocks;
+            super_block.total_blocks = total_blocks;
+        })?;
+        Ok(Some(total_blocks))
+    }
+
+    /// Allocate a data block below block `limit` if there is a free one,
+    /// or else anywhere in the data area.
+    fn alloc_data_below(&mut self, limit: u32) -> Result<u32, IoError> {
+        let below = self.data_bitmap.alloc_run(
+            &self.block_device,
+            usize::MAX,
+            1,
+            (limit - self.data_area_start_block) as usize,
+        )?;
+        match below {
+            Some((start, _)) => Ok(start as u32 + self.data_area_start_block),
+            None => self.alloc_data(),
+        }
+    }
+
+    /// Move a block at or above block `limit` below it and return where it
+    /// is, `checksum` is the one of the block.
+    fn move_block(&mut self, block_id: u32, checksum: Option<Checksum>, limit: u32) -> Result<u32, IoError> {
+        if block_id < limit {
+            return Ok(block_id);
+        }
+        let new_block_id = self.alloc_data_below(limit)?;
+        if new_block_id > block_id {
+            // no room left below, better stay
+            self.dealloc_data(new_block_id)?;
+            return Ok(block_id);
+        }
+        self.copy_block(block_id, new_block_id, checksum)?;
+        self.dealloc_data(block_id)?;
+        Ok(new_block_id)
+    }
+}
+
+fn as_bytes_mut(words: &mut [u64]) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 8) }
 }
\ No newline at end of file
@@ -1,21 +1,136 @@
-use core::fmt::{Debug, Formatter, Result};
+use core::fmt::{self, Debug, Formatter};
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    BlockCache,
+    Checksum,
+    IoError,
     get_block_cache,
+    get_checked_block_cache,
+    crc32c,
+    lz4,
 };
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
+use spin::Mutex;
 
-const EFS_MAGIC: u32 = 0x3b800001;
-const INODE_DIRECT_COUNT: usize = 28;
+/// Changed along with the on-disk layout until it got versioned, so that
+/// images of another layout are not taken for this one:
+///
+/// 0x3b800001: the original layout.
+/// 0x3b800002: `DiskInode` has timestamps.
+/// 0x3b800003: `DiskInode` has a mode and owner.
+///
+/// It stays as it is from then on, layout changes bump `EFS_VERSION`.
+const EFS_MAGIC: u32 = 0x3b800003;
+/// Magic numbers of older layouts, which are refused as such rather than
+/// as foreign images.
+const OLD_MAGICS: [u32; 2] = [0x3b800001, 0x3b800002];
+/// Version of the on-disk layout, bumped whenever it changes.
+///
+/// 1: `DiskInode` has a triple indirect block.
+/// 2: metadata blocks have CRC32C checksums, `DiskInode` gave a direct
+///    pointer for its own.
+/// 3: `SuperBlock` records the refcount area and the snapshot table.
+const EFS_VERSION: u32 = 3;
+/// Kept small enough for a `DiskInode` to stay 128 bytes with its
+/// timestamps, ownership and checksum.
+const INODE_DIRECT_COUNT: usize = 22;
 const NAME_LENGTH_LIMIT: usize = 27;
-const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
-const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
-const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
-#[allow(unused)]
-const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// Extents kept in the space of direct pointers of an inode.
+const INLINE_EXTENTS: usize = INODE_DIRECT_COUNT / 2;
+/// Inode flag telling that blocks are mapped by extents.
+const INODE_FLAG_EXTENTS: u8 = 1;
+/// Inode flag telling that its blocks may be shared with a snapshot, so
+/// its indirect blocks have to be copied before they are changed.
+const INODE_FLAG_SHARED: u8 = 2;
+/// The data is compressed cluster by cluster, see `DiskInode::compress`.
+const INODE_FLAG_COMPRESSED: u8 = 4;
+/// Blocks compressed together in a compressed file.
+const CLUSTER_BLOCKS: u32 = 4;
+/// Seconds after which atime is always updated on read.
+const RELATIME_INTERVAL: u32 = 24 * 60 * 60;
+
+/// New inodes map their blocks by extents instead of direct/indirect pointers.
+///
+/// Incompatible: an implementation without it would misread such inodes.
+pub const FEATURE_EXTENTS: u32 = 1;
+/// Data blocks are reference counted so that snapshots can share them.
+///
+/// Incompatible: an implementation without it would free or change blocks
+/// still used by a snapshot.
+pub const FEATURE_SNAPSHOTS: u32 = 2;
+/// Files may be compressed.
+///
+/// Incompatible: an implementation without it would read compressed data
+/// as it is stored.
+pub const FEATURE_COMPRESSION: u32 = 4;
+/// Incompatible features understood here, images with any other one are
+/// refused. Unknown compatible features are ignored.
+const INCOMPAT_SUPPORTED: u32 = FEATURE_EXTENTS | FEATURE_SNAPSHOTS | FEATURE_COMPRESSION;
+/// Values of `SuperBlock::state`, 0 so that older images count as clean.
+const STATE_CLEAN: u32 = 0;
+const STATE_DIRTY: u32 = 1;
+const LABEL_LENGTH: usize = 16;
+
+/// Why an image can not be mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// The magic number does not match, it is not an easy-fs image.
+    NotEasyFs,
+    /// The layout version is not `EFS_VERSION`, 0 for a layout older than
+    /// the version field.
+    UnsupportedVersion(u32),
+    /// Incompatible features this implementation does not know.
+    UnsupportedFeatures(u32),
+    BadBlockSize(u32),
+    /// The SuperBlock does not match its checksum.
+    BadChecksum,
+    /// The SuperBlock could not be read, or marked mounted.
+    Io(IoError),
+}
+
+/// Bounds of the block mapping of an inode, which depend on the block size.
+struct Bounds {
+    /// Block ids in an indirect block, the last word is its checksum.
+    per_indirect: usize,
+    indirect1: usize,
+    indirect2: usize,
+    indirect3: usize,
+    /// Extents in an extent block, which takes the place of indirect1.
+    per_extent_block: usize,
+    extent1: usize,
+    /// Extents up to the end of the blocks indirect2 points to.
+    max_extents: usize,
+}
+
+impl Bounds {
+    fn of(block_device: &Arc<dyn BlockDevice>) -> Self {
+        let per_indirect = block_device.block_size() / 4 - 1;
+        let indirect1 = DIRECT_BOUND + per_indirect;
+        let indirect2 = indirect1 + per_indirect.pow(2);
+        Self {
+            per_indirect,
+            indirect1,
+            indirect2,
+            indirect3: indirect2 + per_indirect.pow(3),
+            per_extent_block: per_indirect / 2,
+            extent1: INLINE_EXTENTS + per_indirect / 2,
+            max_extents: INLINE_EXTENTS + per_indirect / 2 + per_indirect * (per_indirect / 2),
+        }
+    }
+}
+
+/// Load an indirect block or a block of extents, which end with a checksum.
+fn indirect_block(
+    block_id: u32,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<Arc<Mutex<BlockCache>>, IoError> {
+    get_checked_block_cache(block_id as usize, Arc::clone(block_device), Checksum::Tail)
+}
 
 #[repr(C)]
 pub struct SuperBlock {
@@ -25,28 +140,64 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// Incompatible `FEATURE_*` flags, 0 on images made before features
+    /// were introduced.
+    pub incompat_features: u32,
+    pub version: u32,
+    /// Bytes in a block, a multiple of `BLOCK_SZ`. Blocks are addressed in
+    /// this size from the start of the device, the SuperBlock lies in the
+    /// first sector of block 0.
+    block_size: u32,
+    /// Features an implementation may safely ignore.
+    pub compat_features: u32,
+    /// `STATE_DIRTY` while mounted, so an image that was not unmounted
+    /// cleanly can be told.
+    state: u32,
+    /// Volume label, padded with zeros.
+    label: [u8; LABEL_LENGTH],
+    pub uuid: [u8; 16],
+    /// Blocks of reference counts after the data bitmap, a byte for every
+    /// data block. 0 without `FEATURE_SNAPSHOTS`.
+    pub refcount_blocks: u32,
+    /// Block of the snapshot table, 0 without `FEATURE_SNAPSHOTS`.
+    pub snapshot_table: u32,
+    /// CRC32C of the fields above.
+    checksum: u32,
 }
 
 impl Debug for SuperBlock {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("SuperBlock")
             .field("total_blocks", &self.total_blocks)
             .field("inode_bitmap_blocks", &self.inode_bitmap_blocks)
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("incompat_features", &self.incompat_features)
+            .field("version", &self.version)
+            .field("block_size", &self.block_size)
+            .field("compat_features", &self.compat_features)
+            .field("state", &self.state)
+            .field("label", &self.label())
+            .field("uuid", &self.uuid)
+            .field("refcount_blocks", &self.refcount_blocks)
+            .field("snapshot_table", &self.snapshot_table)
             .finish()
     }
 }
 
 impl SuperBlock {
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         &mut self,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
+        refcount_blocks: u32,
         data_area_blocks: u32,
+        incompat_features: u32,
+        block_size: usize,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
@@ -55,39 +206,152 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            incompat_features,
+            version: EFS_VERSION,
+            block_size: block_size as u32,
+            compat_features: 0,
+            state: STATE_CLEAN,
+            label: [0; LABEL_LENGTH],
+            uuid: [0; 16],
+            refcount_blocks,
+            snapshot_table: 0,
+            checksum: 0,
+        }
+    }
+    /// Images made before the block size was recorded have 0 there.
+    pub fn block_size(&self) -> usize {
+        match self.block_size {
+            0 => BLOCK_SZ,
+            block_size => block_size as usize,
+        }
+    }
+    /// Check that the image can be understood, images of other layout
+    /// versions can not.
+    pub fn check(&self) -> Result<(), OpenError> {
+        if OLD_MAGICS.contains(&self.magic) {
+            return Err(OpenError::UnsupportedVersion(0));
+        }
+        if self.magic != EFS_MAGIC {
+            return Err(OpenError::NotEasyFs);
+        }
+        if self.version != EFS_VERSION {
+            return Err(OpenError::UnsupportedVersion(self.version));
         }
+        if !self.checksum_ok() {
+            return Err(OpenError::BadChecksum);
+        }
+        let unknown = self.incompat_features & !INCOMPAT_SUPPORTED;
+        if unknown != 0 {
+            return Err(OpenError::UnsupportedFeatures(unknown));
+        }
+        let block_size = self.block_size();
+        if block_size % BLOCK_SZ != 0 || !block_size.is_power_of_two() {
+            return Err(OpenError::BadBlockSize(self.block_size));
+        }
+        Ok(())
+    }
+    fn checksummed_bytes(&self) -> &[u8] {
+        let len = core::mem::size_of::<Self>() - 4;
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, len) }
+    }
+    pub fn checksum_ok(&self) -> bool {
+        self.checksum == crc32c(self.checksummed_bytes())
+    }
+    /// Has to be called after every change.
+    pub fn update_checksum(&mut self) {
+        self.checksum = crc32c(self.checksummed_bytes());
+    }
+    pub fn is_clean(&self) -> bool {
+        self.state == STATE_CLEAN
+    }
+    pub fn set_clean(&mut self, clean: bool) {
+        self.state = if clean { STATE_CLEAN } else { STATE_DIRTY };
+    }
+    pub fn label(&self) -> &str {
+        let len = self.label.iter().position(|b| *b == 0).unwrap_or(LABEL_LENGTH);
+        core::str::from_utf8(&self.label[..len]).unwrap_or("")
     }
-    pub fn is_valid(&self) -> bool {
-        self.magic == EFS_MAGIC
+    /// Longer labels are cut to `LABEL_LENGTH` bytes.
+    pub fn set_label(&mut self, label: &str) {
+        let mut len = label.len().min(LABEL_LENGTH);
+        while !label.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.label = [0; LABEL_LENGTH];
+        self.label[..len].copy_from_slice(&label.as_bytes()[..len]);
     }
 }
 
+/// Where a lookup in the extents of an inode stopped, so that the next one
+/// goes on from there rather than from the first extent. It is only valid
+/// as long as the inode does not change.
+#[derive(Default)]
+pub struct ExtentCursor {
+    index: usize,
+    /// The first block mapped by extent `index`.
+    first: u32,
+    /// The last cluster of a compressed file read, and its content, so that
+    /// reading it in small pieces decompresses it once.
+    cluster: Option<(u32, Vec<u8>)>,
+}
+
 #[derive(PartialEq)]
 pub enum DiskInodeType {
     File,
     Directory,
 }
 
-type IndirectBlock = [u32; BLOCK_SZ / 4];
-type DataBlock = [u8; BLOCK_SZ];
-
 #[repr(C)]
 pub struct DiskInode {
     pub size: u32,
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
+    pub indirect3: u32,
+    /// Last access, last modification of the content and last change of
+    /// the inode, in seconds since the Unix epoch.
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    /// Permission bits in the same layout as Unix, without the file type.
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
     type_: DiskInodeType,
+    flags: u8,
+    /// CRC32C of the fields above, updated when the block is written back.
+    checksum: u32,
 }
 
+/// Inode blocks are arrays of `DiskInode`s, each with its own checksum.
+pub const INODE_CHECKSUM: Checksum = Checksum::Records(core::mem::size_of::<DiskInode>());
+
 impl DiskInode {
-    /// indirect1 and indirect2 block are allocated only when they are needed.
-    pub fn initialize(&mut self, type_: DiskInodeType) {
+    /// indirect1/2/3 blocks are allocated only when they are needed.
+    ///
+    /// New inodes belong to root. Since the only directory is the root
+    /// directory shared by everyone, it is sticky like `/tmp`.
+    ///
+    /// With `extents`, the space of direct pointers holds the first extents,
+    /// indirect1 points to a block of extents and indirect2 points to a block
+    /// of pointers to such blocks.
+    pub fn initialize(&mut self, type_: DiskInodeType, time: u32, extents: bool) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.indirect3 = 0;
+        self.atime = time;
+        self.mtime = time;
+        self.ctime = time;
+        self.mode = match type_ {
+            DiskInodeType::File => 0o644,
+            DiskInodeType::Directory => 0o1777,
+        };
+        self.uid = 0;
+        self.gid = 0;
         self.type_ = type_;
+        self.flags = if extents { INODE_FLAG_EXTENTS } else { 0 };
     }
     pub fn is_dir(&self) -> bool {
         self.type_ == DiskInodeType::Directory
@@ -96,306 +360,1000 @@ impl DiskInode {
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
+    pub fn uses_extents(&self) -> bool {
+        self.flags & INODE_FLAG_EXTENTS != 0
+    }
+    pub fn is_shared(&self) -> bool {
+        self.flags & INODE_FLAG_SHARED != 0
+    }
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_FLAG_COMPRESSED != 0
+    }
+    pub fn set_shared(&mut self, shared: bool) {
+        if shared {
+            self.flags |= INODE_FLAG_SHARED;
+        } else {
+            self.flags &= !INODE_FLAG_SHARED;
+        }
+    }
+    /// The content has been changed at `time`.
+    pub fn touch(&mut self, time: u32) {
+        self.mtime = time;
+        self.ctime = time;
+    }
+    /// Like relatime, atime is only updated if it is not newer than
+    /// mtime/ctime or it is more than a day old, so that most reads do not
+    /// dirty the inode block.
+    pub fn atime_needs_update(&self, time: u32) -> bool {
+        self.atime <= self.mtime
+            || self.atime <= self.ctime
+            || time >= self.atime.saturating_add(RELATIME_INTERVAL)
+    }
     /// Return block number correspond to size.
-    pub fn data_blocks(&self) -> u32 {
-        Self::_data_blocks(self.size)
-    }
-    fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
-    }
-    /// Return number of blocks needed include indirect1/2.
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
-        }
-        // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total += (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
-        }
-        total as u32
-    }
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
-    }
-    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
-        let inner_id = inner_id as usize;
-        if inner_id < INODE_DIRECT_COUNT {
-            self.direct[inner_id]
-        } else if inner_id < INDIRECT1_BOUND {
-            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect_block: &IndirectBlock| {
-                    indirect_block[inner_id - INODE_DIRECT_COUNT]
-                })
+    pub fn data_blocks(&self, block_size: usize) -> u32 {
+        ((self.size as usize + block_size - 1) / block_size) as u32
+    }
+    /// Return (level, index) of the pointer to the `inner_id`-th block,
+    /// level 0 is `direct`, level n is under the tree of indirect{n}.
+    fn locate(inner_id: usize, bounds: &Bounds) -> Result<(usize, usize), IoError> {
+        Ok(if inner_id < DIRECT_BOUND {
+            (0, inner_id)
+        } else if inner_id < bounds.indirect1 {
+            (1, inner_id - DIRECT_BOUND)
+        } else if inner_id < bounds.indirect2 {
+            (2, inner_id - bounds.indirect1)
+        } else if inner_id < bounds.indirect3 {
+            (3, inner_id - bounds.indirect2)
         } else {
-            let last = inner_id - INDIRECT1_BOUND;
-            let indirect1 = get_block_cache(
-                self.indirect2 as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .read(0, |indirect2: &IndirectBlock| {
-                indirect2[last / INODE_INDIRECT1_COUNT]
-            });
-            get_block_cache(
-                indirect1 as usize,
-                Arc::clone(block_device)
-            )
+            return Err(IoError::TooLarge);
+        })
+    }
+    /// Bytes the file may grow to, as many as `size` holds, and without
+    /// extents as many as indirect3 reaches.
+    pub fn max_size(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        let max_size = u32::MAX as usize;
+        if self.uses_extents() {
+            max_size
+        } else {
+            max_size.min(Bounds::of(block_device).indirect3 * block_device.block_size())
+        }
+    }
+    fn indirect_root(&mut self, level: usize) -> &mut u32 {
+        match level {
+            1 => &mut self.indirect1,
+            2 => &mut self.indirect2,
+            _ => &mut self.indirect3,
+        }
+    }
+    /// A zero block id is a hole, which reads as zeros. It is also returned
+    /// when an indirect block on the way is a hole.
+    pub fn get_block_id(
+        &self,
+        inner_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<u32, IoError> {
+        self.get_block_id_from(inner_id, block_device, &mut ExtentCursor::default())
+    }
+    /// Like `get_block_id`, looking through extents from `cursor` on, which
+    /// makes going through the blocks in order linear.
+    pub fn get_block_id_from(
+        &self,
+        inner_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        cursor: &mut ExtentCursor,
+    ) -> Result<u32, IoError> {
+        if self.uses_extents() {
+            return self.extent_block_id(inner_id, block_device, cursor);
+        }
+        let bounds = Bounds::of(block_device);
+        let (level, mut index) = Self::locate(inner_id as usize, &bounds)?;
+        let mut block_id = match level {
+            0 => return Ok(self.direct[index]),
+            1 => self.indirect1,
+            2 => self.indirect2,
+            _ => self.indirect3,
+        };
+        for l in (0..level).rev() {
+            if block_id == 0 {
+                return Ok(0);
+            }
+            let span = bounds.per_indirect.pow(l as u32);
+            block_id = indirect_block(block_id, block_device)?
+                .lock()
+                .read_slice(|indirect: &[u32]| indirect[index / span]);
+            index %= span;
+        }
+        Ok(block_id)
+    }
+    /// Point the `inner_id`-th block to `block_id`, indirect blocks missing
+    /// on the way are taken from `alloc` unless a hole is being made.
+    fn set_block_id(
+        &mut self,
+        inner_id: u32,
+        block_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        alloc: &mut impl FnMut() -> Result<u32, IoError>,
+    ) -> Result<(), IoError> {
+        let bounds = Bounds::of(block_device);
+        let (level, mut index) = Self::locate(inner_id as usize, &bounds)?;
+        if level == 0 {
+            self.direct[index] = block_id;
+            return Ok(());
+        }
+        let root = self.indirect_root(level);
+        if *root == 0 {
+            if block_id == 0 {
+                return Ok(());
+            }
+            *root = alloc()?;
+        }
+        let mut indirect = *root;
+        for l in (1..level).rev() {
+            let span = bounds.per_indirect.pow(l as u32);
+            indirect = indirect_block(indirect, block_device)?
+                .lock()
+                .modify_slice(|indirect: &mut [u32]| {
+                    let entry = &mut indirect[index / span];
+                    if *entry == 0 && block_id != 0 {
+                        *entry = alloc()?;
+                    }
+                    Ok(*entry)
+                })?;
+            if indirect == 0 {
+                return Ok(());
+            }
+            index %= span;
+        }
+        indirect_block(indirect, block_device)?
             .lock()
-            .read(0, |indirect1: &IndirectBlock| {
-                indirect1[last % INODE_INDIRECT1_COUNT]
-            })
+            .modify_slice(|indirect: &mut [u32]| indirect[index] = block_id);
+        Ok(())
+    }
+    /// Back the holes among blocks [first, end) with blocks from `alloc`,
+    /// the size is not changed. Extent inodes are handled by `remap_extents`.
+    ///
+    /// If `alloc` fails, the blocks backed so far are holes again and pushed
+    /// to `v` to be freed, indirect blocks taken on the way are kept.
+    pub fn alloc_blocks(
+        &mut self,
+        first: u32,
+        end: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        mut alloc: impl FnMut() -> Result<u32, IoError>,
+        v: &mut Vec<u32>,
+    ) -> Result<(), IoError> {
+        assert!(!self.uses_extents());
+        let mut backed: Vec<u32> = Vec::new();
+        for inner_id in first..end {
+            if self.get_block_id(inner_id, block_device)? != 0 {
+                continue;
+            }
+            // indirect blocks on the way first, so that a data block is only
+            // taken once it can be mapped
+            let block_id = self.set_block_id(inner_id, u32::MAX, block_device, &mut alloc)
+                .and_then(|_| alloc());
+            match block_id {
+                Ok(block_id) => {
+                    self.set_block_id(inner_id, block_id, block_device, &mut alloc)?;
+                    backed.push(inner_id);
+                }
+                Err(err) => {
+                    self.set_block_id(inner_id, 0, block_device, &mut alloc)?;
+                    for inner_id in backed {
+                        v.push(self.get_block_id(inner_id, block_device)?);
+                        self.set_block_id(inner_id, 0, block_device, &mut alloc)?;
+                    }
+                    return Err(err);
+                }
+            }
         }
+        Ok(())
     }
-    pub fn increase_size(
+    /// Turn blocks [first, end) into holes and return the data blocks that
+    /// should be deallocated. Indirect blocks are kept even if they become
+    /// empty, `clear_size` frees them at last.
+    pub fn punch_blocks(
         &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
+        first: u32,
+        end: u32,
         block_device: &Arc<dyn BlockDevice>,
-    ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32{
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
+    ) -> Result<Vec<u32>, IoError> {
+        assert!(!self.uses_extents());
+        let mut v: Vec<u32> = Vec::new();
+        for inner_id in first..end {
+            let block_id = self.get_block_id(inner_id, block_device)?;
+            if block_id != 0 {
+                self.set_block_id(inner_id, 0, block_device, &mut || unreachable!())?;
+                v.push(block_id);
             }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
+        }
+        Ok(v)
+    }
+    /// Point the `inner_id`-th block, which is not a hole, to `block_id`
+    /// instead and return the blocks of extents no longer used.
+    pub fn replace_block(
+        &mut self,
+        inner_id: u32,
+        block_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        mut alloc: impl FnMut() -> Result<u32, IoError>,
+    ) -> Result<Vec<u32>, IoError> {
+        if self.uses_extents() {
+            self.remap_extents(inner_id, vec![(block_id, 1)], block_device, alloc)
         } else {
-            return;
+            self.set_block_id(inner_id, block_id, block_device, &mut alloc)?;
+            Ok(Vec::new())
         }
-        // fill indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                current_blocks += 1;
+    }
+    /// Replace every indirect block or block of extents by `copy` of it,
+    /// starting from the top so that a block is changed only after it has
+    /// been copied. Data blocks are left to the writes.
+    pub fn unshare(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        copy: impl FnMut(u32) -> Result<u32, IoError>,
+    ) -> Result<(), IoError> {
+        self.move_indirect(block_device, copy)?;
+        self.set_shared(false);
+        Ok(())
+    }
+    /// Point to `move_block` of every indirect block or block of extents,
+    /// which returns where the block is from now on. Blocks are moved from
+    /// the top down so that only blocks at their new place are changed.
+    ///
+    /// Both mappings keep their indirect blocks under indirect{n} at level
+    /// n, extents just do not use indirect3.
+    pub fn move_indirect(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        mut move_block: impl FnMut(u32) -> Result<u32, IoError>,
+    ) -> Result<(), IoError> {
+        for level in 1..=3 {
+            let root = self.indirect_root(level);
+            if *root != 0 {
+                *root = move_block(*root)?;
+                Self::move_indirect_entries(*root, level, block_device, &mut move_block)?;
             }
-        });
-        // alloc indirect2
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
+        }
+        Ok(())
+    }
+    fn move_indirect_entries(
+        block_id: u32,
+        level: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        move_block: &mut impl FnMut(u32) -> Result<u32, IoError>,
+    ) -> Result<(), IoError> {
+        if level == 1 {
+            return Ok(());
+        }
+        let per_indirect = Bounds::of(block_device).per_indirect;
+        let entries = indirect_block(block_id, block_device)?
+            .lock()
+            .read_slice(|indirect: &[u32]| indirect[..per_indirect].to_vec());
+        for (i, &entry) in entries.iter().enumerate().filter(|(_, entry)| **entry != 0) {
+            let new_entry = move_block(entry)?;
+            if new_entry != entry {
+                indirect_block(block_id, block_device)?
+                    .lock()
+                    .modify_slice(|indirect: &mut [u32]| indirect[i] = new_entry);
             }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
-        } else {
-            return;
+            Self::move_indirect_entries(new_entry, level - 1, block_device, move_block)?;
         }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
-        // alloc low-level indirect1
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                if b0 == 0 {
-                    indirect2[a0] = new_blocks.next().unwrap();
-                }
-                // fill current
-                get_block_cache(
-                    indirect2[a0] as usize,
-                    Arc::clone(block_device)
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    indirect1[b0] = new_blocks.next().unwrap();
-                });
-                // move to next
-                b0 += 1;
-                if b0 == INODE_INDIRECT1_COUNT {
-                    b0 = 0;
-                    a0 += 1;
-                }
-            } 
-        });
+        Ok(())
+    }
+    /// Return the block id of every data block in order, 0 for holes.
+    pub fn block_map(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u32>, IoError> {
+        if !self.uses_extents() {
+            return (0..self.data_blocks(block_device.block_size()))
+                .map(|inner_id| self.get_block_id(inner_id, block_device))
+                .collect();
+        }
+        let mut v: Vec<u32> = Vec::new();
+        let (count, _) = self.last_extent(block_device)?;
+        for i in 0..count {
+            match self.extent(i, block_device)? {
+                (0, len) => v.resize(v.len() + len as usize, 0),
+                (start, len) => v.extend(start..start + len),
+            }
+        }
+        Ok(v)
+    }
+    /// Point data blocks found in `moved` to the blocks they map to and
+    /// return the blocks of extents no longer used. Blocks for extents are
+    /// taken from `alloc`.
+    pub fn move_data(
+        &mut self,
+        moved: &BTreeMap<u32, u32>,
+        block_device: &Arc<dyn BlockDevice>,
+        mut alloc: impl FnMut() -> Result<u32, IoError>,
+    ) -> Result<Vec<u32>, IoError> {
+        let block_map = self.block_map(block_device)?;
+        let new_block_id = |block_id: &u32| *moved.get(block_id).unwrap_or(block_id);
+        if self.uses_extents() {
+            let mut runs: Vec<(u32, u32)> = Vec::new();
+            for block_id in block_map.iter() {
+                Self::push_extent(&mut runs, (new_block_id(block_id), 1));
+            }
+            return self.remap_extents(0, runs, block_device, alloc);
+        }
+        for (inner_id, block_id) in block_map.iter().enumerate() {
+            if *block_id != 0 && moved.contains_key(block_id) {
+                self.set_block_id(inner_id as u32, new_block_id(block_id), block_device, &mut alloc)?;
+            }
+        }
+        Ok(Vec::new())
+    }
+    /// Return every block used by this inode, data blocks as well as
+    /// indirect blocks or blocks of extents.
+    pub fn blocks(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u32>, IoError> {
+        if self.uses_extents() {
+            return self.blocks_extents(block_device);
+        }
+        // holes are zero pointers at every level
+        let mut v: Vec<u32> = self.direct.iter().copied().filter(|id| *id != 0).collect();
+        for level in 1..=3 {
+            let root = match level {
+                1 => self.indirect1,
+                2 => self.indirect2,
+                _ => self.indirect3,
+            };
+            Self::collect_indirect(root, level, true, block_device, &mut v)?;
+        }
+        Ok(v)
     }
 
     /// Clear size to zero and return blocks that should be deallocated.
     ///
     /// We will clear the block contents to zero later.
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
-        let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
+    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u32>, IoError> {
+        let v = self.blocks(block_device)?;
+        // nothing is changed unless every block could be read
         self.size = 0;
-        let mut current_blocks = 0usize;
-        // direct
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
-        }
-        // indirect1 block
-        if data_blocks > INODE_DIRECT_COUNT {
-            v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.indirect1 = 0;
+        self.indirect2 = 0;
+        self.indirect3 = 0;
+        // nothing is left to share or to decompress
+        self.set_shared(false);
+        self.flags &= !INODE_FLAG_COMPRESSED;
+        Ok(v)
+    }
+    /// Push an indirect block of `level` and the indirect blocks under it to
+    /// `v`, also the data blocks under it if `data`.
+    fn collect_indirect(
+        block_id: u32,
+        level: usize,
+        data: bool,
+        block_device: &Arc<dyn BlockDevice>,
+        v: &mut Vec<u32>,
+    ) -> Result<(), IoError> {
+        if block_id == 0 {
+            return Ok(());
+        }
+        v.push(block_id);
+        // copied out so that no more than one block is locked at a time
+        let per_indirect = Bounds::of(block_device).per_indirect;
+        let entries = indirect_block(block_id, block_device)?
+            .lock()
+            .read_slice(|indirect: &[u32]| indirect[..per_indirect].to_vec());
+        for &entry in entries.iter().filter(|entry| **entry != 0) {
+            if level > 1 {
+                Self::collect_indirect(entry, level - 1, data, block_device, v)?;
+            } else if data {
+                v.push(entry);
+            }
+        }
+        Ok(())
+    }
+    /// Push the blocks of this inode which have checksums to `v`: indirect
+    /// blocks or blocks of extents, and data blocks of a directory.
+    pub fn checked_blocks(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        v: &mut Vec<u32>,
+    ) -> Result<(), IoError> {
+        if self.uses_extents() {
+            let (count, _) = self.last_extent(block_device)?;
+            if count > INLINE_EXTENTS {
+                v.push(self.indirect1);
+            }
+            let bounds = Bounds::of(block_device);
+            if count > bounds.extent1 {
+                v.push(self.indirect2);
+                let blocks = (count - bounds.extent1 + bounds.per_extent_block - 1) / bounds.per_extent_block;
+                indirect_block(self.indirect2, block_device)?
+                    .lock()
+                    .read_slice(|indirect2: &[u32]| v.extend_from_slice(&indirect2[..blocks]));
+            }
         } else {
-            return v;
-        }
-        // indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                v.push(indirect1[current_blocks]);
-                //indirect1[current_blocks] = 0;
-                current_blocks += 1;
+            for level in 1..=3 {
+                let root = match level {
+                    1 => self.indirect1,
+                    2 => self.indirect2,
+                    _ => self.indirect3,
+                };
+                Self::collect_indirect(root, level, false, block_device, v)?;
             }
-        });
-        self.indirect1 = 0;
-        // indirect2 block
-        if data_blocks > INODE_INDIRECT1_COUNT {
-            v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
+        }
+        if self.is_dir() {
+            for inner_id in 0..self.data_blocks(block_device.block_size()) {
+                match self.get_block_id(inner_id, block_device)? {
+                    0 => {}
+                    block_id => v.push(block_id),
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Return (start block id, length) of the i-th extent, a start of zero
+    /// is a hole.
+    fn extent(&self, i: usize, block_device: &Arc<dyn BlockDevice>) -> Result<(u32, u32), IoError> {
+        let bounds = Bounds::of(block_device);
+        let (block_id, pos) = if i < INLINE_EXTENTS {
+            return Ok((self.direct[2 * i], self.direct[2 * i + 1]));
+        } else if i < bounds.extent1 {
+            (self.indirect1, i - INLINE_EXTENTS)
         } else {
-            return v;
-        }
-        // indirect2
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            // full indirect1 blocks
-            for i in 0..a1 {
-                v.push(indirect2[i]);
-                get_block_cache(
-                    indirect2[i] as usize,
-                    Arc::clone(block_device),
-                )
+            let last = i - bounds.extent1;
+            let block_id = indirect_block(self.indirect2, block_device)?
                 .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..INODE_INDIRECT1_COUNT {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
-                    }
-                });
-                //indirect2[i] = 0;
+                .read_slice(|indirect2: &[u32]| indirect2[last / bounds.per_extent_block]);
+            (block_id, last % bounds.per_extent_block)
+        };
+        Ok(indirect_block(block_id, block_device)?
+            .lock()
+            .read_slice(|extents: &[u32]| (extents[2 * pos], extents[2 * pos + 1])))
+    }
+    /// The block holding the extent must be there, see `remap_extents`.
+    fn set_extent(
+        &mut self,
+        i: usize,
+        extent: (u32, u32),
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<(), IoError> {
+        let bounds = Bounds::of(block_device);
+        let (block_id, pos) = if i < INLINE_EXTENTS {
+            self.direct[2 * i] = extent.0;
+            self.direct[2 * i + 1] = extent.1;
+            return Ok(());
+        } else if i < bounds.extent1 {
+            (self.indirect1, i - INLINE_EXTENTS)
+        } else {
+            let last = i - bounds.extent1;
+            let block_id = indirect_block(self.indirect2, block_device)?
+                .lock()
+                .read_slice(|indirect2: &[u32]| indirect2[last / bounds.per_extent_block]);
+            (block_id, last % bounds.per_extent_block)
+        };
+        indirect_block(block_id, block_device)?
+            .lock()
+            .modify_slice(|extents: &mut [u32]| {
+                extents[2 * pos] = extent.0;
+                extents[2 * pos + 1] = extent.1;
+            });
+        Ok(())
+    }
+    /// Return the number of extents and the last one of them. Extents that
+    /// do not cover the size before the last possible one are corrupted.
+    fn last_extent(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<(usize, Option<(u32, u32)>), IoError> {
+        let data_blocks = self.data_blocks(block_device.block_size());
+        let max_extents = Bounds::of(block_device).max_extents;
+        let mut mapped = 0u32;
+        let mut count = 0usize;
+        let mut last = None;
+        while mapped < data_blocks {
+            if count == max_extents {
+                return Err(IoError::Corrupted(self.indirect2 as usize));
+            }
+            let extent = self.extent(count, block_device)?;
+            mapped += extent.1;
+            count += 1;
+            last = Some(extent);
+        }
+        Ok((count, last))
+    }
+    fn extent_block_id(
+        &self,
+        inner_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        cursor: &mut ExtentCursor,
+    ) -> Result<u32, IoError> {
+        if inner_id < cursor.first {
+            cursor.index = 0;
+            cursor.first = 0;
+        }
+        let max_extents = Bounds::of(block_device).max_extents;
+        while cursor.index < max_extents {
+            let (start, len) = self.extent(cursor.index, block_device)?;
+            if inner_id < cursor.first + len {
+                return Ok(if start == 0 { 0 } else { start + inner_id - cursor.first });
+            }
+            cursor.first += len;
+            cursor.index += 1;
+        }
+        Err(IoError::Corrupted(self.indirect2 as usize))
+    }
+    /// Return the block right after the last data block, where the file
+    /// would best grow to, or `None` for an empty file or one ending with
+    /// a hole.
+    pub fn next_block_goal(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Option<u32>, IoError> {
+        if !self.uses_extents() {
+            let data_blocks = self.data_blocks(block_device.block_size());
+            return Ok(if data_blocks == 0 {
+                None
+            } else {
+                match self.get_block_id(data_blocks - 1, block_device)? {
+                    0 => None,
+                    block_id => Some(block_id + 1),
+                }
+            });
+        }
+        Ok(self.last_extent(block_device)?.1
+            .filter(|(start, _)| *start != 0)
+            .map(|(start, len)| start + len))
+    }
+    /// Append `extent` to `extents`, merging it into the last one if they
+    /// are contiguous or both holes.
+    pub fn push_extent(extents: &mut Vec<(u32, u32)>, extent: (u32, u32)) {
+        if extent.1 == 0 {
+            return;
+        }
+        if let Some(last) = extents.last_mut() {
+            if (last.0 == 0 && extent.0 == 0)
+                || (last.0 != 0 && last.0 + last.1 == extent.0) {
+                last.1 += extent.1;
+                return;
             }
-            // last indirect1 block
-            if b1 > 0 {
-                v.push(indirect2[a1]);
-                get_block_cache(
-                    indirect2[a1] as usize,
-                    Arc::clone(block_device),
-                )
+        }
+        extents.push(extent);
+    }
+    /// Map blocks [first, first + n) to `runs` which are n blocks in total,
+    /// and return the blocks of extents no longer used. Blocks previously
+    /// mapped there are not freed. `first` may be at most the current
+    /// number of data blocks, the size is updated by the caller afterwards.
+    ///
+    /// Since the logical position of an extent is implied by the ones before
+    /// it, all of them are rewritten. Blocks for extents are taken from
+    /// `alloc`. Nothing is changed if the extents would not fit in the inode,
+    /// or `alloc` fails, in which case blocks it gave are not used.
+    pub fn remap_extents(
+        &mut self,
+        first: u32,
+        runs: Vec<(u32, u32)>,
+        block_device: &Arc<dyn BlockDevice>,
+        mut alloc: impl FnMut() -> Result<u32, IoError>,
+    ) -> Result<Vec<u32>, IoError> {
+        assert!(self.uses_extents() && first <= self.data_blocks(block_device.block_size()));
+        let end = first + runs.iter().map(|(_, len)| len).sum::<u32>();
+        let (old_count, _) = self.last_extent(block_device)?;
+        let mut before: Vec<(u32, u32)> = Vec::new();
+        let mut after: Vec<(u32, u32)> = Vec::new();
+        let mut pos = 0u32;
+        for i in 0..old_count {
+            let (start, len) = self.extent(i, block_device)?;
+            let offset = |n: u32| if start == 0 { 0 } else { start + n };
+            if pos < first {
+                Self::push_extent(&mut before, (start, len.min(first - pos)));
+            }
+            if pos + len > end {
+                let skip = end.max(pos) - pos;
+                Self::push_extent(&mut after, (offset(skip), len - skip));
+            }
+            pos += len;
+        }
+        for extent in runs.into_iter().chain(after) {
+            Self::push_extent(&mut before, extent);
+        }
+        let extents = before;
+        let bounds = Bounds::of(block_device);
+        if extents.len() > bounds.max_extents {
+            return Err(IoError::TooLarge);
+        }
+        let new_count = extents.len();
+        let blocks_of = |count: usize| if count > bounds.extent1 {
+            (count - bounds.extent1 + bounds.per_extent_block - 1) / bounds.per_extent_block
+        } else {
+            0
+        };
+        let (old_blocks, new_blocks) = (blocks_of(old_count), blocks_of(new_count));
+        // blocks for more extents are all taken before anything is changed
+        let indirect1 = match self.indirect1 {
+            0 if new_count > INLINE_EXTENTS => alloc()?,
+            indirect1 => indirect1,
+        };
+        let indirect2 = match self.indirect2 {
+            0 if new_blocks > 0 => alloc()?,
+            indirect2 => indirect2,
+        };
+        let mut extent_blocks: Vec<u32> = Vec::new();
+        for _ in old_blocks..new_blocks {
+            extent_blocks.push(alloc()?);
+        }
+        if !extent_blocks.is_empty() {
+            indirect_block(indirect2, block_device)?
+                .lock()
+                .modify_slice(|indirect2: &mut [u32]| {
+                    indirect2[old_blocks..new_blocks].copy_from_slice(&extent_blocks);
+                });
+        }
+        self.indirect1 = indirect1;
+        self.indirect2 = indirect2;
+        for (i, extent) in extents.iter().enumerate() {
+            self.set_extent(i, *extent, block_device)?;
+        }
+        // free blocks of extents beyond the new count
+        let mut v: Vec<u32> = Vec::new();
+        if old_blocks > new_blocks {
+            indirect_block(self.indirect2, block_device)?
                 .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..b1 {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
+                .modify_slice(|indirect2: &mut [u32]| {
+                    for entry in indirect2[new_blocks..old_blocks].iter_mut() {
+                        v.push(*entry);
+                        *entry = 0;
                     }
                 });
-                //indirect2[a1] = 0;
+            if new_blocks == 0 {
+                v.push(self.indirect2);
+                self.indirect2 = 0;
             }
-        });
-        self.indirect2 = 0;
-        v
+        }
+        if old_count > INLINE_EXTENTS && new_count <= INLINE_EXTENTS {
+            v.push(self.indirect1);
+            self.indirect1 = 0;
+        }
+        Ok(v)
     }
+    fn blocks_extents(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u32>, IoError> {
+        let mut v: Vec<u32> = Vec::new();
+        let (count, _) = self.last_extent(block_device)?;
+        for i in 0..count {
+            let (start, len) = self.extent(i, block_device)?;
+            if start != 0 {
+                v.extend(start..start + len);
+            }
+        }
+        if count > INLINE_EXTENTS {
+            v.push(self.indirect1);
+        }
+        let bounds = Bounds::of(block_device);
+        if count > bounds.extent1 {
+            v.push(self.indirect2);
+            let blocks = (count - bounds.extent1 + bounds.per_extent_block - 1) / bounds.per_extent_block;
+            indirect_block(self.indirect2, block_device)?
+                .lock()
+                .read_slice(|indirect2: &[u32]| {
+                    v.extend_from_slice(&indirect2[..blocks]);
+                });
+        }
+        Ok(v)
+    }
+    /// Number of entries if this is a directory, see `dirent_offset`.
+    pub fn dirent_count(&self, block_size: usize) -> usize {
+        let size = self.size as usize;
+        size / block_size * (block_size / DIRENT_SZ - 1) + size % block_size / DIRENT_SZ
+    }
+    /// Data blocks of directories end with a checksum, those of files are
+    /// not checked. Either can fail to be read from the device.
+    fn data_block(
+        &self,
+        block_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Arc<Mutex<BlockCache>>, IoError> {
+        if self.is_dir() {
+            get_checked_block_cache(block_id as usize, Arc::clone(block_device), Checksum::Tail)
+        } else {
+            get_block_cache(block_id as usize, Arc::clone(block_device))
+        }
+    }
+    /// Compressed files are decompressed cluster by cluster on the way.
     pub fn read_at(
         &self,
         offset: usize,
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
-    ) -> usize {
+    ) -> Result<usize, IoError> {
+        self.read_at_from(offset, buf, block_device, &mut ExtentCursor::default())
+    }
+    /// Like `read_at`, looking up blocks from `cursor` on, see `get_block_id_from`.
+    pub fn read_at_from(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+        cursor: &mut ExtentCursor,
+    ) -> Result<usize, IoError> {
+        if self.is_compressed() {
+            return self.read_compressed(offset, buf, block_device, &mut cursor.cluster);
+        }
+        let block_size = block_device.block_size();
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         if start >= end {
-            return 0;
+            return Ok(0);
         }
-        let mut start_block = start / BLOCK_SZ;
+        let mut start_block = start / block_size;
         let mut read_size = 0usize;
         loop {
             // calculate end of current block
-            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
+            let mut end_current_block = (start / block_size + 1) * block_size;
             end_current_block = end_current_block.min(end);
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            match self.get_block_id_from(start_block as u32, block_device, cursor)? {
+                // a hole
+                0 => dst.iter_mut().for_each(|b| *b = 0),
+                block_id => self.data_block(block_id, block_device)?
+                    .lock()
+                    .read_slice(|data_block: &[u8]| {
+                        let src = &data_block[start % block_size..start % block_size + block_read_size];
+                        dst.copy_from_slice(src);
+                    }),
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end { break; }
             start_block += 1;
             start = end_current_block;
         }
-        read_size
+        Ok(read_size)
     }
-    /// File size must be adjusted before.
+    /// File size must be adjusted and blocks written to must be allocated
+    /// before, a hole among them is taken for a damaged block map, which is
+    /// reported on its root like by `extent_block_id`. A compressed file has
+    /// to be decompressed first.
     pub fn write_at(
         &mut self,
         offset: usize,
         buf: &[u8],
         block_device: &Arc<dyn BlockDevice>,
-    ) -> usize {
+    ) -> Result<usize, IoError> {
+        assert!(!self.is_compressed(), "writing to a compressed file");
+        let block_size = block_device.block_size();
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
-        assert!(start <= end);
-        let mut start_block = start / BLOCK_SZ;
+        if start >= end {
+            return Ok(0);
+        }
+        let mut start_block = start / block_size;
         let mut write_size = 0usize;
+        let mut cursor = ExtentCursor::default();
         loop {
             // calculate end of current block
-            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
+            let mut end_current_block = (start / block_size + 1) * block_size;
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                let src = &buf[write_size..write_size + block_write_size];
-                let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = match self.get_block_id_from(start_block as u32, block_device, &mut cursor)? {
+                0 => return Err(IoError::Corrupted(self.indirect2 as usize)),
+                block_id => block_id,
+            };
+            self.data_block(block_id, block_device)?
+                .lock()
+                .modify_slice(|data_block: &mut [u8]| {
+                    let src = &buf[write_size..write_size + block_write_size];
+                    let dst = &mut data_block[start % block_size..start % block_size + block_write_size];
+                    dst.copy_from_slice(src);
+                });
             write_size += block_write_size;
             // move to next block
             if end_current_block == end { break; }
             start_block += 1;
             start = end_current_block;
         }
-        write_size
+        Ok(write_size)
+    }
+    /// Zero bytes [offset, end) except those in holes, which are zeros
+    /// already.
+    pub fn zero_at(
+        &self,
+        offset: usize,
+        end: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<(), IoError> {
+        assert!(!self.is_compressed(), "writing to a compressed file");
+        let block_size = block_device.block_size();
+        let mut start = offset;
+        while start < end {
+            let end_current_block = ((start / block_size + 1) * block_size).min(end);
+            let block_id = self.get_block_id((start / block_size) as u32, block_device)?;
+            if block_id != 0 {
+                self.data_block(block_id, block_device)?
+                    .lock()
+                    .modify_slice(|data_block: &mut [u8]| {
+                        data_block[start % block_size..(end_current_block - 1) % block_size + 1]
+                            .iter_mut()
+                            .for_each(|b| *b = 0);
+                    });
+            }
+            start = end_current_block;
+        }
+        Ok(())
+    }
+    /// Compress the data cluster by cluster and return the blocks no longer
+    /// used. Blocks for extents are taken from `alloc`.
+    ///
+    /// A cluster of zeros becomes a hole. Any other one is compressed into
+    /// the blocks at its start, the first of them beginning with the length
+    /// of the compressed data, and the rest of it becomes a hole. Clusters
+    /// which would not need a block less are stored as they are, without
+    /// holes, so that a hole after the first block of a cluster always
+    /// tells a compressed one.
+    pub fn compress(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        mut alloc: impl FnMut() -> Result<u32, IoError>,
+    ) -> Result<Vec<u32>, IoError> {
+        if self.is_compressed() {
+            return Ok(Vec::new());
+        }
+        let block_size = block_device.block_size();
+        let mut v: Vec<u32> = Vec::new();
+        for cluster in 0..self.clusters(block_size) {
+            let (first, len) = self.cluster_range(cluster, block_size);
+            let mut content = vec![0u8; len as usize * block_size];
+            self.read_at(first as usize * block_size, &mut content, block_device)?;
+            let kept = if content.iter().all(|byte| *byte == 0) {
+                0
+            } else {
+                let compressed = lz4::compress(&content);
+                let stored = ((4 + compressed.len() + block_size - 1) / block_size) as u32;
+                if stored < len {
+                    content = (compressed.len() as u32).to_le_bytes().to_vec();
+                    content.extend(compressed);
+                    stored
+                } else {
+                    len
+                }
+            };
+            v.extend(self.map_cluster(first, len, kept, block_device, &mut alloc)?);
+            if kept != 0 {
+                self.write_cluster(first, &content, block_device)?;
+            }
+        }
+        self.flags |= INODE_FLAG_COMPRESSED;
+        Ok(v)
+    }
+    /// Store every cluster as it is again and return the blocks of extents
+    /// no longer used. Blocks are taken from `alloc`.
+    pub fn decompress(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        mut alloc: impl FnMut() -> Result<u32, IoError>,
+    ) -> Result<Vec<u32>, IoError> {
+        let block_size = block_device.block_size();
+        let mut v: Vec<u32> = Vec::new();
+        for cluster in 0..self.clusters(block_size) {
+            let (first, len) = self.cluster_range(cluster, block_size);
+            if self.get_block_id(first, block_device)? == 0
+                || self.get_block_id(first + len - 1, block_device)? != 0 {
+                // zeros or stored as is
+                continue;
+            }
+            let content = self.read_cluster(cluster, block_device)?;
+            v.extend(self.map_cluster(first, len, len, block_device, &mut alloc)?);
+            self.write_cluster(first, &content, block_device)?;
+        }
+        self.flags &= !INODE_FLAG_COMPRESSED;
+        Ok(v)
+    }
+    fn clusters(&self, block_size: usize) -> u32 {
+        (self.data_blocks(block_size) + CLUSTER_BLOCKS - 1) / CLUSTER_BLOCKS
+    }
+    /// Return (first block, number of blocks) of a cluster, the last one
+    /// ends with the file.
+    fn cluster_range(&self, cluster: u32, block_size: usize) -> (u32, u32) {
+        let first = cluster * CLUSTER_BLOCKS;
+        (first, (self.data_blocks(block_size) - first).min(CLUSTER_BLOCKS))
+    }
+    /// Return the content of a cluster of a compressed file, all of its
+    /// blocks even if the file ends in the last one.
+    fn read_cluster(&self, cluster: u32, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u8>, IoError> {
+        let block_size = block_device.block_size();
+        let (first, len) = self.cluster_range(cluster, block_size);
+        let first_block_id = self.get_block_id(first, block_device)?;
+        let mut stored: Vec<u8> = Vec::new();
+        for inner_id in first..first + len {
+            match self.get_block_id(inner_id, block_device)? {
+                0 => break,
+                block_id => self.data_block(block_id, block_device)?
+                    .lock()
+                    .read_slice(|data_block: &[u8]| stored.extend_from_slice(data_block)),
+            }
+        }
+        if stored.is_empty() || stored.len() == len as usize * block_size {
+            stored.resize(len as usize * block_size, 0);
+            return Ok(stored);
+        }
+        let mut content = vec![0u8; len as usize * block_size];
+        let compressed_len = u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]]) as usize;
+        stored.get(4..4 + compressed_len)
+            .and_then(|compressed| lz4::decompress(compressed, &mut content))
+            .ok_or(IoError::Corrupted(first_block_id as usize))?;
+        Ok(content)
+    }
+    /// `last` keeps the last cluster decompressed.
+    fn read_compressed(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+        last: &mut Option<(u32, Vec<u8>)>,
+    ) -> Result<usize, IoError> {
+        let cluster_size = CLUSTER_BLOCKS as usize * block_device.block_size();
+        let end = (offset + buf.len()).min(self.size as usize);
+        let mut start = offset;
+        while start < end {
+            let cluster = start / cluster_size;
+            let end_current_cluster = ((cluster + 1) * cluster_size).min(end);
+            if last.as_ref().map(|(i, _)| *i) != Some(cluster as u32) {
+                *last = Some((cluster as u32, self.read_cluster(cluster as u32, block_device)?));
+            }
+            let content = &last.as_ref().unwrap().1;
+            let src = start % cluster_size;
+            buf[start - offset..end_current_cluster - offset]
+                .copy_from_slice(&content[src..src + end_current_cluster - start]);
+            start = end_current_cluster;
+        }
+        Ok(end.max(offset) - offset)
+    }
+    /// Back the first `kept` of blocks [first, first + len) with blocks from
+    /// `alloc` and make holes of the others. Return the blocks no longer
+    /// used, including those of extents.
+    fn map_cluster(
+        &mut self,
+        first: u32,
+        len: u32,
+        kept: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        alloc: &mut impl FnMut() -> Result<u32, IoError>,
+    ) -> Result<Vec<u32>, IoError> {
+        let mut v: Vec<u32> = Vec::new();
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for inner_id in first..first + len {
+            let block_id = self.get_block_id(inner_id, block_device)?;
+            let new_block_id = match block_id {
+                0 if inner_id < first + kept => alloc()?,
+                block_id if inner_id < first + kept => block_id,
+                0 => 0,
+                block_id => {
+                    v.push(block_id);
+                    0
+                }
+            };
+            if self.uses_extents() {
+                Self::push_extent(&mut runs, (new_block_id, 1));
+            } else if new_block_id != block_id {
+                self.set_block_id(inner_id, new_block_id, block_device, alloc)?;
+            }
+        }
+        if self.uses_extents() {
+            v.extend(self.remap_extents(first, runs, block_device, alloc)?);
+        }
+        Ok(v)
+    }
+    /// Write `content` from the start of block `first` on, the blocks are
+    /// allocated.
+    fn write_cluster(
+        &self,
+        first: u32,
+        content: &[u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<(), IoError> {
+        let block_size = block_device.block_size();
+        for (i, chunk) in content.chunks(block_size).enumerate() {
+            let block_id = self.get_block_id(first + i as u32, block_device)?;
+            self.data_block(block_id, block_device)?
+                .lock()
+                .modify_slice(|data_block: &mut [u8]| {
+                    data_block[..chunk.len()].copy_from_slice(chunk);
+                    data_block[chunk.len()..].iter_mut().for_each(|b| *b = 0);
+                });
+        }
+        Ok(())
     }
 }
 
@@ -407,6 +1365,13 @@ pub struct DirEntry {
 
 pub const DIRENT_SZ: usize = 32;
 
+/// Offset of the `index`-th entry of a directory. The last slot of every
+/// block is left unused, it holds the checksum of the block.
+pub fn dirent_offset(index: usize, block_size: usize) -> usize {
+    let per_block = block_size / DIRENT_SZ - 1;
+    index / per_block * block_size + index % per_block * DIRENT_SZ
+}
+
 impl DirEntry {
     pub fn empty() -> Self {
         Self {
@@ -416,7 +1381,7 @@ impl DirEntry {
     }
     pub fn new(name: &str, inode_number: u32) -> Self {
         let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
-        &mut bytes[..name.len()].copy_from_slice(name.as_bytes());
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
         Self {
             name: bytes,
             inode_number,
@@ -438,6 +1403,10 @@ impl DirEntry {
             )
         }
     }
+    /// Entries of removed files have an empty name.
+    pub fn is_empty(&self) -> bool {
+        self.name[0] == 0
+    }
     pub fn name(&self) -> &str {
         let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
         core::str::from_utf8(&self.name[..len]).unwrap()
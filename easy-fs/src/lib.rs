@@ -8,11 +8,29 @@ mod efs;
 mod bitmap;
 mod vfs;
 mod block_cache;
+mod clock;
+mod crc;
+mod lz4;
 
+/// Size of a sector of `BlockDevice`, and of easy-fs blocks by default.
 pub const BLOCK_SZ: usize = 512;
-pub use block_dev::BlockDevice;
+pub use block_dev::{BlockDevice, IoError};
 pub use efs::EasyFileSystem;
 pub use vfs::Inode;
+pub use block_cache::{
+    BlockCache,
+    Checksum,
+    get_block_cache,
+    get_checked_block_cache,
+    block_cache_sync_all,
+    block_cache_sync,
+    block_cache_reload,
+    block_cache_discard,
+};
+pub use clock::set_time_source;
+pub use crc::crc32c;
+pub use layout::{FEATURE_EXTENTS, FEATURE_SNAPSHOTS, FEATURE_COMPRESSION, OpenError};
 use layout::*;
 use bitmap::Bitmap;
-use block_cache::get_block_cache;
\ No newline at end of file
+use block_dev::SectorGroup;
+use clock::current_time;
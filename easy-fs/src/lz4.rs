@@ -0,0 +1,130 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Compression in the LZ4 block format: a sequence is a token with the
+/// lengths of its literals (high half) and of its match (low half), the
+/// literals, the offset of the match and the rest of the match length. A
+/// length of 15 in the token goes on in bytes added to it until one is not
+/// 255. The last sequence only has literals.
+const MIN_MATCH: usize = 4;
+/// As in LZ4, the last 5 bytes are always literals and no match starts in
+/// the last 12.
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 0xffff;
+const HASH_BITS: u32 = 12;
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+}
+
+fn write_length(output: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        output.push(255);
+        len -= 255;
+    }
+    output.push(len as u8);
+}
+
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    output.push((literals.len().min(15) << 4 | match_len.min(15)) as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(output, match_len - 15);
+        }
+    }
+}
+
+/// Matches are found by a hash table of the last position of every 4 bytes
+/// seen, which is fast rather than thorough.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::new();
+    let mut table = vec![0u32; 1 << HASH_BITS];
+    let mut anchor = 0usize;
+    let mut pos = 0usize;
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - LAST_LITERALS;
+        while pos < input.len() - MF_LIMIT {
+            let sequence = read_u32(input, pos);
+            let entry = &mut table[hash(sequence)];
+            let candidate = *entry as usize;
+            *entry = pos as u32;
+            if candidate >= pos || pos - candidate > MAX_OFFSET || read_u32(input, candidate) != sequence {
+                pos += 1;
+                continue;
+            }
+            let mut len = MIN_MATCH;
+            while pos + len < match_limit && input[candidate + len] == input[pos + len] {
+                len += 1;
+            }
+            write_sequence(&mut output, &input[anchor..pos], Some((pos - candidate, len)));
+            pos += len;
+            anchor = pos;
+        }
+    }
+    write_sequence(&mut output, &input[anchor..], None);
+    output
+}
+
+fn read_length(input: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut len = 0usize;
+    loop {
+        let byte = *input.get(*pos)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Return the number of bytes decompressed into `output`, or `None` if
+/// `input` is malformed or does not fit.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut pos = 0usize;
+    let mut out = 0usize;
+    loop {
+        let token = *input.get(pos)?;
+        pos += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(input, &mut pos)?;
+        }
+        output
+            .get_mut(out..out + literals)?
+            .copy_from_slice(input.get(pos..pos + literals)?);
+        pos += literals;
+        out += literals;
+        if pos == input.len() {
+            return Some(out);
+        }
+        let offset = u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out {
+            return None;
+        }
+        let mut len = (token & 15) as usize;
+        if len == 15 {
+            len += read_length(input, &mut pos)?;
+        }
+        len += MIN_MATCH;
+        if out + len > output.len() {
+            return None;
+        }
+        // the match may overlap what it produces
+        for i in out..out + len {
+            output[i] = output[i - offset];
+        }
+        out += len;
+    }
+}
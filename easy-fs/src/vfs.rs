@@ -1,205 +1,664 @@
 use super::{
     BlockDevice,
+    Checksum,
     DiskInode,
     DiskInodeType,
     DirEntry,
+    ExtentCursor,
     EasyFileSystem,
+    IoError,
     DIRENT_SZ,
-    get_block_cache,
+    INODE_CHECKSUM,
+    dirent_offset,
+    get_checked_block_cache,
+    current_time,
 };
 use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use spin::{Mutex, MutexGuard};
 
+/// A handle on an inode, counted by `EasyFileSystem` so that an unlinked
+/// inode is only freed once every handle on it is dropped.
 pub struct Inode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
     fs: Arc<Mutex<EasyFileSystem>>,
     block_device: Arc<dyn BlockDevice>,
+    /// Where the last read stopped in the extents, with the version of the
+    /// block of the inode it was worked out from, so that reading on from
+    /// there does not go through all extents again.
+    read_cursor: Mutex<(usize, ExtentCursor)>,
 }
 
 impl Inode {
-    /// We should not acquire efs lock here.
+    /// We should not acquire efs lock here, the caller counts the handle
+    /// with `EasyFileSystem::open_inode`.
     pub fn new(
+        inode_id: u32,
         block_id: u32,
         block_offset: usize,
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
+            read_cursor: Mutex::new((0, ExtentCursor::default())),
         }
     }
 
-    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
-        get_block_cache(
+    /// Fails if the block of the inode does not match its checksums, or `f`
+    /// fails to read another block.
+    fn read_disk_inode<V>(
+        &self,
+        f: impl FnOnce(&DiskInode) -> Result<V, IoError>,
+    ) -> Result<V, IoError> {
+        get_checked_block_cache(
             self.block_id,
-            Arc::clone(&self.block_device)
-        ).lock().read(self.block_offset, f)
+            Arc::clone(&self.block_device),
+            INODE_CHECKSUM,
+        )?.lock().read(self.block_offset, f)
     }
 
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
-        get_block_cache(
+    fn modify_disk_inode<V>(
+        &self,
+        f: impl FnOnce(&mut DiskInode) -> Result<V, IoError>,
+    ) -> Result<V, IoError> {
+        get_checked_block_cache(
             self.block_id,
-            Arc::clone(&self.block_device)
-        ).lock().modify(self.block_offset, f)
+            Arc::clone(&self.block_device),
+            INODE_CHECKSUM,
+        )?.lock().modify(self.block_offset, f)
     }
 
-    fn find_inode_id(
+    /// Return (index of the entry, inode id) of a file in this directory.
+    fn find_dirent(
         &self,
         name: &str,
         disk_inode: &DiskInode,
-    ) -> Option<u32> {
+    ) -> Result<Option<(usize, u32)>, IoError> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+        let block_size = self.block_device.block_size();
         let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
+        for i in 0..disk_inode.dirent_count(block_size) {
             assert_eq!(
                 disk_inode.read_at(
-                    DIRENT_SZ * i,
+                    dirent_offset(i, block_size),
                     dirent.as_bytes_mut(),
                     &self.block_device,
-                ),
+                )?,
                 DIRENT_SZ,
             );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
+            if !dirent.is_empty() && dirent.name() == name {
+                return Ok(Some((i, dirent.inode_number() as u32)));
             }
         }
-        None
+        Ok(None)
+    }
+
+    fn find_inode_id(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+    ) -> Result<Option<u32>, IoError> {
+        Ok(self.find_dirent(name, disk_inode)?
+            .map(|(_, inode_id)| inode_id))
     }
 
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
+    pub fn find(&self, name: &str) -> Result<Option<Arc<Inode>>, IoError> {
+        let mut fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode)
+            Ok(self.find_inode_id(name, disk_inode)?
             .map(|inode_id| {
                 let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+                fs.open_inode(inode_id);
                 Arc::new(Self::new(
+                    inode_id,
                     block_id,
                     block_offset,
                     self.fs.clone(),
                     self.block_device.clone(),
                 ))
-            })
+            }))
         })
     }
 
-    fn increase_size(
+    /// Give the inode its own indirect blocks if they may be shared with a
+    /// snapshot, which has to be done before its mapping is changed.
+    fn unshare(
+        &self,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<(), IoError> {
+        if disk_inode.is_shared() {
+            disk_inode.unshare(&self.block_device, |block_id| {
+                fs.cow_block(block_id, Some(Checksum::Tail))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Copy the blocks of bytes [offset, end) shared with a snapshot, so
+    /// that they can be written. `unshare` must have been called before.
+    fn unshare_range(
         &self,
-        new_size: u32,
+        offset: usize,
+        end: usize,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
-        if new_size < disk_inode.size {
-            return;
+    ) -> Result<(), IoError> {
+        if offset >= end {
+            return Ok(());
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        let block_size = self.block_device.block_size();
+        // data blocks of directories end with a checksum
+        let checksum = if disk_inode.is_dir() { Some(Checksum::Tail) } else { None };
+        for inner_id in (offset / block_size) as u32..((end + block_size - 1) / block_size) as u32 {
+            let block_id = disk_inode.get_block_id(inner_id, &self.block_device)?;
+            if block_id == 0 || !fs.is_shared(block_id)? {
+                continue;
+            }
+            let new_block_id = fs.cow_block(block_id, checksum)?;
+            // with blocks taken for extents
+            let mut new_blocks = vec![new_block_id];
+            let freed = match disk_inode.replace_block(
+                inner_id,
+                new_block_id,
+                &self.block_device,
+                || {
+                    let block_id = fs.alloc_data()?;
+                    new_blocks.push(block_id);
+                    Ok(block_id)
+                },
+            ) {
+                Ok(freed) => freed,
+                Err(err) => {
+                    for block_id in new_blocks.into_iter() {
+                        fs.dealloc_data(block_id)?;
+                    }
+                    return Err(err);
+                }
+            };
+            for block_id in freed.into_iter() {
+                fs.dealloc_data(block_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a compressed file as it is again, so that it can be changed.
+    /// `unshare` must have been called before.
+    fn decompress(
+        &self,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<(), IoError> {
+        if !disk_inode.is_compressed() {
+            return Ok(());
+        }
+        self.unshare_range(0, disk_inode.size as usize, disk_inode, fs)?;
+        let freed = disk_inode.decompress(&self.block_device, || fs.alloc_data())?;
+        for block_id in freed.into_iter() {
+            fs.dealloc_data(block_id)?;
+        }
+        Ok(())
+    }
+
+    /// Fail with `IoError::TooLarge` if the file can not grow to `end`, so
+    /// that nothing is changed then.
+    fn check_size(&self, end: usize, disk_inode: &DiskInode) -> Result<(), IoError> {
+        if end > disk_inode.max_size(&self.block_device) {
+            Err(IoError::TooLarge)
+        } else {
+            Ok(())
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
     }
 
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+    /// Back bytes [offset, end) with blocks and grow the file to `end` if it
+    /// is smaller. Blocks between the old end of the file and `offset` are
+    /// not allocated but left as a hole.
+    fn alloc_range(
+        &self,
+        offset: usize,
+        end: usize,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<(), IoError> {
+        if offset >= end {
+            return Ok(());
+        }
+        self.check_size(end, disk_inode)?;
+        let block_size = self.block_device.block_size();
+        let new_size = disk_inode.size.max(end as u32);
+        let first = (offset / block_size) as u32;
+        let end_block = ((end + block_size - 1) / block_size) as u32;
+        if !disk_inode.uses_extents() {
+            let mut unused: Vec<u32> = Vec::new();
+            let allocated = disk_inode.alloc_blocks(
+                first,
+                end_block,
+                &self.block_device,
+                || fs.alloc_data(),
+                &mut unused,
+            );
+            for block_id in unused.into_iter() {
+                fs.dealloc_data(block_id)?;
+            }
+            allocated?;
+            disk_inode.size = new_size;
+            return Ok(());
+        }
+        // freed again if they can not be mapped
+        let mut new_blocks: Vec<u32> = Vec::new();
+        match self.alloc_extents(first, end_block, &mut new_blocks, disk_inode, fs) {
+            Ok(freed) => {
+                for block_id in freed.into_iter() {
+                    fs.dealloc_data(block_id)?;
+                }
+            }
+            Err(err) => {
+                for block_id in new_blocks.into_iter() {
+                    fs.dealloc_data(block_id)?;
+                }
+                return Err(err);
+            }
+        }
+        disk_inode.size = new_size;
+        Ok(())
+    }
+
+    /// The part of `alloc_range` for extent inodes, blocks [first, end_block)
+    /// are mapped. Blocks taken are pushed to `new_blocks`, and only used if
+    /// it succeeds. Return the blocks of extents no longer used.
+    fn alloc_extents(
+        &self,
+        first: u32,
+        end_block: u32,
+        new_blocks: &mut Vec<u32>,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<Vec<u32>, IoError> {
+        // extents covering [start, end_block), existing blocks are kept
+        let old_blocks = disk_inode.data_blocks(self.block_device.block_size());
+        let start = first.min(old_blocks);
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        let mut allocated = first > old_blocks;
+        if allocated {
+            runs.push((0, first - old_blocks));
+        }
+        let mapped = |inner_id: u32| if inner_id < old_blocks {
+            disk_inode.get_block_id(inner_id, &self.block_device)
+        } else {
+            Ok(0)
+        };
+        let mut inner_id = first;
+        while inner_id < end_block {
+            let block_id = mapped(inner_id)?;
+            if block_id != 0 {
+                DiskInode::push_extent(&mut runs, (block_id, 1));
+                inner_id += 1;
+                continue;
+            }
+            let mut hole_end = inner_id + 1;
+            while hole_end < end_block && mapped(hole_end)? == 0 {
+                hole_end += 1;
+            }
+            let goal = match runs.last() {
+                Some(&(start, len)) if start != 0 => Some(start + len),
+                _ => disk_inode.next_block_goal(&self.block_device)?,
+            };
+            let (start, len) = fs.alloc_data_run(goal, (hole_end - inner_id) as usize)?;
+            DiskInode::push_extent(&mut runs, (start, len as u32));
+            new_blocks.extend(start..start + len as u32);
+            inner_id += len as u32;
+            allocated = true;
+        }
+        if !allocated {
+            return Ok(Vec::new());
+        }
+        disk_inode.remap_extents(start, runs, &self.block_device, || {
+            let block_id = fs.alloc_data()?;
+            new_blocks.push(block_id);
+            Ok(block_id)
+        })
+    }
+
+    pub fn create(&self, name: &str) -> Result<Option<Arc<Inode>>, IoError> {
         let mut fs = self.fs.lock();
         if self.modify_disk_inode(|root_inode| {
             // assert it is a directory
             assert!(root_inode.is_dir());
             // has the file been created?
             self.find_inode_id(name, root_inode)
-        }).is_some() {
-            return None;
+        })?.is_some() {
+            return Ok(None);
         }
         // create a new file
         // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
+        let new_inode_id = fs.alloc_inode()?;
+        let time = current_time();
         // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) 
+        let (new_inode_block_id, new_inode_block_offset)
             = fs.get_disk_inode_pos(new_inode_id);
-        get_block_cache(
+        get_checked_block_cache(
             new_inode_block_id as usize,
-            Arc::clone(&self.block_device)
-        ).lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-            new_inode.initialize(DiskInodeType::File);
+            Arc::clone(&self.block_device),
+            INODE_CHECKSUM,
+        )?.lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
+            new_inode.initialize(DiskInodeType::File, time, fs.uses_extents());
         });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
+        let added = self.modify_disk_inode(|root_inode| {
+            // reuse the entry of a removed file or append one
+            let block_size = self.block_device.block_size();
+            let file_count = root_inode.dirent_count(block_size);
+            let mut dirent = DirEntry::empty();
+            let mut index = file_count;
+            for i in 0..file_count {
+                root_inode.read_at(dirent_offset(i, block_size), dirent.as_bytes_mut(), &self.block_device)?;
+                if dirent.is_empty() {
+                    index = i;
+                    break;
+                }
+            }
+            let offset = dirent_offset(index, block_size);
+            self.unshare(root_inode, &mut fs)?;
+            if index == file_count {
+                // increase size
+                self.alloc_range(offset, offset + DIRENT_SZ, root_inode, &mut fs)?;
+            }
+            self.unshare_range(offset, offset + DIRENT_SZ, root_inode, &mut fs)?;
             // write dirent
             let dirent = DirEntry::new(name, new_inode_id);
             root_inode.write_at(
-                file_count * DIRENT_SZ,
+                offset,
                 dirent.as_bytes(),
                 &self.block_device,
-            );
+            )?;
+            root_inode.touch(time);
+            Ok(())
         });
+        // the directory can not grow
+        if let Err(err) = added {
+            fs.dealloc_inode(new_inode_id)?;
+            return Err(err);
+        }
 
         let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
+        fs.open_inode(new_inode_id);
         // return inode
-        Some(Arc::new(Self::new(
+        Ok(Some(Arc::new(Self::new(
+            new_inode_id,
             block_id,
             block_offset,
             self.fs.clone(),
             self.block_device.clone(),
-        )))
+        ))))
         // release efs lock automatically by compiler
     }
 
-    pub fn ls(&self) -> Vec<String> {
+    pub fn ls(&self) -> Result<Vec<String>, IoError> {
         let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+            let block_size = self.block_device.block_size();
             let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
+            for i in 0..disk_inode.dirent_count(block_size) {
                 let mut dirent = DirEntry::empty();
                 assert_eq!(
                     disk_inode.read_at(
-                        i * DIRENT_SZ,
+                        dirent_offset(i, block_size),
                         dirent.as_bytes_mut(),
                         &self.block_device,
-                    ),
+                    )?,
                     DIRENT_SZ,
                 );
-                v.push(String::from(dirent.name()));
+                if !dirent.is_empty() {
+                    v.push(String::from(dirent.name()));
+                }
             }
-            v
+            Ok(v)
         })
     }
 
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, IoError> {
         let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            disk_inode.read_at(offset, buf, &self.block_device)
-        })
+        let time = current_time();
+        let inode_block = get_checked_block_cache(
+            self.block_id,
+            Arc::clone(&self.block_device),
+            INODE_CHECKSUM,
+        )?;
+        let (read_size, update_atime) = {
+            let inode_block = inode_block.lock();
+            let mut read_cursor = self.read_cursor.lock();
+            // the extents changed since
+            if read_cursor.0 != inode_block.version() {
+                *read_cursor = (inode_block.version(), ExtentCursor::default());
+            }
+            inode_block.read(self.block_offset, |disk_inode: &DiskInode| -> Result<_, IoError> {
+                Ok((
+                    disk_inode.read_at_from(offset, buf, &self.block_device, &mut read_cursor.1)?,
+                    disk_inode.atime_needs_update(time),
+                ))
+            })?
+        };
+        if update_atime {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.atime = time;
+                Ok(())
+            })?;
+        }
+        Ok(read_size)
     }
 
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, IoError> {
         let mut fs = self.fs.lock();
         self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
+            self.check_size(offset.saturating_add(buf.len()), disk_inode)?;
+            self.unshare(disk_inode, &mut fs)?;
+            self.decompress(disk_inode, &mut fs)?;
+            self.alloc_range(offset, offset + buf.len(), disk_inode, &mut fs)?;
+            self.unshare_range(offset, offset + buf.len(), disk_inode, &mut fs)?;
+            disk_inode.touch(current_time());
             disk_inode.write_at(offset, buf, &self.block_device)
         })
     }
 
-    pub fn clear(&self) {
+    /// Allocate blocks for [offset, offset + len) like `fallocate`, the file
+    /// grows to cover the range unless `keep_size`.
+    pub fn fallocate(&self, offset: usize, len: usize, keep_size: bool) -> Result<(), IoError> {
+        let mut fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            let mut end = offset.saturating_add(len);
+            if keep_size {
+                end = end.min(disk_inode.size as usize);
+            }
+            self.check_size(end, disk_inode)?;
+            self.unshare(disk_inode, &mut fs)?;
+            self.decompress(disk_inode, &mut fs)?;
+            self.alloc_range(offset, end, disk_inode, &mut fs)?;
+            disk_inode.ctime = current_time();
+            Ok(())
+        })
+    }
+
+    /// Deallocate blocks in [offset, offset + len) like `fallocate` with
+    /// `FALLOC_FL_PUNCH_HOLE`, the range reads as zeros and the size is
+    /// kept. Blocks partly in the range are zeroed instead.
+    pub fn punch_hole(&self, offset: usize, len: usize) -> Result<(), IoError> {
+        let mut fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            let size = disk_inode.size as usize;
+            let end = (offset + len).min(size);
+            if offset >= end {
+                return Ok(());
+            }
+            let block_size = self.block_device.block_size();
+            let first = (offset + block_size - 1) / block_size;
+            // the last block may be freed as a whole when it ends the file
+            let end_block = if end == size {
+                disk_inode.data_blocks(block_size) as usize
+            } else {
+                end / block_size
+            };
+            self.unshare(disk_inode, &mut fs)?;
+            self.decompress(disk_inode, &mut fs)?;
+            if first >= end_block {
+                self.unshare_range(offset, end, disk_inode, &mut fs)?;
+                disk_inode.zero_at(offset, end, &self.block_device)?;
+            } else {
+                self.unshare_range(offset, first * block_size, disk_inode, &mut fs)?;
+                self.unshare_range(end_block * block_size, end, disk_inode, &mut fs)?;
+                disk_inode.zero_at(offset, first * block_size, &self.block_device)?;
+                disk_inode.zero_at(end_block * block_size, end, &self.block_device)?;
+                let (first, end_block) = (first as u32, end_block as u32);
+                let freed = if disk_inode.uses_extents() {
+                    let mut v: Vec<u32> = Vec::new();
+                    for inner_id in first..end_block {
+                        match disk_inode.get_block_id(inner_id, &self.block_device)? {
+                            0 => {}
+                            block_id => v.push(block_id),
+                        }
+                    }
+                    v.extend(disk_inode.remap_extents(
+                        first,
+                        vec![(0, end_block - first)],
+                        &self.block_device,
+                        || fs.alloc_data(),
+                    )?);
+                    v
+                } else {
+                    disk_inode.punch_blocks(first, end_block, &self.block_device)?
+                };
+                for block_id in freed.into_iter() {
+                    fs.dealloc_data(block_id)?;
+                }
+            }
+            disk_inode.touch(current_time());
+            Ok(())
+        })
+    }
+
+    /// Compress the file if the file system allows it and return whether
+    /// it is compressed. Reads decompress it on the way, it is stored as it
+    /// is again once it is changed.
+    pub fn compress(&self) -> Result<bool, IoError> {
+        let mut fs = self.fs.lock();
+        if !fs.allows_compression() {
+            return Ok(false);
+        }
+        self.modify_disk_inode(|disk_inode| {
+            if disk_inode.is_dir() {
+                return Ok(false);
+            }
+            self.unshare(disk_inode, &mut fs)?;
+            self.unshare_range(0, disk_inode.size as usize, disk_inode, &mut fs)?;
+            let freed = disk_inode.compress(&self.block_device, || fs.alloc_data())?;
+            for block_id in freed.into_iter() {
+                fs.dealloc_data(block_id)?;
+            }
+            disk_inode.ctime = current_time();
+            Ok(true)
+        })
+    }
+
+    pub fn is_dir(&self) -> Result<bool, IoError> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| Ok(disk_inode.is_dir()))
+    }
+
+    /// Return (mode, uid, gid).
+    pub fn attr(&self) -> Result<(u16, u16, u16), IoError> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            Ok((disk_inode.mode, disk_inode.uid, disk_inode.gid))
+        })
+    }
+
+    pub fn set_mode(&self, mode: u16) -> Result<(), IoError> {
+        let _fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.mode = mode;
+            disk_inode.ctime = current_time();
+            Ok(())
+        })
+    }
+
+    pub fn set_owner(&self, uid: u16, gid: u16) -> Result<(), IoError> {
+        let _fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.uid = uid;
+            disk_inode.gid = gid;
+            disk_inode.ctime = current_time();
+            Ok(())
+        })
+    }
+
+    /// Return (atime, mtime, ctime).
+    pub fn times(&self) -> Result<(u32, u32, u32), IoError> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            Ok((disk_inode.atime, disk_inode.mtime, disk_inode.ctime))
+        })
+    }
+
+    /// Set atime and mtime like `utimes`, ctime becomes the current time.
+    pub fn set_times(&self, atime: u32, mtime: u32) -> Result<(), IoError> {
+        let _fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.atime = atime;
+            disk_inode.mtime = mtime;
+            disk_inode.ctime = current_time();
+            Ok(())
+        })
+    }
+
+    pub fn clear(&self) -> Result<(), IoError> {
         let mut fs = self.fs.lock();
         self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
+            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device)?;
             for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
+                fs.dealloc_data(data_block)?;
             }
-        });
+            disk_inode.touch(current_time());
+            Ok(())
+        })
+    }
+
+    /// Remove a file from this directory and free its inode and blocks, as
+    /// soon as no `Inode` handle is left on it. Return false if there is no
+    /// such file.
+    pub fn unlink(&self, name: &str) -> Result<bool, IoError> {
+        let mut fs = self.fs.lock();
+        let (index, inode_id) = match self.read_disk_inode(|root_inode| {
+            self.find_dirent(name, root_inode)
+        })? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        fs.unlink_inode(inode_id)?;
+        self.modify_disk_inode(|root_inode| {
+            let offset = dirent_offset(index, self.block_device.block_size());
+            self.unshare(root_inode, &mut fs)?;
+            self.unshare_range(offset, offset + DIRENT_SZ, root_inode, &mut fs)?;
+            root_inode.write_at(offset, DirEntry::empty().as_bytes(), &self.block_device)?;
+            root_inode.touch(current_time());
+            Ok(())
+        })?;
+        Ok(true)
+    }
+}
+
+impl Drop for Inode {
+    /// An unlinked inode is freed with its last handle. Errors can not be
+    /// reported here, the inode and its blocks are leaked then.
+    fn drop(&mut self) {
+        let _ = self.fs.lock().close_inode(self.inode_id);
     }
 }
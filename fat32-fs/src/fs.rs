@@ -0,0 +1,332 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use super::{
+    BlockDevice,
+    IoError,
+    BLOCK_SZ,
+    BootSector,
+    FsInfo,
+    FatInode,
+    fat32_partition,
+    get_block_cache,
+    FAT_ENTRY_MASK,
+    FAT_EOC,
+    FAT_EOC_MARK,
+    FAT_FREE,
+    FSINFO_UNKNOWN,
+};
+
+type Sector = [u8; BLOCK_SZ];
+
+pub struct FatFileSystem {
+    pub block_device: Arc<dyn BlockDevice>,
+    sectors_per_cluster: u32,
+    fat_start_sector: u32,
+    fat_size: u32,
+    fat_count: u32,
+    data_start_sector: u32,
+    /// Valid cluster ids are in `[2, cluster_count + 2)`.
+    cluster_count: u32,
+    root_cluster: u32,
+    fs_info_sector: Option<u32>,
+    next_free: u32,
+    /// Handles given out on each file, by the position of its entry, see
+    /// `FatInode::unlink`.
+    open_files: BTreeMap<(usize, usize), usize>,
+}
+
+impl FatFileSystem {
+    /// Format the device as FAT32 with 2 FATs and 32 reserved sectors.
+    pub fn create(
+        block_device: Arc<dyn BlockDevice>,
+        total_sectors: u32,
+        sectors_per_cluster: u8,
+    ) -> Result<Arc<Mutex<Self>>, IoError> {
+        let reserved_sectors = 32u32;
+        let fat_count = 2u32;
+        // from the FAT specification of Microsoft, slightly larger than needed
+        let tmp1 = total_sectors - reserved_sectors;
+        let tmp2 = (256 * sectors_per_cluster as u32 + fat_count) / 2;
+        let fat_size = (tmp1 + tmp2 - 1) / tmp2;
+        let boot_sector = BootSector {
+            bytes_per_sector: BLOCK_SZ as u16,
+            sectors_per_cluster,
+            reserved_sectors: reserved_sectors as u16,
+            fat_count: fat_count as u8,
+            total_sectors,
+            fat_size,
+            root_cluster: 2,
+            fs_info_sector: 1,
+            backup_boot_sector: 6,
+        };
+        let data_start_sector = reserved_sectors + fat_count * fat_size;
+        let cluster_count = (total_sectors - data_start_sector) / sectors_per_cluster as u32;
+        // clear reserved area and FATs
+        for sector_id in 0..data_start_sector {
+            get_block_cache(sector_id as usize, Arc::clone(&block_device))?
+                .lock()
+                .modify(0, |sector: &mut Sector| {
+                    sector.iter_mut().for_each(|b| *b = 0);
+                });
+        }
+        for &sector_id in [0, boot_sector.backup_boot_sector as u32].iter() {
+            get_block_cache(sector_id as usize, Arc::clone(&block_device))?
+                .lock()
+                .modify(0, |sector: &mut Sector| {
+                    boot_sector.write(sector, total_sectors ^ fat_size);
+                });
+        }
+        for &sector_id in [1, boot_sector.backup_boot_sector as u32 + 1].iter() {
+            get_block_cache(sector_id as usize, Arc::clone(&block_device))?
+                .lock()
+                .modify(0, |sector: &mut Sector| {
+                    // the root directory takes cluster 2
+                    FsInfo::initialize(sector, cluster_count - 1, 3);
+                });
+        }
+        let fs = Self::open(block_device)?.unwrap();
+        {
+            let fs_guard = fs.lock();
+            fs_guard.set_fat(0, 0x0fff_ff00 | 0xf8)?;
+            fs_guard.set_fat(1, FAT_EOC_MARK)?;
+            fs_guard.set_fat(2, FAT_EOC_MARK)?;
+            fs_guard.clear_cluster(2)?;
+        }
+        Ok(fs)
+    }
+
+    /// Return (first sector, boot sector) of the file system, which takes
+    /// either the whole device or the first FAT32 partition in its MBR.
+    fn find_boot_sector(block_device: &Arc<dyn BlockDevice>) -> Result<Option<(u32, BootSector)>, IoError> {
+        let (boot_sector, partition) = get_block_cache(0, Arc::clone(block_device))?
+            .lock()
+            .read(0, |sector: &Sector| (BootSector::parse(sector), fat32_partition(sector)));
+        if let Some(boot_sector) = boot_sector {
+            return Ok(Some((0, boot_sector)));
+        }
+        let start_sector = match partition {
+            Some(start_sector) => start_sector,
+            None => return Ok(None),
+        };
+        Ok(get_block_cache(start_sector as usize, Arc::clone(block_device))?
+            .lock()
+            .read(0, |sector: &Sector| BootSector::parse(sector))
+            .map(|boot_sector| (start_sector, boot_sector)))
+    }
+
+    /// Return `None` if there is no FAT32 file system on the device.
+    ///
+    /// Sectors are kept relative to the device, so those of a partition
+    /// include its start.
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Option<Arc<Mutex<Self>>>, IoError> {
+        let (start_sector, boot_sector) = match Self::find_boot_sector(&block_device)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if boot_sector.bytes_per_sector as usize != BLOCK_SZ {
+            return Ok(None);
+        }
+        let reserved_sectors = boot_sector.reserved_sectors as u32;
+        let meta_sectors = reserved_sectors + boot_sector.fat_count as u32 * boot_sector.fat_size;
+        if meta_sectors >= boot_sector.total_sectors {
+            return Ok(None);
+        }
+        let cluster_count = (boot_sector.total_sectors - meta_sectors)
+            / boot_sector.sectors_per_cluster as u32;
+        let fat_start_sector = start_sector + reserved_sectors;
+        let data_start_sector = start_sector + meta_sectors;
+        // the FSInfo sector is optional
+        let fs_info_sector = match boot_sector.fs_info_sector as u32 {
+            0 | 0xffff => None,
+            sector_id => {
+                let sector_id = start_sector + sector_id;
+                let valid = get_block_cache(sector_id as usize, Arc::clone(&block_device))?
+                    .lock()
+                    .read(0, |sector: &Sector| FsInfo::is_valid(sector));
+                Some(sector_id).filter(|_| valid)
+            }
+        };
+        let mut next_free = 2;
+        if let Some(sector_id) = fs_info_sector {
+            let (_, hint) = get_block_cache(sector_id as usize, Arc::clone(&block_device))?
+                .lock()
+                .read(0, |sector: &Sector| FsInfo::get(sector));
+            if hint >= 2 && hint < cluster_count + 2 {
+                next_free = hint;
+            }
+        }
+        Ok(Some(Arc::new(Mutex::new(Self {
+            block_device,
+            sectors_per_cluster: boot_sector.sectors_per_cluster as u32,
+            fat_start_sector,
+            fat_size: boot_sector.fat_size,
+            fat_count: boot_sector.fat_count as u32,
+            data_start_sector,
+            cluster_count,
+            root_cluster: boot_sector.root_cluster,
+            fs_info_sector,
+            next_free,
+            open_files: BTreeMap::new(),
+        }))))
+    }
+
+    pub fn root_inode(fs: &Arc<Mutex<Self>>) -> FatInode {
+        let block_device = Arc::clone(&fs.lock().block_device);
+        FatInode::new(None, Arc::clone(fs), block_device)
+    }
+
+    pub fn open_file(&mut self, dirent_pos: (usize, usize)) {
+        *self.open_files.entry(dirent_pos).or_insert(0) += 1;
+    }
+
+    pub fn close_file(&mut self, dirent_pos: (usize, usize)) {
+        let handles = self.open_files.get_mut(&dirent_pos).unwrap();
+        *handles -= 1;
+        if *handles == 0 {
+            self.open_files.remove(&dirent_pos);
+        }
+    }
+
+    pub fn is_open(&self, dirent_pos: (usize, usize)) -> bool {
+        self.open_files.contains_key(&dirent_pos)
+    }
+
+    pub fn root_cluster(&self) -> u32 {
+        self.root_cluster
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * BLOCK_SZ
+    }
+
+    pub fn sectors_per_cluster(&self) -> usize {
+        self.sectors_per_cluster as usize
+    }
+
+    /// Return the first sector of a cluster, a cluster out of the data area
+    /// can only come from a damaged FAT.
+    pub fn cluster_sector(&self, cluster: u32) -> Result<usize, IoError> {
+        if !(2..self.cluster_count + 2).contains(&cluster) {
+            return Err(IoError::Corrupted(self.fat_start_sector as usize));
+        }
+        Ok((self.data_start_sector + (cluster - 2) * self.sectors_per_cluster) as usize)
+    }
+
+    /// Return (sector, offset) of the FAT entry of a cluster in the i-th FAT.
+    fn fat_entry_pos(&self, fat_id: u32, cluster: u32) -> (usize, usize) {
+        let offset = cluster as usize * 4;
+        (
+            (self.fat_start_sector + fat_id * self.fat_size) as usize + offset / BLOCK_SZ,
+            offset % BLOCK_SZ,
+        )
+    }
+
+    pub fn get_fat(&self, cluster: u32) -> Result<u32, IoError> {
+        let (sector_id, offset) = self.fat_entry_pos(0, cluster);
+        Ok(get_block_cache(sector_id, Arc::clone(&self.block_device))?
+            .lock()
+            .read(offset, |entry: &u32| *entry & FAT_ENTRY_MASK))
+    }
+
+    /// Update the entry in every copy of the FAT.
+    pub fn set_fat(&self, cluster: u32, value: u32) -> Result<(), IoError> {
+        for fat_id in 0..self.fat_count {
+            let (sector_id, offset) = self.fat_entry_pos(fat_id, cluster);
+            get_block_cache(sector_id, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(offset, |entry: &mut u32| {
+                    // the high 4 bits are reserved
+                    *entry = (*entry & !FAT_ENTRY_MASK) | (value & FAT_ENTRY_MASK);
+                });
+        }
+        Ok(())
+    }
+
+    /// Return clusters of a chain in order, an empty chain starts from cluster 0.
+    ///
+    /// Fail with `IoError::Corrupted` on the FAT sector of the entry leading
+    /// out of the data area or into a loop.
+    pub fn cluster_chain(&self, first_cluster: u32) -> Result<Vec<u32>, IoError> {
+        let mut chain: Vec<u32> = Vec::new();
+        let mut cluster = first_cluster;
+        while (2..FAT_EOC).contains(&cluster) {
+            if cluster >= self.cluster_count + 2 || chain.len() == self.cluster_count as usize {
+                let sector_id = match chain.last() {
+                    Some(&prev) => self.fat_entry_pos(0, prev).0,
+                    None => self.fat_start_sector as usize,
+                };
+                return Err(IoError::Corrupted(sector_id));
+            }
+            chain.push(cluster);
+            cluster = self.get_fat(cluster)?;
+        }
+        Ok(chain)
+    }
+
+    fn clear_cluster(&self, cluster: u32) -> Result<(), IoError> {
+        let start_sector = self.cluster_sector(cluster)?;
+        for sector_id in start_sector..start_sector + self.sectors_per_cluster as usize {
+            get_block_cache(sector_id, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(0, |sector: &mut Sector| {
+                    sector.iter_mut().for_each(|b| *b = 0);
+                });
+        }
+        Ok(())
+    }
+
+    fn update_fs_info(&self, delta: i32) -> Result<(), IoError> {
+        if let Some(sector_id) = self.fs_info_sector {
+            let next_free = self.next_free;
+            get_block_cache(sector_id as usize, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(0, |sector: &mut Sector| {
+                    let (free_count, _) = FsInfo::get(sector);
+                    let free_count = if free_count == FSINFO_UNKNOWN {
+                        free_count
+                    } else {
+                        (free_count as i32 + delta) as u32
+                    };
+                    FsInfo::set(sector, free_count, next_free);
+                });
+        }
+        Ok(())
+    }
+
+    /// Allocate a zeroed cluster and append it after `prev` if it is not 0,
+    /// return `None` if the disk is full.
+    pub fn alloc_cluster(&mut self, prev: u32) -> Result<Option<u32>, IoError> {
+        let total = self.cluster_count;
+        let start = self.next_free - 2;
+        let mut free = None;
+        for cluster in (0..total).map(|i| (start + i) % total + 2) {
+            if self.get_fat(cluster)? == FAT_FREE {
+                free = Some(cluster);
+                break;
+            }
+        }
+        let cluster = match free {
+            Some(cluster) => cluster,
+            None => return Ok(None),
+        };
+        self.set_fat(cluster, FAT_EOC_MARK)?;
+        if prev != 0 {
+            self.set_fat(prev, cluster)?;
+        }
+        self.clear_cluster(cluster)?;
+        self.next_free = if cluster + 1 < total + 2 { cluster + 1 } else { 2 };
+        self.update_fs_info(-1)?;
+        Ok(Some(cluster))
+    }
+
+    /// Free all clusters in a chain.
+    pub fn dealloc_chain(&mut self, first_cluster: u32) -> Result<(), IoError> {
+        for cluster in self.cluster_chain(first_cluster)? {
+            self.set_fat(cluster, FAT_FREE)?;
+            self.update_fs_info(1)?;
+        }
+        Ok(())
+    }
+}
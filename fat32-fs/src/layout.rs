@@ -0,0 +1,345 @@
+use super::BLOCK_SZ;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+pub const DIRENT_SZ: usize = 32;
+/// Only the low 28 bits of a FAT32 entry are meaningful.
+pub const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+/// Any entry greater or equal to this marks the end of a cluster chain.
+pub const FAT_EOC: u32 = 0x0fff_fff8;
+pub const FAT_EOC_MARK: u32 = 0x0fff_ffff;
+pub const FAT_FREE: u32 = 0;
+/// `free_count`/`next_free` in FSInfo is unknown.
+pub const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// First byte of a directory entry which has been deleted.
+pub const DIRENT_DELETED: u8 = 0xe5;
+/// First byte of the directory entry after the last used one.
+pub const DIRENT_END: u8 = 0x00;
+
+const LFN_LAST: u8 = 0x40;
+const LFN_ORDER_MASK: u8 = 0x1f;
+pub const LFN_CHARS: usize = 13;
+
+/// NT reserved flags telling that base name/extension are shown in lowercase.
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+/// 1980-01-01, the epoch of FAT timestamps.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIG: u32 = 0xaa55_0000;
+
+type Sector = [u8; BLOCK_SZ];
+
+fn read_u16(sector: &Sector, offset: usize) -> u16 {
+    u16::from_le_bytes(sector[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(sector: &Sector, offset: usize) -> u32 {
+    u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u16(sector: &mut Sector, offset: usize, value: u16) {
+    sector[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(sector: &mut Sector, offset: usize, value: u32) {
+    sector[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The fields of the FAT32 BIOS parameter block we care about.
+///
+/// It is parsed by hand since most fields are not naturally aligned.
+#[derive(Debug, Clone, Copy)]
+pub struct BootSector {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fat_count: u8,
+    pub total_sectors: u32,
+    pub fat_size: u32,
+    pub root_cluster: u32,
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+}
+
+impl BootSector {
+    /// Return `None` if the sector does not hold a FAT32 boot sector.
+    pub fn parse(sector: &Sector) -> Option<Self> {
+        if sector[510] != 0x55 || sector[511] != 0xaa {
+            return None;
+        }
+        let root_entries = read_u16(sector, 17);
+        let fat_size_16 = read_u16(sector, 22);
+        let fat_size = read_u32(sector, 36);
+        // FAT12/16 have a fixed root directory and a 16-bit FAT size
+        if root_entries != 0 || fat_size_16 != 0 || fat_size == 0 {
+            return None;
+        }
+        let total_sectors = match read_u32(sector, 32) {
+            0 => read_u16(sector, 19) as u32,
+            n => n,
+        };
+        let boot_sector = Self {
+            bytes_per_sector: read_u16(sector, 11),
+            sectors_per_cluster: sector[13],
+            reserved_sectors: read_u16(sector, 14),
+            fat_count: sector[16],
+            total_sectors,
+            fat_size,
+            root_cluster: read_u32(sector, 44),
+            fs_info_sector: read_u16(sector, 48),
+            backup_boot_sector: read_u16(sector, 50),
+        };
+        if boot_sector.sectors_per_cluster == 0
+            || !boot_sector.sectors_per_cluster.is_power_of_two()
+            || boot_sector.fat_count == 0 {
+            return None;
+        }
+        Some(boot_sector)
+    }
+    pub fn write(&self, sector: &mut Sector, volume_id: u32) {
+        sector.iter_mut().for_each(|b| *b = 0);
+        sector[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        sector[3..11].copy_from_slice(b"EASYFAT ");
+        write_u16(sector, 11, self.bytes_per_sector);
+        sector[13] = self.sectors_per_cluster;
+        write_u16(sector, 14, self.reserved_sectors);
+        sector[16] = self.fat_count;
+        // media descriptor of fixed disks
+        sector[21] = 0xf8;
+        write_u16(sector, 24, 63);
+        write_u16(sector, 26, 255);
+        write_u32(sector, 32, self.total_sectors);
+        write_u32(sector, 36, self.fat_size);
+        write_u32(sector, 44, self.root_cluster);
+        write_u16(sector, 48, self.fs_info_sector);
+        write_u16(sector, 50, self.backup_boot_sector);
+        sector[64] = 0x80;
+        sector[66] = 0x29;
+        write_u32(sector, 67, volume_id);
+        sector[71..82].copy_from_slice(b"NO NAME    ");
+        sector[82..90].copy_from_slice(b"FAT32   ");
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+    }
+}
+
+/// Partition types of FAT32 in an MBR, with CHS and LBA addressing.
+const PARTITION_FAT32: [u8; 2] = [0x0b, 0x0c];
+
+/// Return the first sector of the first FAT32 partition in an MBR.
+pub fn fat32_partition(sector: &Sector) -> Option<u32> {
+    if sector[510] != 0x55 || sector[511] != 0xaa {
+        return None;
+    }
+    (0..4)
+        .map(|i| 446 + i * 16)
+        .find(|&entry| PARTITION_FAT32.contains(&sector[entry + 4]))
+        .map(|entry| read_u32(sector, entry + 8))
+        .filter(|&start| start != 0)
+}
+
+/// Free cluster count and next free cluster hints in the FSInfo sector.
+pub struct FsInfo;
+
+impl FsInfo {
+    pub fn is_valid(sector: &Sector) -> bool {
+        read_u32(sector, 0) == FSINFO_LEAD_SIG
+            && read_u32(sector, 484) == FSINFO_STRUCT_SIG
+            && read_u32(sector, 508) == FSINFO_TRAIL_SIG
+    }
+    pub fn initialize(sector: &mut Sector, free_count: u32, next_free: u32) {
+        sector.iter_mut().for_each(|b| *b = 0);
+        write_u32(sector, 0, FSINFO_LEAD_SIG);
+        write_u32(sector, 484, FSINFO_STRUCT_SIG);
+        write_u32(sector, 508, FSINFO_TRAIL_SIG);
+        Self::set(sector, free_count, next_free);
+    }
+    /// Return (free_count, next_free).
+    pub fn get(sector: &Sector) -> (u32, u32) {
+        (read_u32(sector, 488), read_u32(sector, 492))
+    }
+    pub fn set(sector: &mut Sector, free_count: u32, next_free: u32) {
+        write_u32(sector, 488, free_count);
+        write_u32(sector, 492, next_free);
+    }
+}
+
+#[repr(C)]
+pub struct ShortDirEntry {
+    name: [u8; 11],
+    pub attr: u8,
+    nt_res: u8,
+    create_time_tenth: u8,
+    create_time: u16,
+    create_date: u16,
+    access_date: u16,
+    first_cluster_hi: u16,
+    write_time: u16,
+    write_date: u16,
+    first_cluster_lo: u16,
+    pub file_size: u32,
+}
+
+impl ShortDirEntry {
+    pub fn new(name: &[u8; 11], attr: u8) -> Self {
+        Self {
+            name: *name,
+            attr,
+            nt_res: 0,
+            create_time_tenth: 0,
+            create_time: 0,
+            create_date: DEFAULT_DATE,
+            access_date: DEFAULT_DATE,
+            first_cluster_hi: 0,
+            write_time: 0,
+            write_date: DEFAULT_DATE,
+            first_cluster_lo: 0,
+            file_size: 0,
+        }
+    }
+    pub fn raw_name(&self) -> &[u8; 11] {
+        &self.name
+    }
+    pub fn is_end(&self) -> bool {
+        self.name[0] == DIRENT_END
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.name[0] == DIRENT_DELETED
+    }
+    pub fn is_long_name(&self) -> bool {
+        self.attr & ATTR_LONG_NAME == ATTR_LONG_NAME
+    }
+    pub fn is_volume_id(&self) -> bool {
+        self.attr & ATTR_VOLUME_ID != 0
+    }
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+    pub fn is_dot(&self) -> bool {
+        &self.name == b".          " || &self.name == b"..         "
+    }
+    pub fn first_cluster(&self) -> u32 {
+        (self.first_cluster_hi as u32) << 16 | self.first_cluster_lo as u32
+    }
+    pub fn set_first_cluster(&mut self, cluster: u32) {
+        self.first_cluster_hi = (cluster >> 16) as u16;
+        self.first_cluster_lo = cluster as u16;
+    }
+    /// Name in the usual "NAME.EXT" form, honoring the lowercase flags of Windows NT.
+    pub fn name(&self) -> String {
+        let mut name = String::new();
+        let mut push = |bytes: &[u8], lower: bool| {
+            for &b in bytes.iter().take_while(|b| **b != b' ') {
+                // 0x05 stands for a leading 0xe5
+                let b = if b == 0x05 { 0xe5 } else { b };
+                let c = b as char;
+                name.push(if lower { c.to_ascii_lowercase() } else { c });
+            }
+        };
+        push(&self.name[..8], self.nt_res & NT_LOWER_BASE != 0);
+        if self.name[8] != b' ' {
+            push(b".", false);
+            push(&self.name[8..], self.nt_res & NT_LOWER_EXT != 0);
+        }
+        name
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const _ as usize as *const u8,
+                DIRENT_SZ,
+            )
+        }
+    }
+}
+
+/// Checksum of a short name stored in the long name entries belonging to it.
+pub fn short_name_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &b| {
+        (if sum & 1 != 0 { 0x80u8 } else { 0 })
+            .wrapping_add(sum >> 1)
+            .wrapping_add(b)
+    })
+}
+
+#[repr(C)]
+pub struct LongDirEntry {
+    order: u8,
+    name1: [u8; 10],
+    attr: u8,
+    type_: u8,
+    checksum: u8,
+    name2: [u8; 12],
+    first_cluster_lo: u16,
+    name3: [u8; 4],
+}
+
+impl LongDirEntry {
+    /// `part` holds at most `LFN_CHARS` UTF-16 code units of the name.
+    pub fn new(order: u8, last: bool, part: &[u16], checksum: u8) -> Self {
+        let mut chars = [0xffffu16; LFN_CHARS];
+        chars[..part.len()].copy_from_slice(part);
+        if part.len() < LFN_CHARS {
+            chars[part.len()] = 0;
+        }
+        let mut bytes = [0u8; LFN_CHARS * 2];
+        for (i, c) in chars.iter().enumerate() {
+            bytes[2 * i..2 * i + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        let mut entry = Self {
+            order: if last { order | LFN_LAST } else { order },
+            name1: [0; 10],
+            attr: ATTR_LONG_NAME,
+            type_: 0,
+            checksum,
+            name2: [0; 12],
+            first_cluster_lo: 0,
+            name3: [0; 4],
+        };
+        entry.name1.copy_from_slice(&bytes[..10]);
+        entry.name2.copy_from_slice(&bytes[10..22]);
+        entry.name3.copy_from_slice(&bytes[22..]);
+        entry
+    }
+    pub fn order(&self) -> usize {
+        (self.order & LFN_ORDER_MASK) as usize
+    }
+    pub fn is_last(&self) -> bool {
+        self.order & LFN_LAST != 0
+    }
+    pub fn checksum(&self) -> u8 {
+        self.checksum
+    }
+    /// UTF-16 code units of this part, without the terminator and padding.
+    pub fn chars(&self) -> Vec<u16> {
+        self.name1
+            .chunks(2)
+            .chain(self.name2.chunks(2))
+            .chain(self.name3.chunks(2))
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0 && *c != 0xffff)
+            .collect()
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const _ as usize as *const u8,
+                DIRENT_SZ,
+            )
+        }
+    }
+}
@@ -0,0 +1,13 @@
+#![no_std]
+
+extern crate alloc;
+
+mod layout;
+mod fs;
+mod vfs;
+
+pub use easy_fs::{BlockDevice, BLOCK_SZ, IoError};
+pub use fs::FatFileSystem;
+pub use vfs::FatInode;
+use layout::*;
+use easy_fs::get_block_cache;
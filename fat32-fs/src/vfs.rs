@@ -0,0 +1,467 @@
+use super::{
+    BlockDevice,
+    IoError,
+    BLOCK_SZ,
+    FatFileSystem,
+    ShortDirEntry,
+    LongDirEntry,
+    short_name_checksum,
+    get_block_cache,
+    DIRENT_SZ,
+    LFN_CHARS,
+    ATTR_ARCHIVE,
+    DIRENT_DELETED,
+};
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::format;
+use spin::{Mutex, MutexGuard};
+
+type Sector = [u8; BLOCK_SZ];
+
+/// A file or directory of a FAT32 file system.
+///
+/// Since FAT keeps the size and first cluster of a file in its directory
+/// entry, an inode is identified by the position of that entry.
+pub struct FatInode {
+    /// (sector, offset) of the short directory entry, `None` for the root directory.
+    dirent_pos: Option<(usize, usize)>,
+    fs: Arc<Mutex<FatFileSystem>>,
+    block_device: Arc<dyn BlockDevice>,
+    /// Given out by `find` or `create`, and counted by the file system
+    /// until dropped.
+    counted: bool,
+}
+
+/// Room for one directory entry, aligned for `ShortDirEntry` and `LongDirEntry`.
+#[repr(C, align(4))]
+struct RawDirEntry([u8; DIRENT_SZ]);
+
+/// A directory entry together with the long name entries in front of it.
+struct DirItem {
+    name: String,
+    short_name: [u8; 11],
+    /// Index of the short entry in the directory.
+    index: usize,
+    /// Index of the first long name entry, or the short entry without a long name.
+    first_index: usize,
+}
+
+impl FatInode {
+    /// We should not acquire fs lock here.
+    pub fn new(
+        dirent_pos: Option<(usize, usize)>,
+        fs: Arc<Mutex<FatFileSystem>>,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Self {
+        Self {
+            dirent_pos,
+            fs,
+            block_device,
+            counted: false,
+        }
+    }
+
+    fn read_dirent<V>(&self, f: impl FnOnce(&ShortDirEntry) -> V) -> Result<Option<V>, IoError> {
+        match self.dirent_pos {
+            Some((sector_id, offset)) => Ok(Some(
+                get_block_cache(sector_id, Arc::clone(&self.block_device))?
+                    .lock()
+                    .read(offset, f)
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn modify_dirent<V>(&self, f: impl FnOnce(&mut ShortDirEntry) -> V) -> Result<Option<V>, IoError> {
+        match self.dirent_pos {
+            Some((sector_id, offset)) => Ok(Some(
+                get_block_cache(sector_id, Arc::clone(&self.block_device))?
+                    .lock()
+                    .modify(offset, f)
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn first_cluster(&self, fs: &MutexGuard<FatFileSystem>) -> Result<u32, IoError> {
+        Ok(self.read_dirent(|dirent| dirent.first_cluster())?
+            .unwrap_or_else(|| fs.root_cluster()))
+    }
+
+    pub fn is_dir(&self) -> Result<bool, IoError> {
+        Ok(self.read_dirent(|dirent| dirent.is_dir())?.unwrap_or(true))
+    }
+
+    /// Size in bytes, directories are as large as their cluster chains.
+    fn size(&self, fs: &MutexGuard<FatFileSystem>) -> Result<usize, IoError> {
+        match self.read_dirent(|dirent| (dirent.is_dir(), dirent.file_size))? {
+            Some((false, size)) => Ok(size as usize),
+            _ => Ok(fs.cluster_chain(self.first_cluster(fs)?)?.len() * fs.cluster_size()),
+        }
+    }
+
+    /// Return (sector, offset) of the byte at `pos` of a chain.
+    fn chain_pos(fs: &MutexGuard<FatFileSystem>, chain: &[u32], pos: usize) -> Result<(usize, usize), IoError> {
+        let cluster = chain[pos / fs.cluster_size()];
+        let offset = pos % fs.cluster_size();
+        Ok((fs.cluster_sector(cluster)? + offset / BLOCK_SZ, offset % BLOCK_SZ))
+    }
+
+    /// Copy between `buf` and the content of a chain starting from `offset`,
+    /// the chain must be long enough.
+    fn access_chain(
+        &self,
+        fs: &MutexGuard<FatFileSystem>,
+        chain: &[u32],
+        offset: usize,
+        len: usize,
+        mut f: impl FnMut(&mut Sector, usize, usize, usize),
+    ) -> Result<(), IoError> {
+        let mut done = 0usize;
+        while done < len {
+            let (sector_id, sector_offset) = Self::chain_pos(fs, chain, offset + done)?;
+            let size = (BLOCK_SZ - sector_offset).min(len - done);
+            get_block_cache(sector_id, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(0, |sector: &mut Sector| f(sector, sector_offset, done, size));
+            done += size;
+        }
+        Ok(())
+    }
+
+    fn read_chain(
+        &self,
+        fs: &MutexGuard<FatFileSystem>,
+        chain: &[u32],
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), IoError> {
+        let mut done = 0usize;
+        while done < buf.len() {
+            let (sector_id, sector_offset) = Self::chain_pos(fs, chain, offset + done)?;
+            let size = (BLOCK_SZ - sector_offset).min(buf.len() - done);
+            let dst = &mut buf[done..done + size];
+            get_block_cache(sector_id, Arc::clone(&self.block_device))?
+                .lock()
+                .read(0, |sector: &Sector| {
+                    dst.copy_from_slice(&sector[sector_offset..sector_offset + size]);
+                });
+            done += size;
+        }
+        Ok(())
+    }
+
+    /// Return all entries of this directory, deleted ones are skipped.
+    fn dir_items(&self, fs: &MutexGuard<FatFileSystem>) -> Result<Vec<DirItem>, IoError> {
+        assert!(self.is_dir()?);
+        let chain = fs.cluster_chain(self.first_cluster(fs)?)?;
+        let entry_count = chain.len() * fs.cluster_size() / DIRENT_SZ;
+        let mut items = Vec::new();
+        // (checksum, parts of the long name, next order expected, index of the first part)
+        let mut long_name: Option<(u8, Vec<Vec<u16>>, usize, usize)> = None;
+        let mut raw = RawDirEntry([0u8; DIRENT_SZ]);
+        for index in 0..entry_count {
+            self.read_chain(fs, &chain, index * DIRENT_SZ, &mut raw.0)?;
+            let dirent = unsafe { &*(raw.0.as_ptr() as *const ShortDirEntry) };
+            if dirent.is_end() {
+                break;
+            }
+            if dirent.is_deleted() {
+                long_name = None;
+                continue;
+            }
+            if dirent.is_long_name() {
+                let lfn = unsafe { &*(raw.0.as_ptr() as *const LongDirEntry) };
+                if lfn.is_last() {
+                    long_name = Some((
+                        lfn.checksum(),
+                        vec![lfn.chars()],
+                        lfn.order().saturating_sub(1),
+                        index,
+                    ));
+                } else {
+                    long_name = match long_name.take() {
+                        Some((checksum, mut parts, order, first_index))
+                            if checksum == lfn.checksum() && order == lfn.order() && order > 0 => {
+                            parts.push(lfn.chars());
+                            Some((checksum, parts, order - 1, first_index))
+                        }
+                        _ => None,
+                    };
+                }
+                continue;
+            }
+            let lfn = long_name.take();
+            if dirent.is_volume_id() || dirent.is_dot() {
+                continue;
+            }
+            let (name, first_index) = match lfn {
+                Some((checksum, parts, 0, first_index))
+                    if checksum == short_name_checksum(dirent.raw_name()) => {
+                    let chars: Vec<u16> = parts.into_iter().rev().flatten().collect();
+                    let name = core::char::decode_utf16(chars)
+                        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                        .collect();
+                    (name, first_index)
+                }
+                _ => (dirent.name(), index),
+            };
+            items.push(DirItem {
+                name,
+                short_name: *dirent.raw_name(),
+                index,
+                first_index,
+            });
+        }
+        Ok(items)
+    }
+
+    fn find_item(&self, name: &str, fs: &MutexGuard<FatFileSystem>) -> Result<Option<DirItem>, IoError> {
+        Ok(self.dir_items(fs)?
+            .into_iter()
+            .find(|item| item.name.eq_ignore_ascii_case(name)))
+    }
+
+    /// Return the inode of the entry with given index in this directory.
+    fn child(&self, index: usize, fs: &MutexGuard<FatFileSystem>) -> Result<Arc<FatInode>, IoError> {
+        let chain = fs.cluster_chain(self.first_cluster(fs)?)?;
+        let pos = Self::chain_pos(fs, &chain, index * DIRENT_SZ)?;
+        Ok(Arc::new(Self::new(
+            Some(pos),
+            self.fs.clone(),
+            self.block_device.clone(),
+        )))
+    }
+
+    /// Return a counted handle on the entry with given index, so that the
+    /// file is not unlinked while it is in use.
+    fn open_child(&self, index: usize, fs: &mut MutexGuard<FatFileSystem>) -> Result<Arc<FatInode>, IoError> {
+        let chain = fs.cluster_chain(self.first_cluster(fs)?)?;
+        let pos = Self::chain_pos(fs, &chain, index * DIRENT_SZ)?;
+        fs.open_file(pos);
+        let mut child = Self::new(Some(pos), self.fs.clone(), self.block_device.clone());
+        child.counted = true;
+        Ok(Arc::new(child))
+    }
+
+    pub fn find(&self, name: &str) -> Result<Option<Arc<FatInode>>, IoError> {
+        let mut fs = self.fs.lock();
+        match self.find_item(name, &fs)? {
+            Some(item) => Ok(Some(self.open_child(item.index, &mut fs)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn ls(&self) -> Result<Vec<String>, IoError> {
+        let fs = self.fs.lock();
+        Ok(self.dir_items(&fs)?
+            .into_iter()
+            .map(|item| item.name)
+            .collect())
+    }
+
+    /// Generate a unique short name in "BASIS~N EXT" form, or just the
+    /// name itself if it is a valid uppercase 8.3 name.
+    fn short_name(name: &str, items: &[DirItem]) -> ([u8; 11], bool) {
+        fn convert(s: &str) -> Vec<u8> {
+            s.bytes()
+                .filter(|b| *b != b' ' && *b != b'.')
+                .map(|b| match b.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' | c @ b'0'..=b'9' => c,
+                    c if b"$%'-_@~`!(){}^#&".contains(&c) => c,
+                    _ => b'_',
+                })
+                .collect()
+        }
+        let (base, ext) = match name.rfind('.') {
+            Some(idx) if idx > 0 => (&name[..idx], &name[idx + 1..]),
+            _ => (name, ""),
+        };
+        let (basis, ext_bytes) = (convert(base), convert(ext));
+        let mut short_name = [b' '; 11];
+        let ext_len = ext_bytes.len().min(3);
+        short_name[8..8 + ext_len].copy_from_slice(&ext_bytes[..ext_len]);
+        let exact = basis.len() <= 8 && ext_bytes.len() <= 3
+            && base.as_bytes() == &basis[..] && ext.as_bytes() == &ext_bytes[..];
+        if exact {
+            short_name[..basis.len()].copy_from_slice(&basis);
+            return (short_name, true);
+        }
+        for n in 1.. {
+            let tail = format!("~{}", n);
+            let len = basis.len().min(8 - tail.len());
+            let mut candidate = short_name;
+            candidate[..len].copy_from_slice(&basis[..len]);
+            candidate[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+            if items.iter().all(|item| item.short_name != candidate) {
+                return (candidate, false);
+            }
+        }
+        unreachable!();
+    }
+
+    pub fn create(&self, name: &str) -> Result<Option<Arc<FatInode>>, IoError> {
+        let mut fs = self.fs.lock();
+        let items = self.dir_items(&fs)?;
+        if name.is_empty() || items.iter().any(|item| item.name.eq_ignore_ascii_case(name)) {
+            return Ok(None);
+        }
+        let (short_name, exact) = Self::short_name(name, &items);
+        let checksum = short_name_checksum(&short_name);
+        let utf16: Vec<u16> = name.encode_utf16().collect();
+        let lfn_count = if exact { 0 } else { (utf16.len() + LFN_CHARS - 1) / LFN_CHARS };
+        if lfn_count > 20 {
+            // a long name has at most 255 characters
+            return Ok(None);
+        }
+        let needed = lfn_count + 1;
+        // find `needed` consecutive free entries, extending the directory if necessary
+        let mut chain = fs.cluster_chain(self.first_cluster(&fs)?)?;
+        let mut start = 0usize;
+        let mut run = 0usize;
+        let mut index = 0usize;
+        let mut raw = [0u8; DIRENT_SZ];
+        while run < needed {
+            if index == chain.len() * fs.cluster_size() / DIRENT_SZ {
+                let cluster = match fs.alloc_cluster(*chain.last().unwrap())? {
+                    Some(cluster) => cluster,
+                    None => return Ok(None),
+                };
+                chain.push(cluster);
+            }
+            self.read_chain(&fs, &chain, index * DIRENT_SZ, &mut raw)?;
+            if raw[0] == DIRENT_DELETED || raw[0] == 0 {
+                if run == 0 {
+                    start = index;
+                }
+                run += 1;
+            } else {
+                run = 0;
+            }
+            index += 1;
+        }
+        // long name entries are stored in reverse order
+        let mut entries: Vec<Vec<u8>> = Vec::new();
+        for order in (1..=lfn_count).rev() {
+            let part = &utf16[(order - 1) * LFN_CHARS..utf16.len().min(order * LFN_CHARS)];
+            let lfn = LongDirEntry::new(order as u8, order == lfn_count, part, checksum);
+            entries.push(lfn.as_bytes().to_vec());
+        }
+        let dirent = ShortDirEntry::new(&short_name, ATTR_ARCHIVE);
+        entries.push(dirent.as_bytes().to_vec());
+        for (i, entry) in entries.iter().enumerate() {
+            self.access_chain(&fs, &chain, (start + i) * DIRENT_SZ, DIRENT_SZ, |sector, offset, done, size| {
+                sector[offset..offset + size].copy_from_slice(&entry[done..done + size]);
+            })?;
+        }
+        Ok(Some(self.open_child(start + lfn_count, &mut fs)?))
+        // release fs lock automatically by compiler
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, IoError> {
+        let fs = self.fs.lock();
+        let size = self.size(&fs)?;
+        let end = (offset + buf.len()).min(size);
+        if offset >= end {
+            return Ok(0);
+        }
+        let chain = fs.cluster_chain(self.first_cluster(&fs)?)?;
+        self.read_chain(&fs, &chain, offset, &mut buf[..end - offset])?;
+        Ok(end - offset)
+    }
+
+    /// Fail with `IoError::IsDir` on a directory.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, IoError> {
+        let mut fs = self.fs.lock();
+        if self.is_dir()? {
+            return Err(IoError::IsDir);
+        }
+        let old_size = self.size(&fs)?;
+        let new_size = old_size.max(offset + buf.len());
+        let mut chain = fs.cluster_chain(self.first_cluster(&fs)?)?;
+        let clusters_needed = (new_size + fs.cluster_size() - 1) / fs.cluster_size();
+        while chain.len() < clusters_needed {
+            let prev = chain.last().copied().unwrap_or(0);
+            let cluster = match fs.alloc_cluster(prev)? {
+                Some(cluster) => cluster,
+                // the disk is full
+                None => break,
+            };
+            if prev == 0 {
+                self.modify_dirent(|dirent| dirent.set_first_cluster(cluster))?;
+            }
+            chain.push(cluster);
+        }
+        let capacity = chain.len() * fs.cluster_size();
+        if offset > capacity {
+            return Ok(0);
+        }
+        let len = buf.len().min(capacity - offset);
+        // the gap after the old end may still hold stale data
+        if offset > old_size {
+            self.access_chain(&fs, &chain, old_size, offset - old_size, |sector, sector_offset, _, size| {
+                sector[sector_offset..sector_offset + size].iter_mut().for_each(|b| *b = 0);
+            })?;
+        }
+        self.access_chain(&fs, &chain, offset, len, |sector, sector_offset, done, size| {
+            sector[sector_offset..sector_offset + size].copy_from_slice(&buf[done..done + size]);
+        })?;
+        if offset + len > old_size {
+            self.modify_dirent(|dirent| dirent.file_size = (offset + len) as u32)?;
+        }
+        Ok(len)
+    }
+
+    /// Truncate the file to zero and free its clusters, fail with
+    /// `IoError::IsDir` on a directory.
+    pub fn clear(&self) -> Result<(), IoError> {
+        let mut fs = self.fs.lock();
+        if self.is_dir()? {
+            return Err(IoError::IsDir);
+        }
+        let first_cluster = self.first_cluster(&fs)?;
+        fs.dealloc_chain(first_cluster)?;
+        self.modify_dirent(|dirent| {
+            dirent.set_first_cluster(0);
+            dirent.file_size = 0;
+        })?;
+        Ok(())
+    }
+
+    /// Remove a file from this directory and free its clusters, return
+    /// false if there is no such file, it is a directory, or it is open.
+    ///
+    /// The size and clusters of a file are in its entry, which can be
+    /// reused by another file once deleted, so an open file is kept.
+    pub fn unlink(&self, name: &str) -> Result<bool, IoError> {
+        let mut fs = self.fs.lock();
+        let item = match self.find_item(name, &fs)? {
+            Some(item) => item,
+            None => return Ok(false),
+        };
+        let child = self.child(item.index, &fs)?;
+        if child.is_dir()? || fs.is_open(child.dirent_pos.unwrap()) {
+            return Ok(false);
+        }
+        let first_cluster = child.first_cluster(&fs)?;
+        fs.dealloc_chain(first_cluster)?;
+        let chain = fs.cluster_chain(self.first_cluster(&fs)?)?;
+        for index in item.first_index..=item.index {
+            self.access_chain(&fs, &chain, index * DIRENT_SZ, 1, |sector, offset, _, _| {
+                sector[offset] = DIRENT_DELETED;
+            })?;
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        if self.counted {
+            self.fs.lock().close_file(self.dirent_pos.unwrap());
+        }
+    }
+}
@@ -18,6 +18,7 @@ pub const CLOCK_FREQ: usize = 12500000;
 
 #[cfg(feature = "board_qemu")]
 pub const MMIO: &[(usize, usize)] = &[
+    (0x0010_1000, 0x1000),      /* RTC       */
     (0x10001000, 0x1000),
 ];
 
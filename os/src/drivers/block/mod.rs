@@ -12,7 +12,13 @@ type BlockDeviceImpl = virtio_blk::VirtIOBlock;
 type BlockDeviceImpl = sdcard::SDCardWrapper;
 
 lazy_static! {
-    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = Arc::new(BlockDeviceImpl::new());
+    static ref BLOCK_DEVICE_IMPL: Arc<BlockDeviceImpl> = Arc::new(BlockDeviceImpl::new());
+    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = BLOCK_DEVICE_IMPL.clone();
+}
+
+/// Capacity of `BLOCK_DEVICE` in blocks.
+pub fn block_device_blocks() -> usize {
+    BLOCK_DEVICE_IMPL.num_blocks()
 }
 
 #[allow(unused)]
@@ -22,8 +28,8 @@ pub fn block_device_test() {
     let mut read_buffer = [0u8; 512];
     for i in 0..512 {
         for byte in write_buffer.iter_mut() { *byte = i as u8; }
-        block_device.write_block(i as usize, &write_buffer);
-        block_device.read_block(i as usize, &mut read_buffer);
+        block_device.write_block(i as usize, &write_buffer).unwrap();
+        block_device.read_block(i as usize, &mut read_buffer).unwrap();
         assert_eq!(write_buffer, read_buffer);
     }
     println!("block device test passed!");
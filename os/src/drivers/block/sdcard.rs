@@ -16,6 +16,7 @@ use k210_soc::{
 use spin::Mutex;
 use lazy_static::*;
 use super::BlockDevice;
+use easy_fs::IoError;
 use core::convert::TryInto;
 
 pub struct SDCard<SPI> {
@@ -714,7 +715,8 @@ lazy_static! {
     static ref PERIPHERALS: Mutex<Peripherals> = Mutex::new(Peripherals::take().unwrap());
 }
 
-fn init_sdcard() -> SDCard<SPIImpl<SPI0>> {
+/// Return the initialized card and its capacity in sectors.
+fn init_sdcard() -> (SDCard<SPIImpl<SPI0>>, usize) {
     // wait previous output
     usleep(100000);
     let peripherals = unsafe { Peripherals::steal() };
@@ -732,22 +734,26 @@ fn init_sdcard() -> SDCard<SPIImpl<SPI0>> {
     assert!(num_sectors > 0);
 
     println!("init sdcard!");
-    sd
+    (sd, num_sectors as usize)
 }
 
-pub struct SDCardWrapper(Mutex<SDCard<SPIImpl<SPI0>>>);
+pub struct SDCardWrapper(Mutex<SDCard<SPIImpl<SPI0>>>, usize);
 
 impl SDCardWrapper {
     pub fn new() -> Self {
-        Self(Mutex::new(init_sdcard()))
+        let (sd, num_sectors) = init_sdcard();
+        Self(Mutex::new(sd), num_sectors)
+    }
+    pub fn num_blocks(&self) -> usize {
+        self.1
     }
 }
 
 impl BlockDevice for SDCardWrapper {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        self.0.lock().read_sector(buf,block_id as u32).unwrap();
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), IoError> {
+        self.0.lock().read_sector(buf,block_id as u32).map_err(|_| IoError::Device(block_id))
     }
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
-        self.0.lock().write_sector(buf,block_id as u32).unwrap();
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), IoError> {
+        self.0.lock().write_sector(buf,block_id as u32).map_err(|_| IoError::Device(block_id))
     }
 }
\ No newline at end of file
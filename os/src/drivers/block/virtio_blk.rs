@@ -12,12 +12,15 @@ use crate::mm::{
     kernel_token,
 };
 use super::BlockDevice;
+use easy_fs::IoError;
 use spin::Mutex;
 use alloc::vec::Vec;
 use lazy_static::*;
 
 #[allow(unused)]
 const VIRTIO0: usize = 0x10001000;
+/// Offset of the device-specific configuration space in a virtio-mmio device.
+const VIRTIO_MMIO_CONFIG: usize = 0x100;
 
 pub struct VirtIOBlock(Mutex<VirtIOBlk<'static>>);
 
@@ -26,11 +29,11 @@ lazy_static! {
 }
 
 impl BlockDevice for VirtIOBlock {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        self.0.lock().read_block(block_id, buf).expect("Error when reading VirtIOBlk");
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), IoError> {
+        self.0.lock().read_block(block_id, buf).map_err(|_| IoError::Device(block_id))
     }
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
-        self.0.lock().write_block(block_id, buf).expect("Error when writing VirtIOBlk");
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), IoError> {
+        self.0.lock().write_block(block_id, buf).map_err(|_| IoError::Device(block_id))
     }
 }
 
@@ -41,6 +44,13 @@ impl VirtIOBlock {
             unsafe { &mut *(VIRTIO0 as *mut VirtIOHeader) }
         ).unwrap()))
     }
+    /// The first field of the virtio-blk configuration is the capacity
+    /// in 512-byte sectors.
+    pub fn num_blocks(&self) -> usize {
+        unsafe {
+            ((VIRTIO0 + VIRTIO_MMIO_CONFIG) as *const u64).read_volatile() as usize
+        }
+    }
 }
 
 #[no_mangle]
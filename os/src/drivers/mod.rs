@@ -1,3 +1,3 @@
 mod block;
 
-pub use block::BLOCK_DEVICE;
\ No newline at end of file
+pub use block::{BLOCK_DEVICE, block_device_blocks};
\ No newline at end of file
@@ -0,0 +1,198 @@
+use super::{File, OpenFlags, Stdin, Stdout};
+use super::inode::{permits, current_cred};
+use crate::mm::UserBuffer;
+use crate::drivers::{BLOCK_DEVICE, block_device_blocks};
+use easy_fs::{BLOCK_SZ, IoError, block_cache_sync_all, block_cache_reload};
+use alloc::sync::Arc;
+use spin::Mutex;
+
+/// `/dev/null`: reads hit end-of-file at once and writes are discarded.
+pub struct NullDev {
+    readable: bool,
+    writable: bool,
+}
+
+impl File for NullDev {
+    fn readable(&self) -> bool { self.readable }
+    fn writable(&self) -> bool { self.writable }
+    fn read(&self, _buf: UserBuffer) -> Result<usize, IoError> {
+        Ok(0)
+    }
+    fn write(&self, buf: UserBuffer) -> Result<usize, IoError> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/zero`: reads give as many zero bytes as requested and writes are discarded.
+pub struct ZeroDev {
+    readable: bool,
+    writable: bool,
+}
+
+impl File for ZeroDev {
+    fn readable(&self) -> bool { self.readable }
+    fn writable(&self) -> bool { self.writable }
+    fn read(&self, mut buf: UserBuffer) -> Result<usize, IoError> {
+        for slice in buf.buffers.iter_mut() {
+            slice.iter_mut().for_each(|byte| *byte = 0);
+        }
+        Ok(buf.len())
+    }
+    fn write(&self, buf: UserBuffer) -> Result<usize, IoError> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/console`: reads come from `Stdin` and writes go to `Stdout`.
+pub struct Console {
+    readable: bool,
+    writable: bool,
+}
+
+impl File for Console {
+    fn readable(&self) -> bool { self.readable }
+    fn writable(&self) -> bool { self.writable }
+    fn read(&self, buf: UserBuffer) -> Result<usize, IoError> {
+        Stdin.read(buf)
+    }
+    fn write(&self, buf: UserBuffer) -> Result<usize, IoError> {
+        Stdout.write(buf)
+    }
+    fn is_tty(&self) -> bool { true }
+}
+
+/// `/dev/blk0`: the raw `BLOCK_DEVICE` seen as a seekable byte stream.
+///
+/// The block cache of easy-fs is flushed before every access, so reading
+/// the device gives the latest content of the file system on it. Cached
+/// blocks written over are dropped afterwards, so that the file system
+/// neither serves them stale nor writes them back over the new data.
+pub struct BlockDev {
+    readable: bool,
+    writable: bool,
+    offset: Mutex<usize>,
+}
+
+impl BlockDev {
+    fn size(&self) -> usize {
+        block_device_blocks() * BLOCK_SZ
+    }
+}
+
+impl File for BlockDev {
+    fn readable(&self) -> bool { self.readable }
+    fn writable(&self) -> bool { self.writable }
+    fn read(&self, mut buf: UserBuffer) -> Result<usize, IoError> {
+        block_cache_sync_all()?;
+        let mut offset = self.offset.lock();
+        let size = self.size();
+        let mut block = [0u8; BLOCK_SZ];
+        let mut total_read_size = 0usize;
+        'outer: for slice in buf.buffers.iter_mut() {
+            let mut pos = 0usize;
+            while pos < slice.len() && *offset < size {
+                let block_offset = *offset % BLOCK_SZ;
+                let len = (BLOCK_SZ - block_offset)
+                    .min(slice.len() - pos)
+                    .min(size - *offset);
+                if let Err(err) = BLOCK_DEVICE.read_block(*offset / BLOCK_SZ, &mut block) {
+                    if total_read_size + pos == 0 {
+                        return Err(err);
+                    }
+                    total_read_size += pos;
+                    break 'outer;
+                }
+                slice[pos..pos + len].copy_from_slice(&block[block_offset..block_offset + len]);
+                pos += len;
+                *offset += len;
+            }
+            total_read_size += pos;
+            if pos < slice.len() {
+                break;
+            }
+        }
+        Ok(total_read_size)
+    }
+    fn write(&self, buf: UserBuffer) -> Result<usize, IoError> {
+        block_cache_sync_all()?;
+        let mut offset = self.offset.lock();
+        let first_sector = *offset / BLOCK_SZ;
+        let size = self.size();
+        let mut block = [0u8; BLOCK_SZ];
+        let mut total_write_size = 0usize;
+        'outer: for slice in buf.buffers.iter() {
+            let mut pos = 0usize;
+            while pos < slice.len() && *offset < size {
+                let block_id = *offset / BLOCK_SZ;
+                let block_offset = *offset % BLOCK_SZ;
+                let len = (BLOCK_SZ - block_offset)
+                    .min(slice.len() - pos)
+                    .min(size - *offset);
+                // partial blocks have to be read back first
+                let result = if len < BLOCK_SZ {
+                    BLOCK_DEVICE.read_block(block_id, &mut block)
+                } else {
+                    Ok(())
+                }.and_then(|_| {
+                    block[block_offset..block_offset + len].copy_from_slice(&slice[pos..pos + len]);
+                    BLOCK_DEVICE.write_block(block_id, &block)
+                });
+                if let Err(err) = result {
+                    if total_write_size + pos == 0 {
+                        return Err(err);
+                    }
+                    total_write_size += pos;
+                    break 'outer;
+                }
+                pos += len;
+                *offset += len;
+            }
+            total_write_size += pos;
+            if pos < slice.len() {
+                break;
+            }
+        }
+        let end_sector = (*offset + BLOCK_SZ - 1) / BLOCK_SZ;
+        if let Err(err) = block_cache_reload(&BLOCK_DEVICE, first_sector..end_sector) {
+            if total_write_size == 0 {
+                return Err(err);
+            }
+        }
+        Ok(total_write_size)
+    }
+}
+
+/// Return the mode of a device node, all of them are owned by root and
+/// only root may access the file system under the raw block device.
+fn node_mode(name: &str) -> u16 {
+    match name {
+        "blk0" => 0o600,
+        _ => 0o666,
+    }
+}
+
+/// `path` is relative to the mount point `/dev`.
+///
+/// `CREATE` and `TRUNC` are accepted and ignored, so that shell
+/// redirections such as `> /dev/null` work.
+pub fn open_dev(path: &str, flags: OpenFlags) -> Option<Arc<dyn File + Send + Sync>> {
+    let (readable, writable) = flags.read_write();
+    let name = path.trim_start_matches('/');
+    let (uid, gid) = current_cred();
+    if !permits((node_mode(name), 0, 0), uid, gid, flags.access()) {
+        return None;
+    }
+    match name {
+        "null" => Some(Arc::new(NullDev { readable, writable })),
+        "zero" => Some(Arc::new(ZeroDev { readable, writable })),
+        "console" | "tty" => Some(Arc::new(Console { readable, writable })),
+        "stdin" if !writable => Some(Arc::new(Stdin)),
+        "stdout" | "stderr" if !readable => Some(Arc::new(Stdout)),
+        "blk0" => Some(Arc::new(BlockDev {
+            readable,
+            writable,
+            offset: Mutex::new(0),
+        })),
+        _ => None,
+    }
+}
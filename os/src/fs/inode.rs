@@ -1,12 +1,21 @@
 use easy_fs::{
     EasyFileSystem,
     Inode,
+    IoError,
+    set_time_source,
+};
+use fat32_fs::{
+    FatFileSystem,
+    FatInode,
 };
 use crate::drivers::BLOCK_DEVICE;
+use crate::timer::get_real_time;
+use crate::task::current_task;
 use alloc::sync::Arc;
 use lazy_static::*;
 use bitflags::*;
 use alloc::vec::Vec;
+use alloc::string::String;
 use spin::Mutex;
 use super::File;
 use crate::mm::UserBuffer;
@@ -19,14 +28,14 @@ pub struct OSInode {
 
 pub struct OSInodeInner {
     offset: usize,
-    inode: Arc<Inode>,
+    inode: FsInode,
 }
 
 impl OSInode {
     pub fn new(
         readable: bool,
         writable: bool,
-        inode: Arc<Inode>,
+        inode: FsInode,
     ) -> Self {
         Self {
             readable,
@@ -42,7 +51,8 @@ impl OSInode {
         let mut buffer = [0u8; 512];
         let mut v: Vec<u8> = Vec::new();
         loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
+            // a damaged file is cut short
+            let len = inner.inode.read_at(inner.offset, &mut buffer).unwrap_or(0);
             if len == 0 {
                 break;
             }
@@ -53,17 +63,176 @@ impl OSInode {
     }
 }
 
+/// An inode of whichever file system is mounted on `BLOCK_DEVICE`.
+///
+/// Both fail with an `IoError` when the device does, easy-fs also when
+/// metadata does not match its checksum or a file can not grow any more.
+#[derive(Clone)]
+pub enum FsInode {
+    Efs(Arc<Inode>),
+    Fat(Arc<FatInode>),
+}
+
+impl FsInode {
+    pub fn find(&self, name: &str) -> Result<Option<FsInode>, IoError> {
+        match self {
+            Self::Efs(inode) => Ok(inode.find(name)?.map(Self::Efs)),
+            Self::Fat(inode) => Ok(inode.find(name)?.map(Self::Fat)),
+        }
+    }
+    pub fn create(&self, name: &str) -> Result<Option<FsInode>, IoError> {
+        match self {
+            Self::Efs(inode) => Ok(inode.create(name)?.map(Self::Efs)),
+            Self::Fat(inode) => Ok(inode.create(name)?.map(Self::Fat)),
+        }
+    }
+    pub fn is_dir(&self) -> Result<bool, IoError> {
+        match self {
+            Self::Efs(inode) => inode.is_dir(),
+            Self::Fat(inode) => inode.is_dir(),
+        }
+    }
+    pub fn ls(&self) -> Result<Vec<String>, IoError> {
+        match self {
+            Self::Efs(inode) => inode.ls(),
+            Self::Fat(inode) => inode.ls(),
+        }
+    }
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, IoError> {
+        match self {
+            Self::Efs(inode) => inode.read_at(offset, buf),
+            Self::Fat(inode) => inode.read_at(offset, buf),
+        }
+    }
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, IoError> {
+        match self {
+            Self::Efs(inode) => inode.write_at(offset, buf),
+            Self::Fat(inode) => inode.write_at(offset, buf),
+        }
+    }
+    pub fn clear(&self) -> Result<(), IoError> {
+        match self {
+            Self::Efs(inode) => inode.clear(),
+            Self::Fat(inode) => inode.clear(),
+        }
+    }
+    /// Only easy-fs has holes, so FAT supports no mode at all.
+    pub fn fallocate(&self, mode: FallocFlags, offset: usize, len: usize) -> Result<bool, IoError> {
+        let inode = match self {
+            Self::Efs(inode) => inode,
+            Self::Fat(_) => return Ok(false),
+        };
+        let keep_size = mode.contains(FallocFlags::KEEP_SIZE);
+        if mode.contains(FallocFlags::PUNCH_HOLE) {
+            // the size can not change by punching
+            if !keep_size {
+                return Ok(false);
+            }
+            inode.punch_hole(offset, len)?;
+        } else {
+            inode.fallocate(offset, len, keep_size)?;
+        }
+        Ok(true)
+    }
+    pub fn unlink(&self, name: &str) -> Result<bool, IoError> {
+        match self {
+            Self::Efs(inode) => inode.unlink(name),
+            Self::Fat(inode) => inode.unlink(name),
+        }
+    }
+    /// Return (mode, uid, gid), FAT has no owners so everything is
+    /// accessible to everyone there.
+    pub fn attr(&self) -> Result<(u16, u16, u16), IoError> {
+        match self {
+            Self::Efs(inode) => inode.attr(),
+            Self::Fat(_) => Ok((0o777, 0, 0)),
+        }
+    }
+    pub fn set_owner(&self, uid: u16, gid: u16) -> Result<(), IoError> {
+        match self {
+            Self::Efs(inode) => inode.set_owner(uid, gid),
+            Self::Fat(_) => Ok(()),
+        }
+    }
+    /// Nothing is permitted on an inode that can not be read, see `permits`.
+    pub fn permits(&self, uid: u32, gid: u32, access: Access) -> bool {
+        match self.attr() {
+            Ok(attr) => permits(attr, uid, gid, access),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Check `access` against the permission bits in (mode, uid, gid) of a
+/// file, of the owner, group or others, whichever applies to (uid, gid)
+/// first.
+pub fn permits((mode, owner, group): (u16, u16, u16), uid: u32, gid: u32, access: Access) -> bool {
+    if uid == 0 {
+        // root may do anything but executing a file without any x bit
+        return !access.contains(Access::EXEC) || mode & 0o111 != 0;
+    }
+    let bits = if uid == owner as u32 {
+        mode >> 6
+    } else if gid == group as u32 {
+        mode >> 3
+    } else {
+        mode
+    };
+    Access::from_bits_truncate(bits & 0o7).contains(access)
+}
+
+bitflags! {
+    /// Permission bits of one class in the mode of an inode.
+    pub struct Access: u16 {
+        const READ = 0o4;
+        const WRITE = 0o2;
+        const EXEC = 0o1;
+    }
+}
+
+/// The sticky bit, only owners may remove files in such a directory.
+const MODE_STICKY: u16 = 0o1000;
+
+/// Return (uid, gid) of the current process, the kernel itself acts as root.
+pub fn current_cred() -> (u32, u32) {
+    current_task()
+        .map(|task| {
+            let process = task.process();
+            let inner = process.acquire_inner_lock();
+            (inner.uid, inner.gid)
+        })
+        .unwrap_or((0, 0))
+}
+
 lazy_static! {
-    pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
+    /// SD cards written by other systems are usually FAT32, so it is tried
+    /// first, then the device is expected to hold an easy-fs image.
+    pub static ref ROOT_INODE: FsInode = {
+        let fat = match FatFileSystem::open(BLOCK_DEVICE.clone()) {
+            Ok(fat) => fat,
+            Err(err) => panic!("Error reading the block device: {:?}", err),
+        };
+        if let Some(fat) = fat {
+            FsInode::Fat(Arc::new(FatFileSystem::root_inode(&fat)))
+        } else {
+            set_time_source(get_real_time);
+            let efs = match EasyFileSystem::open(BLOCK_DEVICE.clone()) {
+                Ok(efs) => efs,
+                Err(err) => panic!("Error loading EFS: {:?}", err),
+            };
+            if !efs.lock().was_clean() {
+                println!("[kernel] easy-fs was not unmounted cleanly, it may be inconsistent");
+            }
+            FsInode::Efs(Arc::new(EasyFileSystem::root_inode(&efs)))
+        }
     };
 }
 
 pub fn list_apps() {
     println!("/**** APPS ****");
-    for app in ROOT_INODE.ls() {
-        println!("{}", app);
+    match ROOT_INODE.ls() {
+        Ok(apps) => apps.iter().for_each(|app| println!("{}", app)),
+        Err(err) => println!("[kernel] cannot list apps: {:?}", err),
     }
     println!("**************/")
 }
@@ -78,6 +247,14 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Modes of `fallocate`, the default allocates blocks and grows the file.
+    pub struct FallocFlags: u32 {
+        const KEEP_SIZE = 1 << 0;
+        const PUNCH_HOLE = 1 << 1;
+    }
+}
+
 impl OpenFlags {
     /// Do not check validity for simplicity
     /// Return (readable, writable)
@@ -90,70 +267,155 @@ impl OpenFlags {
             (true, true)
         }
     }
+    /// Access needed to read or write the file as requested.
+    pub fn access(&self) -> Access {
+        let (readable, writable) = self.read_write();
+        let mut access = Access::empty();
+        access.set(Access::READ, readable);
+        access.set(Access::WRITE, writable);
+        access
+    }
 }
 
 pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+    let (uid, gid) = current_cred();
     let (readable, writable) = flags.read_write();
+    let mut access = flags.access();
+    // a damaged directory or inode can not be opened, nor a directory
+    // for writing
     if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
+        if let Some(inode) = ROOT_INODE.find(name).ok()? {
+            if inode.is_dir().ok()? || !inode.permits(uid, gid, access | Access::WRITE) {
+                return None;
+            }
             // clear size
-            inode.clear();
+            inode.clear().ok()?;
             Some(Arc::new(OSInode::new(
                 readable,
                 writable,
                 inode,
             )))
         } else {
+            if !ROOT_INODE.permits(uid, gid, Access::WRITE) {
+                return None;
+            }
             // create file
-            ROOT_INODE.create(name)
-                .map(|inode| {
-                    Arc::new(OSInode::new(
-                        readable,
-                        writable,
-                        inode,
-                    ))
-                })
+            let inode = ROOT_INODE.create(name).ok()??;
+            inode.set_owner(uid as u16, gid as u16).ok()?;
+            Some(Arc::new(OSInode::new(
+                readable,
+                writable,
+                inode,
+            )))
         }
     } else {
-        ROOT_INODE.find(name)
-            .map(|inode| {
-                if flags.contains(OpenFlags::TRUNC) {
-                    inode.clear();
-                }
-                Arc::new(OSInode::new(
-                    readable,
-                    writable,
-                    inode
-                ))
-            })
+        if flags.contains(OpenFlags::TRUNC) {
+            access |= Access::WRITE;
+        }
+        let inode = ROOT_INODE.find(name).ok()?
+            .filter(|inode| inode.permits(uid, gid, access))?;
+        if access.contains(Access::WRITE) && inode.is_dir().ok()? {
+            return None;
+        }
+        if flags.contains(OpenFlags::TRUNC) {
+            inode.clear().ok()?;
+        }
+        Some(Arc::new(OSInode::new(
+            readable,
+            writable,
+            inode
+        )))
     }
 }
 
+/// Open an executable for `exec`, which needs the x bit instead of the r bit.
+pub fn open_exec(name: &str) -> Option<Arc<OSInode>> {
+    let (uid, gid) = current_cred();
+    ROOT_INODE.find(name).ok()?
+        .filter(|inode| inode.permits(uid, gid, Access::EXEC))
+        .map(|inode| Arc::new(OSInode::new(true, false, inode)))
+}
+
+/// Remove a file, return false if it does not exist or it is not allowed.
+/// A file still open is freed once closed on easy-fs, and kept on FAT.
+pub fn unlink_file(name: &str) -> bool {
+    let (uid, gid) = current_cred();
+    let inode = match ROOT_INODE.find(name) {
+        Ok(Some(inode)) => inode,
+        _ => return false,
+    };
+    if !ROOT_INODE.permits(uid, gid, Access::WRITE) {
+        return false;
+    }
+    let (dir_mode, dir_owner) = match ROOT_INODE.attr() {
+        Ok((mode, owner, _)) => (mode, owner),
+        Err(_) => return false,
+    };
+    let owner = match inode.attr() {
+        Ok((_, owner, _)) => owner,
+        Err(_) => return false,
+    };
+    // FAT refuses to unlink a file with a handle on it, and easy-fs only
+    // frees one with its last handle, which should not be this one
+    drop(inode);
+    if dir_mode & MODE_STICKY != 0
+        && uid != 0
+        && uid != owner as u32
+        && uid != dir_owner as u32 {
+        return false;
+    }
+    ROOT_INODE.unlink(name).unwrap_or(false)
+}
+
 impl File for OSInode {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
-    fn read(&self, mut buf: UserBuffer) -> usize {
+    fn read(&self, mut buf: UserBuffer) -> Result<usize, IoError> {
         let mut inner = self.inner.lock();
         let mut total_read_size = 0usize;
         for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
+            // stop at a damaged block, what has been read is returned
+            let read_size = match inner.inode.read_at(inner.offset, *slice) {
+                Ok(read_size) => read_size,
+                Err(err) if total_read_size == 0 => return Err(err),
+                Err(_) => break,
+            };
             if read_size == 0 {
                 break;
             }
             inner.offset += read_size;
             total_read_size += read_size;
         }
-        total_read_size
+        Ok(total_read_size)
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> Result<usize, IoError> {
         let mut inner = self.inner.lock();
         let mut total_write_size = 0usize;
         for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
+            let write_size = match inner.inode.write_at(inner.offset, *slice) {
+                Ok(write_size) => write_size,
+                Err(err) if total_write_size == 0 => return Err(err),
+                Err(_) => break,
+            };
             inner.offset += write_size;
             total_write_size += write_size;
+            // the file system is full
+            if write_size < slice.len() {
+                break;
+            }
+        }
+        Ok(total_write_size)
+    }
+    fn fallocate(&self, mode: u32, offset: usize, len: usize) -> isize {
+        let mode = match FallocFlags::from_bits(mode) {
+            Some(mode) => mode,
+            None => return -1,
+        };
+        let inner = self.inner.lock();
+        if let Ok(true) = inner.inode.fallocate(mode, offset, len) {
+            0
+        } else {
+            -1
         }
-        total_write_size
     }
 }
\ No newline at end of file
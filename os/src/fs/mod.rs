@@ -1,16 +1,47 @@
 mod pipe;
 mod stdio;
+mod tty;
 mod inode;
+mod procfs;
+mod devfs;
 
 use crate::mm::UserBuffer;
+use alloc::sync::Arc;
+use easy_fs::IoError;
 
 pub trait File : Send + Sync {
     fn readable(&self) -> bool;
     fn writable(&self) -> bool;
-    fn read(&self, buf: UserBuffer) -> usize;
-    fn write(&self, buf: UserBuffer) -> usize;
+    /// Fail only if nothing could be transferred, otherwise the bytes done
+    /// before an error are counted and the error shows up on the next call.
+    fn read(&self, buf: UserBuffer) -> Result<usize, IoError>;
+    fn write(&self, buf: UserBuffer) -> Result<usize, IoError>;
+    /// Allocate or punch a range of a regular file, see `FallocFlags`.
+    fn fallocate(&self, _mode: u32, _offset: usize, _len: usize) -> isize {
+        -1
+    }
+    /// Whether the file is the terminal, which `sys_ioctl` works on.
+    fn is_tty(&self) -> bool {
+        false
+    }
 }
 
 pub use pipe::{Pipe, make_pipe};
 pub use stdio::{Stdin, Stdout};
-pub use inode::{OSInode, open_file, OpenFlags, list_apps};
\ No newline at end of file
+pub use tty::{poll_tty, tcgetpgrp, tcsetpgrp};
+pub use inode::{OSInode, open_file, open_exec, unlink_file, OpenFlags, list_apps};
+pub use procfs::{ProcFile, open_proc};
+pub use devfs::open_dev;
+
+/// Open a file by path, dispatching to the file system mounted on it.
+pub fn open_path(path: &str, flags: OpenFlags) -> Option<Arc<dyn File + Send + Sync>> {
+    if path == "/proc" || path.starts_with("/proc/") {
+        open_proc(&path["/proc".len()..], flags)
+            .map(|file| file as Arc<dyn File + Send + Sync>)
+    } else if path.starts_with("/dev/") {
+        open_dev(&path["/dev".len()..], flags)
+    } else {
+        open_file(path.trim_start_matches('/'), flags)
+            .map(|file| file as Arc<dyn File + Send + Sync>)
+    }
+}
@@ -1,10 +1,11 @@
 use super::File;
 use alloc::sync::{Arc, Weak};
 use spin::Mutex;
+use easy_fs::IoError;
 use crate::mm::{
     UserBuffer,
 };
-use crate::task::suspend_current_and_run_next;
+use crate::task::{WaitQueue, block_current_and_run_next, signal_pending};
 
 pub struct Pipe {
     readable: bool,
@@ -44,6 +45,10 @@ pub struct PipeRingBuffer {
     tail: usize,
     status: RingBufferStatus,
     write_end: Option<Weak<Pipe>>,
+    /// Readers blocked until there is something to read or the write end is
+    /// closed, and writers blocked until there is room.
+    readers: WaitQueue,
+    writers: WaitQueue,
 }
 
 impl PipeRingBuffer {
@@ -54,6 +59,8 @@ impl PipeRingBuffer {
             tail: 0,
             status: RingBufferStatus::EMPTY,
             write_end: None,
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
         }
     }
     pub fn set_write_end(&mut self, write_end: &Arc<Pipe>) {
@@ -115,7 +122,7 @@ pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
 impl File for Pipe {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
-    fn read(&self, buf: UserBuffer) -> usize {
+    fn read(&self, buf: UserBuffer) -> Result<usize, IoError> {
         assert_eq!(self.readable(), true);
         let mut buf_iter = buf.into_iter();
         let mut read_size = 0usize;
@@ -123,25 +130,27 @@ impl File for Pipe {
             let mut ring_buffer = self.buffer.lock();
             let loop_read = ring_buffer.available_read();
             if loop_read == 0 {
-                if ring_buffer.all_write_ends_closed() {
-                    return read_size;
+                if ring_buffer.all_write_ends_closed() || signal_pending() {
+                    return Ok(read_size);
                 }
+                ring_buffer.readers.add_current();
                 drop(ring_buffer);
-                suspend_current_and_run_next();
+                block_current_and_run_next();
                 continue;
             }
-            // read at most loop_read bytes
+            // read at most loop_read bytes, there is room for writers then
+            ring_buffer.writers.wake_all();
             for _ in 0..loop_read {
                 if let Some(byte_ref) = buf_iter.next() {
                     unsafe { *byte_ref = ring_buffer.read_byte(); }
                     read_size += 1;
                 } else {
-                    return read_size;
+                    return Ok(read_size);
                 }
             }
         }
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> Result<usize, IoError> {
         assert_eq!(self.writable(), true);
         let mut buf_iter = buf.into_iter();
         let mut write_size = 0usize;
@@ -149,19 +158,33 @@ impl File for Pipe {
             let mut ring_buffer = self.buffer.lock();
             let loop_write = ring_buffer.available_write();
             if loop_write == 0 {
+                if signal_pending() {
+                    return Ok(write_size);
+                }
+                ring_buffer.writers.add_current();
                 drop(ring_buffer);
-                suspend_current_and_run_next();
+                block_current_and_run_next();
                 continue;
             }
-            // write at most loop_write bytes
+            // write at most loop_write bytes, there is something for readers then
+            ring_buffer.readers.wake_all();
             for _ in 0..loop_write {
                 if let Some(byte_ref) = buf_iter.next() {
                     ring_buffer.write_byte(unsafe { *byte_ref });
                     write_size += 1;
                 } else {
-                    return write_size;
+                    return Ok(write_size);
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Pipe {
+    /// Readers waiting on a pipe whose write end is closed read 0 bytes.
+    fn drop(&mut self) {
+        if self.writable {
+            self.buffer.lock().readers.wake_all();
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use super::{File, OpenFlags};
+use super::inode::{permits, current_cred};
+use crate::mm::{UserBuffer, MapPermission, VirtAddr, frame_usage};
+use crate::task::{
+    ProcessControlBlock,
+    TaskStatus,
+    current_process,
+    pid2process,
+    all_processes,
+};
+use crate::timer::{get_time_ms, cycles_to_ms};
+use crate::config::PAGE_SIZE;
+use alloc::sync::Arc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::format;
+use spin::Mutex;
+use easy_fs::IoError;
+
+/// A read-only synthetic file whose content is generated when it is opened.
+///
+/// Directories are represented in the same way: reading them gives the
+/// names of their entries, one per line.
+pub struct ProcFile {
+    content: String,
+    offset: Mutex<usize>,
+}
+
+impl ProcFile {
+    fn new(content: String) -> Self {
+        Self {
+            content,
+            offset: Mutex::new(0),
+        }
+    }
+}
+
+impl File for ProcFile {
+    fn readable(&self) -> bool { true }
+    fn writable(&self) -> bool { false }
+    fn read(&self, buf: UserBuffer) -> Result<usize, IoError> {
+        let mut offset = self.offset.lock();
+        let bytes = self.content.as_bytes();
+        let mut read_size = 0usize;
+        for byte_ref in buf.into_iter() {
+            if *offset >= bytes.len() {
+                break;
+            }
+            unsafe { *byte_ref = bytes[*offset]; }
+            *offset += 1;
+            read_size += 1;
+        }
+        Ok(read_size)
+    }
+    fn write(&self, _buf: UserBuffer) -> Result<usize, IoError> {
+        panic!("Cannot write to procfs!");
+    }
+}
+
+/// `path` is relative to the mount point `/proc`.
+///
+/// Files are owned by root, except those of a process, which belong to its
+/// owner. Only the owner may look at the memory and files of a process.
+pub fn open_proc(path: &str, flags: OpenFlags) -> Option<Arc<ProcFile>> {
+    if flags != OpenFlags::RDONLY {
+        return None;
+    }
+    let mut components = path.split('/').filter(|s| !s.is_empty());
+    // (mode, uid, gid) like easy-fs inodes
+    let (content, attr) = match components.next() {
+        None => (root_dir(), (0o444, 0, 0)),
+        Some("meminfo") => (meminfo(), (0o444, 0, 0)),
+        Some("uptime") => (uptime(), (0o444, 0, 0)),
+        Some(pid) => {
+            let process = if pid == "self" {
+                current_process()
+            } else {
+                pid2process(pid.parse::<usize>().ok()?)?
+            };
+            let (owner, group) = {
+                let inner = process.acquire_inner_lock();
+                (inner.uid as u16, inner.gid as u16)
+            };
+            match components.next() {
+                None => (String::from("status\nmaps\nfd\n"), (0o444, owner, group)),
+                Some("status") => (status(&process), (0o444, owner, group)),
+                Some("maps") => (maps(&process), (0o400, owner, group)),
+                Some("fd") => (fd_dir(&process), (0o400, owner, group)),
+                _ => return None,
+            }
+        }
+    };
+    if components.next().is_some() {
+        return None;
+    }
+    let (uid, gid) = current_cred();
+    if !permits(attr, uid, gid, flags.access()) {
+        return None;
+    }
+    Some(Arc::new(ProcFile::new(content)))
+}
+
+fn root_dir() -> String {
+    let mut s = String::new();
+    for process in all_processes() {
+        s.push_str(&format!("{}\n", process.getpid()));
+    }
+    s.push_str("self\nmeminfo\nuptime\n");
+    s
+}
+
+fn meminfo() -> String {
+    let (total, free) = frame_usage();
+    format!(
+        "MemTotal: {} kB\nMemFree: {} kB\nMemUsed: {} kB\n",
+        total * PAGE_SIZE / 1024,
+        free * PAGE_SIZE / 1024,
+        (total - free) * PAGE_SIZE / 1024,
+    )
+}
+
+fn uptime() -> String {
+    let ms = get_time_ms();
+    format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10)
+}
+
+fn status(process: &Arc<ProcessControlBlock>) -> String {
+    let inner = process.acquire_inner_lock();
+    // the most active of the threads
+    let statuses: Vec<_> = inner.tasks
+        .iter()
+        .flatten()
+        .map(|task| task.acquire_inner_lock().task_status)
+        .collect();
+    let state = if inner.is_zombie {
+        "Z (zombie)"
+    } else if inner.stopped {
+        "T (stopped)"
+    } else if statuses.contains(&TaskStatus::Running) {
+        "R (running)"
+    } else if statuses.contains(&TaskStatus::Ready) {
+        "R (ready)"
+    } else {
+        "S (sleeping)"
+    };
+    let ppid = inner.parent
+        .as_ref()
+        .and_then(|parent| parent.upgrade())
+        .map(|parent| parent.getpid().to_string())
+        .unwrap_or_else(|| String::from("0"));
+    let (vm_pages, rss_pages) = inner.memory_set
+        .areas_info()
+        .iter()
+        .fold((0, 0), |(vm, rss), (start, end, _, frames)| {
+            (vm + end.0 - start.0, rss + frames)
+        });
+    let nice = inner.get_task(0).map_or(0, |task| task.acquire_inner_lock().sched.nice);
+    format!(
+        "Pid: {}\nPPid: {}\nPgid: {}\nSid: {}\nState: {}\nThreads: {}\nUid: {}\nGid: {}\nChildren: {}\nFDs: {}\nVmSize: {} kB\nVmRSS: {} kB\nNice: {}\nUTime: {} ms\nSTime: {} ms\nExitCode: {}\n",
+        process.getpid(),
+        ppid,
+        inner.pgid,
+        inner.sid,
+        state,
+        statuses.len(),
+        inner.uid,
+        inner.gid,
+        inner.children.len(),
+        inner.fd_table.iter().filter(|fd| fd.is_some()).count(),
+        vm_pages * PAGE_SIZE / 1024,
+        rss_pages * PAGE_SIZE / 1024,
+        nice,
+        cycles_to_ms(inner.times.utime),
+        cycles_to_ms(inner.times.stime),
+        inner.exit_code,
+    )
+}
+
+fn maps(process: &Arc<ProcessControlBlock>) -> String {
+    let inner = process.acquire_inner_lock();
+    let mut s = String::new();
+    for (start, end, perm, frames) in inner.memory_set.areas_info() {
+        let start_va: VirtAddr = start.into();
+        let end_va: VirtAddr = end.into();
+        s.push_str(&format!(
+            "{:016x}-{:016x} {}{}{}{} {} kB\n",
+            start_va.0,
+            end_va.0,
+            if perm.contains(MapPermission::R) { 'r' } else { '-' },
+            if perm.contains(MapPermission::W) { 'w' } else { '-' },
+            if perm.contains(MapPermission::X) { 'x' } else { '-' },
+            if perm.contains(MapPermission::U) { 'u' } else { '-' },
+            frames * PAGE_SIZE / 1024,
+        ));
+    }
+    s
+}
+
+fn fd_dir(process: &Arc<ProcessControlBlock>) -> String {
+    let inner = process.acquire_inner_lock();
+    let mut s = String::new();
+    for (fd, file) in inner.fd_table.iter().enumerate() {
+        if let Some(file) = file {
+            s.push_str(&format!(
+                "{} {}{}\n",
+                fd,
+                if file.readable() { 'r' } else { '-' },
+                if file.writable() { 'w' } else { '-' },
+            ));
+        }
+    }
+    s
+}
@@ -1,7 +1,7 @@
 use super::File;
+use easy_fs::IoError;
 use crate::mm::{UserBuffer};
-use crate::sbi::console_getchar;
-use crate::task::suspend_current_and_run_next;
+use super::tty::tty_getchar;
 
 pub struct Stdin;
 
@@ -10,24 +10,21 @@ pub struct Stdout;
 impl File for Stdin {
     fn readable(&self) -> bool { true }
     fn writable(&self) -> bool { false }
-    fn read(&self, mut user_buf: UserBuffer) -> usize {
-        assert_eq!(user_buf.len(), 1);
-        // busy loop
-        let mut c: usize;
-        loop {
-            c = console_getchar();
-            if c == 0 {
-                suspend_current_and_run_next();
-                continue;
-            } else {
-                break;
-            }
+    fn is_tty(&self) -> bool { true }
+    /// Read a single character whatever the size of the buffer is.
+    fn read(&self, mut user_buf: UserBuffer) -> Result<usize, IoError> {
+        if user_buf.len() == 0 {
+            return Ok(0);
         }
-        let ch = c as u8;
+        let ch = match tty_getchar() {
+            Some(ch) => ch,
+            // interrupted by a signal
+            None => return Ok(0),
+        };
         unsafe { user_buf.buffers[0].as_mut_ptr().write_volatile(ch); }
-        1
+        Ok(1)
     }
-    fn write(&self, _user_buf: UserBuffer) -> usize {
+    fn write(&self, _user_buf: UserBuffer) -> Result<usize, IoError> {
         panic!("Cannot write to stdin!");
     }
 }
@@ -35,13 +32,14 @@ impl File for Stdin {
 impl File for Stdout {
     fn readable(&self) -> bool { false }
     fn writable(&self) -> bool { true }
-    fn read(&self, _user_buf: UserBuffer) -> usize{
+    fn is_tty(&self) -> bool { true }
+    fn read(&self, _user_buf: UserBuffer) -> Result<usize, IoError> {
         panic!("Cannot read from stdout!");
     }
-    fn write(&self, user_buf: UserBuffer) -> usize {
+    fn write(&self, user_buf: UserBuffer) -> Result<usize, IoError> {
         for buffer in user_buf.buffers.iter() {
             print!("{}", core::str::from_utf8(*buffer).unwrap());
         }
-        user_buf.len()
+        Ok(user_buf.len())
     }
 }
\ No newline at end of file
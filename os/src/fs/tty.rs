@@ -0,0 +1,109 @@
+use crate::sbi::console_getchar;
+use crate::task::{
+    WaitQueue,
+    SignalFlags,
+    current_process,
+    block_current_and_run_next,
+    signal_pending,
+    send_group_signal,
+};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::*;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_Z: u8 = 0x1a;
+const CTRL_BACKSLASH: u8 = 0x1c;
+
+/// The console as a terminal, input is kept raw apart from the control
+/// characters that raise signals in the foreground process group.
+///
+/// The console raises no interrupt, so it is polled on every timer tick,
+/// see `poll_tty`, for control characters to work while nobody reads.
+struct Tty {
+    input: VecDeque<u8>,
+    readers: WaitQueue,
+    /// Process group that may read, and gets the signals, `None` until
+    /// `tcsetpgrp` is called.
+    foreground: Option<usize>,
+}
+
+lazy_static! {
+    static ref TTY: Mutex<Tty> = Mutex::new(Tty {
+        input: VecDeque::new(),
+        readers: WaitQueue::new(),
+        foreground: None,
+    });
+}
+
+fn getchar() -> Option<u8> {
+    match console_getchar() {
+        0 => None,
+        c => Some(c as u8),
+    }
+}
+
+/// Move what was typed to the input of the terminal, wake up its readers
+/// and send the signals of the control characters typed.
+pub fn poll_tty() {
+    let mut tty = TTY.lock();
+    let mut signals = Vec::new();
+    while let Some(c) = getchar() {
+        let signal = match c {
+            CTRL_C => SignalFlags::SIGINT,
+            CTRL_Z => SignalFlags::SIGTSTP,
+            CTRL_BACKSLASH => SignalFlags::SIGQUIT,
+            _ => {
+                tty.input.push_back(c);
+                continue;
+            }
+        };
+        // what was typed before is for the interrupted program
+        tty.input.clear();
+        signals.push(signal);
+    }
+    if !tty.input.is_empty() {
+        tty.readers.wake_all();
+    }
+    let foreground = tty.foreground;
+    drop(tty);
+    if let Some(pgid) = foreground {
+        for signal in signals {
+            send_group_signal(pgid, signal);
+        }
+    }
+}
+
+/// Block until a character is typed. Return `None` if a signal came first,
+/// or if the current process is not in the foreground, its process group is
+/// stopped by `SIGTTIN` then.
+pub fn tty_getchar() -> Option<u8> {
+    let pgid = current_process().acquire_inner_lock().pgid;
+    loop {
+        poll_tty();
+        let mut tty = TTY.lock();
+        if tty.foreground.map_or(false, |foreground| foreground != pgid) {
+            drop(tty);
+            send_group_signal(pgid, SignalFlags::SIGTTIN);
+            return None;
+        }
+        if let Some(c) = tty.input.pop_front() {
+            return Some(c);
+        }
+        if signal_pending() {
+            return None;
+        }
+        tty.readers.add_current();
+        drop(tty);
+        block_current_and_run_next();
+    }
+}
+
+pub fn tcgetpgrp() -> Option<usize> {
+    TTY.lock().foreground
+}
+
+pub fn tcsetpgrp(pgid: usize) {
+    TTY.lock().foreground = Some(pgid);
+}
@@ -39,6 +39,7 @@ trait FrameAllocator {
 }
 
 pub struct StackFrameAllocator {
+    start: usize,
     current: usize,
     end: usize,
     recycled: Vec<usize>,
@@ -46,14 +47,20 @@ pub struct StackFrameAllocator {
 
 impl StackFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.start = l.0;
         self.current = l.0;
         self.end = r.0;
         println!("last {} Physical Frames.", self.end - self.current);
     }
+    /// Return (total, free) number of physical frames.
+    pub fn usage(&self) -> (usize, usize) {
+        (self.end - self.start, self.end - self.current + self.recycled.len())
+    }
 }
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start: 0,
             current: 0,
             end: 0,
             recycled: Vec::new(),
@@ -114,6 +121,13 @@ pub fn frame_dealloc(ppn: PhysPageNum) {
         .dealloc(ppn);
 }
 
+/// Return (total, free) number of physical frames.
+pub fn frame_usage() -> (usize, usize) {
+    FRAME_ALLOCATOR
+        .lock()
+        .usage()
+}
+
 #[allow(unused)]
 pub fn frame_allocator_test() {
     let mut v: Vec<FrameTracker> = Vec::new();
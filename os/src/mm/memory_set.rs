@@ -12,8 +12,6 @@ use crate::config::{
     MEMORY_END,
     PAGE_SIZE,
     TRAMPOLINE,
-    TRAP_CONTEXT,
-    USER_STACK_SIZE,
     MMIO,
 };
 
@@ -142,8 +140,9 @@ impl MemorySet {
         }
         memory_set
     }
-    /// Include sections in elf and trampoline and TrapContext and user stack,
-    /// also returns user_sp and entry point.
+    /// Include sections in elf and trampoline, also returns the base of the
+    /// user stacks and entry point. The user stacks and TrapContexts are
+    /// mapped for each thread.
     pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
         let mut memory_set = Self::new_bare();
         // map trampoline
@@ -178,26 +177,10 @@ impl MemorySet {
                 );
             }
         }
-        // map user stack with U flags
+        // user stacks of the threads are mapped from here on, after a guard page
         let max_end_va: VirtAddr = max_end_vpn.into();
-        let mut user_stack_bottom: usize = max_end_va.into();
-        // guard page
-        user_stack_bottom += PAGE_SIZE;
-        let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
-        memory_set.push(MapArea::new(
-            user_stack_bottom.into(),
-            user_stack_top.into(),
-            MapType::Framed,
-            MapPermission::R | MapPermission::W | MapPermission::U,
-        ), None);
-        // map TrapContext
-        memory_set.push(MapArea::new(
-            TRAP_CONTEXT.into(),
-            TRAMPOLINE.into(),
-            MapType::Framed,
-            MapPermission::R | MapPermission::W,
-        ), None);
-        (memory_set, user_stack_top, elf.header.pt2.entry_point() as usize)
+        let ustack_base = usize::from(max_end_va) + PAGE_SIZE;
+        (memory_set, ustack_base, elf.header.pt2.entry_point() as usize)
     }
     pub fn from_existed_user(user_space: &MemorySet) -> MemorySet {
         let mut memory_set = Self::new_bare();
@@ -226,6 +209,18 @@ impl MemorySet {
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
+    /// Return (start_vpn, end_vpn, permission, mapped frames) of every area.
+    pub fn areas_info(&self) -> Vec<(VirtPageNum, VirtPageNum, MapPermission, usize)> {
+        self.areas
+            .iter()
+            .map(|area| (
+                area.vpn_range.get_start(),
+                area.vpn_range.get_end(),
+                area.map_perm,
+                area.data_frames.len(),
+            ))
+            .collect()
+    }
     pub fn recycle_data_pages(&mut self) {
         //*self = Self::new_bare();
         self.areas.clear();
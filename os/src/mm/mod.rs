@@ -4,17 +4,19 @@ mod frame_allocator;
 mod page_table;
 mod memory_set;
 
-use page_table::PTEFlags;
 use address::VPNRange;
 pub use address::{PhysAddr, VirtAddr, PhysPageNum, VirtPageNum, StepByOne};
-pub use frame_allocator::{FrameTracker, frame_alloc, frame_dealloc,};
+pub use frame_allocator::{FrameTracker, frame_alloc, frame_dealloc, frame_usage};
 pub use page_table::{
     PageTable,
     PageTableEntry,
+    PTEFlags,
     translated_byte_buffer,
     translated_str,
     translated_ref,
     translated_refmut,
+    copy_to_user,
+    copy_from_user,
     UserBuffer,
     UserBufferIterator,
 };
@@ -174,6 +174,63 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
     v
 }
 
+/// The pages of `len` bytes at `ptr` of a user space, `None` if some page
+/// is not mapped for the user, or not writable if `write` is set.
+fn user_pages(token: usize, ptr: usize, len: usize, write: bool) -> Option<Vec<&'static mut [u8]>> {
+    let page_table = PageTable::from_token(token);
+    let mut start = ptr;
+    let end = start.checked_add(len)?;
+    let mut v = Vec::new();
+    while start < end {
+        let start_va = VirtAddr::from(start);
+        let mut vpn = start_va.floor();
+        let pte = page_table.translate(vpn)?;
+        if !pte.is_valid() || !pte.flags().contains(PTEFlags::U) || (write && !pte.writable()) {
+            return None;
+        }
+        vpn.step();
+        let end_va: VirtAddr = VirtAddr::from(vpn).min(VirtAddr::from(end));
+        let page = pte.ppn().get_bytes_array();
+        if end_va.page_offset() == 0 {
+            v.push(&mut page[start_va.page_offset()..]);
+        } else {
+            v.push(&mut page[start_va.page_offset()..end_va.page_offset()]);
+        }
+        start = end_va.into();
+    }
+    Some(v)
+}
+
+/// Unlike `translated_byte_buffer`, fail rather than panic if the user
+/// passed a bad address.
+pub fn copy_to_user(token: usize, ptr: *mut u8, data: &[u8]) -> bool {
+    match user_pages(token, ptr as usize, data.len(), true) {
+        Some(pages) => {
+            let mut copied = 0;
+            for page in pages {
+                page.copy_from_slice(&data[copied..copied + page.len()]);
+                copied += page.len();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn copy_from_user(token: usize, ptr: *const u8, data: &mut [u8]) -> bool {
+    match user_pages(token, ptr as usize, data.len(), false) {
+        Some(pages) => {
+            let mut copied = 0;
+            for page in pages {
+                data[copied..copied + page.len()].copy_from_slice(page);
+                copied += page.len();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
 /// Load a string from other address spaces into kernel space without an end `\0`.
 pub fn translated_str(token: usize, ptr: *const u8) -> String {
     let page_table = PageTable::from_token(token);
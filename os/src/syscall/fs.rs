@@ -1,17 +1,31 @@
 use crate::mm::{
     UserBuffer,
+    copy_to_user,
+    copy_from_user,
     translated_byte_buffer,
     translated_refmut,
     translated_str,
 };
-use crate::task::{current_user_token, current_task};
-use crate::fs::{make_pipe, OpenFlags, open_file};
+use crate::task::{current_user_token, current_process, all_processes, signal_pending};
+use crate::fs::{make_pipe, OpenFlags, open_path, unlink_file, tcgetpgrp, tcsetpgrp};
 use alloc::sync::Arc;
+use easy_fs::IoError;
+
+/// Returned by `sys_read` and `sys_write` when the device or the file
+/// system on it fails.
+const EIO: isize = -5;
+/// Returned by `sys_write` when the file can not grow any more.
+const EFBIG: isize = -27;
+/// Returned by `sys_write` when the file system is full.
+const ENOSPC: isize = -28;
+/// Returned by `sys_read` and `sys_write` when a signal came before any
+/// byte could be transferred, pipes and stdin return early then.
+const EINTR: isize = -4;
 
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
-    let task = current_task().unwrap();
-    let inner = task.acquire_inner_lock();
+    let process = current_process();
+    let inner = process.acquire_inner_lock();
     if fd >= inner.fd_table.len() {
         return -1;
     }
@@ -22,9 +36,17 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
         let file = file.clone();
         // release Task lock manually to avoid deadlock
         drop(inner);
-        file.write(
+        // not held while blocked on a pipe, see `sys_waitpid`
+        drop(process);
+        match file.write(
             UserBuffer::new(translated_byte_buffer(token, buf, len))
-        ) as isize
+        ) {
+            Ok(0) if len != 0 && signal_pending() => EINTR,
+            Ok(write_size) => write_size as isize,
+            Err(IoError::TooLarge) => EFBIG,
+            Err(IoError::NoSpace) => ENOSPC,
+            Err(_) => EIO,
+        }
     } else {
         -1
     }
@@ -32,8 +54,8 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
 
 pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
-    let task = current_task().unwrap();
-    let inner = task.acquire_inner_lock();
+    let process = current_process();
+    let inner = process.acquire_inner_lock();
     if fd >= inner.fd_table.len() {
         return -1;
     }
@@ -44,23 +66,29 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
         }
         // release Task lock manually to avoid deadlock
         drop(inner);
-        file.read(
+        // not held while blocked on a pipe or the tty, see `sys_waitpid`
+        drop(process);
+        match file.read(
             UserBuffer::new(translated_byte_buffer(token, buf, len))
-        ) as isize
+        ) {
+            Ok(0) if len != 0 && signal_pending() => EINTR,
+            Ok(read_size) => read_size as isize,
+            Err(_) => EIO,
+        }
     } else {
         -1
     }
 }
 
 pub fn sys_open(path: *const u8, flags: u32) -> isize {
-    let task = current_task().unwrap();
+    let process = current_process();
     let token = current_user_token();
     let path = translated_str(token, path);
-    if let Some(inode) = open_file(
+    if let Some(inode) = open_path(
         path.as_str(),
         OpenFlags::from_bits(flags).unwrap()
     ) {
-        let mut inner = task.acquire_inner_lock();
+        let mut inner = process.acquire_inner_lock();
         let fd = inner.alloc_fd();
         inner.fd_table[fd] = Some(inode);
         fd as isize
@@ -69,9 +97,38 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
     }
 }
 
+/// `dirfd` and `flags` are ignored since there is only the root directory.
+pub fn sys_unlinkat(_dirfd: isize, path: *const u8, _flags: u32) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    if unlink_file(path.trim_start_matches('/')) {
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_fallocate(fd: usize, mode: u32, offset: usize, len: usize) -> isize {
+    let process = current_process();
+    let inner = process.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        if !file.writable() {
+            return -1;
+        }
+        let file = file.clone();
+        drop(inner);
+        file.fallocate(mode, offset, len)
+    } else {
+        -1
+    }
+}
+
 pub fn sys_close(fd: usize) -> isize {
-    let task = current_task().unwrap();
-    let mut inner = task.acquire_inner_lock();
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
     if fd >= inner.fd_table.len() {
         return -1;
     }
@@ -83,9 +140,9 @@ pub fn sys_close(fd: usize) -> isize {
 }
 
 pub fn sys_pipe(pipe: *mut usize) -> isize {
-    let task = current_task().unwrap();
+    let process = current_process();
     let token = current_user_token();
-    let mut inner = task.acquire_inner_lock();
+    let mut inner = process.acquire_inner_lock();
     let (pipe_read, pipe_write) = make_pipe();
     let read_fd = inner.alloc_fd();
     inner.fd_table[read_fd] = Some(pipe_read);
@@ -97,8 +154,8 @@ pub fn sys_pipe(pipe: *mut usize) -> isize {
 }
 
 pub fn sys_dup(fd: usize) -> isize {
-    let task = current_task().unwrap();
-    let mut inner = task.acquire_inner_lock();
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
     if fd >= inner.fd_table.len() {
         return -1;
     }
@@ -108,4 +165,47 @@ pub fn sys_dup(fd: usize) -> isize {
     let new_fd = inner.alloc_fd();
     inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
     new_fd as isize
-}
\ No newline at end of file
+}
+/// Get and set the foreground process group of the terminal, a `pid_t`
+/// at `arg`.
+const TIOCGPGRP: usize = 0x540f;
+const TIOCSPGRP: usize = 0x5410;
+
+/// Only the requests on the terminal above are known. The foreground may
+/// be set to a process group of the session of the caller.
+pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
+    let process = current_process();
+    let inner = process.acquire_inner_lock();
+    match inner.fd_table.get(fd) {
+        Some(Some(file)) if file.is_tty() => {}
+        _ => return -1,
+    }
+    let token = inner.get_user_token();
+    let sid = inner.sid;
+    drop(inner);
+    match request {
+        TIOCGPGRP => {
+            let pgid = match tcgetpgrp() {
+                Some(pgid) => pgid as i32,
+                None => return -1,
+            };
+            if copy_to_user(token, arg as *mut u8, &pgid.to_le_bytes()) { 0 } else { -1 }
+        }
+        TIOCSPGRP => {
+            let mut bytes = [0u8; 4];
+            if !copy_from_user(token, arg as *const u8, &mut bytes) {
+                return -1;
+            }
+            let pgid = i32::from_le_bytes(bytes) as usize;
+            if !all_processes().iter().any(|process| {
+                let inner = process.acquire_inner_lock();
+                inner.pgid == pgid && inner.sid == sid
+            }) {
+                return -1;
+            }
+            tcsetpgrp(pgid);
+            0
+        }
+        _ => -1,
+    }
+}
@@ -1,21 +1,42 @@
 use crate::task::{
     suspend_current_and_run_next,
+    block_current_and_run_next,
     exit_current_and_run_next,
-    current_task,
+    current_process,
     current_user_token,
+    ProcessControlBlock,
     add_task,
+    pid2process,
+    all_processes,
+    remove_from_pid2process,
+    SignalFlags,
+    SignalAction,
+    SIG_IGN,
+    NICE_MIN,
+    NICE_MAX,
+    send_signal,
+    sigreturn,
+};
+use crate::timer::{
+    TimeSpec,
+    TimeVal,
+    get_time,
+    cycles_to_ticks,
+    get_time_ms,
+    get_real_time,
+    duration_to_cycles,
+    sleep_until,
 };
-use crate::timer::get_time_ms;
 use crate::mm::{
     translated_str,
     translated_refmut,
     translated_ref,
+    copy_to_user,
+    copy_from_user,
 };
-use crate::fs::{
-    open_file,
-    OpenFlags,
-};
+use crate::fs::open_exec;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 
@@ -33,14 +54,86 @@ pub fn sys_get_time() -> isize {
     get_time_ms() as isize
 }
 
+/// A blocking call was interrupted by a signal.
+const EINTR: isize = -4;
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const TIMER_ABSTIME: usize = 1;
+
+/// The remaining time of a sleep interrupted by a signal is not written.
+pub fn sys_nanosleep(req: *const TimeSpec) -> isize {
+    sys_clock_nanosleep(CLOCK_MONOTONIC, 0, req)
+}
+
+/// `CLOCK_MONOTONIC` counts from boot, `CLOCK_REALTIME` from the epoch of
+/// the RTC, with a resolution of a second.
+pub fn sys_clock_nanosleep(clock_id: usize, flags: usize, req: *const TimeSpec) -> isize {
+    let req = *translated_ref(current_user_token(), req);
+    if req.nsec >= 1_000_000_000 || flags & !TIMER_ABSTIME != 0 {
+        return -1;
+    }
+    let now = get_time();
+    let deadline = match (clock_id, flags & TIMER_ABSTIME != 0) {
+        (CLOCK_MONOTONIC, false) | (CLOCK_REALTIME, false) => {
+            now + duration_to_cycles(req.sec, req.nsec)
+        }
+        (CLOCK_MONOTONIC, true) => duration_to_cycles(req.sec, req.nsec),
+        (CLOCK_REALTIME, true) => {
+            let real_now = get_real_time() as usize;
+            if req.sec < real_now {
+                return 0;
+            }
+            now + duration_to_cycles(req.sec - real_now, req.nsec)
+        }
+        _ => return -1,
+    };
+    if sleep_until(deadline) { 0 } else { EINTR }
+}
+
 pub fn sys_getpid() -> isize {
-    current_task().unwrap().pid.0 as isize
+    current_process().pid.0 as isize
+}
+
+pub fn sys_getuid() -> isize {
+    current_process().acquire_inner_lock().uid as isize
+}
+
+pub fn sys_getgid() -> isize {
+    current_process().acquire_inner_lock().gid as isize
+}
+
+/// Only root may change its uid, as ids are stored in 16 bits on disk they
+/// must be below 65536.
+pub fn sys_setuid(uid: usize) -> isize {
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
+    if uid > u16::MAX as usize || (inner.uid != 0 && inner.uid as usize != uid) {
+        return -1;
+    }
+    inner.uid = uid as u32;
+    0
 }
 
+pub fn sys_setgid(gid: usize) -> isize {
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
+    if gid > u16::MAX as usize || (inner.uid != 0 && inner.gid as usize != gid) {
+        return -1;
+    }
+    inner.gid = gid as u32;
+    0
+}
+
+/// Only a process with a single thread may fork.
 pub fn sys_fork() -> isize {
-    let current_task = current_task().unwrap();
-    let new_task = current_task.fork();
-    let new_pid = new_task.pid.0;
+    let current_process = current_process();
+    if current_process.acquire_inner_lock().thread_count() > 1 {
+        return -1;
+    }
+    let new_process = current_process.fork();
+    let new_pid = new_process.getpid();
+    let new_task = new_process.acquire_inner_lock().get_task(0).unwrap();
     // modify trap context of new_task, because it returns immediately after switching
     let trap_cx = new_task.acquire_inner_lock().get_trap_cx();
     // we do not have to move to next instruction since we have done it before
@@ -51,7 +144,12 @@ pub fn sys_fork() -> isize {
     new_pid as isize
 }
 
+/// Only a process with a single thread may exec.
 pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
+    let process = current_process();
+    if process.acquire_inner_lock().thread_count() > 1 {
+        return -1;
+    }
     let token = current_user_token();
     let path = translated_str(token, path);
     let mut args_vec: Vec<String> = Vec::new();
@@ -63,11 +161,10 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
         args_vec.push(translated_str(token, arg_str_ptr as *const u8));
         unsafe { args = args.add(1); }
     }
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
+    if let Some(app_inode) = open_exec(path.as_str()) {
         let all_data = app_inode.read_all();
-        let task = current_task().unwrap();
         let argc = args_vec.len();
-        task.exec(all_data.as_slice(), args_vec);
+        process.exec(all_data.as_slice(), args_vec);
         // return argc because cx.x[10] will be covered with it later
         argc as isize
     } else {
@@ -75,41 +172,376 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
     }
 }
 
+/// Return at once instead of blocking if no child has exited yet.
+const WNOHANG: usize = 1;
+/// Also return for a child stopped by a signal, with an exit code of
+/// `0x7f | signum << 8` as on Linux.
+const WUNTRACED: usize = 2;
+
 /// If there is not a child process whose pid is same as given, return -1.
-/// Else if there is a child process but it is still running, return -2.
-pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
-    let task = current_task().unwrap();
-    // find a child process
-
-    // ---- hold current PCB lock
-    let mut inner = task.acquire_inner_lock();
-    if inner.children
-        .iter()
-        .find(|p| {pid == -1 || pid as usize == p.getpid()})
-        .is_none() {
-        return -1;
+/// Else block until such a child exits, or return -2 at once if it is still
+/// running and `options` has `WNOHANG`, or `EINTR` if a signal comes first.
+pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32, options: usize) -> isize {
+    loop {
+        let process = current_process();
+        // ---- hold current PCB lock
+        let mut inner = process.acquire_inner_lock();
+        if inner.children
+            .iter()
+            .find(|p| {pid == -1 || pid as usize == p.getpid()})
+            .is_none() {
+            return -1;
+            // ---- release current PCB lock
+        }
+        let pair = inner.children
+            .iter()
+            .enumerate()
+            .find(|(_, p)| {
+                // ++++ temporarily hold child PCB lock
+                p.acquire_inner_lock().is_zombie && (pid == -1 || pid as usize == p.getpid())
+                // ++++ release child PCB lock
+            });
+        if let Some((idx, _)) = pair {
+            let child = inner.children.remove(idx);
+            remove_from_pid2process(child.getpid());
+            // confirm that child will be deallocated after being removed from children list
+            assert_eq!(Arc::strong_count(&child), 1);
+            let found_pid = child.getpid();
+            // ++++ temporarily hold child lock
+            let child_inner = child.acquire_inner_lock();
+            let exit_code = child_inner.exit_code;
+            inner.times.reap(&child_inner.times);
+            drop(child_inner);
+            // ++++ release child PCB lock
+            *translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
+            return found_pid as isize;
+        }
+        if options & WUNTRACED != 0 {
+            let stopped = inner.children
+                .iter()
+                .filter(|p| pid == -1 || pid as usize == p.getpid())
+                .find_map(|p| {
+                    let signal = p.acquire_inner_lock().stop_signal.take()?;
+                    Some((p.getpid(), signal))
+                });
+            if let Some((found_pid, signal)) = stopped {
+                *translated_refmut(inner.memory_set.token(), exit_code_ptr) =
+                    0x7f | (signal.signum() as i32) << 8;
+                return found_pid as isize;
+            }
+        }
+        if options & WNOHANG != 0 {
+            return -2;
+        }
+        if inner.signal_pending() {
+            return EINTR;
+        }
+        // woken up by an exiting child, which may not be the one waited for
+        inner.wait_queue.add_current();
+        drop(inner);
         // ---- release current PCB lock
+        // not held while blocked, the process may exit meanwhile and its
+        // blocked threads are never woken up to drop it
+        drop(process);
+        block_current_and_run_next();
     }
-    let pair = inner.children
-        .iter()
-        .enumerate()
-        .find(|(_, p)| {
-            // ++++ temporarily hold child PCB lock
-            p.acquire_inner_lock().is_zombie() && (pid == -1 || pid as usize == p.getpid())
-            // ++++ release child PCB lock
-        });
-    if let Some((idx, _)) = pair {
-        let child = inner.children.remove(idx);
-        // confirm that child will be deallocated after being removed from children list
-        assert_eq!(Arc::strong_count(&child), 1);
-        let found_pid = child.getpid();
-        // ++++ temporarily hold child lock
-        let exit_code = child.acquire_inner_lock().exit_code;
-        // ++++ release child PCB lock
-        *translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
-        found_pid as isize
+}
+
+/// A positive `pid` is a process, 0 the process group of the caller and
+/// `-pgid` another process group. A `signum` of 0 only checks that there
+/// is a target and that it may be signalled, which needs the same uid
+/// unless the sender is root. initproc, with pid 0, can not be signalled.
+pub fn sys_kill(pid: isize, signum: usize) -> isize {
+    let signal = match signum {
+        0 => None,
+        _ => match SignalFlags::from_signum(signum) {
+            Some(signal) => Some(signal),
+            None => return -1,
+        },
+    };
+    let (uid, pgid) = {
+        let process = current_process();
+        let inner = process.acquire_inner_lock();
+        (inner.uid, inner.pgid)
+    };
+    let targets: Vec<_> = match pid {
+        pid if pid > 0 => pid2process(pid as usize).into_iter().collect(),
+        -1 => return -1,
+        pid => {
+            let pgid = if pid == 0 { pgid } else { (-pid) as usize };
+            all_processes()
+                .into_iter()
+                .filter(|process| process.getpid() != 0 && process.acquire_inner_lock().pgid == pgid)
+                .collect()
+        }
+    };
+    if targets.is_empty()
+        || (uid != 0 && targets.iter().any(|process| process.acquire_inner_lock().uid != uid)) {
+        return -1;
+    }
+    if let Some(signal) = signal {
+        for target in targets.iter() {
+            send_signal(target, signal);
+        }
+    }
+    0
+}
+
+/// `pid` and `pgid` of 0 stand for the caller. A process may move itself
+/// or a child to a new group of its pid, or to a group of its session.
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+    let process = current_process();
+    let target = if pid == 0 || pid == process.getpid() {
+        process.clone()
     } else {
-        -2
+        let inner = process.acquire_inner_lock();
+        match inner.children.iter().find(|child| child.getpid() == pid) {
+            Some(child) => child.clone(),
+            None => return -1,
+        }
+    };
+    let pgid = if pgid == 0 { target.getpid() } else { pgid };
+    let sid = process.acquire_inner_lock().sid;
+    let target_sid = target.acquire_inner_lock().sid;
+    // a session leader stays where it is
+    if target_sid != sid || target.getpid() == sid {
+        return -1;
     }
-    // ---- release current PCB lock automatically
-}
\ No newline at end of file
+    if pgid != target.getpid() && !all_processes().iter().any(|process| {
+        let inner = process.acquire_inner_lock();
+        inner.pgid == pgid && inner.sid == sid
+    }) {
+        return -1;
+    }
+    target.acquire_inner_lock().pgid = pgid;
+    0
+}
+
+pub fn sys_getpgid(pid: usize) -> isize {
+    let process = if pid == 0 { Some(current_process()) } else { pid2process(pid) };
+    match process {
+        Some(process) => process.acquire_inner_lock().pgid as isize,
+        None => -1,
+    }
+}
+
+/// Make the caller the leader of a new session and process group, which
+/// a process group leader can not do.
+pub fn sys_setsid() -> isize {
+    let process = current_process();
+    let pid = process.getpid();
+    let mut inner = process.acquire_inner_lock();
+    if inner.pgid == pid {
+        return -1;
+    }
+    inner.pgid = pid;
+    inner.sid = pid;
+    pid as isize
+}
+
+pub fn sys_getsid(pid: usize) -> isize {
+    let process = if pid == 0 { Some(current_process()) } else { pid2process(pid) };
+    match process {
+        Some(process) => process.acquire_inner_lock().sid as isize,
+        None => -1,
+    }
+}
+
+/// Either pointer may be null. `SIGKILL` and `SIGSTOP` can not be caught
+/// or ignored.
+pub fn sys_sigaction(
+    signum: usize,
+    action: *const SignalAction,
+    old_action: *mut SignalAction,
+) -> isize {
+    let signal = match SignalFlags::from_signum(signum) {
+        Some(signal) if !signal.intersects(SignalFlags::SIGKILL | SignalFlags::SIGSTOP) => signal,
+        _ => return -1,
+    };
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
+    let token = inner.get_user_token();
+    let old = inner.signal_actions[signum];
+    if !old_action.is_null() && !copy_to_user(token, old_action as *mut u8, as_bytes(&old)) {
+        return -1;
+    }
+    if !action.is_null() {
+        let mut new = SignalAction::default();
+        if !copy_from_user(token, action as *const u8, as_bytes_mut(&mut new)) {
+            return -1;
+        }
+        new.mask = SignalFlags::from_bits_truncate(new.mask.bits());
+        if new.handler == SIG_IGN {
+            inner.signals.remove(signal);
+        }
+        inner.signal_actions[signum] = new;
+    }
+    0
+}
+
+const SIG_BLOCK: usize = 0;
+const SIG_UNBLOCK: usize = 1;
+const SIG_SETMASK: usize = 2;
+
+/// Either pointer may be null, `SIGKILL` and `SIGSTOP` are never blocked.
+pub fn sys_sigprocmask(how: usize, set: *const u32, old_set: *mut u32) -> isize {
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
+    let token = inner.get_user_token();
+    let old = inner.signal_mask.bits();
+    if !old_set.is_null() && !copy_to_user(token, old_set as *mut u8, &old.to_le_bytes()) {
+        return -1;
+    }
+    if !set.is_null() {
+        let mut bytes = [0u8; 4];
+        if !copy_from_user(token, set as *const u8, &mut bytes) {
+            return -1;
+        }
+        let set = SignalFlags::from_bits_truncate(u32::from_le_bytes(bytes))
+            - SignalFlags::SIGKILL - SignalFlags::SIGSTOP;
+        match how {
+            SIG_BLOCK => inner.signal_mask |= set,
+            SIG_UNBLOCK => inner.signal_mask -= set,
+            SIG_SETMASK => inner.signal_mask = set,
+            _ => return -1,
+        }
+    }
+    0
+}
+
+pub fn sys_sigreturn() -> isize {
+    sigreturn()
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>()) }
+}
+
+fn as_bytes_mut<T>(value: &mut T) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(value as *mut T as *mut u8, core::mem::size_of::<T>()) }
+}
+
+const PRIO_PROCESS: usize = 0;
+const PRIO_PGRP: usize = 1;
+
+/// The processes `which` and `who` stand for, `who` of 0 is the caller or
+/// its process group.
+fn priority_targets(which: usize, who: usize) -> Option<Vec<Arc<ProcessControlBlock>>> {
+    let process = current_process();
+    let targets: Vec<_> = match which {
+        PRIO_PROCESS if who == 0 => vec![process],
+        PRIO_PROCESS => pid2process(who).into_iter().collect(),
+        PRIO_PGRP => {
+            let pgid = if who == 0 { process.acquire_inner_lock().pgid } else { who };
+            all_processes()
+                .into_iter()
+                .filter(|process| process.acquire_inner_lock().pgid == pgid)
+                .collect()
+        }
+        _ => return None,
+    };
+    if targets.is_empty() { None } else { Some(targets) }
+}
+
+/// Set the nice value of the threads of processes, clamped to
+/// `NICE_MIN..=NICE_MAX`. Only root may lower it, or change that of
+/// processes of another uid.
+pub fn sys_setpriority(which: usize, who: usize, nice: isize) -> isize {
+    let targets = match priority_targets(which, who) {
+        Some(targets) => targets,
+        None => return -1,
+    };
+    let uid = current_process().acquire_inner_lock().uid;
+    let nice = nice.max(NICE_MIN).min(NICE_MAX);
+    if uid != 0 && targets.iter().any(|process| {
+        let inner = process.acquire_inner_lock();
+        inner.uid != uid
+            || inner.tasks.iter().flatten().any(|task| nice < task.acquire_inner_lock().sched.nice)
+    }) {
+        return -1;
+    }
+    for process in targets {
+        for task in process.acquire_inner_lock().tasks.iter().flatten() {
+            task.acquire_inner_lock().sched.nice = nice;
+        }
+    }
+    0
+}
+
+/// Return `20 - nice` of the most favoured of the threads of the
+/// processes, which is never negative, as on Linux.
+pub fn sys_getpriority(which: usize, who: usize) -> isize {
+    match priority_targets(which, who) {
+        Some(targets) => targets
+            .iter()
+            .flat_map(|process| {
+                let inner = process.acquire_inner_lock();
+                inner.tasks
+                    .iter()
+                    .flatten()
+                    .map(|task| 20 - task.acquire_inner_lock().sched.nice)
+                    .collect::<Vec<_>>()
+            })
+            .max()
+            .unwrap(),
+        None => -1,
+    }
+}
+
+/// Process times in clock ticks, as `struct tms`.
+#[repr(C)]
+pub struct Tms {
+    pub utime: usize,
+    pub stime: usize,
+    pub cutime: usize,
+    pub cstime: usize,
+}
+
+/// Write the CPU times of the caller and its reaped children to `tms`,
+/// and return the clock ticks since boot.
+pub fn sys_times(tms: *mut Tms) -> isize {
+    let process = current_process();
+    let inner = process.acquire_inner_lock();
+    let times = Tms {
+        utime: cycles_to_ticks(inner.times.utime),
+        stime: cycles_to_ticks(inner.times.stime),
+        cutime: cycles_to_ticks(inner.times.cutime),
+        cstime: cycles_to_ticks(inner.times.cstime),
+    };
+    if !tms.is_null() && !copy_to_user(inner.get_user_token(), tms as *mut u8, as_bytes(&times)) {
+        return -1;
+    }
+    cycles_to_ticks(get_time()) as isize
+}
+
+const RUSAGE_SELF: isize = 0;
+const RUSAGE_CHILDREN: isize = -1;
+
+/// Resource usage, as `struct rusage` of Linux. Only the times are kept
+/// track of, the other fields are zero.
+#[repr(C)]
+#[derive(Default)]
+pub struct RUsage {
+    pub utime: TimeVal,
+    pub stime: TimeVal,
+    pub others: [usize; 14],
+}
+
+pub fn sys_getrusage(who: isize, usage: *mut RUsage) -> isize {
+    let process = current_process();
+    let inner = process.acquire_inner_lock();
+    let (utime, stime) = match who {
+        RUSAGE_SELF => (inner.times.utime, inner.times.stime),
+        RUSAGE_CHILDREN => (inner.times.cutime, inner.times.cstime),
+        _ => return -1,
+    };
+    let rusage = RUsage {
+        utime: TimeVal::from_cycles(utime),
+        stime: TimeVal::from_cycles(stime),
+        ..RUsage::default()
+    };
+    if !copy_to_user(inner.get_user_token(), usage as *mut u8, as_bytes(&rusage)) {
+        return -1;
+    }
+    0
+}
@@ -0,0 +1,142 @@
+use crate::task::{
+    current_process,
+    current_user_token,
+    signal_pending,
+    futex_wait,
+    futex_wake,
+    UserMutex,
+    Semaphore,
+    Condvar,
+};
+use crate::mm::{PageTable, PTEFlags, VirtAddr};
+use alloc::sync::Arc;
+
+/// A blocking call was interrupted by a signal.
+const EINTR: isize = -4;
+/// The futex word did not hold the expected value.
+const EAGAIN: isize = -11;
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+
+pub fn sys_mutex_create() -> isize {
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
+    inner.mutex_list.push(Arc::new(UserMutex::new()));
+    inner.mutex_list.len() as isize - 1
+}
+
+/// Return -1 if there is no such mutex, or `EINTR` if a signal comes
+/// before the lock.
+pub fn sys_mutex_lock(mutex_id: usize) -> isize {
+    let mutex = match current_process().acquire_inner_lock().mutex_list.get(mutex_id) {
+        Some(mutex) => mutex.clone(),
+        None => return -1,
+    };
+    if mutex.lock() { 0 } else { EINTR }
+}
+
+/// Return -1 if there is no such mutex or it is not locked.
+pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
+    let mutex = match current_process().acquire_inner_lock().mutex_list.get(mutex_id) {
+        Some(mutex) => mutex.clone(),
+        None => return -1,
+    };
+    if mutex.unlock() { 0 } else { -1 }
+}
+
+pub fn sys_semaphore_create(count: usize) -> isize {
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
+    inner.semaphore_list.push(Arc::new(Semaphore::new(count)));
+    inner.semaphore_list.len() as isize - 1
+}
+
+pub fn sys_semaphore_up(sem_id: usize) -> isize {
+    let sem = match current_process().acquire_inner_lock().semaphore_list.get(sem_id) {
+        Some(sem) => sem.clone(),
+        None => return -1,
+    };
+    sem.up();
+    0
+}
+
+/// Return -1 if there is no such semaphore, or `EINTR` if a signal comes
+/// while its count is 0.
+pub fn sys_semaphore_down(sem_id: usize) -> isize {
+    let sem = match current_process().acquire_inner_lock().semaphore_list.get(sem_id) {
+        Some(sem) => sem.clone(),
+        None => return -1,
+    };
+    if sem.down() { 0 } else { EINTR }
+}
+
+pub fn sys_condvar_create() -> isize {
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
+    inner.condvar_list.push(Arc::new(Condvar::new()));
+    inner.condvar_list.len() as isize - 1
+}
+
+pub fn sys_condvar_signal(condvar_id: usize) -> isize {
+    let condvar = match current_process().acquire_inner_lock().condvar_list.get(condvar_id) {
+        Some(condvar) => condvar.clone(),
+        None => return -1,
+    };
+    condvar.signal();
+    0
+}
+
+pub fn sys_condvar_broadcast(condvar_id: usize) -> isize {
+    let condvar = match current_process().acquire_inner_lock().condvar_list.get(condvar_id) {
+        Some(condvar) => condvar.clone(),
+        None => return -1,
+    };
+    condvar.broadcast();
+    0
+}
+
+/// Release the mutex, which the caller holds, until the condition variable
+/// is signaled, then take it again. Return -1 if either does not exist, or
+/// `EINTR` if a signal comes first, without the mutex held.
+pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
+    let process = current_process();
+    let inner = process.acquire_inner_lock();
+    let (condvar, mutex) = match (inner.condvar_list.get(condvar_id), inner.mutex_list.get(mutex_id)) {
+        (Some(condvar), Some(mutex)) => (condvar.clone(), mutex.clone()),
+        _ => return -1,
+    };
+    drop(inner);
+    // not held while blocked, see `sys_waitpid`
+    drop(process);
+    if condvar.wait(&mutex) { 0 } else { EINTR }
+}
+
+/// `FUTEX_WAIT` blocks while the aligned 32-bit word at `uaddr` holds `val`,
+/// returning `EAGAIN` if it does not, and `EINTR` if a signal is pending.
+/// It may return 0 without a wake, the caller checks the word again.
+/// `FUTEX_WAKE` wakes up at most `val` threads waiting on the word and
+/// returns how many. Return -1 on a bad address or operation.
+pub fn sys_futex(uaddr: usize, op: usize, val: usize) -> isize {
+    if uaddr % core::mem::size_of::<u32>() != 0 {
+        return -1;
+    }
+    let page_table = PageTable::from_token(current_user_token());
+    let va = VirtAddr::from(uaddr);
+    // the word has to be one the user could read itself
+    match page_table.translate(va.floor()) {
+        Some(pte) if pte.is_valid() && pte.flags().contains(PTEFlags::U) && pte.readable() => {}
+        _ => return -1,
+    }
+    let pa = page_table.translate_va(va).unwrap();
+    match op {
+        FUTEX_WAIT => {
+            if signal_pending() {
+                return EINTR;
+            }
+            if futex_wait(pa, val as u32) { 0 } else { EAGAIN }
+        }
+        FUTEX_WAKE => futex_wake(pa, val) as isize,
+        _ => -1,
+    }
+}
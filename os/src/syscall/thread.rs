@@ -0,0 +1,81 @@
+use crate::task::{
+    TaskControlBlock,
+    current_task,
+    current_process,
+    add_task,
+    block_current_and_run_next,
+};
+use crate::trap::{TrapContext, trap_handler};
+use crate::mm::kernel_token;
+use alloc::sync::Arc;
+
+/// A blocking call was interrupted by a signal.
+const EINTR: isize = -4;
+
+/// Start a thread of the process of the caller at `entry`, with `arg` in
+/// `a0` and a user stack of its own, and return its tid. The thread has
+/// nothing to return to, it has to call `sys_exit`.
+pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
+    let task = current_task().unwrap();
+    let process = task.process();
+    let sched = task.acquire_inner_lock().sched;
+    let ustack_base = process.acquire_inner_lock().ustack_base;
+    let new_task = Arc::new(TaskControlBlock::new(&process, ustack_base, true));
+    let mut new_task_inner = new_task.acquire_inner_lock();
+    let tid = new_task_inner.res.tid;
+    new_task_inner.sched = sched;
+    let trap_cx = new_task_inner.get_trap_cx();
+    *trap_cx = TrapContext::app_init_context(
+        entry,
+        new_task_inner.res.ustack_top(),
+        kernel_token(),
+        new_task.kernel_stack.get_top(),
+        trap_handler as usize,
+    );
+    trap_cx.x[10] = arg;
+    drop(new_task_inner);
+    let mut process_inner = process.acquire_inner_lock();
+    while process_inner.tasks.len() <= tid {
+        process_inner.tasks.push(None);
+    }
+    process_inner.tasks[tid] = Some(new_task.clone());
+    drop(process_inner);
+    add_task(new_task);
+    tid as isize
+}
+
+pub fn sys_gettid() -> isize {
+    current_task().unwrap().gettid() as isize
+}
+
+/// Block until the thread `tid` of the process of the caller exits, then
+/// release it and return its exit code. Return -1 if there is no such
+/// thread or it is the caller, or `EINTR` if a signal comes first.
+pub fn sys_waittid(tid: usize) -> isize {
+    if tid == current_task().unwrap().gettid() {
+        return -1;
+    }
+    loop {
+        let process = current_process();
+        // ---- hold current PCB lock
+        let mut inner = process.acquire_inner_lock();
+        let exit_code = match inner.get_task(tid) {
+            Some(waited) => waited.acquire_inner_lock().exit_code,
+            None => return -1,
+        };
+        if let Some(exit_code) = exit_code {
+            inner.remove_task(tid);
+            return exit_code as isize;
+        }
+        if inner.signal_pending() {
+            return EINTR;
+        }
+        // woken up by an exiting thread, which may not be the one waited for
+        inner.wait_queue.add_current();
+        drop(inner);
+        // ---- release current PCB lock
+        // not held while blocked, see `sys_waitpid`
+        drop(process);
+        block_current_and_run_next();
+    }
+}
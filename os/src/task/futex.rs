@@ -0,0 +1,54 @@
+use crate::mm::PhysAddr;
+use super::{WaitQueue, block_current_and_run_next};
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use lazy_static::*;
+
+lazy_static! {
+    /// Threads waiting on a futex word, by its physical address so that
+    /// the same word is found from any address space it is mapped in.
+    static ref FUTEX_QUEUES: Mutex<BTreeMap<usize, WaitQueue>> = Mutex::new(BTreeMap::new());
+}
+
+/// Block on the word at `pa` if it still holds `expected`, checked under
+/// the lock a waker takes, so that a wake between the check in user space
+/// and here is not missed. Return false if the word had changed. The
+/// caller is to check its condition again on return, whatever woke it up.
+pub fn futex_wait(pa: PhysAddr, expected: u32) -> bool {
+    let key: usize = pa.into();
+    let mut queues = FUTEX_QUEUES.lock();
+    if *pa.get_ref::<u32>() != expected {
+        return false;
+    }
+    queues.entry(key).or_insert_with(WaitQueue::new).add_current();
+    drop(queues);
+    block_current_and_run_next();
+    // still queued if woken up by a signal
+    let mut queues = FUTEX_QUEUES.lock();
+    if let Some(queue) = queues.get_mut(&key) {
+        queue.remove_current();
+        if queue.is_empty() {
+            queues.remove(&key);
+        }
+    }
+    true
+}
+
+/// Wake up at most `count` threads waiting on the word at `pa`, and return
+/// how many there were.
+pub fn futex_wake(pa: PhysAddr, count: usize) -> usize {
+    let key: usize = pa.into();
+    let mut queues = FUTEX_QUEUES.lock();
+    let queue = match queues.get_mut(&key) {
+        Some(queue) => queue,
+        None => return 0,
+    };
+    let mut woken = 0;
+    while woken < count && queue.wake_one() {
+        woken += 1;
+    }
+    if queue.is_empty() {
+        queues.remove(&key);
+    }
+    woken
+}
@@ -1,28 +1,44 @@
-use super::TaskControlBlock;
-use alloc::collections::VecDeque;
+use super::{TaskControlBlock, ProcessControlBlock};
+use super::scheduler::Scheduler;
+#[cfg(not(any(feature = "sched_stride", feature = "sched_mlfq")))]
+use super::scheduler::FifoScheduler as DefaultScheduler;
+#[cfg(feature = "sched_stride")]
+use super::scheduler::StrideScheduler as DefaultScheduler;
+#[cfg(feature = "sched_mlfq")]
+use super::scheduler::MlfqScheduler as DefaultScheduler;
+use alloc::collections::BTreeMap;
+use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::*;
 
+/// The ready queue, in the order of the scheduler picked by the `sched_*`
+/// feature, round robin by default.
 pub struct TaskManager {
-    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+    scheduler: Box<dyn Scheduler>,
 }
 
-/// A simple FIFO scheduler.
 impl TaskManager {
     pub fn new() -> Self {
-        Self { ready_queue: VecDeque::new(), }
+        Self { scheduler: Box::new(DefaultScheduler::new()) }
     }
     pub fn add(&mut self, task: Arc<TaskControlBlock>) {
-        self.ready_queue.push_back(task);
+        self.scheduler.add(task);
     }
     pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        self.ready_queue.pop_front()
+        self.scheduler.fetch()
+    }
+    pub fn tick(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        self.scheduler.tick(task)
     }
 }
 
 lazy_static! {
     pub static ref TASK_MANAGER: Mutex<TaskManager> = Mutex::new(TaskManager::new());
+    /// Every process that has not been reaped yet, indexed by pid.
+    pub static ref PID2PROCESS: Mutex<BTreeMap<usize, Arc<ProcessControlBlock>>> =
+        Mutex::new(BTreeMap::new());
 }
 
 pub fn add_task(task: Arc<TaskControlBlock>) {
@@ -31,4 +47,32 @@ pub fn add_task(task: Arc<TaskControlBlock>) {
 
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
     TASK_MANAGER.lock().fetch()
-}
\ No newline at end of file
+}
+
+/// Account a timer tick to `task`, which is running, return whether it is
+/// to be preempted.
+pub fn tick_task(task: &Arc<TaskControlBlock>) -> bool {
+    TASK_MANAGER.lock().tick(task)
+}
+
+pub fn insert_into_pid2process(pid: usize, process: Arc<ProcessControlBlock>) {
+    PID2PROCESS.lock().insert(pid, process);
+}
+
+pub fn remove_from_pid2process(pid: usize) {
+    if PID2PROCESS.lock().remove(&pid).is_none() {
+        panic!("cannot find pid {} in pid2process!", pid);
+    }
+}
+
+pub fn pid2process(pid: usize) -> Option<Arc<ProcessControlBlock>> {
+    PID2PROCESS.lock().get(&pid).map(Arc::clone)
+}
+
+/// Snapshot of all live processes ordered by pid.
+///
+/// The map lock is released before returning, so callers are free to
+/// acquire the inner lock of each process afterwards.
+pub fn all_processes() -> Vec<Arc<ProcessControlBlock>> {
+    PID2PROCESS.lock().values().map(Arc::clone).collect()
+}
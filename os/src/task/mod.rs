@@ -1,28 +1,73 @@
 mod context;
 mod switch;
 mod task;
+mod process;
 mod manager;
 mod processor;
 mod pid;
+mod wait_queue;
+mod signal;
+mod scheduler;
+mod sync;
+mod futex;
 
 use crate::fs::{open_file, OpenFlags};
+use crate::timer::get_time;
 use switch::__switch;
-use task::{TaskControlBlock, TaskStatus};
 use alloc::sync::Arc;
 use manager::fetch_task;
 use lazy_static::*;
 pub use context::TaskContext;
+pub use task::{TaskControlBlock, TaskStatus, TaskUserRes};
+pub use process::ProcessControlBlock;
 
 pub use processor::{
     run_tasks,
     current_task,
+    current_process,
     current_user_token,
     current_trap_cx,
+    current_trap_cx_user_va,
     take_current_task,
     schedule,
 };
-pub use manager::add_task;
-pub use pid::{PidHandle, pid_alloc, KernelStack};
+pub use manager::{
+    add_task,
+    tick_task,
+    pid2process,
+    all_processes,
+    insert_into_pid2process,
+    remove_from_pid2process,
+};
+pub use pid::{PidHandle, pid_alloc, KernelStack, RecycleAllocator};
+pub use wait_queue::WaitQueue;
+pub use sync::{UserMutex, Semaphore, Condvar};
+pub use futex::{futex_wait, futex_wake};
+pub use scheduler::{SchedInfo, NICE_MIN, NICE_MAX};
+pub use signal::{
+    SignalFlags,
+    SignalAction,
+    SignalActions,
+    SIG_IGN,
+    send_signal,
+    send_group_signal,
+    raise_fault_signal,
+    signal_pending,
+    handle_signals,
+    sigreturn,
+};
+
+/// Charge the time since the current thread got back to user mode to the
+/// user time of its process.
+pub fn enter_kernel() {
+    current_process().acquire_inner_lock().times.enter_kernel(get_time());
+}
+
+/// Charge the time since the current thread trapped or was switched in to
+/// the kernel time of its process.
+pub fn leave_kernel() {
+    current_process().acquire_inner_lock().times.leave_kernel(get_time());
+}
 
 pub fn suspend_current_and_run_next() {
     // There must be an application running.
@@ -42,15 +87,85 @@ pub fn suspend_current_and_run_next() {
     schedule(task_cx_ptr2);
 }
 
+/// The current task must have been put where it will be woken up from, a
+/// wait queue holds it in the meantime.
+pub fn block_current_and_run_next() {
+    let task = take_current_task().unwrap();
+    let mut task_inner = task.acquire_inner_lock();
+    let task_cx_ptr2 = task_inner.get_task_cx_ptr2();
+    task_inner.task_status = TaskStatus::Blocked;
+    drop(task_inner);
+    drop(task);
+    schedule(task_cx_ptr2);
+}
+
+/// Put a blocked task back to the ready queue. A task may be woken up
+/// by a signal before the event it waits for, it is left alone then.
+pub fn wake_up(task: Arc<TaskControlBlock>) {
+    let mut inner = task.acquire_inner_lock();
+    if inner.task_status != TaskStatus::Blocked {
+        return;
+    }
+    inner.task_status = TaskStatus::Ready;
+    drop(inner);
+    add_task(task);
+}
+
+/// Wake up the threads waiting for a child or a thread of `process` to
+/// exit or stop, which may be the current one, so its lock is released
+/// first.
+fn wake_up_waiters(process: &Arc<ProcessControlBlock>) {
+    let mut waiters = core::mem::take(&mut process.acquire_inner_lock().wait_queue);
+    waiters.wake_all();
+}
+
+/// Exit the current thread, and its process if it is the main thread.
 pub fn exit_current_and_run_next(exit_code: i32) {
+    exit_current(exit_code, false);
+}
+
+/// Exit the process of the current thread, whichever thread it is, as on a
+/// fatal signal.
+pub fn exit_process_and_run_next(exit_code: i32) {
+    exit_current(exit_code, true);
+}
+
+fn exit_current(exit_code: i32, whole_process: bool) {
     // take from Processor
     let task = take_current_task().unwrap();
+    let process = task.process();
+    // **** hold current TCB lock
+    let mut task_inner = task.acquire_inner_lock();
+    let tid = task_inner.res.tid;
+    task_inner.task_status = TaskStatus::Zombie;
+    task_inner.exit_code = Some(exit_code);
+    drop(task_inner);
+    // **** release current TCB lock
+    // the thread is kept by its process until waited for or reaped
+    drop(task);
+    if tid == 0 || whole_process {
+        exit_process(&process, exit_code);
+    } else {
+        wake_up_waiters(&process);
+    }
+    drop(process);
+    // we do not have to save task context
+    let _unused: usize = 0;
+    schedule(&_unused as *const _);
+}
+
+fn exit_process(process: &Arc<ProcessControlBlock>, exit_code: i32) {
     // **** hold current PCB lock
-    let mut inner = task.acquire_inner_lock();
-    // Change status to Zombie
-    inner.task_status = TaskStatus::Zombie;
+    let mut inner = process.acquire_inner_lock();
+    inner.is_zombie = true;
     // Record exit code
     inner.exit_code = exit_code;
+    // the other threads are dropped from the ready queue as they come up,
+    // and blocked ones are never woken up, so they block without a strong
+    // reference to the process, or it could not be freed when reaped
+    for task in inner.tasks.iter().flatten() {
+        task.acquire_inner_lock().task_status = TaskStatus::Zombie;
+    }
     // do not move to its parent but under initproc
 
     // ++++++ hold initproc PCB lock here
@@ -63,26 +178,33 @@ pub fn exit_current_and_run_next(exit_code: i32) {
     }
     // ++++++ release parent PCB lock here
 
+    // zombies among them are to be reaped by initproc now
+    let orphans = !inner.children.is_empty();
     inner.children.clear();
-    // deallocate user space
+    // deallocate user space, with the user stacks and TrapContexts of all
+    // threads
     inner.memory_set.recycle_data_pages();
+    let parent = inner.parent.as_ref().and_then(|parent| parent.upgrade());
     drop(inner);
     // **** release current PCB lock
-    // drop task manually to maintain rc correctly
-    drop(task);
-    // we do not have to save task context
-    let _unused: usize = 0;
-    schedule(&_unused as *const _);
+    if let Some(parent) = parent {
+        send_signal(&parent, SignalFlags::SIGCHLD);
+        wake_up_waiters(&parent);
+    }
+    if orphans {
+        wake_up_waiters(&INITPROC);
+    }
 }
 
 lazy_static! {
-    pub static ref INITPROC: Arc<TaskControlBlock> = Arc::new({
+    pub static ref INITPROC: Arc<ProcessControlBlock> = {
         let inode = open_file("initproc", OpenFlags::RDONLY).unwrap();
         let v = inode.read_all();
-        TaskControlBlock::new(v.as_slice())
-    });
+        ProcessControlBlock::new(v.as_slice())
+    };
 }
 
 pub fn add_initproc() {
-    add_task(INITPROC.clone());
+    insert_into_pid2process(INITPROC.getpid(), INITPROC.clone());
+    add_task(INITPROC.acquire_inner_lock().get_task(0).unwrap());
 }
@@ -8,38 +8,41 @@ use crate::config::{
     KERNEL_STACK_SIZE,
 };
 
-struct PidAllocator {
+/// Hands out the lowest ids not in use: pids, kernel stacks and the tids
+/// of every process.
+pub struct RecycleAllocator {
     current: usize,
     recycled: Vec<usize>,
 }
 
-impl PidAllocator {
+impl RecycleAllocator {
     pub fn new() -> Self {
-        PidAllocator {
+        RecycleAllocator {
             current: 0,
             recycled: Vec::new(),
         }
     }
-    pub fn alloc(&mut self) -> PidHandle {
-        if let Some(pid) = self.recycled.pop() {
-            PidHandle(pid)
+    pub fn alloc(&mut self) -> usize {
+        if let Some(id) = self.recycled.pop() {
+            id
         } else {
             self.current += 1;
-            PidHandle(self.current - 1)
+            self.current - 1
         }
     }
-    pub fn dealloc(&mut self, pid: usize) {
-        assert!(pid < self.current);
+    pub fn dealloc(&mut self, id: usize) {
+        assert!(id < self.current);
         assert!(
-            self.recycled.iter().find(|ppid| **ppid == pid).is_none(),
-            "pid {} has been deallocated!", pid
+            self.recycled.iter().find(|i| **i == id).is_none(),
+            "id {} has been deallocated!", id
         );
-        self.recycled.push(pid);
+        self.recycled.push(id);
     }
 }
 
 lazy_static! {
-    static ref PID_ALLOCATOR : Mutex<PidAllocator> = Mutex::new(PidAllocator::new());
+    static ref PID_ALLOCATOR: Mutex<RecycleAllocator> = Mutex::new(RecycleAllocator::new());
+    static ref KSTACK_ALLOCATOR: Mutex<RecycleAllocator> = Mutex::new(RecycleAllocator::new());
 }
 
 pub struct PidHandle(pub usize);
@@ -52,24 +55,25 @@ impl Drop for PidHandle {
 }
 
 pub fn pid_alloc() -> PidHandle {
-    PID_ALLOCATOR.lock().alloc()
+    PidHandle(PID_ALLOCATOR.lock().alloc())
 }
 
 /// Return (bottom, top) of a kernel stack in kernel space.
-pub fn kernel_stack_position(app_id: usize) -> (usize, usize) {
-    let top = TRAMPOLINE - app_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
+pub fn kernel_stack_position(id: usize) -> (usize, usize) {
+    let top = TRAMPOLINE - id * (KERNEL_STACK_SIZE + PAGE_SIZE);
     let bottom = top - KERNEL_STACK_SIZE;
     (bottom, top)
 }
 
+/// The kernel stack of a thread, placed by an id of its own.
 pub struct KernelStack {
-    pid: usize,
+    id: usize,
 }
 
 impl KernelStack {
-    pub fn new(pid_handle: &PidHandle) -> Self {
-        let pid = pid_handle.0;
-        let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(pid);
+    pub fn new() -> Self {
+        let id = KSTACK_ALLOCATOR.lock().alloc();
+        let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(id);
         KERNEL_SPACE
             .lock()
             .insert_framed_area(
@@ -77,9 +81,7 @@ impl KernelStack {
                 kernel_stack_top.into(),
                 MapPermission::R | MapPermission::W,
             );
-        KernelStack {
-            pid: pid_handle.0,
-        }
+        KernelStack { id }
     }
     pub fn push_on_top<T>(&self, value: T) -> *mut T where
         T: Sized, {
@@ -89,17 +91,18 @@ impl KernelStack {
         ptr_mut
     }
     pub fn get_top(&self) -> usize {
-        let (_, kernel_stack_top) = kernel_stack_position(self.pid);
+        let (_, kernel_stack_top) = kernel_stack_position(self.id);
         kernel_stack_top
     }
 }
 
 impl Drop for KernelStack {
     fn drop(&mut self) {
-        let (kernel_stack_bottom, _) = kernel_stack_position(self.pid);
+        let (kernel_stack_bottom, _) = kernel_stack_position(self.id);
         let kernel_stack_bottom_va: VirtAddr = kernel_stack_bottom.into();
         KERNEL_SPACE
             .lock()
             .remove_area_with_start_vpn(kernel_stack_bottom_va.into());
+        KSTACK_ALLOCATOR.lock().dealloc(self.id);
     }
 }
\ No newline at end of file
@@ -0,0 +1,348 @@
+use crate::mm::{
+    MemorySet,
+    KERNEL_SPACE,
+    MapPermission,
+    VirtAddr,
+    translated_refmut,
+};
+use crate::trap::{TrapContext, trap_handler};
+use crate::config::PAGE_SIZE;
+use super::{TaskControlBlock, TaskUserRes};
+use super::{PidHandle, pid_alloc, RecycleAllocator, WaitQueue, insert_into_pid2process};
+use super::{SignalFlags, SignalAction, SignalActions, SIG_IGN};
+use super::{UserMutex, Semaphore, Condvar};
+use alloc::sync::{Weak, Arc};
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::string::String;
+use spin::{Mutex, MutexGuard};
+use crate::fs::{File, Stdin, Stdout};
+
+/// What the threads of a process share: the address space, files,
+/// credentials and signal state.
+pub struct ProcessControlBlock {
+    // immutable
+    pub pid: PidHandle,
+    // mutable
+    inner: Mutex<ProcessControlBlockInner>,
+}
+
+pub struct ProcessControlBlockInner {
+    pub is_zombie: bool,
+    pub memory_set: MemorySet,
+    /// Where the user stacks of the threads begin, see `TaskUserRes`.
+    pub ustack_base: usize,
+    pub parent: Option<Weak<ProcessControlBlock>>,
+    pub children: Vec<Arc<ProcessControlBlock>>,
+    pub exit_code: i32,
+    /// Threads blocked in `sys_waitpid` until a child of this process exits
+    /// or stops, or in `sys_waittid` until a thread of it exits.
+    pub wait_queue: WaitQueue,
+    pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    /// Credentials checked against the owner and mode of files, 0 is root.
+    pub uid: u32,
+    pub gid: u32,
+    /// Signals sent to the process and not handled yet, by whichever thread
+    /// gets back to user space first.
+    pub signals: SignalFlags,
+    /// Signals kept pending until unblocked.
+    pub signal_mask: SignalFlags,
+    pub signal_actions: SignalActions,
+    /// Stopped by a signal, every thread is blocked until `SIGCONT`.
+    pub stopped: bool,
+    /// The signal that stopped the process, until reported by `sys_waitpid`.
+    pub stop_signal: Option<SignalFlags>,
+    /// Process group, for job control, and session, of which the shell is
+    /// the leader.
+    pub pgid: usize,
+    pub sid: usize,
+    pub times: TaskTimes,
+    /// Threads indexed by tid, the main thread is 0. An exited thread is
+    /// kept until it is waited for, or its process is reaped.
+    pub tasks: Vec<Option<Arc<TaskControlBlock>>>,
+    tid_allocator: RecycleAllocator,
+    /// Synchronization objects of the threads, by id.
+    pub mutex_list: Vec<Arc<UserMutex>>,
+    pub semaphore_list: Vec<Arc<Semaphore>>,
+    pub condvar_list: Vec<Arc<Condvar>>,
+}
+
+/// CPU time in cycles of `get_time`, split between user and kernel mode at
+/// trap entry and return, and at context switches. As only a thread runs
+/// at a time, the threads of a process share a span.
+#[derive(Clone, Copy, Default)]
+pub struct TaskTimes {
+    pub utime: usize,
+    pub stime: usize,
+    /// Times of the reaped children, with those of their own children.
+    pub cutime: usize,
+    pub cstime: usize,
+    /// When the current span in user or kernel mode began.
+    last: usize,
+}
+
+impl TaskTimes {
+    pub fn enter_kernel(&mut self, now: usize) {
+        self.utime += now - self.last;
+        self.last = now;
+    }
+    pub fn leave_kernel(&mut self, now: usize) {
+        self.stime += now - self.last;
+        self.last = now;
+    }
+    /// A thread is switched in and out in kernel mode.
+    pub fn switch_in(&mut self, now: usize) {
+        self.last = now;
+    }
+    pub fn switch_out(&mut self, now: usize) {
+        self.leave_kernel(now);
+    }
+    /// Add the times of a reaped child.
+    pub fn reap(&mut self, child: &TaskTimes) {
+        self.cutime += child.utime + child.cutime;
+        self.cstime += child.stime + child.cstime;
+    }
+}
+
+impl ProcessControlBlockInner {
+    pub fn get_user_token(&self) -> usize {
+        self.memory_set.token()
+    }
+    /// Signals to be handled on the way back to user space.
+    pub fn signal_pending(&self) -> bool {
+        !(self.signals - self.signal_mask).is_empty()
+    }
+    pub fn alloc_fd(&mut self) -> usize {
+        if let Some(fd) = (0..self.fd_table.len())
+            .find(|fd| self.fd_table[*fd].is_none()) {
+            fd
+        } else {
+            self.fd_table.push(None);
+            self.fd_table.len() - 1
+        }
+    }
+    pub fn alloc_tid(&mut self) -> usize {
+        self.tid_allocator.alloc()
+    }
+    pub fn get_task(&self, tid: usize) -> Option<Arc<TaskControlBlock>> {
+        self.tasks.get(tid).cloned().flatten()
+    }
+    /// Threads not waited for yet, exited or not.
+    pub fn thread_count(&self) -> usize {
+        self.tasks.iter().filter(|task| task.is_some()).count()
+    }
+    /// Map the user stack and TrapContext of a thread.
+    pub fn map_user_res(&mut self, res: &TaskUserRes) {
+        self.memory_set.insert_framed_area(
+            res.ustack_bottom().into(),
+            res.ustack_top().into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        );
+        self.memory_set.insert_framed_area(
+            res.trap_cx_user_va().into(),
+            (res.trap_cx_user_va() + PAGE_SIZE).into(),
+            MapPermission::R | MapPermission::W,
+        );
+    }
+    /// Drop an exited thread, with its user stack and TrapContext and tid.
+    pub fn remove_task(&mut self, tid: usize) {
+        let task = self.tasks[tid].take().unwrap();
+        let res = task.acquire_inner_lock().res;
+        self.memory_set.remove_area_with_start_vpn(VirtAddr::from(res.ustack_bottom()).into());
+        self.memory_set.remove_area_with_start_vpn(VirtAddr::from(res.trap_cx_user_va()).into());
+        self.tid_allocator.dealloc(tid);
+    }
+}
+
+impl ProcessControlBlock {
+    pub fn acquire_inner_lock(&self) -> MutexGuard<ProcessControlBlockInner> {
+        self.inner.lock()
+    }
+    /// The main thread is not added to the ready queue yet.
+    pub fn new(elf_data: &[u8]) -> Arc<Self> {
+        // memory_set with elf program headers/trampoline
+        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data);
+        let pid_handle = pid_alloc();
+        let pid = pid_handle.0;
+        let process = Arc::new(Self {
+            pid: pid_handle,
+            inner: Mutex::new(ProcessControlBlockInner {
+                is_zombie: false,
+                memory_set,
+                ustack_base,
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                wait_queue: WaitQueue::new(),
+                fd_table: vec![
+                    // 0 -> stdin
+                    Some(Arc::new(Stdin)),
+                    // 1 -> stdout
+                    Some(Arc::new(Stdout)),
+                    // 2 -> stderr
+                    Some(Arc::new(Stdout)),
+                ],
+                uid: 0,
+                gid: 0,
+                signals: SignalFlags::empty(),
+                signal_mask: SignalFlags::empty(),
+                signal_actions: Default::default(),
+                stopped: false,
+                stop_signal: None,
+                pgid: pid,
+                sid: pid,
+                times: TaskTimes::default(),
+                tasks: Vec::new(),
+                tid_allocator: RecycleAllocator::new(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+            }),
+        });
+        // the main thread, with its user stack and TrapContext
+        let task = Arc::new(TaskControlBlock::new(&process, ustack_base, true));
+        let task_inner = task.acquire_inner_lock();
+        *task_inner.get_trap_cx() = TrapContext::app_init_context(
+            entry_point,
+            task_inner.res.ustack_top(),
+            KERNEL_SPACE.lock().token(),
+            task.kernel_stack.get_top(),
+            trap_handler as usize,
+        );
+        drop(task_inner);
+        process.acquire_inner_lock().tasks.push(Some(task));
+        process
+    }
+    /// Only a process with a single thread, which is the caller, may exec.
+    pub fn exec(&self, elf_data: &[u8], args: Vec<String>) {
+        // memory_set with elf program headers/trampoline
+        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data);
+
+        // **** hold current PCB lock
+        let mut inner = self.acquire_inner_lock();
+        // substitute memory_set
+        inner.memory_set = memory_set;
+        inner.ustack_base = ustack_base;
+        // map the user stack and TrapContext of the main thread again
+        let task = inner.get_task(0).unwrap();
+        let mut task_inner = task.acquire_inner_lock();
+        task_inner.res.ustack_base = ustack_base;
+        let res = task_inner.res;
+        inner.map_user_res(&res);
+        task_inner.trap_cx_ppn = res.trap_cx_ppn(&inner.memory_set);
+        // push arguments on user stack
+        let token = inner.get_user_token();
+        let mut user_sp = res.ustack_top();
+        user_sp -= (args.len() + 1) * core::mem::size_of::<usize>();
+        let argv_base = user_sp;
+        let mut argv: Vec<_> = (0..=args.len())
+            .map(|arg| {
+                translated_refmut(
+                    token,
+                    (argv_base + arg * core::mem::size_of::<usize>()) as *mut usize
+                )
+            })
+            .collect();
+        *argv[args.len()] = 0;
+        for i in 0..args.len() {
+            user_sp -= args[i].len() + 1;
+            *argv[i] = user_sp;
+            let mut p = user_sp;
+            for c in args[i].as_bytes() {
+                *translated_refmut(token, p as *mut u8) = *c;
+                p += 1;
+            }
+            *translated_refmut(token, p as *mut u8) = 0;
+        }
+        // make the user_sp aligned to 8B for k210 platform
+        user_sp -= user_sp % core::mem::size_of::<usize>();
+        // initialize trap_cx
+        let mut trap_cx = TrapContext::app_init_context(
+            entry_point,
+            user_sp,
+            KERNEL_SPACE.lock().token(),
+            task.kernel_stack.get_top(),
+            trap_handler as usize,
+        );
+        trap_cx.x[10] = args.len();
+        trap_cx.x[11] = argv_base;
+        *task_inner.get_trap_cx() = trap_cx;
+        drop(task_inner);
+        // handlers are gone with the old memory_set, ignored signals stay so
+        for action in inner.signal_actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SignalAction::default();
+            }
+        }
+        inner.mutex_list.clear();
+        inner.semaphore_list.clear();
+        inner.condvar_list.clear();
+        // **** release current PCB lock
+    }
+    /// Only a process with a single thread, which is the caller, may fork.
+    /// The main thread of the child is not added to the ready queue yet, and
+    /// the synchronization objects are not inherited.
+    pub fn fork(self: &Arc<ProcessControlBlock>) -> Arc<ProcessControlBlock> {
+        // ---- hold parent PCB lock
+        let mut parent_inner = self.acquire_inner_lock();
+        // copy user space(include trap context)
+        let memory_set = MemorySet::from_existed_user(
+            &parent_inner.memory_set
+        );
+        let pid_handle = pid_alloc();
+        // copy fd table
+        let mut new_fd_table: Vec<Option<Arc<dyn File + Send + Sync>>> = Vec::new();
+        for fd in parent_inner.fd_table.iter() {
+            if let Some(file) = fd {
+                new_fd_table.push(Some(file.clone()));
+            } else {
+                new_fd_table.push(None);
+            }
+        }
+        let child = Arc::new(ProcessControlBlock {
+            pid: pid_handle,
+            inner: Mutex::new(ProcessControlBlockInner {
+                is_zombie: false,
+                memory_set,
+                ustack_base: parent_inner.ustack_base,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+                wait_queue: WaitQueue::new(),
+                fd_table: new_fd_table,
+                uid: parent_inner.uid,
+                gid: parent_inner.gid,
+                signals: SignalFlags::empty(),
+                signal_mask: parent_inner.signal_mask,
+                signal_actions: parent_inner.signal_actions,
+                stopped: false,
+                stop_signal: None,
+                pgid: parent_inner.pgid,
+                sid: parent_inner.sid,
+                times: TaskTimes::default(),
+                tasks: Vec::new(),
+                tid_allocator: RecycleAllocator::new(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+            }),
+        });
+        // the main thread, whose user stack and TrapContext are copied
+        let task = Arc::new(TaskControlBlock::new(&child, parent_inner.ustack_base, false));
+        let mut task_inner = task.acquire_inner_lock();
+        task_inner.sched = parent_inner.get_task(0).unwrap().acquire_inner_lock().sched;
+        // modify kernel_sp in trap_cx
+        task_inner.get_trap_cx().kernel_sp = task.kernel_stack.get_top();
+        drop(task_inner);
+        child.acquire_inner_lock().tasks.push(Some(task));
+        // add child
+        parent_inner.children.push(child.clone());
+        insert_into_pid2process(child.getpid(), child.clone());
+        // return
+        child
+        // ---- release parent PCB lock
+    }
+    pub fn getpid(&self) -> usize {
+        self.pid.0
+    }
+}
@@ -1,10 +1,13 @@
-use super::TaskControlBlock;
+use super::{TaskControlBlock, ProcessControlBlock};
 use alloc::sync::Arc;
 use core::cell::RefCell;
 use lazy_static::*;
 use super::{fetch_task, TaskStatus};
 use super::__switch;
-use crate::trap::TrapContext;
+use crate::trap::{TrapContext, timer_tick};
+use crate::timer::get_time;
+use riscv::register::sip;
+use riscv::asm::wfi;
 
 pub struct Processor {
     inner: RefCell<ProcessorInner>,
@@ -36,17 +39,33 @@ impl Processor {
                 let idle_task_cx_ptr2 = self.get_idle_task_cx_ptr2();
                 // acquire
                 let mut task_inner = task.acquire_inner_lock();
+                // its process exited while it was ready
+                if task_inner.task_status == TaskStatus::Zombie {
+                    continue;
+                }
                 let next_task_cx_ptr2 = task_inner.get_task_cx_ptr2();
                 task_inner.task_status = TaskStatus::Running;
                 drop(task_inner);
                 // release
-                self.inner.borrow_mut().current = Some(task);
+                task.process().acquire_inner_lock().times.switch_in(get_time());
+                self.inner.borrow_mut().current = Some(task.clone());
                 unsafe {
                     __switch(
                         idle_task_cx_ptr2,
                         next_task_cx_ptr2,
                     );
                 }
+                // back here as soon as the task is switched out, before its
+                // process could be reaped
+                task.process().acquire_inner_lock().times.switch_out(get_time());
+            } else {
+                // every task is blocked: sleep until an interrupt is pending,
+                // interrupts are not taken in the kernel so a tick is handled
+                // here
+                unsafe { wfi(); }
+                if sip::read().stimer() {
+                    timer_tick();
+                }
             }
         }
     }
@@ -74,9 +93,13 @@ pub fn current_task() -> Option<Arc<TaskControlBlock>> {
     PROCESSOR.current()
 }
 
+pub fn current_process() -> Arc<ProcessControlBlock> {
+    current_task().unwrap().process()
+}
+
 pub fn current_user_token() -> usize {
-    let task = current_task().unwrap();
-    let token = task.acquire_inner_lock().get_user_token();
+    let process = current_process();
+    let token = process.acquire_inner_lock().get_user_token();
     token
 }
 
@@ -84,6 +107,11 @@ pub fn current_trap_cx() -> &'static mut TrapContext {
     current_task().unwrap().acquire_inner_lock().get_trap_cx()
 }
 
+/// Where the TrapContext of the current thread is in user space.
+pub fn current_trap_cx_user_va() -> usize {
+    current_task().unwrap().acquire_inner_lock().res.trap_cx_user_va()
+}
+
 pub fn schedule(switched_task_cx_ptr2: *const usize) {
     let idle_task_cx_ptr2 = PROCESSOR.get_idle_task_cx_ptr2();
     unsafe {
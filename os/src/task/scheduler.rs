@@ -0,0 +1,203 @@
+// only the scheduler picked by a `sched_*` feature is used
+#![allow(dead_code)]
+
+use super::TaskControlBlock;
+use alloc::collections::{VecDeque, BinaryHeap};
+use alloc::sync::Arc;
+use core::cmp::Ordering;
+
+/// Nice values range from `-20`, the most favoured, to `19`.
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
+
+/// Scheduling state of a task, each scheduler uses its own fields.
+#[derive(Clone, Copy, Default)]
+pub struct SchedInfo {
+    pub nice: isize,
+    /// Virtual time of the stride scheduler.
+    pass: u64,
+    /// Queue of the multilevel feedback scheduler, and ticks used there.
+    level: usize,
+    level_ticks: usize,
+}
+
+/// Picks the next task of the ready queue to run.
+pub trait Scheduler: Send {
+    fn add(&mut self, task: Arc<TaskControlBlock>);
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>>;
+    /// Called on every timer tick for the running `task`, return whether
+    /// its time slice is over.
+    fn tick(&mut self, _task: &Arc<TaskControlBlock>) -> bool {
+        true
+    }
+}
+
+/// Round robin with a time slice of a tick.
+pub struct FifoScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl FifoScheduler {
+    pub fn new() -> Self {
+        Self { ready_queue: VecDeque::new() }
+    }
+}
+
+impl Scheduler for FifoScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+}
+
+/// Weights of the nice values from `NICE_MIN` on, as in Linux: a task gets
+/// about 10% more CPU time than one with a nice value higher by one.
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+const BIG_STRIDE: u64 = 1 << 24;
+
+struct StrideEntry {
+    pass: u64,
+    task: Arc<TaskControlBlock>,
+}
+
+impl PartialEq for StrideEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.pass == other.pass
+    }
+}
+
+impl Eq for StrideEntry {}
+
+impl PartialOrd for StrideEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Reversed, so that the heap gives the lowest pass first.
+impl Ord for StrideEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.pass.cmp(&self.pass)
+    }
+}
+
+/// Stride scheduling: the task with the lowest pass runs, then its pass
+/// goes up by a stride inversely proportional to the weight of its nice
+/// value, so tasks share the CPU in proportion to their weights.
+pub struct StrideScheduler {
+    ready_queue: BinaryHeap<StrideEntry>,
+    /// Pass of the last task fetched, below which a task woken up after a
+    /// long sleep is not let to start, lest it take the CPU for as long.
+    min_pass: u64,
+}
+
+impl StrideScheduler {
+    pub fn new() -> Self {
+        Self { ready_queue: BinaryHeap::new(), min_pass: 0 }
+    }
+}
+
+impl Scheduler for StrideScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut inner = task.acquire_inner_lock();
+        inner.sched.pass = inner.sched.pass.max(self.min_pass);
+        let pass = inner.sched.pass;
+        drop(inner);
+        self.ready_queue.push(StrideEntry { pass, task });
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let task = self.ready_queue.pop()?.task;
+        let mut inner = task.acquire_inner_lock();
+        self.min_pass = inner.sched.pass;
+        let weight = NICE_TO_WEIGHT[(inner.sched.nice - NICE_MIN) as usize];
+        inner.sched.pass += BIG_STRIDE / weight;
+        drop(inner);
+        Some(task)
+    }
+}
+
+/// Time slices, in ticks, of the queues from the highest priority down.
+const MLFQ_SLICES: [usize; 4] = [1, 2, 4, 8];
+/// Every task is moved back to the highest queue this often, in ticks,
+/// so that none starves.
+const MLFQ_BOOST_TICKS: usize = 100;
+
+/// Multilevel feedback queues: a task that uses up the time slice of its
+/// queue, even over several runs, goes down to the next queue with a
+/// longer slice, and lower queues only run when higher ones are empty. A
+/// task that blocks early, as an interactive one does, stays high.
+///
+/// A nice value moves a task up or down a queue for every 10.
+pub struct MlfqScheduler {
+    queues: [VecDeque<Arc<TaskControlBlock>>; 4],
+    ticks: usize,
+}
+
+impl MlfqScheduler {
+    pub fn new() -> Self {
+        Self {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            ticks: 0,
+        }
+    }
+    fn boost(&mut self) {
+        for level in 1..MLFQ_SLICES.len() {
+            while let Some(task) = self.queues[level].pop_front() {
+                self.queues[0].push_back(task);
+            }
+        }
+        for task in self.queues[0].iter() {
+            let mut inner = task.acquire_inner_lock();
+            inner.sched.level = 0;
+            inner.sched.level_ticks = 0;
+        }
+    }
+}
+
+/// The queue of a task, its level moved by its nice value.
+fn mlfq_queue(sched: &SchedInfo) -> usize {
+    (sched.level as isize + sched.nice / 10)
+        .max(0)
+        .min(MLFQ_SLICES.len() as isize - 1) as usize
+}
+
+impl Scheduler for MlfqScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let queue = mlfq_queue(&task.acquire_inner_lock().sched);
+        self.queues[queue].push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.queues.iter_mut().find_map(|queue| queue.pop_front())
+    }
+    fn tick(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        self.ticks += 1;
+        if self.ticks % MLFQ_BOOST_TICKS == 0 {
+            self.boost();
+            let mut inner = task.acquire_inner_lock();
+            inner.sched.level = 0;
+            inner.sched.level_ticks = 0;
+            return true;
+        }
+        let mut inner = task.acquire_inner_lock();
+        inner.sched.level_ticks += 1;
+        if inner.sched.level_ticks < MLFQ_SLICES[inner.sched.level] {
+            // a task woken up in a higher queue runs at once
+            let queue = mlfq_queue(&inner.sched);
+            return self.queues[..queue].iter().any(|queue| !queue.is_empty());
+        }
+        inner.sched.level_ticks = 0;
+        inner.sched.level = (inner.sched.level + 1).min(MLFQ_SLICES.len() - 1);
+        true
+    }
+}
@@ -0,0 +1,295 @@
+use super::{
+    ProcessControlBlock,
+    all_processes,
+    current_task,
+    current_process,
+    wake_up_waiters,
+    block_current_and_run_next,
+    exit_process_and_run_next,
+    wake_up,
+};
+use crate::mm::{copy_to_user, copy_from_user};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+pub const MAX_SIG: usize = 31;
+
+bitflags! {
+    /// A set of signals, signal `n` is bit `n`.
+    pub struct SignalFlags: u32 {
+        const SIGHUP = 1 << 1;
+        const SIGINT = 1 << 2;
+        const SIGQUIT = 1 << 3;
+        const SIGILL = 1 << 4;
+        const SIGTRAP = 1 << 5;
+        const SIGABRT = 1 << 6;
+        const SIGBUS = 1 << 7;
+        const SIGFPE = 1 << 8;
+        const SIGKILL = 1 << 9;
+        const SIGUSR1 = 1 << 10;
+        const SIGSEGV = 1 << 11;
+        const SIGUSR2 = 1 << 12;
+        const SIGPIPE = 1 << 13;
+        const SIGALRM = 1 << 14;
+        const SIGTERM = 1 << 15;
+        const SIGSTKFLT = 1 << 16;
+        const SIGCHLD = 1 << 17;
+        const SIGCONT = 1 << 18;
+        const SIGSTOP = 1 << 19;
+        const SIGTSTP = 1 << 20;
+        const SIGTTIN = 1 << 21;
+        const SIGTTOU = 1 << 22;
+        const SIGURG = 1 << 23;
+        const SIGXCPU = 1 << 24;
+        const SIGXFSZ = 1 << 25;
+        const SIGVTALRM = 1 << 26;
+        const SIGPROF = 1 << 27;
+        const SIGWINCH = 1 << 28;
+        const SIGIO = 1 << 29;
+        const SIGPWR = 1 << 30;
+        const SIGSYS = 1 << 31;
+    }
+}
+
+/// Neither caught, blocked nor ignored.
+const UNCATCHABLE: SignalFlags = SignalFlags::from_bits_truncate(
+    SignalFlags::SIGKILL.bits | SignalFlags::SIGSTOP.bits
+);
+const STOP_SIGNALS: SignalFlags = SignalFlags::from_bits_truncate(
+    SignalFlags::SIGSTOP.bits | SignalFlags::SIGTSTP.bits
+        | SignalFlags::SIGTTIN.bits | SignalFlags::SIGTTOU.bits
+);
+const IGNORED_BY_DEFAULT: SignalFlags = SignalFlags::from_bits_truncate(
+    SignalFlags::SIGCHLD.bits | SignalFlags::SIGCONT.bits
+        | SignalFlags::SIGURG.bits | SignalFlags::SIGWINCH.bits
+);
+
+impl SignalFlags {
+    pub fn from_signum(signum: usize) -> Option<Self> {
+        if signum == 0 || signum > MAX_SIG {
+            return None;
+        }
+        Some(Self::from_bits_truncate(1 << signum))
+    }
+    /// The lowest numbered signal of the set.
+    fn first(&self) -> Option<Self> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(Self::from_bits_truncate(1 << self.bits.trailing_zeros()))
+        }
+    }
+    /// The number of a single signal.
+    pub fn signum(&self) -> usize {
+        self.bits.trailing_zeros() as usize
+    }
+    /// Tasks terminated by a signal exit with minus its number.
+    fn exit_code(&self) -> i32 {
+        -(self.signum() as i32)
+    }
+}
+
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+/// What to do on a signal, set by `sys_sigaction`. A handler runs with
+/// `mask` and its own signal blocked in addition to the mask of the task,
+/// and returns to `restorer`, which calls `sys_sigreturn`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SignalAction {
+    pub handler: usize,
+    pub mask: SignalFlags,
+    pub restorer: usize,
+}
+
+impl Default for SignalAction {
+    fn default() -> Self {
+        Self {
+            handler: SIG_DFL,
+            mask: SignalFlags::empty(),
+            restorer: 0,
+        }
+    }
+}
+
+impl SignalAction {
+    fn ignores(&self, signal: SignalFlags) -> bool {
+        self.handler == SIG_IGN
+            || (self.handler == SIG_DFL && IGNORED_BY_DEFAULT.contains(signal))
+    }
+}
+
+pub type SignalActions = [SignalAction; MAX_SIG + 1];
+
+/// Pushed on the user stack when a handler is called, the registers and
+/// mask of the task to get back to in `sys_sigreturn`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalFrame {
+    x: [usize; 32],
+    sepc: usize,
+    mask: usize,
+}
+
+impl SignalFrame {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>()) }
+    }
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of::<Self>()) }
+    }
+}
+
+/// Make `signal` pending for `process`, ignored signals are discarded.
+/// Its blocked threads are woken up if the signal is to be handled, so
+/// that the system calls they are in return early.
+pub fn send_signal(process: &Arc<ProcessControlBlock>, signal: SignalFlags) {
+    let mut inner = process.acquire_inner_lock();
+    if inner.is_zombie {
+        return;
+    }
+    let mut wake = false;
+    if signal == SignalFlags::SIGCONT {
+        inner.signals.remove(STOP_SIGNALS);
+        wake = inner.stopped;
+        inner.stopped = false;
+        inner.stop_signal = None;
+    } else if STOP_SIGNALS.contains(signal) {
+        inner.signals.remove(SignalFlags::SIGCONT);
+    }
+    if !inner.signal_actions[signal.signum()].ignores(signal) || signal == SignalFlags::SIGCONT {
+        inner.signals.insert(signal);
+        // a stopped task only gets to handle SIGKILL
+        wake |= !inner.signal_mask.contains(signal)
+            && (!inner.stopped || signal == SignalFlags::SIGKILL);
+    }
+    if wake {
+        let tasks: Vec<_> = inner.tasks.iter().flatten().cloned().collect();
+        drop(inner);
+        for task in tasks {
+            wake_up(task);
+        }
+    }
+}
+
+/// Send `signal` to every process of the process group `pgid` but initproc.
+pub fn send_group_signal(pgid: usize, signal: SignalFlags) {
+    for process in all_processes() {
+        if process.getpid() != 0 && process.acquire_inner_lock().pgid == pgid {
+            send_signal(&process, signal);
+        }
+    }
+}
+
+/// Raise a signal for a fault of the current thread, which can not go on
+/// if it is blocked or ignored.
+pub fn raise_fault_signal(signal: SignalFlags) {
+    let process = current_process();
+    let mut inner = process.acquire_inner_lock();
+    inner.signal_mask.remove(signal);
+    let action = &mut inner.signal_actions[signal.signum()];
+    if action.handler == SIG_IGN {
+        *action = SignalAction::default();
+    }
+    inner.signals.insert(signal);
+}
+
+/// Whether a blocking system call of the current thread should return early.
+pub fn signal_pending() -> bool {
+    current_process().acquire_inner_lock().signal_pending()
+}
+
+/// Act on the pending signals of the process of the current thread on its
+/// way back to user space: terminate the process, stop it until `SIGCONT`,
+/// or get the thread to run a handler.
+pub fn handle_signals() {
+    loop {
+        let task = current_task().unwrap();
+        let process = task.process();
+        let mut inner = process.acquire_inner_lock();
+        let mut deliverable = inner.signals - inner.signal_mask;
+        if inner.stopped {
+            deliverable &= SignalFlags::SIGKILL;
+        }
+        let signal = match deliverable.first() {
+            Some(signal) => signal,
+            None if inner.stopped => {
+                drop(inner);
+                drop(process);
+                drop(task);
+                block_current_and_run_next();
+                continue;
+            }
+            None => return,
+        };
+        inner.signals.remove(signal);
+        let action = inner.signal_actions[signal.signum()];
+        if action.handler == SIG_IGN {
+            continue;
+        }
+        if action.handler == SIG_DFL {
+            if STOP_SIGNALS.contains(signal) {
+                inner.stopped = true;
+                inner.stop_signal = Some(signal);
+                let parent = inner.parent.as_ref().and_then(|parent| parent.upgrade());
+                drop(inner);
+                if let Some(parent) = parent {
+                    send_signal(&parent, SignalFlags::SIGCHLD);
+                    wake_up_waiters(&parent);
+                }
+            } else if !IGNORED_BY_DEFAULT.contains(signal) {
+                drop(inner);
+                drop(process);
+                drop(task);
+                exit_process_and_run_next(signal.exit_code());
+            }
+            continue;
+        }
+        // call the handler with a frame on the user stack
+        let cx = task.acquire_inner_lock().get_trap_cx();
+        let frame = SignalFrame {
+            x: cx.x,
+            sepc: cx.sepc,
+            mask: inner.signal_mask.bits as usize,
+        };
+        let sp = cx.x[2].wrapping_sub(size_of::<SignalFrame>()) & !0xf;
+        if !copy_to_user(inner.get_user_token(), sp as *mut u8, frame.as_bytes()) {
+            drop(inner);
+            drop(process);
+            drop(task);
+            exit_process_and_run_next(SignalFlags::SIGSEGV.exit_code());
+            continue;
+        }
+        inner.signal_mask |= action.mask | signal;
+        inner.signal_mask -= UNCATCHABLE;
+        cx.x[1] = action.restorer;
+        cx.x[2] = sp;
+        cx.x[10] = signal.signum();
+        cx.sepc = action.handler;
+        return;
+    }
+}
+
+/// Get back to where the current thread was before the handler was
+/// called, the frame is where the stack pointer is after the handler
+/// returned. Return the `a0` to get back to.
+pub fn sigreturn() -> isize {
+    let task = current_task().unwrap();
+    let process = task.process();
+    let mut inner = process.acquire_inner_lock();
+    let cx = task.acquire_inner_lock().get_trap_cx();
+    let mut frame = SignalFrame { x: [0; 32], sepc: 0, mask: 0 };
+    if !copy_from_user(inner.get_user_token(), cx.x[2] as *const u8, frame.as_bytes_mut()) {
+        drop(inner);
+        drop(process);
+        drop(task);
+        exit_process_and_run_next(SignalFlags::SIGSEGV.exit_code());
+        unreachable!();
+    }
+    cx.x = frame.x;
+    cx.sepc = frame.sepc;
+    inner.signal_mask = SignalFlags::from_bits_truncate(frame.mask as u32) - UNCATCHABLE;
+    cx.x[10] as isize
+}
@@ -0,0 +1,129 @@
+use super::{WaitQueue, block_current_and_run_next, signal_pending};
+use spin::Mutex;
+
+/// Mutexes, semaphores and condition variables for the threads of a process,
+/// which block in the kernel instead of spinning.
+///
+/// Their locks are taken before the lock of the process, to check for
+/// signals. A thread interrupted by a signal gives up and leaves the wait
+/// queue, so that it is not woken up in place of another later.
+pub struct UserMutex {
+    inner: Mutex<UserMutexInner>,
+}
+
+struct UserMutexInner {
+    locked: bool,
+    wait_queue: WaitQueue,
+}
+
+impl UserMutex {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(UserMutexInner {
+                locked: false,
+                wait_queue: WaitQueue::new(),
+            }),
+        }
+    }
+    /// Return false if interrupted by a signal before getting the lock.
+    pub fn lock(&self) -> bool {
+        loop {
+            let mut inner = self.inner.lock();
+            if !inner.locked {
+                inner.locked = true;
+                inner.wait_queue.remove_current();
+                return true;
+            }
+            if signal_pending() {
+                inner.wait_queue.remove_current();
+                return false;
+            }
+            inner.wait_queue.add_current();
+            drop(inner);
+            block_current_and_run_next();
+        }
+    }
+    /// Return false if it was not locked.
+    pub fn unlock(&self) -> bool {
+        let mut inner = self.inner.lock();
+        if !inner.locked {
+            return false;
+        }
+        inner.locked = false;
+        inner.wait_queue.wake_one();
+        true
+    }
+}
+
+pub struct Semaphore {
+    inner: Mutex<SemaphoreInner>,
+}
+
+struct SemaphoreInner {
+    count: usize,
+    wait_queue: WaitQueue,
+}
+
+impl Semaphore {
+    pub fn new(count: usize) -> Self {
+        Self {
+            inner: Mutex::new(SemaphoreInner {
+                count,
+                wait_queue: WaitQueue::new(),
+            }),
+        }
+    }
+    pub fn up(&self) {
+        let mut inner = self.inner.lock();
+        inner.count += 1;
+        inner.wait_queue.wake_one();
+    }
+    /// Return false if interrupted by a signal while the count is 0.
+    pub fn down(&self) -> bool {
+        loop {
+            let mut inner = self.inner.lock();
+            if inner.count > 0 {
+                inner.count -= 1;
+                inner.wait_queue.remove_current();
+                return true;
+            }
+            if signal_pending() {
+                inner.wait_queue.remove_current();
+                return false;
+            }
+            inner.wait_queue.add_current();
+            drop(inner);
+            block_current_and_run_next();
+        }
+    }
+}
+
+pub struct Condvar {
+    wait_queue: Mutex<WaitQueue>,
+}
+
+impl Condvar {
+    pub fn new() -> Self {
+        Self { wait_queue: Mutex::new(WaitQueue::new()) }
+    }
+    pub fn signal(&self) {
+        self.wait_queue.lock().wake_one();
+    }
+    pub fn broadcast(&self) {
+        self.wait_queue.lock().wake_all();
+    }
+    /// Release `mutex` and block until signaled, then take `mutex` again.
+    /// The caller has to check its condition again, as a signal may wake
+    /// it up first. Return false if interrupted by a signal, `mutex` is
+    /// not held then.
+    pub fn wait(&self, mutex: &UserMutex) -> bool {
+        // queued before unlocking, so that no wakeup is missed in between
+        self.wait_queue.lock().add_current();
+        mutex.unlock();
+        if !signal_pending() {
+            block_current_and_run_next();
+        }
+        self.wait_queue.lock().remove_current();
+        mutex.lock()
+    }
+}
@@ -1,39 +1,56 @@
-use crate::mm::{
-    MemorySet,
-    PhysPageNum,
-    KERNEL_SPACE, 
-    VirtAddr,
-    translated_refmut,
-};
-use crate::trap::{TrapContext, trap_handler};
-use crate::config::{TRAP_CONTEXT};
+use crate::mm::{MemorySet, PhysPageNum, VirtAddr};
+use crate::trap::TrapContext;
+use crate::config::{TRAP_CONTEXT, PAGE_SIZE, USER_STACK_SIZE};
 use super::TaskContext;
-use super::{PidHandle, pid_alloc, KernelStack};
+use super::{KernelStack, ProcessControlBlock, SchedInfo};
 use alloc::sync::{Weak, Arc};
-use alloc::vec;
-use alloc::vec::Vec;
-use alloc::string::String;
 use spin::{Mutex, MutexGuard};
-use crate::fs::{File, Stdin, Stdout};
 
+/// A thread of a process, what is scheduled.
 pub struct TaskControlBlock {
     // immutable
-    pub pid: PidHandle,
+    pub process: Weak<ProcessControlBlock>,
     pub kernel_stack: KernelStack,
     // mutable
     inner: Mutex<TaskControlBlockInner>,
 }
 
 pub struct TaskControlBlockInner {
+    pub res: TaskUserRes,
     pub trap_cx_ppn: PhysPageNum,
-    pub base_size: usize,
     pub task_cx_ptr: usize,
     pub task_status: TaskStatus,
-    pub memory_set: MemorySet,
-    pub parent: Option<Weak<TaskControlBlock>>,
-    pub children: Vec<Arc<TaskControlBlock>>,
-    pub exit_code: i32,
-    pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    /// Set as the thread exits, until it is waited for.
+    pub exit_code: Option<i32>,
+    pub sched: SchedInfo,
+}
+
+/// The tid of a thread, by which its user stack and TrapContext are placed
+/// in the memory set of its process: user stacks from `ustack_base` up,
+/// with a guard page between each, and TrapContexts from `TRAP_CONTEXT`
+/// down.
+#[derive(Clone, Copy)]
+pub struct TaskUserRes {
+    pub tid: usize,
+    pub ustack_base: usize,
+}
+
+impl TaskUserRes {
+    pub fn ustack_bottom(&self) -> usize {
+        self.ustack_base + self.tid * (PAGE_SIZE + USER_STACK_SIZE)
+    }
+    pub fn ustack_top(&self) -> usize {
+        self.ustack_bottom() + USER_STACK_SIZE
+    }
+    pub fn trap_cx_user_va(&self) -> usize {
+        TRAP_CONTEXT - self.tid * PAGE_SIZE
+    }
+    pub fn trap_cx_ppn(&self, memory_set: &MemorySet) -> PhysPageNum {
+        memory_set
+            .translate(VirtAddr::from(self.trap_cx_user_va()).into())
+            .unwrap()
+            .ppn()
+    }
 }
 
 impl TaskControlBlockInner {
@@ -43,188 +60,53 @@ impl TaskControlBlockInner {
     pub fn get_trap_cx(&self) -> &'static mut TrapContext {
         self.trap_cx_ppn.get_mut()
     }
-    pub fn get_user_token(&self) -> usize {
-        self.memory_set.token()
-    }
-    fn get_status(&self) -> TaskStatus {
-        self.task_status
-    }
-    pub fn is_zombie(&self) -> bool {
-        self.get_status() == TaskStatus::Zombie
-    }
-    pub fn alloc_fd(&mut self) -> usize {
-        if let Some(fd) = (0..self.fd_table.len())
-            .find(|fd| self.fd_table[*fd].is_none()) {
-            fd
-        } else {
-            self.fd_table.push(None);
-            self.fd_table.len() - 1
-        }
-    }
 }
 
 impl TaskControlBlock {
     pub fn acquire_inner_lock(&self) -> MutexGuard<TaskControlBlockInner> {
         self.inner.lock()
     }
-    pub fn new(elf_data: &[u8]) -> Self {
-        // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
-        let trap_cx_ppn = memory_set
-            .translate(VirtAddr::from(TRAP_CONTEXT).into())
-            .unwrap()
-            .ppn();
-        // alloc a pid and a kernel stack in kernel space
-        let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
-        let kernel_stack_top = kernel_stack.get_top();
+    /// A thread of `process` with a new tid, whose user stack and
+    /// TrapContext are mapped if `alloc_user_res`, or are there already
+    /// as after fork. Its TrapContext is left to the caller to fill in.
+    pub fn new(process: &Arc<ProcessControlBlock>, ustack_base: usize, alloc_user_res: bool) -> Self {
+        let mut process_inner = process.acquire_inner_lock();
+        let res = TaskUserRes { tid: process_inner.alloc_tid(), ustack_base };
+        if alloc_user_res {
+            process_inner.map_user_res(&res);
+        }
+        let trap_cx_ppn = res.trap_cx_ppn(&process_inner.memory_set);
+        drop(process_inner);
         // push a task context which goes to trap_return to the top of kernel stack
+        let kernel_stack = KernelStack::new();
         let task_cx_ptr = kernel_stack.push_on_top(TaskContext::goto_trap_return());
-        let task_control_block = Self {
-            pid: pid_handle,
+        Self {
+            process: Arc::downgrade(process),
             kernel_stack,
             inner: Mutex::new(TaskControlBlockInner {
+                res,
                 trap_cx_ppn,
-                base_size: user_sp,
                 task_cx_ptr: task_cx_ptr as usize,
                 task_status: TaskStatus::Ready,
-                memory_set,
-                parent: None,
-                children: Vec::new(),
-                exit_code: 0,
-                fd_table: vec![
-                    // 0 -> stdin
-                    Some(Arc::new(Stdin)),
-                    // 1 -> stdout
-                    Some(Arc::new(Stdout)),
-                    // 2 -> stderr
-                    Some(Arc::new(Stdout)),
-                ],
+                exit_code: None,
+                sched: SchedInfo::default(),
             }),
-        };
-        // prepare TrapContext in user space
-        let trap_cx = task_control_block.acquire_inner_lock().get_trap_cx();
-        *trap_cx = TrapContext::app_init_context(
-            entry_point,
-            user_sp,
-            KERNEL_SPACE.lock().token(),
-            kernel_stack_top,
-            trap_handler as usize,
-        );
-        task_control_block
-    }
-    pub fn exec(&self, elf_data: &[u8], args: Vec<String>) {
-        // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, mut user_sp, entry_point) = MemorySet::from_elf(elf_data);
-        let trap_cx_ppn = memory_set
-            .translate(VirtAddr::from(TRAP_CONTEXT).into())
-            .unwrap()
-            .ppn();
-        // push arguments on user stack
-        user_sp -= (args.len() + 1) * core::mem::size_of::<usize>();
-        let argv_base = user_sp;
-        let mut argv: Vec<_> = (0..=args.len())
-            .map(|arg| {
-                translated_refmut(
-                    memory_set.token(),
-                    (argv_base + arg * core::mem::size_of::<usize>()) as *mut usize
-                )
-            })
-            .collect();
-        *argv[args.len()] = 0;
-        for i in 0..args.len() {
-            user_sp -= args[i].len() + 1;
-            *argv[i] = user_sp;
-            let mut p = user_sp;
-            for c in args[i].as_bytes() {
-                *translated_refmut(memory_set.token(), p as *mut u8) = *c;
-                p += 1;
-            }
-            *translated_refmut(memory_set.token(), p as *mut u8) = 0;
         }
-        // make the user_sp aligned to 8B for k210 platform
-        user_sp -= user_sp % core::mem::size_of::<usize>();
-
-        // **** hold current PCB lock
-        let mut inner = self.acquire_inner_lock();
-        // substitute memory_set
-        inner.memory_set = memory_set;
-        // update trap_cx ppn
-        inner.trap_cx_ppn = trap_cx_ppn;
-        // initialize trap_cx
-        let mut trap_cx = TrapContext::app_init_context(
-            entry_point,
-            user_sp,
-            KERNEL_SPACE.lock().token(),
-            self.kernel_stack.get_top(),
-            trap_handler as usize,
-        );
-        trap_cx.x[10] = args.len();
-        trap_cx.x[11] = argv_base;
-        *inner.get_trap_cx() = trap_cx;
-        // **** release current PCB lock
     }
-    pub fn fork(self: &Arc<TaskControlBlock>) -> Arc<TaskControlBlock> {
-        // ---- hold parent PCB lock
-        let mut parent_inner = self.acquire_inner_lock();
-        // copy user space(include trap context)
-        let memory_set = MemorySet::from_existed_user(
-            &parent_inner.memory_set
-        );
-        let trap_cx_ppn = memory_set
-            .translate(VirtAddr::from(TRAP_CONTEXT).into())
-            .unwrap()
-            .ppn();
-        // alloc a pid and a kernel stack in kernel space
-        let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
-        let kernel_stack_top = kernel_stack.get_top();
-        // push a goto_trap_return task_cx on the top of kernel stack
-        let task_cx_ptr = kernel_stack.push_on_top(TaskContext::goto_trap_return());
-        // copy fd table
-        let mut new_fd_table: Vec<Option<Arc<dyn File + Send + Sync>>> = Vec::new();
-        for fd in parent_inner.fd_table.iter() {
-            if let Some(file) = fd {
-                new_fd_table.push(Some(file.clone()));
-            } else {
-                new_fd_table.push(None);
-            }
-        }
-        let task_control_block = Arc::new(TaskControlBlock {
-            pid: pid_handle,
-            kernel_stack,
-            inner: Mutex::new(TaskControlBlockInner {
-                trap_cx_ppn,
-                base_size: parent_inner.base_size,
-                task_cx_ptr: task_cx_ptr as usize,
-                task_status: TaskStatus::Ready,
-                memory_set,
-                parent: Some(Arc::downgrade(self)),
-                children: Vec::new(),
-                exit_code: 0,
-                fd_table: new_fd_table,
-            }),
-        });
-        // add child
-        parent_inner.children.push(task_control_block.clone());
-        // modify kernel_sp in trap_cx
-        // **** acquire child PCB lock
-        let trap_cx = task_control_block.acquire_inner_lock().get_trap_cx();
-        // **** release child PCB lock
-        trap_cx.kernel_sp = kernel_stack_top;
-        // return
-        task_control_block
-        // ---- release parent PCB lock
+    /// A thread never runs after its process is reaped.
+    pub fn process(&self) -> Arc<ProcessControlBlock> {
+        self.process.upgrade().unwrap()
     }
-    pub fn getpid(&self) -> usize {
-        self.pid.0
+    pub fn gettid(&self) -> usize {
+        self.acquire_inner_lock().res.tid
     }
-
 }
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum TaskStatus {
     Ready,
     Running,
+    /// Waiting for an event, out of the ready queue until woken up.
+    Blocked,
     Zombie,
-}
\ No newline at end of file
+}
@@ -0,0 +1,54 @@
+use super::{TaskControlBlock, current_task, wake_up};
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+
+/// Tasks blocked until an event happens.
+///
+/// A queue is kept under the lock of whatever the event is about, so that
+/// the event can not slip in between checking for it and blocking: the
+/// waiter adds itself with `add_current`, releases the lock and calls
+/// `block_current_and_run_next`, then checks again once woken up.
+///
+/// Tasks are held weakly, as one may exit by a signal while waiting.
+#[derive(Default)]
+pub struct WaitQueue {
+    queue: VecDeque<Weak<TaskControlBlock>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self { queue: VecDeque::new() }
+    }
+    /// A task woken up for another reason than the event is queued once.
+    pub fn add_current(&mut self) {
+        let task = Arc::downgrade(&current_task().unwrap());
+        if !self.queue.iter().any(|queued| queued.ptr_eq(&task)) {
+            self.queue.push_back(task);
+        }
+    }
+    /// Leave the queue without the event, as on a signal.
+    pub fn remove_current(&mut self) {
+        let task = Arc::downgrade(&current_task().unwrap());
+        self.queue.retain(|queued| !queued.ptr_eq(&task));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+    /// Return false if no task was waiting.
+    pub fn wake_one(&mut self) -> bool {
+        while let Some(task) = self.queue.pop_front() {
+            if let Some(task) = task.upgrade() {
+                wake_up(task);
+                return true;
+            }
+        }
+        false
+    }
+    pub fn wake_all(&mut self) {
+        while let Some(task) = self.queue.pop_front() {
+            if let Some(task) = task.upgrade() {
+                wake_up(task);
+            }
+        }
+    }
+}
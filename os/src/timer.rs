@@ -1,18 +1,154 @@
 use riscv::register::time;
 use crate::sbi::set_timer;
 use crate::config::CLOCK_FREQ;
+use crate::task::{
+    TaskControlBlock,
+    current_task,
+    block_current_and_run_next,
+    signal_pending,
+    wake_up,
+};
+use alloc::collections::BinaryHeap;
+use alloc::sync::{Arc, Weak};
+use core::cmp::Ordering;
+use spin::Mutex;
+use lazy_static::*;
 
 const TICKS_PER_SEC: usize = 100;
 const MSEC_PER_SEC: usize = 1000;
+const USEC_PER_SEC: usize = 1_000_000;
+const NSEC_PER_SEC: usize = 1_000_000_000;
 
 pub fn get_time() -> usize {
     time::read()
 }
 
 pub fn get_time_ms() -> usize {
-    time::read() / (CLOCK_FREQ / MSEC_PER_SEC)
+    cycles_to_ms(time::read())
+}
+
+pub fn cycles_to_ms(cycles: usize) -> usize {
+    cycles / (CLOCK_FREQ / MSEC_PER_SEC)
+}
+
+/// Clock ticks are those of the timer interrupt, reported by `sys_times`.
+pub fn cycles_to_ticks(cycles: usize) -> usize {
+    cycles / (CLOCK_FREQ / TICKS_PER_SEC)
+}
+
+/// Goldfish RTC of the QEMU virt machine, giving nanoseconds since the Unix epoch.
+#[cfg(feature = "board_qemu")]
+const RTC_BASE: usize = 0x101000;
+
+/// Return seconds since the Unix epoch.
+#[cfg(feature = "board_qemu")]
+pub fn get_real_time() -> u32 {
+    unsafe {
+        // reading the low half latches the high half
+        let low = (RTC_BASE as *const u32).read_volatile() as u64;
+        let high = ((RTC_BASE + 0x4) as *const u32).read_volatile() as u64;
+        ((high << 32 | low) / 1_000_000_000) as u32
+    }
+}
+
+/// Return seconds since boot, as the RTC of K210 is not kept across power cycles.
+#[cfg(feature = "board_k210")]
+pub fn get_real_time() -> u32 {
+    (get_time_ms() / MSEC_PER_SEC) as u32
 }
 
 pub fn set_next_trigger() {
     set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
-}
\ No newline at end of file
+}
+/// A duration, or a time since the epoch of a clock.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+/// A duration with microseconds, as reported by `sys_getrusage`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct TimeVal {
+    pub sec: usize,
+    pub usec: usize,
+}
+
+impl TimeVal {
+    pub fn from_cycles(cycles: usize) -> Self {
+        Self {
+            sec: cycles / CLOCK_FREQ,
+            usec: cycles % CLOCK_FREQ * USEC_PER_SEC / CLOCK_FREQ,
+        }
+    }
+}
+
+/// A task sleeping until `deadline`, in cycles of `get_time`, held weakly
+/// as it may exit by a signal before.
+struct Sleeper {
+    deadline: usize,
+    task: Weak<TaskControlBlock>,
+}
+
+impl PartialEq for Sleeper {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Sleeper {}
+
+impl PartialOrd for Sleeper {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Reversed, so that the heap gives the earliest deadline first.
+impl Ord for Sleeper {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+lazy_static! {
+    /// Checked on every tick, so sleeps are rounded up to the next tick.
+    static ref SLEEPERS: Mutex<BinaryHeap<Sleeper>> = Mutex::new(BinaryHeap::new());
+}
+
+/// Convert a duration to cycles of `get_time`, rounding up.
+pub fn duration_to_cycles(sec: usize, nsec: usize) -> usize {
+    sec * CLOCK_FREQ + (nsec * CLOCK_FREQ + NSEC_PER_SEC - 1) / NSEC_PER_SEC
+}
+
+/// Block the current task until `get_time` reaches `deadline`, return
+/// false if a signal came first.
+pub fn sleep_until(deadline: usize) -> bool {
+    loop {
+        if get_time() >= deadline {
+            return true;
+        }
+        if signal_pending() {
+            return false;
+        }
+        let task = Arc::downgrade(&current_task().unwrap());
+        SLEEPERS.lock().push(Sleeper { deadline, task });
+        block_current_and_run_next();
+    }
+}
+
+/// Wake up the tasks whose deadline has passed.
+pub fn check_sleepers() {
+    let now = get_time();
+    let mut sleepers = SLEEPERS.lock();
+    while let Some(sleeper) = sleepers.peek() {
+        if sleeper.deadline > now {
+            break;
+        }
+        if let Some(task) = sleepers.pop().unwrap().task.upgrade() {
+            wake_up(task);
+        }
+    }
+}
@@ -12,15 +12,23 @@ use riscv::register::{
     stval,
     sie,
 };
+use crate::fs::poll_tty;
 use crate::syscall::syscall;
 use crate::task::{
-    exit_current_and_run_next,
+    SignalFlags,
+    raise_fault_signal,
+    handle_signals,
     suspend_current_and_run_next,
+    current_task,
+    tick_task,
+    enter_kernel,
+    leave_kernel,
     current_user_token,
     current_trap_cx,
+    current_trap_cx_user_va,
 };
-use crate::timer::set_next_trigger;
-use crate::config::{TRAP_CONTEXT, TRAMPOLINE};
+use crate::timer::{set_next_trigger, check_sleepers};
+use crate::config::TRAMPOLINE;
 
 global_asm!(include_str!("trap.S"));
 
@@ -47,6 +55,7 @@ pub fn enable_timer_interrupt() {
 #[no_mangle]
 pub fn trap_handler() -> ! {
     set_kernel_trap_entry();
+    enter_kernel();
     let scause = scause::read();
     let stval = stval::read();
     match scause.cause() {
@@ -55,7 +64,7 @@ pub fn trap_handler() -> ! {
             let mut cx = current_trap_cx();
             cx.sepc += 4;
             // get system call return value
-            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12]]);
+            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12], cx.x[13]]);
             // cx is changed during sys_exec, so we have to call it again
             cx = current_trap_cx();
             cx.x[10] = result as usize;
@@ -67,22 +76,22 @@ pub fn trap_handler() -> ! {
         Trap::Exception(Exception::LoadFault) |
         Trap::Exception(Exception::LoadPageFault) => {
             println!(
-                "[kernel] {:?} in application, bad addr = {:#x}, bad instruction = {:#x}, core dumped.",
+                "[kernel] {:?} in application, bad addr = {:#x}, bad instruction = {:#x}.",
                 scause.cause(),
                 stval,
                 current_trap_cx().sepc,
             );
-            // page fault exit code
-            exit_current_and_run_next(-2);
+            raise_fault_signal(SignalFlags::SIGSEGV);
         }
         Trap::Exception(Exception::IllegalInstruction) => {
-            println!("[kernel] IllegalInstruction in application, core dumped.");
-            // illegal instruction exit code
-            exit_current_and_run_next(-3);
+            println!("[kernel] IllegalInstruction in application.");
+            raise_fault_signal(SignalFlags::SIGILL);
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            set_next_trigger();
-            suspend_current_and_run_next();
+            timer_tick();
+            if tick_task(&current_task().unwrap()) {
+                suspend_current_and_run_next();
+            }
         }
         _ => {
             panic!("Unsupported trap {:?}, stval = {:#x}!", scause.cause(), stval);
@@ -92,10 +101,19 @@ pub fn trap_handler() -> ! {
     trap_return();
 }
 
+/// Work done on every timer tick, in the trap handler or in the idle loop.
+pub fn timer_tick() {
+    set_next_trigger();
+    check_sleepers();
+    poll_tty();
+}
+
 #[no_mangle]
 pub fn trap_return() -> ! {
+    handle_signals();
+    leave_kernel();
     set_user_trap_entry();
-    let trap_cx_ptr = TRAP_CONTEXT;
+    let trap_cx_ptr = current_trap_cx_user_va();
     let user_satp = current_user_token();
     extern "C" {
         fn __alltraps();
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use user_lib::{
+    open,
+    OpenFlags,
+    close,
+    read,
+    write,
+};
+use alloc::string::String;
+use alloc::format;
+use alloc::vec;
+
+/// Usage: dd [if=FILE] [of=FILE] [bs=BYTES] [count=N]
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let mut input = String::from("/dev/stdin");
+    let mut output = String::from("/dev/stdout");
+    let mut bs = 512usize;
+    let mut count = usize::MAX;
+    for arg in argv.iter().take(argc).skip(1) {
+        if let Some(v) = arg.strip_prefix("if=") {
+            input = String::from(v);
+        } else if let Some(v) = arg.strip_prefix("of=") {
+            output = String::from(v);
+        } else if let Some(v) = arg.strip_prefix("bs=") {
+            bs = v.parse().expect("dd: invalid bs");
+        } else if let Some(v) = arg.strip_prefix("count=") {
+            count = v.parse().expect("dd: invalid count");
+        } else {
+            println!("dd: unknown operand {}", arg);
+            return -1;
+        }
+    }
+    let in_fd = open(format!("{}\0", input).as_str(), OpenFlags::RDONLY);
+    if in_fd == -1 {
+        println!("dd: cannot open {}", input);
+        return -1;
+    }
+    let out_fd = open(format!("{}\0", output).as_str(), OpenFlags::CREATE | OpenFlags::WRONLY);
+    if out_fd == -1 {
+        println!("dd: cannot open {}", output);
+        return -1;
+    }
+    let (in_fd, out_fd) = (in_fd as usize, out_fd as usize);
+    let mut buf = vec![0u8; bs];
+    let (mut blocks, mut bytes) = (0usize, 0usize);
+    while blocks < count {
+        let size = read(in_fd, &mut buf);
+        if size <= 0 { break; }
+        write(out_fd, &buf[..size as usize]);
+        blocks += 1;
+        bytes += size as usize;
+    }
+    close(in_fd);
+    close(out_fd);
+    println!("dd: {} blocks, {} bytes copied", blocks, bytes);
+    0
+}
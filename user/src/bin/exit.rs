@@ -3,7 +3,7 @@
 
 #[macro_use]
 extern crate user_lib;
-use user_lib::{fork, yield_, waitpid, exit, wait};
+use user_lib::{fork, yield_, waitpid, waitpid_flags, exit, wait, WaitFlags};
 
 const MAGIC: i32 = -0x10384;
 
@@ -20,6 +20,8 @@ pub fn main() -> i32 {
     }
     println!("I am the parent, waiting now..");
     let mut xstate: i32 = 0;
+    // the child is still yielding
+    assert_eq!(waitpid_flags(pid, &mut xstate, WaitFlags::WNOHANG), -2);
     assert!(waitpid(pid as usize, &mut xstate) == pid && xstate == MAGIC);
     assert!(waitpid(pid as usize, &mut xstate) < 0 && wait(&mut xstate) <= 0);
     println!("waitpid {} ok.", pid);
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::sync::atomic::AtomicU32;
+use user_lib::sync::Mutex;
+use user_lib::{thread_create, waittid, exit, yield_, futex_wait, futex_wake, EAGAIN};
+
+const THREADS: usize = 4;
+const ROUNDS: usize = 50;
+
+static COUNTER: Mutex<usize> = Mutex::new(0);
+
+/// Yield while holding the lock, so that the others contend for it and
+/// block in the kernel.
+extern "C" fn adder(_arg: usize) -> ! {
+    for _ in 0..ROUNDS {
+        let mut counter = COUNTER.lock();
+        let value = *counter;
+        yield_();
+        *counter = value + 1;
+    }
+    exit(0);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let word = AtomicU32::new(1);
+    assert_eq!(futex_wait(&word, 0), EAGAIN);
+    assert_eq!(futex_wake(&word, 1), 0);
+    // the trampoline is mapped in every address space, but not for the user
+    let trampoline = unsafe { &*((usize::MAX - 4096 + 1) as *const AtomicU32) };
+    assert_eq!(futex_wake(trampoline, 1), -1);
+
+    let mut tids = [0isize; THREADS];
+    for tid in tids.iter_mut() {
+        *tid = thread_create(adder, 0);
+    }
+    for tid in tids.iter() {
+        assert_eq!(waittid(*tid as usize), 0);
+    }
+    assert_eq!(*COUNTER.lock(), THREADS * ROUNDS);
+    assert!(COUNTER.try_lock().is_some());
+    let guard = COUNTER.lock();
+    assert!(COUNTER.try_lock().is_none());
+    drop(guard);
+    println!("futex_test passed!");
+    0
+}
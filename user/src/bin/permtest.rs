@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    open,
+    close,
+    write,
+    unlink,
+    fork,
+    waitpid,
+    exit,
+    getuid,
+    setuid,
+    setgid,
+    OpenFlags,
+};
+
+#[no_mangle]
+pub fn main() -> i32 {
+    assert_eq!(getuid(), 0);
+    // a file owned by root
+    let fd = open("permtest_root\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    write(fd as usize, b"root");
+    close(fd as usize);
+    let fd = open("/dev/blk0\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    close(fd as usize);
+    let pid = fork();
+    if pid == 0 {
+        assert_eq!(setgid(1000), 0);
+        assert_eq!(setuid(1000), 0);
+        assert_eq!(getuid(), 1000);
+        // no way back to root
+        assert_eq!(setuid(0), -1);
+        // others may read but not write files of root
+        let fd = open("permtest_root\0", OpenFlags::RDONLY);
+        assert!(fd > 0);
+        close(fd as usize);
+        assert_eq!(open("permtest_root\0", OpenFlags::RDWR), -1);
+        assert_eq!(open("permtest_root\0", OpenFlags::CREATE | OpenFlags::WRONLY), -1);
+        // nor remove them from the sticky root directory
+        assert_eq!(unlink("permtest_root\0"), -1);
+        // but they can do anything with their own files
+        let fd = open("permtest_user\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+        assert!(fd > 0);
+        close(fd as usize);
+        assert_eq!(unlink("permtest_user\0"), 0);
+        assert_eq!(open("permtest_user\0", OpenFlags::RDONLY), -1);
+        // device nodes belong to root, only root may use the raw disk
+        assert_eq!(open("/dev/blk0\0", OpenFlags::RDONLY), -1);
+        let fd = open("/dev/null\0", OpenFlags::WRONLY);
+        assert!(fd > 0);
+        close(fd as usize);
+        // and only the owner of a process may look at its memory
+        assert_eq!(open("/proc/1/maps\0", OpenFlags::RDONLY), -1);
+        let fd = open("/proc/self/maps\0", OpenFlags::RDONLY);
+        assert!(fd > 0);
+        close(fd as usize);
+        exit(0);
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    assert_eq!(unlink("permtest_root\0"), 0);
+    println!("permtest passed!");
+    0
+}
@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use user_lib::{
+    open,
+    OpenFlags,
+    close,
+    read,
+};
+use alloc::format;
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let path = if argc >= 2 {
+        format!("/proc/{}/maps\0", argv[1])
+    } else {
+        format!("/proc/self/maps\0")
+    };
+    let fd = open(path.as_str(), OpenFlags::RDONLY);
+    if fd == -1 {
+        println!("pmap: no such process");
+        return -1;
+    }
+    let fd = fd as usize;
+    let mut buf = [0u8; 64];
+    loop {
+        let size = read(fd, &mut buf) as usize;
+        if size == 0 { break; }
+        print!("{}", core::str::from_utf8(&buf[..size]).unwrap());
+    }
+    close(fd);
+    0
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    fork, exit, wait, get_time, getpid,
+    nice, getpriority, setpriority, PRIO_PROCESS, NICE_MAX,
+};
+
+const NICES: [isize; 3] = [0, 5, 10];
+const RUN_MS: isize = 1000;
+
+/// Count loops for a while, which a child with a lower nice value gets to
+/// do more of under the stride scheduler.
+fn spin(nice_value: isize) -> ! {
+    setpriority(PRIO_PROCESS, 0, nice_value);
+    let end = get_time() + RUN_MS;
+    let mut count: usize = 0;
+    while get_time() < end {
+        count += 1;
+    }
+    println!("pid {} with nice {} counted to {}", getpid(), nice_value, count);
+    exit(0);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    assert_eq!(getpriority(PRIO_PROCESS, 0), Some(0));
+    assert_eq!(nice(3), Some(3));
+    assert_eq!(getpriority(PRIO_PROCESS, getpid() as usize), Some(3));
+    // clamped to the range
+    assert_eq!(nice(100), Some(NICE_MAX));
+    assert_eq!(setpriority(PRIO_PROCESS, 0, 0), 0);
+    assert_eq!(getpriority(PRIO_PROCESS, 12345), None);
+    for nice_value in NICES.iter() {
+        if fork() == 0 {
+            spin(*nice_value);
+        }
+    }
+    let mut exit_code: i32 = 0;
+    for _ in NICES.iter() {
+        assert!(wait(&mut exit_code) > 0 && exit_code == 0);
+    }
+    println!("priority passed!");
+    0
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use user_lib::{
+    open,
+    OpenFlags,
+    close,
+    read,
+};
+use alloc::string::String;
+use alloc::format;
+
+fn read_to_string(path: &str) -> Option<String> {
+    let fd = open(path, OpenFlags::RDONLY);
+    if fd == -1 {
+        return None;
+    }
+    let fd = fd as usize;
+    let mut buf = [0u8; 64];
+    let mut s = String::new();
+    loop {
+        let size = read(fd, &mut buf) as usize;
+        if size == 0 { break; }
+        s.push_str(core::str::from_utf8(&buf[..size]).unwrap());
+    }
+    close(fd);
+    Some(s)
+}
+
+fn field<'a>(status: &'a str, key: &str) -> &'a str {
+    status
+        .lines()
+        .find(|line| line.starts_with(key) && line[key.len()..].starts_with(':'))
+        .map(|line| line[key.len() + 1..].trim())
+        .unwrap_or("?")
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let procs = read_to_string("/proc\0").expect("procfs is not available");
+    println!("{:>5} {:>5} {:>5} {:<12} {:>8} {:>8}", "PID", "PPID", "PGID", "STATE", "VSZ", "RSS");
+    for pid in procs.lines().filter(|name| name.parse::<usize>().is_ok()) {
+        // the process may have been reaped in the meantime
+        if let Some(status) = read_to_string(format!("/proc/{}/status\0", pid).as_str()) {
+            println!(
+                "{:>5} {:>5} {:>5} {:<12} {:>8} {:>8}",
+                pid,
+                field(&status, "PPid"),
+                field(&status, "Pgid"),
+                field(&status, "State"),
+                field(&status, "VmSize"),
+                field(&status, "VmRSS"),
+            );
+        }
+    }
+    0
+}
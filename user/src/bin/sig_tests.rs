@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use user_lib::{
+    fork, exit, getpid, kill, sleep, waitpid,
+    sigaction, sigprocmask, SignalAction, SignalFlags,
+    SIGUSR1, SIGTERM, SIGSTOP, SIGCONT, SIGKILL, SIGSEGV, SIG_BLOCK, SIG_UNBLOCK,
+};
+
+static CAUGHT: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn handler(signum: usize) {
+    CAUGHT.store(signum, Ordering::SeqCst);
+}
+
+fn caught() -> usize {
+    CAUGHT.swap(0, Ordering::SeqCst)
+}
+
+fn wait_exit_code(pid: isize) -> i32 {
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    exit_code
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let action = SignalAction::new(handler as usize, SignalFlags::empty());
+    assert_eq!(sigaction(SIGUSR1, Some(&action), None), 0);
+    assert!(sigaction(SIGKILL, Some(&action), None) < 0);
+    // delivered on the way back from kill
+    assert_eq!(kill(getpid(), SIGUSR1), 0);
+    assert_eq!(caught(), SIGUSR1);
+    println!("handler ok.");
+
+    assert_eq!(sigprocmask(SIG_BLOCK, Some(SignalFlags::SIGUSR1), None), 0);
+    kill(getpid(), SIGUSR1);
+    assert_eq!(caught(), 0);
+    sigprocmask(SIG_UNBLOCK, Some(SignalFlags::SIGUSR1), None);
+    assert_eq!(caught(), SIGUSR1);
+    println!("mask ok.");
+
+    // a sleeping child is killed at once
+    let pid = fork();
+    if pid == 0 {
+        sleep(100_000);
+        exit(0);
+    }
+    sleep(10);
+    kill(pid, SIGTERM);
+    assert_eq!(wait_exit_code(pid), -(SIGTERM as i32));
+    println!("kill ok.");
+
+    let pid = fork();
+    if pid == 0 {
+        loop {}
+    }
+    kill(pid, SIGSTOP);
+    kill(pid, SIGCONT);
+    kill(pid, SIGSTOP);
+    sleep(10);
+    kill(pid, SIGKILL);
+    assert_eq!(wait_exit_code(pid), -(SIGKILL as i32));
+    println!("stop ok.");
+
+    let pid = fork();
+    if pid == 0 {
+        unsafe { (0 as *mut u8).write_volatile(0); }
+        exit(0);
+    }
+    assert_eq!(wait_exit_code(pid), -(SIGSEGV as i32));
+    println!("fault ok.");
+    println!("sig_tests passed!");
+    0
+}
@@ -4,7 +4,7 @@
 #[macro_use]
 extern crate user_lib;
 
-use user_lib::{get_time, sleep};
+use user_lib::{get_time, sleep, clock_nanosleep, TimeSpec, CLOCK_MONOTONIC, TIMER_ABSTIME};
 
 #[no_mangle]
 pub fn main() -> i32 {
@@ -14,6 +14,11 @@ pub fn main() -> i32 {
     sleep(100);
     let end = get_time();
     println!("time_msec = {} after sleeping 100 ticks, delta = {}ms!", end, end - start);
+    assert!(end - start >= 100);
+    // the monotonic clock counts from boot, as get_time does
+    let deadline = end as usize + 50;
+    assert_eq!(clock_nanosleep(CLOCK_MONOTONIC, TIMER_ABSTIME, &TimeSpec::from_ms(deadline)), 0);
+    assert!(get_time() as usize >= deadline);
     println!("r_sleep passed!");
     0
 }
\ No newline at end of file
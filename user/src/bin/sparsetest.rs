@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    open,
+    close,
+    read,
+    write,
+    fallocate,
+    unlink,
+    FallocFlags,
+    OpenFlags,
+};
+
+const BLOCK: usize = 512;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = open("sparsetest\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let data = [b'a'; BLOCK];
+    for _ in 0..4 {
+        assert_eq!(write(fd, &data), BLOCK as isize);
+    }
+    // the second block becomes a hole, half of the third is zeroed
+    let punch = FallocFlags::PUNCH_HOLE | FallocFlags::KEEP_SIZE;
+    assert_eq!(fallocate(fd, punch, BLOCK, BLOCK + BLOCK / 2), 0);
+    // punching has to keep the size
+    assert_eq!(fallocate(fd, FallocFlags::PUNCH_HOLE, 0, BLOCK), -1);
+    // grow the file by two blocks of zeros
+    assert_eq!(fallocate(fd, FallocFlags::empty(), 4 * BLOCK, 2 * BLOCK), 0);
+    close(fd);
+
+    let fd = open("sparsetest\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let mut buffer = [0u8; 6 * BLOCK];
+    let mut total = 0;
+    loop {
+        let len = read(fd, &mut buffer[total..]);
+        if len <= 0 {
+            break;
+        }
+        total += len as usize;
+    }
+    close(fd);
+    assert_eq!(total, 6 * BLOCK);
+    for (i, byte) in buffer.iter().enumerate() {
+        let zero = (BLOCK..BLOCK * 5 / 2).contains(&i) || i >= 4 * BLOCK;
+        assert_eq!(*byte, if zero { 0 } else { b'a' });
+    }
+    assert_eq!(unlink("sparsetest\0"), 0);
+    println!("sparsetest passed!");
+    0
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    thread_create,
+    waittid,
+    exit,
+    yield_,
+    mutex_create,
+    mutex_lock,
+    mutex_unlock,
+    semaphore_create,
+    semaphore_up,
+    semaphore_down,
+    condvar_create,
+    condvar_signal,
+    condvar_wait,
+};
+
+const THREADS: usize = 4;
+const ROUNDS: usize = 50;
+const ITEMS: usize = 20;
+
+static mut MUTEX: usize = 0;
+static mut COUNTER: usize = 0;
+
+static mut EMPTY: usize = 0;
+static mut FULL: usize = 0;
+static mut SLOT: usize = 0;
+
+static mut CONDVAR: usize = 0;
+static mut READY: bool = false;
+
+/// Increment without an atomic, yielding in the middle so that another
+/// thread would step in if the mutex did not keep it out.
+extern "C" fn adder(_arg: usize) -> ! {
+    for _ in 0..ROUNDS {
+        unsafe {
+            assert_eq!(mutex_lock(MUTEX), 0);
+            let value = COUNTER;
+            yield_();
+            COUNTER = value + 1;
+            assert_eq!(mutex_unlock(MUTEX), 0);
+        }
+    }
+    exit(0);
+}
+
+/// Hand items one at a time through a slot guarded by two semaphores.
+extern "C" fn producer(_arg: usize) -> ! {
+    for item in 1..=ITEMS {
+        unsafe {
+            assert_eq!(semaphore_down(EMPTY), 0);
+            SLOT = item;
+            assert_eq!(semaphore_up(FULL), 0);
+        }
+    }
+    exit(0);
+}
+
+extern "C" fn waker(_arg: usize) -> ! {
+    yield_();
+    unsafe {
+        assert_eq!(mutex_lock(MUTEX), 0);
+        READY = true;
+        assert_eq!(condvar_signal(CONDVAR), 0);
+        assert_eq!(mutex_unlock(MUTEX), 0);
+    }
+    exit(0);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    unsafe {
+        MUTEX = mutex_create() as usize;
+        assert_eq!(mutex_unlock(MUTEX), -1);
+        assert_eq!(mutex_lock(MUTEX + 1), -1);
+        let mut tids = [0isize; THREADS];
+        for tid in tids.iter_mut() {
+            *tid = thread_create(adder, 0);
+        }
+        for tid in tids.iter() {
+            assert_eq!(waittid(*tid as usize), 0);
+        }
+        assert_eq!(COUNTER, THREADS * ROUNDS);
+        println!("mutex passed!");
+
+        EMPTY = semaphore_create(1) as usize;
+        FULL = semaphore_create(0) as usize;
+        let tid = thread_create(producer, 0);
+        for item in 1..=ITEMS {
+            assert_eq!(semaphore_down(FULL), 0);
+            assert_eq!(SLOT, item);
+            assert_eq!(semaphore_up(EMPTY), 0);
+        }
+        assert_eq!(waittid(tid as usize), 0);
+        println!("semaphore passed!");
+
+        CONDVAR = condvar_create() as usize;
+        let tid = thread_create(waker, 0);
+        assert_eq!(mutex_lock(MUTEX), 0);
+        while !READY {
+            assert_eq!(condvar_wait(CONDVAR, MUTEX), 0);
+        }
+        assert_eq!(mutex_unlock(MUTEX), 0);
+        assert_eq!(waittid(tid as usize), 0);
+        println!("condvar passed!");
+    }
+    println!("sync_test passed!");
+    0
+}
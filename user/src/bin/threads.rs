@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    thread_create, gettid, waittid, exit, fork, waitpid, sleep, pipe, read,
+    mutex_create, mutex_lock, condvar_create, condvar_wait,
+};
+
+const N: usize = 16;
+const THREADS: usize = 4;
+type Arr = [[u64; N]; N];
+
+static mut A: Arr = [[0; N]; N];
+static mut B: Arr = [[0; N]; N];
+static mut C: Arr = [[0; N]; N];
+
+/// Multiply a band of rows, the threads share the matrices.
+extern "C" fn worker(band: usize) -> ! {
+    for i in band * N / THREADS..(band + 1) * N / THREADS {
+        for j in 0..N {
+            unsafe {
+                C[i][j] = (0..N).map(|k| A[i][k] * B[k][j]).sum();
+            }
+        }
+    }
+    exit(gettid() as i32 * 10);
+}
+
+/// Block for good, on a pipe nobody writes to, a condition variable or
+/// the main thread.
+extern "C" fn blocker(kind: usize) -> ! {
+    match kind {
+        0 => {
+            let mut pipe_fd = [0usize; 2];
+            assert_eq!(pipe(&mut pipe_fd), 0);
+            read(pipe_fd[0], &mut [0u8; 1]);
+        }
+        1 => {
+            let mutex = mutex_create() as usize;
+            mutex_lock(mutex);
+            condvar_wait(condvar_create() as usize, mutex);
+        }
+        _ => {
+            waittid(0);
+        }
+    }
+    exit(-1);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    assert_eq!(gettid(), 0);
+    unsafe {
+        for i in 0..N {
+            for j in 0..N {
+                A[i][j] = (i + j) as u64;
+                B[i][j] = (i * j % 7) as u64;
+            }
+        }
+    }
+    let mut tids = [0isize; THREADS];
+    for band in 0..THREADS {
+        tids[band] = thread_create(worker, band);
+        assert!(tids[band] > 0);
+    }
+    // only a process with a single thread may fork
+    assert_eq!(fork(), -1);
+    for tid in tids.iter() {
+        assert_eq!(waittid(*tid as usize), *tid * 10);
+        assert_eq!(waittid(*tid as usize), -1);
+    }
+    assert_eq!(waittid(0), -1);
+    for i in 0..N {
+        for j in 0..N {
+            let expected: u64 = (0..N).map(|k| ((i + k) * (k * j % 7)) as u64).sum();
+            assert_eq!(unsafe { C[i][j] }, expected);
+        }
+    }
+    // the main thread exits the process with the others blocked, which
+    // must not keep it from being freed when reaped
+    let pid = fork();
+    if pid == 0 {
+        for kind in 0..3 {
+            assert!(thread_create(blocker, kind) > 0);
+        }
+        sleep(10);
+        exit(7);
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 7);
+    println!("threads passed!");
+    0
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+#[macro_use]
+extern crate user_lib;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use user_lib::{
+    fork, exec, exit, waitpid, get_time, getrusage,
+    RUsage, RUSAGE_CHILDREN,
+};
+
+fn print_time(name: &str, ms: usize) {
+    println!("{}\t{}.{:03}s", name, ms / 1000, ms % 1000);
+}
+
+/// Run a command and report the time it took, and the CPU time it and its
+/// children spent in user and kernel mode.
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 2 {
+        println!("usage: time COMMAND [ARG]...");
+        return 1;
+    }
+    let args: Vec<String> = argv[1..]
+        .iter()
+        .map(|arg| {
+            let mut arg = String::from(*arg);
+            arg.push('\0');
+            arg
+        })
+        .collect();
+    let mut args_addr: Vec<*const u8> = args.iter().map(|arg| arg.as_ptr()).collect();
+    args_addr.push(0 as *const u8);
+    let start = get_time();
+    let pid = fork();
+    if pid == 0 {
+        if exec(args[0].as_str(), args_addr.as_slice()) == -1 {
+            println!("time: cannot execute {}", argv[1]);
+            exit(127);
+        }
+        unreachable!();
+    }
+    let mut exit_code: i32 = 0;
+    waitpid(pid as usize, &mut exit_code);
+    let real = (get_time() - start) as usize;
+    let mut usage = RUsage::default();
+    getrusage(RUSAGE_CHILDREN, &mut usage);
+    print_time("\nreal", real);
+    print_time("user", usage.utime.as_ms());
+    print_time("sys", usage.stime.as_ms());
+    exit_code
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    fork, exit, wait, get_time, times, getrusage,
+    Tms, RUsage, CLK_TCK, RUSAGE_SELF, RUSAGE_CHILDREN,
+};
+
+const SPIN_MS: isize = 300;
+
+fn spin() {
+    let end = get_time() + SPIN_MS;
+    while get_time() < end {}
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let start = get_time();
+    let mut tms = Tms::default();
+    let ticks = times(&mut tms);
+    assert!(ticks as usize >= tms.utime + tms.stime);
+    assert_eq!((tms.cutime, tms.cstime), (0, 0));
+    spin();
+    let mut usage = RUsage::default();
+    assert_eq!(getrusage(RUSAGE_SELF, &mut usage), 0);
+    assert!(usage.utime.as_ms() + usage.stime.as_ms() > 0);
+    if fork() == 0 {
+        spin();
+        exit(0);
+    }
+    let mut exit_code: i32 = 0;
+    assert!(wait(&mut exit_code) > 0);
+    // only reaped children are counted
+    times(&mut tms);
+    assert!(tms.cutime + tms.cstime > 0);
+    assert!((tms.cutime + tms.cstime) * 1000 / CLK_TCK <= (get_time() - start) as usize + 10);
+    assert_eq!(getrusage(RUSAGE_CHILDREN, &mut usage), 0);
+    assert!(usage.utime.as_ms() > 0);
+    assert_eq!(getrusage(1, &mut usage), -1);
+    println!("times_test passed!");
+    0
+}
@@ -16,17 +16,146 @@ use alloc::vec::Vec;
 use user_lib::{
     fork,
     exec,
-    waitpid,
+    waitpid_flags,
+    WaitFlags,
+    stop_signal,
     open,
     OpenFlags,
     close,
     dup,
+    getpid,
+    setpgid,
+    setsid,
+    tcsetpgrp,
+    kill,
+    sigaction,
+    SignalAction,
+    SignalFlags,
+    SIG_IGN,
+    SIG_DFL,
+    SIGINT,
+    SIGQUIT,
+    SIGTSTP,
+    SIGTTIN,
+    SIGTTOU,
+    SIGCONT,
 };
 use user_lib::console::getchar;
 
+/// Job control signals, ignored by the shell and not by its children.
+const JOB_SIGNALS: [usize; 5] = [SIGINT, SIGQUIT, SIGTSTP, SIGTTIN, SIGTTOU];
+
+fn set_job_signals(handler: usize) {
+    let action = SignalAction::new(handler, SignalFlags::empty());
+    for signum in JOB_SIGNALS.iter() {
+        sigaction(*signum, Some(&action), None);
+    }
+}
+
+/// A command in its own process group, whose id is its pid.
+struct Job {
+    id: usize,
+    pgid: usize,
+    command: String,
+    stopped: bool,
+}
+
+/// Jobs in the background or stopped.
+struct Jobs {
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    fn next_id(&self) -> usize {
+        self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1
+    }
+    /// The job `arg`, or the last one.
+    fn take(&mut self, arg: Option<&str>) -> Option<Job> {
+        let idx = match arg {
+            Some(arg) => {
+                let id = arg.trim_start_matches('%').parse::<usize>().ok()?;
+                self.jobs.iter().position(|job| job.id == id)?
+            }
+            None => self.jobs.len().checked_sub(1)?,
+        };
+        Some(self.jobs.remove(idx))
+    }
+    /// Report the jobs that have stopped or exited in the background.
+    fn reap(&mut self) {
+        loop {
+            let mut exit_code: i32 = 0;
+            let pid = waitpid_flags(-1, &mut exit_code, WaitFlags::WNOHANG | WaitFlags::WUNTRACED);
+            if pid <= 0 {
+                break;
+            }
+            if let Some(idx) = self.jobs.iter().position(|job| job.pgid == pid as usize) {
+                if stop_signal(exit_code).is_some() {
+                    self.jobs[idx].stopped = true;
+                    println!("[{}] Stopped {}", self.jobs[idx].id, self.jobs[idx].command);
+                } else {
+                    let job = self.jobs.remove(idx);
+                    println!("[{}] Done ({}) {}", job.id, exit_code, job.command);
+                }
+            }
+        }
+    }
+    /// Give the terminal to `job` until it exits or stops.
+    fn wait_foreground(&mut self, mut job: Job) {
+        tcsetpgrp(0, job.pgid);
+        let mut exit_code: i32 = 0;
+        let exit_pid = waitpid_flags(job.pgid as isize, &mut exit_code, WaitFlags::WUNTRACED);
+        tcsetpgrp(0, getpid() as usize);
+        assert_eq!(job.pgid as isize, exit_pid);
+        if stop_signal(exit_code).is_some() {
+            job.stopped = true;
+            println!("");
+            println!("[{}] Stopped {}", job.id, job.command);
+            self.jobs.push(job);
+        } else {
+            println!("Shell: Process {} exited with code {}", exit_pid, exit_code);
+        }
+    }
+    fn builtin(&mut self, args: &[&str]) -> bool {
+        match args[0] {
+            "jobs" => {
+                for job in self.jobs.iter() {
+                    let state = if job.stopped { "Stopped" } else { "Running" };
+                    println!("[{}] {} {}", job.id, state, job.command);
+                }
+            }
+            "fg" => match self.take(args.get(1).copied()) {
+                Some(mut job) => {
+                    println!("{}", job.command);
+                    tcsetpgrp(0, job.pgid);
+                    kill(-(job.pgid as isize), SIGCONT);
+                    job.stopped = false;
+                    self.wait_foreground(job);
+                }
+                None => println!("fg: no such job"),
+            },
+            "bg" => match self.take(args.get(1).copied()) {
+                Some(mut job) => {
+                    kill(-(job.pgid as isize), SIGCONT);
+                    job.stopped = false;
+                    println!("[{}] {} &", job.id, job.command);
+                    self.jobs.push(job);
+                }
+                None => println!("bg: no such job"),
+            },
+            _ => return false,
+        }
+        true
+    }
+}
+
 #[no_mangle]
 pub fn main() -> i32 {
     println!("Rust user shell");
+    // lead a session owning the terminal, in which each command is a job
+    setsid();
+    tcsetpgrp(0, getpid() as usize);
+    set_job_signals(SIG_IGN);
+    let mut jobs = Jobs { jobs: Vec::new() };
     let mut line: String = String::new();
     print!(">> ");
     loop {
@@ -34,8 +163,13 @@ pub fn main() -> i32 {
         match c {
             LF | CR => {
                 println!("");
-                if !line.is_empty() {
-                    let args: Vec<_> = line.as_str().split(' ').collect();
+                let mut command = line.trim();
+                let background = command.ends_with('&');
+                if background {
+                    command = command[..command.len() - 1].trim_end();
+                }
+                let args: Vec<_> = command.split(' ').collect();
+                if !command.is_empty() && !jobs.builtin(&args) {
                     let mut args_copy: Vec<String> = args
                     .iter()
                     .map(|&arg| {
@@ -78,6 +212,11 @@ pub fn main() -> i32 {
                     args_addr.push(0 as *const u8);
                     let pid = fork();
                     if pid == 0 {
+                        setpgid(0, 0);
+                        if !background {
+                            tcsetpgrp(0, getpid() as usize);
+                        }
+                        set_job_signals(SIG_DFL);
                         // input redirection
                         if !input.is_empty() {
                             let input_fd = open(input.as_str(), OpenFlags::RDONLY);
@@ -112,13 +251,24 @@ pub fn main() -> i32 {
                         }
                         unreachable!();
                     } else {
-                        let mut exit_code: i32 = 0;
-                        let exit_pid = waitpid(pid as usize, &mut exit_code);
-                        assert_eq!(pid, exit_pid);
-                        println!("Shell: Process {} exited with code {}", pid, exit_code);
+                        // as in the child, whichever runs first
+                        setpgid(pid as usize, pid as usize);
+                        let job = Job {
+                            id: jobs.next_id(),
+                            pgid: pid as usize,
+                            command: String::from(command),
+                            stopped: false,
+                        };
+                        if background {
+                            println!("[{}] {}", job.id, pid);
+                            jobs.jobs.push(job);
+                        } else {
+                            jobs.wait_foreground(job);
+                        }
                     }
-                    line.clear();
                 }
+                line.clear();
+                jobs.reap();
                 print!(">> ");
             }
             BS | DL => {
@@ -10,11 +10,17 @@ static TESTS: &[&str] = &[
     "forktest\0",
     "forktest2\0",
     "forktest_simple\0",
+    "futex_test\0",
     "hello_world\0",
     "matrix\0",
+    "priority\0",
+    "sig_tests\0",
     "sleep\0",
     "sleep_simple\0",
     "stack_overflow\0",
+    "sync_test\0",
+    "threads\0",
+    "times_test\0",
     "yield\0",
 ];
 
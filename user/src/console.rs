@@ -3,7 +3,7 @@ use core::fmt::{self, Write};
 const STDIN: usize = 0;
 const STDOUT: usize = 1;
 
-use super::{read, write};
+use super::{read, write, EINTR};
 
 struct Stdout;
 
@@ -32,8 +32,9 @@ macro_rules! println {
     }
 }
 
+/// Retried if interrupted by a signal, as after being stopped and continued.
 pub fn getchar() -> u8 {
     let mut c = [0u8; 1];
-    read(STDIN, &mut c);
+    while read(STDIN, &mut c) == EINTR {}
     c[0]
 }
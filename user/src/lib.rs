@@ -1,11 +1,13 @@
 #![no_std]
 #![feature(llvm_asm)]
+#![feature(global_asm)]
 #![feature(linkage)]
 #![feature(panic_info_message)]
 #![feature(alloc_error_handler)]
 
 #[macro_use]
 pub mod console;
+pub mod sync;
 mod syscall;
 mod lang_items;
 
@@ -16,6 +18,7 @@ extern crate bitflags;
 use syscall::*;
 use buddy_system_allocator::LockedHeap;
 use alloc::vec::Vec;
+use core::sync::atomic::AtomicU32;
 
 const USER_HEAP_SIZE: usize = 32768;
 
@@ -69,40 +72,334 @@ bitflags! {
     }
 }
 
+bitflags! {
+    pub struct FallocFlags: u32 {
+        const KEEP_SIZE = 1 << 0;
+        const PUNCH_HOLE = 1 << 1;
+    }
+}
+
+bitflags! {
+    pub struct WaitFlags: u32 {
+        const WNOHANG = 1 << 0;
+        /// Also return for a stopped child, see `stop_signal`.
+        const WUNTRACED = 1 << 1;
+    }
+}
+
+/// The signal that stopped a child, if `exit_code` from `waitpid_flags`
+/// reports one rather than an exit.
+pub fn stop_signal(exit_code: i32) -> Option<usize> {
+    if exit_code & 0xff == 0x7f {
+        Some((exit_code >> 8) as usize)
+    } else {
+        None
+    }
+}
+
+/// A blocking call was interrupted by a signal.
+pub const EINTR: isize = -4;
+/// A futex word did not hold the expected value.
+pub const EAGAIN: isize = -11;
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+
+const TIOCGPGRP: usize = 0x540f;
+const TIOCSPGRP: usize = 0x5410;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+impl TimeSpec {
+    pub fn from_ms(ms: usize) -> Self {
+        Self { sec: ms / 1000, nsec: ms % 1000 * 1_000_000 }
+    }
+}
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+/// `req` of `clock_nanosleep` is a time of the clock, not a duration.
+pub const TIMER_ABSTIME: u32 = 1;
+
+/// Process times in clock ticks, `CLK_TCK` to a second.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tms {
+    pub utime: usize,
+    pub stime: usize,
+    /// Times of the reaped children, with those of their own children.
+    pub cutime: usize,
+    pub cstime: usize,
+}
+
+pub const CLK_TCK: usize = 100;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeVal {
+    pub sec: usize,
+    pub usec: usize,
+}
+
+impl TimeVal {
+    pub fn as_ms(&self) -> usize {
+        self.sec * 1000 + self.usec / 1000
+    }
+}
+
+/// As `struct rusage` of Linux, of which only the times are filled in.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RUsage {
+    pub utime: TimeVal,
+    pub stime: TimeVal,
+    others: [usize; 14],
+}
+
+pub const RUSAGE_SELF: isize = 0;
+pub const RUSAGE_CHILDREN: isize = -1;
+
+bitflags! {
+    /// A set of signals, signal `n` is bit `n`.
+    pub struct SignalFlags: u32 {
+        const SIGHUP = 1 << 1;
+        const SIGINT = 1 << 2;
+        const SIGQUIT = 1 << 3;
+        const SIGILL = 1 << 4;
+        const SIGTRAP = 1 << 5;
+        const SIGABRT = 1 << 6;
+        const SIGBUS = 1 << 7;
+        const SIGFPE = 1 << 8;
+        const SIGKILL = 1 << 9;
+        const SIGUSR1 = 1 << 10;
+        const SIGSEGV = 1 << 11;
+        const SIGUSR2 = 1 << 12;
+        const SIGPIPE = 1 << 13;
+        const SIGALRM = 1 << 14;
+        const SIGTERM = 1 << 15;
+        const SIGSTKFLT = 1 << 16;
+        const SIGCHLD = 1 << 17;
+        const SIGCONT = 1 << 18;
+        const SIGSTOP = 1 << 19;
+        const SIGTSTP = 1 << 20;
+        const SIGTTIN = 1 << 21;
+        const SIGTTOU = 1 << 22;
+        const SIGURG = 1 << 23;
+        const SIGXCPU = 1 << 24;
+        const SIGXFSZ = 1 << 25;
+        const SIGVTALRM = 1 << 26;
+        const SIGPROF = 1 << 27;
+        const SIGWINCH = 1 << 28;
+        const SIGIO = 1 << 29;
+        const SIGPWR = 1 << 30;
+        const SIGSYS = 1 << 31;
+    }
+}
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGABRT: usize = 6;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+/// A handler is an `extern "C" fn(signum: usize)`, it runs with `mask` and
+/// its own signal blocked. `sigaction` fills in `restorer`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SignalAction {
+    pub handler: usize,
+    pub mask: SignalFlags,
+    pub restorer: usize,
+}
+
+impl SignalAction {
+    pub fn new(handler: usize, mask: SignalFlags) -> Self {
+        Self { handler, mask, restorer: 0 }
+    }
+}
+
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+// Handlers return here, with the stack pointer at the frame the kernel
+// pushed, which `sys_sigreturn` (139) pops.
+global_asm!("
+    .section .text
+    .globl __sigreturn
+__sigreturn:
+    li a7, 139
+    ecall
+");
+
+extern "C" {
+    fn __sigreturn();
+}
+
+pub const PRIO_PROCESS: usize = 0;
+pub const PRIO_PGRP: usize = 1;
+/// Nice values range from -20, the most favoured, to 19.
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
+
+/// Paths are relative to the current directory.
+const AT_FDCWD: isize = -100;
+
 pub fn dup(fd: usize) -> isize { sys_dup(fd) }
 pub fn open(path: &str, flags: OpenFlags) -> isize { sys_open(path, flags.bits) }
 pub fn close(fd: usize) -> isize { sys_close(fd) }
+pub fn unlink(path: &str) -> isize { sys_unlinkat(AT_FDCWD, path, 0) }
+pub fn fallocate(fd: usize, mode: FallocFlags, offset: usize, len: usize) -> isize {
+    sys_fallocate(fd, mode.bits, offset, len)
+}
 pub fn pipe(pipe_fd: &mut [usize]) -> isize { sys_pipe(pipe_fd) }
 pub fn read(fd: usize, buf: &mut [u8]) -> isize { sys_read(fd, buf) }
 pub fn write(fd: usize, buf: &[u8]) -> isize { sys_write(fd, buf) }
 pub fn exit(exit_code: i32) -> ! { sys_exit(exit_code); }
 pub fn yield_() -> isize { sys_yield() }
+pub fn kill(pid: isize, signum: usize) -> isize { sys_kill(pid, signum) }
+pub fn sigaction(signum: usize, action: Option<&SignalAction>, old_action: Option<&mut SignalAction>) -> isize {
+    let action = action.map(|action| SignalAction {
+        restorer: __sigreturn as usize,
+        ..*action
+    });
+    sys_sigaction(
+        signum,
+        action.as_ref().map_or(core::ptr::null(), |action| action as *const _),
+        old_action.map_or(core::ptr::null_mut(), |old_action| old_action as *mut _),
+    )
+}
+pub fn sigprocmask(how: usize, set: Option<SignalFlags>, old_set: Option<&mut SignalFlags>) -> isize {
+    let set = set.map(|set| set.bits);
+    let mut old = 0u32;
+    let ret = sys_sigprocmask(
+        how,
+        set.as_ref().map_or(core::ptr::null(), |set| set as *const _),
+        &mut old as *mut _,
+    );
+    if let Some(old_set) = old_set {
+        *old_set = SignalFlags::from_bits_truncate(old);
+    }
+    ret
+}
+/// A `who` of 0 is the caller, or its process group.
+pub fn setpriority(which: usize, who: usize, nice: isize) -> isize { sys_setpriority(which, who, nice) }
+/// Return the nice value, or `None` if there is no such process.
+pub fn getpriority(which: usize, who: usize) -> Option<isize> {
+    match sys_getpriority(which, who) {
+        -1 => None,
+        prio => Some(20 - prio),
+    }
+}
+/// Add `inc` to the nice value of the caller and return the new one, or
+/// `None` if it may not be lowered.
+pub fn nice(inc: isize) -> Option<isize> {
+    let nice = (getpriority(PRIO_PROCESS, 0)? + inc).max(NICE_MIN).min(NICE_MAX);
+    match setpriority(PRIO_PROCESS, 0, nice) {
+        0 => Some(nice),
+        _ => None,
+    }
+}
+/// Return the clock ticks since boot.
+pub fn times(tms: &mut Tms) -> isize { sys_times(tms as *mut _) }
+pub fn getrusage(who: isize, usage: &mut RUsage) -> isize { sys_getrusage(who, usage as *mut _) }
 pub fn get_time() -> isize { sys_get_time() }
 pub fn getpid() -> isize { sys_getpid() }
+pub fn getuid() -> isize { sys_getuid() }
+pub fn getgid() -> isize { sys_getgid() }
+pub fn setuid(uid: usize) -> isize { sys_setuid(uid) }
+pub fn setgid(gid: usize) -> isize { sys_setgid(gid) }
+pub fn setpgid(pid: usize, pgid: usize) -> isize { sys_setpgid(pid, pgid) }
+pub fn getpgid(pid: usize) -> isize { sys_getpgid(pid) }
+pub fn setsid() -> isize { sys_setsid() }
+pub fn getsid(pid: usize) -> isize { sys_getsid(pid) }
+/// The foreground process group of the terminal `fd`.
+pub fn tcgetpgrp(fd: usize) -> isize {
+    let mut pgid: i32 = 0;
+    match sys_ioctl(fd, TIOCGPGRP, &mut pgid as *mut _ as usize) {
+        0 => pgid as isize,
+        err => err,
+    }
+}
+pub fn tcsetpgrp(fd: usize, pgid: usize) -> isize {
+    let pgid = pgid as i32;
+    sys_ioctl(fd, TIOCSPGRP, &pgid as *const _ as usize)
+}
+/// Only a process with a single thread may fork or exec.
 pub fn fork() -> isize { sys_fork() }
 pub fn exec(path: &str, args: &[*const u8]) -> isize { sys_exec(path, args) }
+/// Block until a child exits, -1 if there is no child.
 pub fn wait(exit_code: &mut i32) -> isize {
-    loop {
-        match sys_waitpid(-1, exit_code as *mut _) {
-            -2 => { yield_(); }
-            // -1 or a real pid
-            exit_pid => return exit_pid,
-        }
-    }
+    sys_waitpid(-1, exit_code as *mut _, 0)
 }
 
 pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
-    loop {
-        match sys_waitpid(pid as isize, exit_code as *mut _) {
-            -2 => { yield_(); }
-            // -1 or a real pid
-            exit_pid => return exit_pid,
-        }
-    }
+    sys_waitpid(pid as isize, exit_code as *mut _, 0)
+}
+
+/// With `WaitFlags::WNOHANG`, return -2 instead of blocking if the child is
+/// still running. A `pid` of -1 stands for any child.
+pub fn waitpid_flags(pid: isize, exit_code: &mut i32, flags: WaitFlags) -> isize {
+    sys_waitpid(pid, exit_code as *mut _, flags.bits)
+}
+pub fn nanosleep(req: &TimeSpec) -> isize { sys_nanosleep(req) }
+pub fn clock_nanosleep(clock_id: usize, flags: u32, req: &TimeSpec) -> isize {
+    sys_clock_nanosleep(clock_id, flags, req)
 }
 pub fn sleep(period_ms: usize) {
-    let start = sys_get_time();
-    while sys_get_time() < start + period_ms as isize {
-        sys_yield();
-    }
-}
\ No newline at end of file
+    sys_nanosleep(&TimeSpec::from_ms(period_ms));
+}
+/// Start a thread running `entry` with `arg`, and return its tid. The
+/// thread has nothing to return to, it has to end with `exit`, which only
+/// ends the whole process from the main thread.
+pub fn thread_create(entry: extern "C" fn(usize) -> !, arg: usize) -> isize {
+    sys_thread_create(entry as usize, arg)
+}
+pub fn gettid() -> isize { sys_gettid() }
+/// Block until the thread `tid` exits and return its exit code, -1 if there
+/// is no such thread or it is the caller.
+pub fn waittid(tid: usize) -> isize { sys_waittid(tid) }
+/// Mutexes, semaphores and condition variables for the threads of the
+/// process, by id. A thread blocked on one returns `EINTR` on a signal.
+pub fn mutex_create() -> isize { sys_mutex_create() }
+pub fn mutex_lock(id: usize) -> isize { sys_mutex_lock(id) }
+pub fn mutex_unlock(id: usize) -> isize { sys_mutex_unlock(id) }
+pub fn semaphore_create(count: usize) -> isize { sys_semaphore_create(count) }
+pub fn semaphore_up(id: usize) -> isize { sys_semaphore_up(id) }
+pub fn semaphore_down(id: usize) -> isize { sys_semaphore_down(id) }
+pub fn condvar_create() -> isize { sys_condvar_create() }
+pub fn condvar_signal(id: usize) -> isize { sys_condvar_signal(id) }
+pub fn condvar_broadcast(id: usize) -> isize { sys_condvar_broadcast(id) }
+/// Release the mutex until the condition variable is signaled, then take it
+/// again. The condition is to be checked again in a loop.
+pub fn condvar_wait(condvar_id: usize, mutex_id: usize) -> isize { sys_condvar_wait(condvar_id, mutex_id) }
+/// Block while `word` holds `expected`, `EAGAIN` if it does not. It may
+/// return without a wake, so `word` is to be checked again in a loop.
+pub fn futex_wait(word: &AtomicU32, expected: u32) -> isize {
+    sys_futex(word as *const AtomicU32 as *const u32, FUTEX_WAIT, expected as usize)
+}
+/// Wake up at most `count` threads blocked on `word`, return how many.
+pub fn futex_wake(word: &AtomicU32, count: usize) -> isize {
+    sys_futex(word as *const AtomicU32 as *const u32, FUTEX_WAKE, count)
+}